@@ -0,0 +1,226 @@
+use std::{
+    env, fs,
+    io::{self, Read},
+    process::ExitCode,
+};
+
+use reg_rust::{
+    diff::diff,
+    fsm::ReError,
+    lint::lint,
+    parser::class_ranges,
+    simplify::{simplifications, simplify},
+    spec::Spec,
+    Regex,
+};
+
+const USAGE: &str = "usage: reg test <spec.toml>...\n       reg fmt --simplify <pattern>...\n       reg class <pattern>...\n       reg highlight <pattern> [<file>...]\n       reg diff <old> <new>\n       reg lint <pattern>...";
+
+/// `reg test` runs the cases of each spec file and reports every failure,
+/// exiting with a non-zero status if any case failed.
+fn test(paths: &[String]) -> ExitCode {
+    let mut failed = 0;
+    let mut total = 0;
+    for path in paths {
+        let spec = match fs::read_to_string(path)
+            .map_err(|error| error.to_string())
+            .and_then(|source| Spec::parse(&source).map_err(|error| error.to_string()))
+        {
+            Ok(spec) => spec,
+            Err(error) => {
+                eprintln!("{}: {}", path, error);
+                return ExitCode::from(2);
+            }
+        };
+        for outcome in spec.run() {
+            total += 1;
+            if outcome.passed() {
+                println!("PASS {}: {}", path, outcome.name);
+            } else {
+                failed += 1;
+                println!("FAIL {}: {}", path, outcome.name);
+                for failure in &outcome.failures {
+                    println!("    {}", failure);
+                }
+            }
+        }
+    }
+    println!("{} passed, {} failed", total - failed, failed);
+    if failed == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// Prints why `pattern` failed to compile, underlining the part of it at fault
+/// if it failed to parse.
+fn report(pattern: &str, error: &ReError) {
+    match error {
+        ReError::ParsingFailed(error) => eprintln!("{}", error.render(pattern)),
+        error => eprintln!("{}: {}", pattern, error),
+    }
+}
+
+/// `reg fmt --simplify` prints each pattern in its simplest proven-equivalent
+/// form, with the rewrites that apply to it as given on standard error.
+fn simplify_patterns(patterns: &[String]) -> ExitCode {
+    for pattern in patterns {
+        let simplified = simplifications(pattern).and_then(|suggestions| {
+            for suggestion in suggestions {
+                eprintln!("{}: {}", pattern, suggestion.rule);
+            }
+            simplify(pattern)
+        });
+        match simplified {
+            Ok(simplified) => println!("{}", simplified),
+            Err(error) => {
+                report(pattern, &error);
+                return ExitCode::from(2);
+            }
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+/// `reg class` prints the chars each pattern matches, for patterns that are a
+/// single class, as ranges with set operations and flags already applied.
+fn show_classes(patterns: &[String]) -> ExitCode {
+    for pattern in patterns {
+        match class_ranges(pattern) {
+            Ok(Some(ranges)) => {
+                let ranges: Vec<String> = ranges
+                    .iter()
+                    .map(|&(start, end)| {
+                        if start == end {
+                            start.escape_debug().to_string()
+                        } else {
+                            format!("{}-{}", start.escape_debug(), end.escape_debug())
+                        }
+                    })
+                    .collect();
+                println!("{}: {}", pattern, ranges.join(" "));
+            }
+            Ok(None) => {
+                eprintln!("{}: not a single character class", pattern);
+                return ExitCode::from(2);
+            }
+            Err(error) => {
+                eprintln!("{}", error.render(pattern));
+                return ExitCode::from(2);
+            }
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+/// `reg highlight` prints each file, or standard input if there is none, as
+/// HTML in a `<pre>`, with the matches of the pattern and their groups marked.
+fn highlight(pattern: &str, paths: &[String]) -> ExitCode {
+    let regex = match Regex::new(pattern) {
+        Ok(regex) => regex,
+        Err(error) => {
+            report(pattern, &error);
+            return ExitCode::from(2);
+        }
+    };
+    let texts = if paths.is_empty() {
+        let mut text = String::new();
+        vec![(
+            "<stdin>",
+            io::stdin().read_to_string(&mut text).map(|_| text),
+        )]
+    } else {
+        paths
+            .iter()
+            .map(|path| (path.as_str(), fs::read_to_string(path)))
+            .collect()
+    };
+    for (path, text) in texts {
+        match text {
+            Ok(text) => println!("<pre>{}</pre>", regex.highlight_html(&text)),
+            Err(error) => {
+                eprintln!("{}: {}", path, error);
+                return ExitCode::from(2);
+            }
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+/// `reg diff` prints how the new version of a pattern behaves differently from
+/// the old one, exiting with status 1 if they match different texts.
+fn diff_patterns(old: &str, new: &str) -> ExitCode {
+    for pattern in [old, new] {
+        if let Err(error) = Regex::new(pattern) {
+            report(pattern, &error);
+            return ExitCode::from(2);
+        }
+    }
+    match diff(old, new) {
+        Ok(diff) => {
+            println!("{}", diff);
+            if diff.equivalent == Some(false) {
+                ExitCode::FAILURE
+            } else {
+                ExitCode::SUCCESS
+            }
+        }
+        Err(error) => {
+            eprintln!("{}", error);
+            ExitCode::from(2)
+        }
+    }
+}
+
+/// `reg lint` prints the groups of each pattern that can never participate in
+/// a match or always capture the same text, and the simpler ways to write it,
+/// exiting with status 1 if there is any.
+fn lint_patterns(patterns: &[String]) -> ExitCode {
+    let mut found = false;
+    for pattern in patterns {
+        match lint(pattern) {
+            Ok(lints) => {
+                for lint in lints {
+                    found = true;
+                    println!("{}: {}", pattern, lint);
+                }
+            }
+            Err(error) => {
+                report(pattern, &error);
+                return ExitCode::from(2);
+            }
+        }
+    }
+    if found {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.split_first() {
+        Some((command, paths)) if command == "test" && !paths.is_empty() => test(paths),
+        Some((command, [flag, patterns @ ..]))
+            if command == "fmt" && flag == "--simplify" && !patterns.is_empty() =>
+        {
+            simplify_patterns(patterns)
+        }
+        Some((command, patterns)) if command == "class" && !patterns.is_empty() => {
+            show_classes(patterns)
+        }
+        Some((command, [pattern, paths @ ..])) if command == "highlight" => {
+            highlight(pattern, paths)
+        }
+        Some((command, [old, new])) if command == "diff" => diff_patterns(old, new),
+        Some((command, patterns)) if command == "lint" && !patterns.is_empty() => {
+            lint_patterns(patterns)
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            ExitCode::from(2)
+        }
+    }
+}
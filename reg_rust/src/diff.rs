@@ -0,0 +1,348 @@
+//! Summarizes how two versions of a pattern behave differently, for reviewing
+//! a change to a pattern: texts only one of them matches, groups that changed,
+//! anchors gained or lost, and changes to the flags and to how long a match
+//! can be.
+//!
+//! The texts come from `verify::distinguishing_texts` where the derivative
+//! oracle understands both patterns. Otherwise both are run, anchored at both
+//! ends, on every short text over the chars they tell apart, which can show
+//! that they differ but never that they agree.
+
+use std::{collections::BTreeMap, fmt::Display};
+
+use crate::{
+    fsm::{ReError, RegexNFA},
+    matching::Matcher,
+    parser::{run_parse, LengthBounds, Node, ParsedRegex, Quantifier, INLINE_FLAGS},
+    utils::RegexFlags,
+    verify::{distinguishing_texts, haystacks},
+};
+
+/// Most texts telling the patterns apart that a diff lists.
+const MAX_TEXTS: usize = 4;
+
+/// One way in which the new version of a pattern differs from the old one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// A text only one of the patterns matches in full, with whether it is the
+    /// old one.
+    Text { text: String, old: bool },
+    /// The group with this index, counting from 1, as written in the new pattern.
+    GroupAdded(usize, String),
+    /// The group with this index as written in the old pattern.
+    GroupRemoved(usize, String),
+    /// The group with this index as written in the old and the new pattern.
+    GroupChanged(usize, String, String),
+    /// An anchor such as `^` or `\b`, with how often each pattern uses it.
+    Anchor {
+        anchor: String,
+        old: usize,
+        new: usize,
+    },
+    /// The inline flags of the whole pattern, such as `i`, before and after.
+    Flags(String, String),
+    /// How many chars a match can span, before and after.
+    Length(LengthBounds, LengthBounds),
+}
+
+fn describe_length(bounds: &LengthBounds) -> String {
+    match bounds.max {
+        Some(max) if max == bounds.min => format!("exactly {} chars", max),
+        Some(max) => format!("{} to {} chars", bounds.min, max),
+        None => format!("at least {} chars", bounds.min),
+    }
+}
+
+fn describe_flags(letters: &str) -> String {
+    if letters.is_empty() {
+        String::from("none")
+    } else {
+        format!("`(?{})`", letters)
+    }
+}
+
+impl Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Text { text, old } => write!(
+                f,
+                "{:?} is matched only by the {} pattern",
+                text,
+                if *old { "old" } else { "new" }
+            ),
+            Self::GroupAdded(index, group) => write!(f, "group {} `{}` was added", index, group),
+            Self::GroupRemoved(index, group) => {
+                write!(f, "group {} `{}` was removed", index, group)
+            }
+            Self::GroupChanged(index, old, new) => {
+                write!(f, "group {} changed from `{}` to `{}`", index, old, new)
+            }
+            Self::Anchor { anchor, old, new } => match (old, new) {
+                (0, _) => write!(f, "anchor `{}` was added", anchor),
+                (_, 0) => write!(f, "anchor `{}` was removed", anchor),
+                _ => write!(f, "anchor `{}` is used {} times, was {}", anchor, new, old),
+            },
+            Self::Flags(old, new) => write!(
+                f,
+                "flags changed from {} to {}",
+                describe_flags(old),
+                describe_flags(new)
+            ),
+            Self::Length(old, new) => write!(
+                f,
+                "a match spans {}, was {}",
+                describe_length(new),
+                describe_length(old)
+            ),
+        }
+    }
+}
+
+/// The differences between two versions of a pattern, from `diff`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatternDiff {
+    /// Whether both match exactly the same texts in full, or `None` if that
+    /// could not be decided. Patterns matching the same texts can still prefer
+    /// different matches in a search, as `a|ab` and `ab|a` do.
+    pub equivalent: Option<bool>,
+    pub changes: Vec<Change>,
+}
+
+impl Display for PatternDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.equivalent {
+            Some(true) => write!(f, "the patterns match the same texts")?,
+            Some(false) => write!(f, "the patterns match different texts")?,
+            None => write!(
+                f,
+                "could not tell whether the patterns match the same texts"
+            )?,
+        }
+        for change in &self.changes {
+            write!(f, "\n- {}", change)?;
+        }
+        Ok(())
+    }
+}
+
+/// A parsed pattern together with the text it was parsed from.
+struct Version<'p> {
+    pattern: &'p str,
+    parsed: ParsedRegex,
+    flags: RegexFlags,
+}
+
+impl<'p> Version<'p> {
+    fn parse(pattern: &'p str) -> Result<Version<'p>, ReError> {
+        let mut flags = RegexFlags::OPTIMIZE;
+        let parsed = run_parse(pattern, &mut flags).map_err(ReError::ParsingFailed)?;
+        Ok(Version {
+            pattern,
+            parsed,
+            flags,
+        })
+    }
+
+    /// Group `index` as written in the pattern, if there is such a group.
+    fn group(&self, index: usize) -> Option<String> {
+        let span = self.parsed.source_map.group(index)?;
+        Some(self.pattern[span].to_string())
+    }
+
+    fn flag_letters(&self) -> String {
+        INLINE_FLAGS
+            .iter()
+            .filter(|(_, flag)| self.flags.contains(*flag))
+            .map(|(letter, _)| letter)
+            .collect()
+    }
+
+    /// How often the pattern uses each anchor.
+    fn anchors(&self) -> BTreeMap<String, usize> {
+        let mut anchors = BTreeMap::new();
+        for node in self.parsed.root.walk() {
+            if let Node::StartOfString
+            | Node::StartOfStringOnly
+            | Node::EndOfString
+            | Node::WordBoundary
+            | Node::NonWordBoundary
+            | Node::EndOfStringOnlyNotNewline
+            | Node::EndOfStringOnlyMaybeNewLine = node
+            {
+                *anchors.entry(node.to_string()).or_default() += 1;
+            }
+        }
+        anchors
+    }
+
+    /// An automaton matching the texts the pattern matches in full.
+    fn anchored(&self) -> Result<RegexNFA, ReError> {
+        let root = Node::Expression(
+            vec![
+                Box::new(Node::StartOfStringOnly),
+                Box::new(Node::Group(
+                    Box::new(self.parsed.root.clone()),
+                    None,
+                    Quantifier::None,
+                )),
+                Box::new(Node::EndOfStringOnlyNotNewline),
+            ],
+            None,
+        );
+        RegexNFA::from_ast(&root, self.flags)
+    }
+}
+
+/// The root of `version` with every char matcher and line anchor set to its
+/// flags, so that it can be compared with a pattern under other flags.
+fn under_own_flags(version: &Version) -> Node {
+    version.parsed.root.clone().with_flags(version.flags)
+}
+
+/// Texts telling the patterns apart, each with whether `old` matches it, and
+/// whether that list settles if they are equivalent.
+fn distinguishing(old: &Version, new: &Version) -> (Option<Vec<(String, bool)>>, bool) {
+    let texts = if old.flags == new.flags {
+        distinguishing_texts(&old.parsed.root, &new.parsed.root, old.flags, MAX_TEXTS)
+    } else {
+        distinguishing_texts(
+            &under_own_flags(old),
+            &under_own_flags(new),
+            RegexFlags::OPTIMIZE,
+            MAX_TEXTS,
+        )
+    };
+    if texts.is_some() {
+        return (texts, true);
+    }
+    let (Ok(old_nfa), Ok(new_nfa)) = (old.anchored(), new.anchored()) else {
+        return (None, false);
+    };
+    let texts: Vec<(String, bool)> =
+        haystacks(&[(&old.parsed.root, old.flags), (&new.parsed.root, new.flags)])
+            .into_iter()
+            .map(|text| text.into_iter().collect::<String>())
+            .filter_map(|text| {
+                let matched = old_nfa.is_match(&text);
+                (matched != new_nfa.is_match(&text)).then_some((text, matched))
+            })
+            .take(MAX_TEXTS)
+            .collect();
+    (Some(texts), false)
+}
+
+/// How the `new` version of a pattern behaves differently from the `old` one.
+pub fn diff(old: &str, new: &str) -> Result<PatternDiff, ReError> {
+    let (old, new) = (Version::parse(old)?, Version::parse(new)?);
+    let mut changes = Vec::new();
+
+    let (texts, decided) = distinguishing(&old, &new);
+    let equivalent = match &texts {
+        Some(texts) if !texts.is_empty() => Some(false),
+        Some(_) if decided => Some(true),
+        _ => None,
+    };
+    for (text, old) in texts.into_iter().flatten() {
+        changes.push(Change::Text { text, old });
+    }
+
+    let group_count = old.parsed.group_count.max(new.parsed.group_count);
+    for index in 1..=group_count {
+        match (old.group(index), new.group(index)) {
+            (Some(before), Some(after)) if before != after => {
+                changes.push(Change::GroupChanged(index, before, after))
+            }
+            (Some(before), None) => changes.push(Change::GroupRemoved(index, before)),
+            (None, Some(after)) => changes.push(Change::GroupAdded(index, after)),
+            _ => {}
+        }
+    }
+
+    let (old_anchors, new_anchors) = (old.anchors(), new.anchors());
+    let mut anchors: Vec<&String> = old_anchors.keys().chain(new_anchors.keys()).collect();
+    anchors.sort();
+    anchors.dedup();
+    for anchor in anchors {
+        let count = |anchors: &BTreeMap<String, usize>| anchors.get(anchor).copied().unwrap_or(0);
+        if count(&old_anchors) != count(&new_anchors) {
+            changes.push(Change::Anchor {
+                anchor: anchor.clone(),
+                old: count(&old_anchors),
+                new: count(&new_anchors),
+            });
+        }
+    }
+
+    if old.flag_letters() != new.flag_letters() {
+        changes.push(Change::Flags(old.flag_letters(), new.flag_letters()));
+    }
+    if old.parsed.length_bounds != new.parsed.length_bounds {
+        changes.push(Change::Length(
+            old.parsed.length_bounds,
+            new.parsed.length_bounds,
+        ));
+    }
+    Ok(PatternDiff {
+        equivalent,
+        changes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        let changed = diff(r"(?P<year>\d+)/(\d+)", r"(?P<year>\d{4})/(\d+)").unwrap();
+        assert_eq!(changed.equivalent, Some(false));
+        assert_eq!(
+            changed.changes,
+            vec![
+                Change::Text {
+                    text: String::from("0/0"),
+                    old: true
+                },
+                Change::GroupChanged(
+                    1,
+                    String::from(r"(?P<year>\d+)"),
+                    String::from(r"(?P<year>\d{4})")
+                ),
+                Change::Length(
+                    LengthBounds { min: 3, max: None },
+                    LengthBounds { min: 6, max: None }
+                ),
+            ]
+        );
+
+        let same = diff("(?:a|b)+", "[ab]+").unwrap();
+        assert_eq!(same.equivalent, Some(true));
+        assert!(same.changes.is_empty());
+        assert_eq!(same.to_string(), "the patterns match the same texts");
+
+        // the oracle knows no anchors, so both patterns are run instead
+        let anchored = diff(r"\bca", "^c(a)t?").unwrap();
+        assert_eq!(anchored.equivalent, Some(false));
+        assert_eq!(
+            anchored.to_string(),
+            "the patterns match different texts\n\
+             - \"cat\" is matched only by the new pattern\n\
+             - group 1 `(a)` was added\n\
+             - anchor `\\b` was removed\n\
+             - anchor `^` was added\n\
+             - a match spans 2 to 3 chars, was exactly 2 chars"
+        );
+
+        let flagged = diff("ab", "(?i)ab").unwrap();
+        assert_eq!(flagged.equivalent, Some(false));
+        assert_eq!(
+            flagged.changes[1..],
+            [Change::Flags(String::new(), String::from("i"))]
+        );
+        assert!(matches!(
+            &flagged.changes[0],
+            Change::Text { old: false, .. }
+        ));
+        assert!(diff("a", "(").is_err());
+    }
+}
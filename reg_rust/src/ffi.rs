@@ -0,0 +1,185 @@
+//! A C ABI for embedding the engine, shaped for wrapping it as a database
+//! extension such as a `REG_MATCH(pattern, text)` SQL function.
+//!
+//! A pattern is compiled once into an opaque handle, which the extension keeps
+//! for as long as the statement lives, for example as SQLite auxiliary data. Texts
+//! are passed as pointer and byte length, since neither SQLite nor Postgres
+//! guarantees a terminating NUL, and match offsets are written into memory the
+//! caller owns, so nothing allocated here ever crosses the boundary except the
+//! handle itself. A panic never unwinds into the caller either: it is caught at
+//! the boundary and reported as a failure.
+
+use std::{
+    os::raw::c_int,
+    panic::{catch_unwind, AssertUnwindSafe},
+    slice, str,
+};
+
+use crate::regex::Regex;
+
+/// The text matched.
+pub const REG_MATCH: c_int = 1;
+/// The text did not match.
+pub const REG_NOMATCH: c_int = 0;
+/// A null handle or text, a text that is not UTF-8, or a panic in the engine.
+pub const REG_EINVAL: c_int = -1;
+
+/// A compiled pattern, opaque to C.
+pub struct RegHandle {
+    regex: Regex,
+}
+
+/// Reads `len` bytes at `bytes` as UTF-8; a null pointer is only allowed when
+/// `len` is 0.
+unsafe fn read_str<'a>(bytes: *const u8, len: usize) -> Option<&'a str> {
+    if len == 0 {
+        return Some("");
+    }
+    if bytes.is_null() {
+        return None;
+    }
+    str::from_utf8(slice::from_raw_parts(bytes, len)).ok()
+}
+
+/// Compiles the `len` bytes of UTF-8 at `pattern`, returning null if they are
+/// not UTF-8 or not a valid pattern, or if compiling panics.
+///
+/// # Safety
+///
+/// `pattern` must point to `len` readable bytes. The handle must be released
+/// with `reg_free`.
+#[no_mangle]
+pub unsafe extern "C" fn reg_compile(pattern: *const u8, len: usize) -> *mut RegHandle {
+    let Some(pattern) = read_str(pattern, len) else {
+        return std::ptr::null_mut();
+    };
+    match catch_unwind(|| Regex::new(pattern)) {
+        Ok(Ok(regex)) => Box::into_raw(Box::new(RegHandle { regex })),
+        _ => std::ptr::null_mut(),
+    }
+}
+
+/// Releases a handle from `reg_compile`. Null is ignored.
+///
+/// # Safety
+///
+/// `handle` must be null or come from `reg_compile`, and not be used again.
+#[no_mangle]
+pub unsafe extern "C" fn reg_free(handle: *mut RegHandle) {
+    if !handle.is_null() {
+        drop(Box::from_raw(handle));
+    }
+}
+
+/// The number of capture groups, not counting the whole match, or 0 for null.
+///
+/// # Safety
+///
+/// `handle` must be null or a live handle from `reg_compile`.
+#[no_mangle]
+pub unsafe extern "C" fn reg_group_count(handle: *const RegHandle) -> usize {
+    handle
+        .as_ref()
+        .map_or(0, |handle| handle.regex.group_count())
+}
+
+/// Looks for the leftmost match in the `len` bytes of UTF-8 at `text`. On a
+/// match, group `i` is written to `offsets[2 * i]` and `offsets[2 * i + 1]` as
+/// byte offsets into `text`, or -1 for both if it did not participate, for as
+/// many groups as fit in `offsets_len` slots. Passing no slots only tests for
+/// a match, which is cheaper.
+///
+/// # Safety
+///
+/// `handle` must be a live handle from `reg_compile`, `text` must point to
+/// `len` readable bytes and `offsets` to `offsets_len` writable ones, unless
+/// `offsets_len` is 0.
+#[no_mangle]
+pub unsafe extern "C" fn reg_match(
+    handle: *const RegHandle,
+    text: *const u8,
+    len: usize,
+    offsets: *mut isize,
+    offsets_len: usize,
+) -> c_int {
+    let (Some(handle), Some(text)) = (handle.as_ref(), read_str(text, len)) else {
+        return REG_EINVAL;
+    };
+    let offsets = match offsets_len == 0 || offsets.is_null() {
+        true => &mut [][..],
+        false => slice::from_raw_parts_mut(offsets, offsets_len),
+    };
+    catch_unwind(AssertUnwindSafe(|| find(&handle.regex, text, offsets))).unwrap_or(REG_EINVAL)
+}
+
+/// The body of `reg_match`, once its arguments have been read.
+fn find(regex: &Regex, text: &str, offsets: &mut [isize]) -> c_int {
+    if offsets.is_empty() {
+        return if regex.is_match(text) {
+            REG_MATCH
+        } else {
+            REG_NOMATCH
+        };
+    }
+    let Some(found) = regex.find(text) else {
+        return REG_NOMATCH;
+    };
+    for (pair, span) in offsets.chunks_exact_mut(2).zip(found.byte_spans()) {
+        match *span {
+            Some((start, end)) => {
+                pair[0] = start as isize;
+                pair[1] = end as isize;
+            }
+            None => pair.fill(-1),
+        }
+    }
+    REG_MATCH
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_match_through_abi() {
+        let pattern = "([a-zé]+)(!)?:([0-9]+)";
+        let text = "n° café:42";
+        unsafe {
+            let handle = reg_compile(pattern.as_ptr(), pattern.len());
+            assert!(!handle.is_null());
+            assert_eq!(reg_group_count(handle), 3);
+
+            let mut offsets = [0isize; 8];
+            let found = reg_match(
+                handle,
+                text.as_ptr(),
+                text.len(),
+                offsets.as_mut_ptr(),
+                offsets.len(),
+            );
+            assert_eq!(found, REG_MATCH);
+            assert_eq!(offsets, [4, 12, 4, 9, -1, -1, 10, 12]);
+            assert_eq!(&text[offsets[2] as usize..offsets[3] as usize], "café");
+
+            let mut whole = [0isize; 3];
+            reg_match(handle, text.as_ptr(), text.len(), whole.as_mut_ptr(), 3);
+            assert_eq!(whole, [4, 12, 0]);
+
+            let none = "no digits";
+            let missing = reg_match(handle, none.as_ptr(), none.len(), std::ptr::null_mut(), 0);
+            assert_eq!(missing, REG_NOMATCH);
+            let invalid = [0xffu8, 0xfe];
+            assert_eq!(
+                reg_match(handle, invalid.as_ptr(), 2, std::ptr::null_mut(), 0),
+                REG_EINVAL
+            );
+            reg_free(handle);
+
+            assert!(reg_compile("a{2,1}".as_ptr(), 6).is_null());
+            assert_eq!(
+                reg_match(std::ptr::null(), "".as_ptr(), 0, std::ptr::null_mut(), 0),
+                REG_EINVAL
+            );
+        }
+    }
+}
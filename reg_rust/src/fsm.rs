@@ -1,20 +1,193 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
     env::temp_dir,
+    error::Error,
+    fmt::Display,
     fs::File,
     io::{self, Write},
+    mem::size_of,
     process::Command,
+    sync::Arc,
+    task::Poll,
 };
 
 use itertools::Itertools;
 
 use crate::{
-    matching::{Context, Cursor},
-    parser::{run_parse, visitor::Visitor, Data, Node, ParserError, Quantifier, UpperBound},
-    utils::RegexFlags,
+    matching::{Callout, CalloutHandler, Context, Cursor, GroupNames, StepBudget},
+    parser::{
+        escape_literal, run_parse_with_limits, visitor::Visitor, Data, GroupTree, LengthBounds,
+        Node, ParsedRegex, ParserError, Quantifier, SourceMap, UpperBound, DEFAULT_MAX_REPETITION,
+        DEFAULT_NEST_LIMIT,
+    },
+    unicode::case_orbit,
+    utils::{
+        EquivalenceClasses, LineStarts, LiteralPrefilter, Prefilter, RegexFlags, RequiredLiterals,
+    },
 };
 
-type State = usize;
+/// A state of an automaton, numbered from 1 in the order they were built.
+pub type State = usize;
+
+/// Longest literal prefix worth extracting for the prefilter.
+const MAX_PREFIX_LENGTH: usize = 32;
+
+/// How many subroutine calls can be in progress at once unless set otherwise.
+/// Every call runs the engine anew on the stack, so deeper calls fail instead.
+pub const DEFAULT_RECURSION_LIMIT: usize = 200;
+
+/// How many states compilation builds between reports to a `ProgressHandler`.
+const PROGRESS_INTERVAL: usize = 1024;
+
+/// What compilation reports to a `ProgressHandler`: the states built so far in
+/// the automaton being compiled, which is the outermost one or one nested in it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompileProgress {
+    pub states: usize,
+}
+
+/// A hook that compilation calls as it goes, which stops it by returning `false`,
+/// so that a huge generated pattern can be held to a deadline.
+#[derive(Clone)]
+pub struct ProgressHandler(Arc<dyn Fn(CompileProgress) -> bool + Send + Sync>);
+
+impl ProgressHandler {
+    pub fn new(
+        handler: impl Fn(CompileProgress) -> bool + Send + Sync + 'static,
+    ) -> ProgressHandler {
+        ProgressHandler(Arc::new(handler))
+    }
+
+    pub fn call(&self, progress: CompileProgress) -> bool {
+        (self.0)(progress)
+    }
+}
+
+impl std::fmt::Debug for ProgressHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ProgressHandler(..)")
+    }
+}
+
+/// How a pattern is compiled: the flags it starts with, the limits its parse is
+/// held to and the hook that follows the compilation. Every option is set with
+/// a `with_*` method on top of the defaults, which compile as `RegexNFA::new`
+/// does, and the engine keeps them for whatever it compiles again later.
+#[derive(Debug, Clone)]
+pub struct CompileOptions {
+    flags: RegexFlags,
+    max_repetition: u64,
+    nest_limit: usize,
+    progress_handler: Option<ProgressHandler>,
+    memory_limit: Option<usize>,
+}
+
+impl Default for CompileOptions {
+    fn default() -> CompileOptions {
+        CompileOptions {
+            flags: RegexFlags::NO_FLAG,
+            max_repetition: DEFAULT_MAX_REPETITION,
+            nest_limit: DEFAULT_NEST_LIMIT,
+            progress_handler: None,
+            memory_limit: None,
+        }
+    }
+}
+
+impl CompileOptions {
+    pub fn new() -> CompileOptions {
+        CompileOptions::default()
+    }
+
+    /// Sets `flags` from the start, as if the pattern began with them inline.
+    pub fn with_flags(mut self, flags: RegexFlags) -> CompileOptions {
+        self.flags = flags;
+        self
+    }
+
+    /// Lets repetitions, and repetitions nested in one another, copy what they
+    /// repeat up to `max_repetition` times rather than `DEFAULT_MAX_REPETITION`.
+    pub fn with_max_repetition(mut self, max_repetition: u64) -> CompileOptions {
+        self.max_repetition = max_repetition;
+        self
+    }
+
+    /// Lets groups and character groups nest up to `nest_limit` deep rather than
+    /// `DEFAULT_NEST_LIMIT`.
+    pub fn with_nest_limit(mut self, nest_limit: usize) -> CompileOptions {
+        self.nest_limit = nest_limit;
+        self
+    }
+
+    /// Calls `handler` once the pattern is parsed and then every
+    /// `PROGRESS_INTERVAL` states. Compilation fails with
+    /// `ReError::CompilationCancelled` as soon as the handler returns `false`.
+    pub fn with_progress_handler(mut self, handler: ProgressHandler) -> CompileOptions {
+        self.progress_handler = Some(handler);
+        self
+    }
+
+    /// Fails compilation with `ReError::MemoryBudgetExceeded` as soon as the
+    /// states and transitions built so far take more than `bytes`. They are
+    /// counted as each state is built, so compilation never gets more than one
+    /// state past the limit.
+    pub fn with_memory_limit(mut self, bytes: usize) -> CompileOptions {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    pub fn flags(&self) -> RegexFlags {
+        self.flags
+    }
+
+    pub fn max_repetition(&self) -> u64 {
+        self.max_repetition
+    }
+
+    pub fn nest_limit(&self) -> usize {
+        self.nest_limit
+    }
+}
+
+/// Why compilation stopped before the whole pattern was built.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stop {
+    /// The progress handler returned `false` once this many states were built.
+    Cancelled(usize),
+    /// This many bytes were built, more than the memory limit, given second.
+    OverBudget(usize, usize),
+}
+
+impl From<Stop> for ReError {
+    fn from(stop: Stop) -> ReError {
+        match stop {
+            Stop::Cancelled(states) => ReError::CompilationCancelled(states),
+            Stop::OverBudget(built, limit) => ReError::MemoryBudgetExceeded(built, limit),
+        }
+    }
+}
+
+/// The states an automaton can be in after reading some chars, as
+/// `RegexNFA::step_char` moves it along. It always holds every state reachable
+/// from its members without reading anything.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StateSet(BTreeSet<State>);
+
+impl StateSet {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Whether no match can continue from here.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The states, in increasing order.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.0.iter().copied()
+    }
+}
 
 #[derive(Hash, Debug, PartialEq, Eq, Clone)]
 pub struct Transition {
@@ -38,12 +211,190 @@ pub struct RegexNFA {
     pub accept: State,
     states: HashSet<State>,
     group_count: usize,
+    script_run_count: usize,
+    group_names: GroupNames,
+    group_tree: GroupTree,
+    source_map: SourceMap,
+    length_bounds: LengthBounds,
+    required_literals: RequiredLiterals,
+    /// The automaton of every lookaround and possessive repetition, keyed by its node.
+    nested: HashMap<Node, RegexNFA>,
+    starting_chars: Option<Vec<Node>>,
+    classes: EquivalenceClasses,
+    /// For every state with transitions on single chars, indexed by equivalence
+    /// class, the positions among its transitions of those taken on a char of
+    /// that class, so a search looks its char's class up instead of asking each.
+    class_table: HashMap<State, Vec<Vec<usize>>>,
+    starting_classes: Option<Vec<bool>>,
+    pub(crate) prefilter: Option<Arc<LiteralPrefilter>>,
+    /// Whether every match starts at a line start, because the pattern begins
+    /// with `^` in MULTILINE mode.
+    line_anchored: bool,
+    callout_handler: Option<CalloutHandler>,
+    /// The tree an automaton from `from_ast` was built from, as it has no
+    /// pattern to parse again.
+    ast: Option<Node>,
+    /// The automaton each subroutine call runs, keyed by the group it calls, with
+    /// `None` for the whole pattern. Only the outermost automaton has them; calls
+    /// in nested automata find them through `Calls`.
+    subroutines: HashMap<Option<usize>, RegexNFA>,
+    recursion_limit: usize,
+    /// How the pattern was compiled, flags as given before any inline ones.
+    options: CompileOptions,
+    /// Why compilation stopped early, after which what is left of the pattern
+    /// is skipped.
+    stopped: Option<Stop>,
+    /// The bytes taken by the states and transitions built so far, including
+    /// those of nested automata, as checked against `CompileOptions::with_memory_limit`.
+    bytes_built: usize,
+    /// The automaton `match_suffix` runs in place of this one, with repetitions
+    /// that never give anything back made possessive; see
+    /// `Node::auto_possessified`. This one stays as written, as an automaton with
+    /// possessive repetitions cannot be stepped a char at a time or reversed.
+    possessive: Option<Box<RegexNFA>>,
+    /// The automaton for the mirror image of the pattern, built along with this
+    /// one if the pattern can be reversed; see `RegexNFA::reversed`.
+    reversed: Option<Box<RegexNFA>>,
+}
+
+/// The subroutine automata of the outermost automaton, and how many calls deep
+/// the search is, handed down to every automaton a search runs.
+#[derive(Debug, Clone, Copy)]
+pub struct Calls<'n> {
+    automata: &'n HashMap<Option<usize>, RegexNFA>,
+    depth: usize,
+    limit: usize,
+    /// The innermost call in progress, if any.
+    active: Option<&'n ActiveCall<'n>>,
+}
+
+/// A transition out of a state, with its position among the state's
+/// transitions and the cursor of the path about to take it.
+type Outgoing<'n> = (State, usize, &'n Transition, Cursor);
+
+/// A path through the automaton: the transition it takes next, the cursor
+/// before it, and the transitions it took since it last consumed a char, so
+/// that it can tell when it goes round a loop that consumes nothing.
+type Path = (Transition, Cursor, Vec<Transition>);
+
+/// The paths of a run still to follow, kept between calls so that a run its
+/// step budget stopped can carry on from where it was.
+#[derive(Debug, Clone)]
+pub(crate) struct Paths {
+    /// The paths of the current round, the preferred one first.
+    queue: VecDeque<Path>,
+    /// The paths of the next round, one char further on.
+    frontier: VecDeque<Path>,
+    visited: HashSet<(usize, State)>,
+    match_result: Option<Cursor>,
+}
+
+/// A subroutine call in progress: the group it calls, where it started, and
+/// the call it was made from.
+#[derive(Debug)]
+struct ActiveCall<'n> {
+    target: Option<usize>,
+    position: usize,
+    caller: Option<&'n ActiveCall<'n>>,
+}
+
+impl<'n> Calls<'n> {
+    /// The outermost calls of a search in `automaton`.
+    fn new(automaton: &'n RegexNFA) -> Calls<'n> {
+        Calls {
+            automata: &automaton.subroutines,
+            depth: 0,
+            limit: automaton.recursion_limit,
+            active: None,
+        }
+    }
+
+    /// The automaton `call` runs and the calls from within it, or `None` if the
+    /// call would go past the recursion limit, or if a call to the same group
+    /// from the same position is already in progress, which would only make the
+    /// same call again without consuming anything.
+    fn enter<'c>(self, call: &'c ActiveCall<'c>) -> Option<(&'c RegexNFA, Calls<'c>)>
+    where
+        'n: 'c,
+    {
+        let looping = std::iter::successors(call.caller, |caller| caller.caller)
+            .any(|caller| caller.target == call.target && caller.position == call.position);
+        (self.depth < self.limit && !looping).then(|| {
+            let calls = Calls {
+                automata: self.automata,
+                depth: self.depth + 1,
+                limit: self.limit,
+                active: Some(call),
+            };
+            (&self.automata[&call.target], calls)
+        })
+    }
 }
 
 #[derive(Debug)]
 pub enum ReError {
     ParsingFailed(ParserError),
     CompilationError,
+    /// The engine cannot run something the pattern uses, named here.
+    Unsupported(String),
+    /// The compiled pattern needs this many bytes, or at least this many if
+    /// compilation stopped partway, but only this many remain in the caller's
+    /// memory budget.
+    MemoryBudgetExceeded(usize, usize),
+    /// The engine and the derivative oracle of `verify` disagree on where matches
+    /// start in this haystack: the oracle's offsets, then the engine's.
+    VerificationFailed(String, Vec<usize>, Vec<usize>),
+    /// The engine found a match spanning these offsets of this haystack, which
+    /// the derivative oracle of `verify` says the pattern does not match.
+    MatchRejected(String, usize, usize),
+    /// A tree handed to `RegexNFA::from_ast` that no pattern parses to, and why.
+    InvalidAst(String),
+    /// The progress handler stopped compilation once this many states were built.
+    CompilationCancelled(usize),
+}
+
+impl Display for ReError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ParsingFailed(error) => write!(f, "{}", error),
+            Self::CompilationError => write!(f, "the pattern could not be compiled"),
+            Self::Unsupported(what) => write!(f, "the engine does not support {}", what),
+            Self::MemoryBudgetExceeded(needed, remaining) => write!(
+                f,
+                "the pattern needs {} bytes but only {} remain in the memory budget",
+                needed, remaining
+            ),
+            Self::VerificationFailed(text, expected, actual) => write!(
+                f,
+                "matches in {:?} start at {:?} but the engine found {:?}",
+                text, expected, actual
+            ),
+            Self::MatchRejected(text, start, end) => write!(
+                f,
+                "the engine found a match at {}..{} in {:?}, which the pattern does not match",
+                start, end, text
+            ),
+            Self::InvalidAst(reason) => write!(f, "invalid pattern tree: {}", reason),
+            Self::CompilationCancelled(states) => {
+                write!(f, "compilation cancelled after {} states", states)
+            }
+        }
+    }
+}
+
+impl Error for ReError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::ParsingFailed(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<ParserError> for ReError {
+    fn from(error: ParserError) -> ReError {
+        ReError::ParsingFailed(error)
+    }
 }
 
 type Fragment = (State, State);
@@ -54,8 +405,48 @@ macro_rules! epsilon {
     };
 }
 
+/// Whether an automaton can take a transition on `node` knowing only the char
+/// it reads, if any: the node reads one char or is bookkeeping for the groups.
+fn steps_on_chars_alone(node: &Node) -> bool {
+    match node {
+        Node::Character(_)
+        | Node::Dot
+        | Node::CharacterGroup(_, _)
+        | Node::Epsilon
+        | Node::GroupLink
+        | Node::GroupEntry(_)
+        | Node::GroupExit(_)
+        | Node::EmptyString
+        | Node::Callout(_) => true,
+        Node::Flagged(item, _) => steps_on_chars_alone(item),
+        _ => false,
+    }
+}
+
 impl RegexNFA {
     pub fn new(pattern: &str) -> Result<RegexNFA, ReError> {
+        RegexNFA::new_with_options(pattern, CompileOptions::default())
+    }
+
+    /// Compiles `pattern` like `new`, but as `options` say.
+    pub fn new_with_options(pattern: &str, options: CompileOptions) -> Result<RegexNFA, ReError> {
+        let mut nfa = RegexNFA::empty(pattern);
+        nfa.flags |= options.flags;
+        nfa.options = options;
+        let nfa = nfa.compile()?;
+        #[cfg(feature = "verify")]
+        crate::verify::verify(pattern, &nfa)?;
+        Ok(nfa)
+    }
+
+    /// An automaton matching `literal` exactly, with no char of it taken as
+    /// syntax, for patterns that come straight from user input. The literal
+    /// becomes the prefilter, so a search jumps from one occurrence to the next.
+    pub fn new_literal(literal: &str) -> Result<RegexNFA, ReError> {
+        RegexNFA::new(&literal.chars().map(escape_literal).collect::<String>())
+    }
+
+    fn empty(pattern: &str) -> RegexNFA {
         RegexNFA {
             state_counter: Default::default(),
             pattern: String::from(pattern),
@@ -66,42 +457,719 @@ impl RegexNFA {
             accept: Default::default(),
             states: HashSet::new(),
             group_count: Default::default(),
+            script_run_count: Default::default(),
+            group_names: GroupNames::default(),
+            group_tree: GroupTree::default(),
+            source_map: SourceMap::default(),
+            length_bounds: LengthBounds::exactly(0),
+            required_literals: RequiredLiterals::default(),
+            nested: HashMap::new(),
+            starting_chars: None,
+            classes: EquivalenceClasses::new(Vec::new()),
+            class_table: HashMap::new(),
+            starting_classes: None,
+            prefilter: None,
+            line_anchored: false,
+            callout_handler: None,
+            ast: None,
+            subroutines: HashMap::new(),
+            recursion_limit: DEFAULT_RECURSION_LIMIT,
+            options: CompileOptions::default(),
+            stopped: None,
+            bytes_built: 0,
+            possessive: None,
+            reversed: None,
         }
-        .compile()
+    }
+
+    /// An automaton for a tree built in code, as a pattern would parse to, under
+    /// `flags`, without writing the tree out as a pattern first. Groups are
+    /// numbered by the indices in the tree and have no names. Fails with
+    /// `ReError::InvalidAst` on a tree the parser could not have produced.
+    pub fn from_ast(root: &Node, flags: RegexFlags) -> Result<RegexNFA, ReError> {
+        let parsed = ParsedRegex::from_root(root.clone(), flags).map_err(ReError::InvalidAst)?;
+        let mut nfa = RegexNFA::empty(&format!("{}", root));
+        nfa.flags = flags;
+        nfa.ast = Some(root.clone());
+        let mut nfa = nfa.build(parsed.clone());
+        nfa.compile_subroutines(root);
+        nfa.compile_possessive(root);
+        nfa.compile_reversed(parsed);
+        Ok(nfa)
     }
 
     pub fn get_flags(&self) -> RegexFlags {
         self.flags
     }
 
+    /// The options the automaton was compiled with.
+    pub fn options(&self) -> &CompileOptions {
+        &self.options
+    }
+
     pub fn group_count(&self) -> usize {
         self.group_count
     }
 
+    pub fn script_run_count(&self) -> usize {
+        self.script_run_count
+    }
+
+    /// Named groups mapped to their group index, counting from 1.
+    pub fn group_names(&self) -> &GroupNames {
+        &self.group_names
+    }
+
+    fn build(&mut self, parsed: ParsedRegex) -> RegexNFA {
+        // nested automata are compiled while visiting and need the counts for their cursors
+        self.group_count = parsed.group_count;
+        self.script_run_count = parsed.script_run_count;
+        self.group_names = GroupNames::new(parsed.group_names);
+        self.group_tree = parsed.group_tree;
+        self.source_map = parsed.source_map;
+        self.length_bounds = parsed.length_bounds;
+        self.required_literals = parsed.required_literals;
+        let root = if self.flags.intersects(RegexFlags::OPTIMIZE) {
+            parsed.root.normalized()
+        } else {
+            parsed.root
+        };
+        let (start, accept) = root.accept(self);
+        self.start = start;
+        self.accept = accept;
+        self.starting_chars = self.compute_starting_chars();
+        self.classes = self.compute_equivalence_classes();
+        self.class_table = self.compute_class_table();
+        self.starting_classes = self.starting_chars.as_ref().map(|nodes| {
+            self.classes
+                .representatives()
+                .map(|c| nodes.iter().any(|node| node.accepts_char(c, self.flags)))
+                .collect()
+        });
+        self.prefilter = LiteralPrefilter::new(
+            &self.compute_literal_prefix(),
+            self.flags.intersects(RegexFlags::IGNORECASE),
+        )
+        .map(Arc::new);
+        self.line_anchored =
+            self.flags.intersects(RegexFlags::MULTILINE) && self.starts_with_line_anchor();
+        self.clone()
+    }
+
+    /// An automaton for the mirror image of the pattern: run over a reversed
+    /// haystack, it finds the matches of `self` from the right. It is `None` for
+    /// patterns that refer to captures or run nested automata, which have no
+    /// mirror image the engine can run: a backreference or condition would come
+    /// before its group, lookarounds would have to swap directions, giving
+    /// nothing back is not symmetric, and neither is a call that keeps the first
+    /// way its group matches.
+    pub fn reversed(&self) -> Option<&RegexNFA> {
+        self.reversed.as_deref()
+    }
+
+    /// Runs `handler` whenever a path reaches a `(?Cn)`, including inside
+    /// lookarounds, and drops the path if it returns `false`. The search explores
+    /// paths breadth first, so a callout can be reported more than once for the
+    /// same position, and positions a prefilter skips are never reported.
+    pub fn with_callout_handler(mut self, handler: CalloutHandler) -> RegexNFA {
+        self.set_callout_handler(&handler);
+        self
+    }
+
+    /// Like `with_callout_handler`, on an automaton in place.
+    pub fn set_callout_handler(&mut self, handler: &CalloutHandler) {
+        self.callout_handler = Some(handler.clone());
+        for nested in self
+            .nested
+            .values_mut()
+            .chain(self.subroutines.values_mut())
+            .chain(self.possessive.as_deref_mut())
+        {
+            nested.set_callout_handler(handler);
+        }
+    }
+
+    /// Sets how many subroutine calls can be in progress at once, the default
+    /// being `DEFAULT_RECURSION_LIMIT`. A call that would go deeper fails, so a
+    /// pattern recursing without consuming anything just stops matching there.
+    pub fn with_recursion_limit(mut self, limit: usize) -> RegexNFA {
+        self.set_recursion_limit(limit);
+        self
+    }
+
+    /// Like `with_recursion_limit`, on an automaton in place.
+    pub fn set_recursion_limit(&mut self, limit: usize) {
+        self.recursion_limit = limit;
+    }
+
+    /// Every state of the automaton, in increasing order, for tools that walk
+    /// it along with `transitions`. `start` and `accept` are among them.
+    pub fn states(&self) -> impl Iterator<Item = State> + '_ {
+        self.states.iter().copied().sorted()
+    }
+
+    /// The transitions out of `state`, the one a search prefers first. Those on
+    /// nodes that read nothing, such as `Epsilon` or an anchor, are taken
+    /// without consuming a char.
+    pub fn transitions(&self, state: State) -> &[Transition] {
+        self.transitions.get(&state).map_or(&[], Vec::as_slice)
+    }
+
+    /// The automaton a transition on `node` runs on its own, for a lookaround or
+    /// a possessive repetition.
+    pub fn nested(&self, node: &Node) -> Option<&RegexNFA> {
+        self.nested.get(node)
+    }
+
+    /// How the groups nest.
+    pub fn group_tree(&self) -> &GroupTree {
+        &self.group_tree
+    }
+
+    /// Where the groups and items are in the pattern.
+    pub fn source_map(&self) -> &SourceMap {
+        &self.source_map
+    }
+
+    /// How many chars a match can span.
+    pub fn length_bounds(&self) -> LengthBounds {
+        self.length_bounds
+    }
+
+    /// Literals every match contains, in order.
+    pub fn required_literals(&self) -> &RequiredLiterals {
+        &self.required_literals
+    }
+
+    /// Whether `node` holds at `cursor`. A lookahead runs its own automaton from
+    /// the cursor, and a lookbehind from as many chars back as it matches; groups
+    /// captured inside either are not kept. A negative lookbehind also holds when
+    /// there are fewer chars before the cursor than it would match. A callout
+    /// holds unless the handler rejects it.
+    pub fn accepts(&self, node: &Node, cursor: &Cursor, context: &Context, calls: Calls) -> bool {
+        match node {
+            Node::Lookahead(_) => self.nested[node]
+                .run(cursor.clone(), context, calls, None)
+                .is_some(),
+            Node::Lookbehind(item, negated) => {
+                let width = item.length_bounds().min;
+                let matched = cursor.position >= width && {
+                    let mut start = cursor.clone();
+                    start.position -= width;
+                    self.nested[node].run(start, context, calls, None).is_some()
+                };
+                matched != *negated
+            }
+            Node::Callout(number) => self.callout_handler.as_ref().is_none_or(|handler| {
+                handler.call(Callout {
+                    number: *number,
+                    position: cursor.position,
+                })
+            }),
+            _ => node.accepts(cursor, context),
+        }
+    }
+
+    /// Whether some transition runs an automaton of its own, for a lookaround, a
+    /// possessive repetition or a subroutine call.
+    pub fn has_nested_automata(&self) -> bool {
+        !self.nested.is_empty() || !self.subroutines.is_empty()
+    }
+
+    /// The cursor after taking a transition on `node`, or `None` if it does not
+    /// match at `cursor`. An atomic repetition ends where its automaton's
+    /// preferred match does, with the groups it captured, and a subroutine call
+    /// likewise but with the groups as they were before it.
+    pub fn advance(
+        &self,
+        node: &Node,
+        cursor: &Cursor,
+        context: &Context,
+        calls: Calls,
+    ) -> Option<Cursor> {
+        match node {
+            Node::Atomic(_) => self.nested[node].run(cursor.clone(), context, calls, None),
+            Node::Subroutine(target) => {
+                let call = ActiveCall {
+                    target: *target,
+                    position: cursor.position,
+                    caller: calls.active,
+                };
+                let (automaton, calls) = calls.enter(&call)?;
+                let end = automaton.run(cursor.clone(), context, calls, None)?;
+                Some(Cursor {
+                    position: end.position,
+                    groups: cursor.groups.clone(),
+                })
+            }
+            Node::Epsilon => Some(cursor.update(node)),
+            _ => self
+                .accepts(node, cursor, context, calls)
+                .then(|| cursor.update(node)),
+        }
+    }
+
+    /// Whether some transition matches previously captured text or depends on
+    /// whether a group captured anything.
+    pub fn refers_to_captures(&self) -> bool {
+        self.alphabet
+            .iter()
+            .any(|node| matches!(node, Node::Backreference(_) | Node::GroupCondition(_, _)))
+    }
+
+    /// The matchers one of which must accept the first character of any match,
+    /// or `None` if the pattern can match the empty string.
+    pub fn starting_chars(&self) -> Option<&[Node]> {
+        self.starting_chars.as_deref()
+    }
+
     pub fn gen_state(&mut self) -> State {
         self.state_counter += 1;
         self.states.insert(self.state_counter);
+        self.bytes_built += size_of::<State>();
+        self.check_memory();
+        if self.state_counter.is_multiple_of(PROGRESS_INTERVAL) {
+            self.report_progress();
+        }
         self.state_counter
     }
 
+    /// Stops compilation if what is built so far is over the memory limit.
+    fn check_memory(&mut self) {
+        if self.stopped.is_some() {
+            return;
+        }
+        if let Some(limit) = self.options.memory_limit {
+            if self.bytes_built > limit {
+                self.stopped = Some(Stop::OverBudget(self.bytes_built, limit));
+            }
+        }
+    }
+
+    /// Stops compilation if what is built so far is over the memory limit, and
+    /// otherwise calls the progress handler, if there is one, and records
+    /// whether it stops it now. Nothing is checked once compilation stopped.
+    fn report_progress(&mut self) {
+        self.check_memory();
+        if self.stopped.is_some() {
+            return;
+        }
+        if let Some(handler) = &self.options.progress_handler {
+            let states = self.state_counter;
+            if !handler.call(CompileProgress { states }) {
+                self.stopped = Some(Stop::Cancelled(states));
+            }
+        }
+    }
+
     pub fn fragment(&mut self) -> Fragment {
         (self.gen_state(), self.gen_state())
     }
 
     pub fn compile(&mut self) -> Result<RegexNFA, ReError> {
-        return match run_parse(&self.pattern, &mut self.flags) {
-            Ok((root, group_count)) => {
-                let (start, accept) = root.accept(self);
-                self.start = start;
-                self.accept = accept;
-                self.group_count = group_count;
-                Ok(self.clone())
+        match run_parse_with_limits(
+            &self.pattern,
+            &mut self.flags,
+            self.options.max_repetition,
+            self.options.nest_limit,
+        ) {
+            Ok(parsed) => {
+                self.report_progress();
+                let root = parsed.root.clone();
+                let mut nfa = self.build(parsed.clone());
+                nfa.compile_subroutines(&root);
+                nfa.compile_possessive(&root);
+                nfa.compile_reversed(parsed);
+                if let Some(stop) = nfa.stopped {
+                    return Err(stop.into());
+                }
+                Ok(nfa)
             }
             Err(parsing_error) => Err(ReError::ParsingFailed(parsing_error)),
+        }
+    }
+
+    /// Bytes held by the compiled automaton. The count is derived from the states,
+    /// transitions and tables themselves, so it is the same on every run and does
+    /// not depend on how collections grew.
+    pub fn memory_usage(&self) -> usize {
+        let transitions: usize = self
+            .transitions
+            .values()
+            .map(|transitions| {
+                size_of::<(State, Vec<Transition>)>()
+                    + transitions
+                        .iter()
+                        .map(|transition| {
+                            size_of::<Transition>() - size_of::<Node>()
+                                + transition.node.memory_usage()
+                        })
+                        .sum::<usize>()
+            })
+            .sum();
+        let alphabet: usize = self.alphabet.iter().map(Node::memory_usage).sum();
+        let starting_chars: usize = self
+            .starting_chars
+            .iter()
+            .flatten()
+            .map(Node::memory_usage)
+            .sum();
+        size_of::<RegexNFA>()
+            + self.pattern.len()
+            + alphabet
+            + transitions
+            + self.states.len() * size_of::<State>()
+            + starting_chars
+            + self.classes.memory_usage()
+            + self
+                .class_table
+                .values()
+                .flatten()
+                .map(|indices| size_of::<Vec<usize>>() + indices.len() * size_of::<usize>())
+                .sum::<usize>()
+            + self.starting_classes.as_ref().map_or(0, Vec::len)
+            + self
+                .ast
+                .as_ref()
+                .map_or(0, |root| root.memory_usage() - size_of::<Node>())
+            + self
+                .prefilter
+                .as_deref()
+                .map_or(0, LiteralPrefilter::memory_usage)
+            + self
+                .nested
+                .values()
+                .chain(self.subroutines.values())
+                .chain(self.possessive.as_deref())
+                .chain(self.reversed.as_deref())
+                .map(RegexNFA::memory_usage)
+                .sum::<usize>()
+            + self
+                .group_names
+                .keys()
+                .map(|name| name.len() + size_of::<usize>())
+                .sum::<usize>()
+    }
+
+    /// A literal every match begins with, if the pattern has one.
+    pub fn prefilter(&self) -> Option<&LiteralPrefilter> {
+        self.prefilter.as_deref()
+    }
+
+    /// Where searches look for match starts: only at line starts if the pattern
+    /// begins with `^` in MULTILINE mode, else wherever the literal prefix occurs.
+    pub fn search_prefilter(&self) -> Option<Arc<dyn Prefilter>> {
+        if self.line_anchored {
+            Some(Arc::new(LineStarts))
+        } else {
+            self.prefilter
+                .clone()
+                .map(|prefilter| prefilter as Arc<dyn Prefilter>)
+        }
+    }
+
+    /// The partition of chars into classes that no transition can distinguish.
+    pub fn equivalence_classes(&self) -> &EquivalenceClasses {
+        &self.classes
+    }
+
+    /// Whether a match can start with a char of `class`, or `None` if the pattern
+    /// can match the empty string.
+    pub fn starting_classes(&self) -> Option<&[bool]> {
+        self.starting_classes.as_deref()
+    }
+
+    /// The transitions on single chars out of `state` indexed by equivalence
+    /// class: entry `i` lists the states reachable by consuming any char of
+    /// class `i`.
+    pub fn class_transitions(&self, state: State) -> Vec<Vec<State>> {
+        let Some(table) = self.class_table.get(&state) else {
+            return vec![Vec::new(); self.classes.len()];
         };
+        let transitions = &self.transitions[&state];
+        table
+            .iter()
+            .map(|indices| {
+                indices
+                    .iter()
+                    .map(|&index| transitions[index].end)
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Whether the transition at `index` out of `state`, on a single char,
+    /// takes the char at `cursor`, looked up by its class.
+    fn takes_char(&self, state: State, index: usize, cursor: &Cursor, context: &Context) -> bool {
+        if cursor.position >= context.end {
+            return false;
+        }
+        let class = self.classes.class_of(context.char_at(cursor.position));
+        self.class_table
+            .get(&state)
+            .is_some_and(|table| table[class].binary_search(&index).is_ok())
+    }
+
+    /// The states before reading anything, to drive the automaton one char at a
+    /// time with `step_char`. Only patterns that need nothing but the chars read
+    /// can be driven that way: anchors, lookarounds, backreferences, subroutine
+    /// calls and the like fail with `ReError::Unsupported`. Callouts are passed
+    /// over.
+    pub fn initial_states(&self) -> Result<StateSet, ReError> {
+        let unsupported = self
+            .transitions
+            .values()
+            .flatten()
+            .find(|transition| !steps_on_chars_alone(&transition.node));
+        if let Some(transition) = unsupported {
+            return Err(ReError::Unsupported(format!(
+                "stepping through {}",
+                transition.node
+            )));
+        }
+        Ok(StateSet(
+            self.zero_width_closure(vec![self.start])
+                .into_iter()
+                .collect(),
+        ))
+    }
+
+    /// The states reached from `states` by reading `c`.
+    pub fn step_char(&self, states: &StateSet, c: char) -> StateSet {
+        let class = self.classes.class_of(c);
+        let targets = states
+            .iter()
+            .filter_map(|state| Some((state, self.class_table.get(&state)?)))
+            .flat_map(|(state, table)| {
+                let transitions = &self.transitions[&state];
+                table[class].iter().map(|&index| transitions[index].end)
+            })
+            .collect();
+        StateSet(self.zero_width_closure(targets).into_iter().collect())
+    }
+
+    /// Whether the chars read to reach `states` are a match in full.
+    pub fn is_accepting(&self, states: &StateSet) -> bool {
+        states.0.contains(&self.accept)
+    }
+
+    /// A shortest non-empty string both `self` and `other` match in full, found by
+    /// walking their product automaton. Assertions are assumed to pass, so a
+    /// witness means the patterns may overlap rather than that they must.
+    pub fn intersection_witness(&self, other: &RegexNFA) -> Option<String> {
+        let classes = EquivalenceClasses::new(
+            self.classes
+                .representatives()
+                .chain(other.classes.representatives())
+                .collect(),
+        );
+        let outgoing = |nfa: &'_ RegexNFA, state: State| -> Vec<Transition> {
+            nfa.transitions.get(&state).cloned().unwrap_or_default()
+        };
+
+        let start = (self.start, other.start, false);
+        let mut seen: HashSet<(State, State, bool)> = HashSet::from([start]);
+        let mut queue = VecDeque::from([(start, String::new())]);
+        while let Some(((left, right, consumed), witness)) = queue.pop_front() {
+            if consumed && left == self.accept && right == other.accept {
+                return Some(witness);
+            }
+            let (left_transitions, right_transitions) =
+                (outgoing(self, left), outgoing(other, right));
+            let mut successors: Vec<((State, State, bool), String)> = Vec::new();
+            for transition in left_transitions.iter().filter(|t| t.node.increment() == 0) {
+                successors.push(((transition.end, right, consumed), witness.clone()));
+            }
+            for transition in right_transitions.iter().filter(|t| t.node.increment() == 0) {
+                successors.push(((left, transition.end, consumed), witness.clone()));
+            }
+            for c in classes.representatives() {
+                for l in left_transitions
+                    .iter()
+                    .filter(|t| t.node.increment() != 0 && t.node.accepts_char(c, self.flags))
+                {
+                    for r in right_transitions
+                        .iter()
+                        .filter(|t| t.node.increment() != 0 && t.node.accepts_char(c, other.flags))
+                    {
+                        let mut witness = witness.clone();
+                        witness.push(c);
+                        successors.push(((l.end, r.end, true), witness));
+                    }
+                }
+            }
+            for (pair, witness) in successors {
+                if seen.insert(pair) {
+                    queue.push_back((pair, witness));
+                }
+            }
+        }
+        None
+    }
+
+    fn compute_class_table(&self) -> HashMap<State, Vec<Vec<usize>>> {
+        self.transitions
+            .iter()
+            .filter(|(_, transitions)| transitions.iter().any(|t| t.node.is_char_matcher()))
+            .map(|(&state, transitions)| {
+                let table = self
+                    .classes
+                    .representatives()
+                    .map(|c| {
+                        (0..transitions.len())
+                            .filter(|&index| {
+                                let node = &transitions[index].node;
+                                node.is_char_matcher() && node.accepts_char(c, self.flags)
+                            })
+                            .collect()
+                    })
+                    .collect();
+                (state, table)
+            })
+            .collect()
+    }
+
+    fn compute_equivalence_classes(&self) -> EquivalenceClasses {
+        let mut boundaries: Vec<char> = Vec::new();
+        for node in &self.alphabet {
+            node.class_boundaries(self.flags, &mut boundaries);
+        }
+        EquivalenceClasses::new(boundaries)
+    }
+
+    /// Collects the consuming transitions reachable from the start state through
+    /// zero-width transitions only. Assertions are assumed to pass, so the result
+    /// over-approximates the set of characters a match can begin with.
+    fn compute_starting_chars(&self) -> Option<Vec<Node>> {
+        let mut starting_chars: Vec<Node> = Vec::new();
+        let mut seen: HashSet<State> = HashSet::new();
+        let mut stack = vec![self.start];
+        while let Some(state) = stack.pop() {
+            if state == self.accept {
+                return None;
+            }
+            if !seen.insert(state) {
+                continue;
+            }
+            if let Some(transitions) = self.transitions.get(&state) {
+                // visit in priority order so the result reads like the pattern
+                for transition in transitions.iter().rev() {
+                    if transition.node.increment() == 0 {
+                        stack.push(transition.end);
+                    }
+                }
+                for transition in transitions {
+                    // what a backreference starts with depends on the haystack, and
+                    // an atomic repetition or a subroutine call may even match nothing
+                    if let Node::Backreference(_) | Node::Atomic(_) | Node::Subroutine(_) =
+                        transition.node
+                    {
+                        return None;
+                    }
+                    if transition.node.increment() != 0
+                        && !starting_chars.contains(&transition.node)
+                    {
+                        starting_chars.push(transition.node.clone());
+                    }
+                }
+            }
+        }
+        Some(starting_chars)
+    }
+
+    /// Whether every path from the start passes a `^` before it consumes anything
+    /// or reaches the accepting state.
+    fn starts_with_line_anchor(&self) -> bool {
+        let mut seen: HashSet<State> = HashSet::new();
+        let mut stack = vec![self.start];
+        while let Some(state) = stack.pop() {
+            if state == self.accept {
+                return false;
+            }
+            if !seen.insert(state) {
+                continue;
+            }
+            for transition in self.transitions.get(&state).into_iter().flatten() {
+                match transition.node {
+                    Node::StartOfString => {}
+                    ref node if node.increment() == 0 => stack.push(transition.end),
+                    _ => return false,
+                }
+            }
+        }
+        true
+    }
+
+    /// The chars every match begins with. Under `IGNORECASE` they are lowercased,
+    /// since the prefilter then has to try every case variant anyway, and end
+    /// before the first with a case outside ASCII.
+    fn compute_literal_prefix(&self) -> Vec<char> {
+        let ignore_case = self.flags.intersects(RegexFlags::IGNORECASE);
+        let mut prefix: Vec<char> = Vec::new();
+        let mut states = vec![self.start];
+        while prefix.len() < MAX_PREFIX_LENGTH {
+            let closure = self.zero_width_closure(states);
+            if closure.contains(&self.accept) {
+                break;
+            }
+            let mut literal: Option<char> = None;
+            let mut targets: Vec<State> = Vec::new();
+            for transition in closure
+                .iter()
+                .filter_map(|state| self.transitions.get(state))
+                .flatten()
+                .filter(|transition| transition.node.increment() != 0)
+            {
+                match transition.node {
+                    Node::Character(c) => {
+                        // the prefilter only knows the ASCII cases of letters
+                        let c = match ignore_case {
+                            true if case_orbit(c)
+                                .is_some_and(|orbit| orbit.iter().any(|c| !c.is_ascii())) =>
+                            {
+                                return prefix
+                            }
+                            true => c.to_ascii_lowercase(),
+                            false => c,
+                        };
+                        if *literal.get_or_insert(c) != c {
+                            return prefix;
+                        }
+                        targets.push(transition.end);
+                    }
+                    _ => return prefix,
+                }
+            }
+            match literal {
+                Some(c) => prefix.push(c),
+                None => break,
+            }
+            states = targets;
+        }
+        prefix
+    }
+
+    /// Every state reachable from `states` without consuming a character.
+    fn zero_width_closure(&self, mut states: Vec<State>) -> HashSet<State> {
+        let mut closure: HashSet<State> = HashSet::new();
+        while let Some(state) = states.pop() {
+            if closure.insert(state) {
+                states.extend(
+                    self.transitions
+                        .get(&state)
+                        .into_iter()
+                        .flatten()
+                        .filter(|transition| transition.node.increment() == 0)
+                        .map(|transition| transition.end),
+                );
+            }
+        }
+        closure
     }
 
     pub fn add_transition(&mut self, start: State, end: State, matcher: Node) -> () {
+        self.bytes_built += size_of::<Transition>() - size_of::<Node>() + matcher.memory_usage();
+        self.check_memory();
         match matcher {
             Node::GroupLink | Node::Epsilon => false,
             _ => self.alphabet.insert(matcher.clone()),
@@ -139,11 +1207,29 @@ impl RegexNFA {
         fragment
     }
 
-    fn zero_or_one(&mut self, fragment: &Fragment, lazy: bool) {
-        self.add_transition(fragment.0, fragment.1, Node::EmptyString);
+    fn zero_or_one(&mut self, fragment: &Fragment, lazy: bool) -> Fragment {
+        (self.bypass(fragment, fragment.1, lazy), fragment.1)
+    }
+
+    /// A new start for `fragment` with a way around it to `exit`. The way around
+    /// cannot leave from the start of `fragment` itself, as a repetition at the
+    /// front of `fragment` loops back there after consuming chars.
+    fn bypass(&mut self, fragment: &Fragment, exit: State, lazy: bool) -> State {
+        let start = self.gen_state();
+        epsilon!(self; start => fragment.0);
+        self.add_transition(start, exit, Node::EmptyString);
         if lazy {
-            self.transitions.get_mut(&fragment.0).unwrap().reverse();
+            self.prefer_last_transition(start);
         }
+        start
+    }
+
+    /// Moves the most recently added transition out of `state` to the front,
+    /// giving it the highest priority without disturbing the order of the rest.
+    fn prefer_last_transition(&mut self, state: State) {
+        let transitions = self.transitions.get_mut(&state).unwrap();
+        let last = transitions.pop().unwrap();
+        transitions.insert(0, last);
     }
 
     fn one_or_more(&mut self, fragment: &Fragment, lazy: bool) -> Fragment {
@@ -156,14 +1242,16 @@ impl RegexNFA {
         (fragment.0, s)
     }
 
+    /// Loops back from the end of `fragment` to the choice before it, so that
+    /// an iteration that consumed nothing comes back to a state it has already
+    /// been to and stops there.
     fn zero_or_more(&mut self, fragment: &Fragment, lazy: bool) -> Fragment {
         let empty = self.symbol_transition(Node::EmptyString);
 
-        epsilon!(self; fragment.1 => empty.1, fragment.1 => fragment.0, empty.0 => fragment.0);
+        epsilon!(self; fragment.1 => empty.0, empty.0 => fragment.0);
 
         if !lazy {
             self.transitions.get_mut(&empty.0).unwrap().reverse();
-            self.transitions.get_mut(&fragment.1).unwrap().reverse();
         }
 
         empty
@@ -194,17 +1282,17 @@ impl RegexNFA {
 
     fn apply_range_quantifier(
         &mut self,
-        node: Node,
+        node: &Node,
         lower: u64,
         upperbound: UpperBound,
         lazy: bool,
     ) -> Fragment {
         if lower == 0 {
             if let UpperBound::Unbounded = upperbound {
-                let frag = node.accept(self);
+                let frag = self.quantifiable_fragment(node);
                 return self.zero_or_more(&frag, lazy);
             }
-            if let UpperBound::Undefined = upperbound {
+            if let UpperBound::Undefined | UpperBound::Bounded(0) = upperbound {
                 return self.symbol_transition(Node::EmptyString);
             }
         }
@@ -212,28 +1300,27 @@ impl RegexNFA {
         match upperbound {
             UpperBound::Unbounded => {
                 for _ in 0..(lower - 1) {
-                    let frag = node.accept(self);
+                    let frag = self.quantifiable_fragment(node);
                     fragments.push(frag);
                 }
-                let frag = node.accept(self);
+                let frag = self.quantifiable_fragment(node);
                 fragments.push(self.one_or_more(&frag, lazy));
             }
             UpperBound::Undefined => {
                 for _ in 0..lower {
-                    let frag = node.accept(self);
+                    let frag = self.quantifiable_fragment(node);
                     fragments.push(frag);
                 }
             }
             UpperBound::Bounded(upper) => {
                 for _ in 0..upper {
-                    let frag = node.accept(self);
+                    let frag = self.quantifiable_fragment(node);
                     fragments.push(frag);
                 }
-                for fragment in &fragments[lower as usize..upper as usize] {
-                    self.add_transition(fragment.0, fragment.1, Node::EmptyString);
-                    if lazy {
-                        self.transitions.get_mut(&fragment.0).unwrap().reverse();
-                    }
+                // every optional iteration can bail out straight to the common exit
+                let exit = fragments.last().unwrap().1;
+                for fragment in &mut fragments[lower as usize..upper as usize] {
+                    fragment.0 = self.bypass(fragment, exit, lazy);
                 }
             }
         }
@@ -243,103 +1330,431 @@ impl RegexNFA {
         (fragments.first().unwrap().0, fragments.last().unwrap().1)
     }
 
-    fn match_or_group(&mut self, node: Node) -> Fragment {
+    /// Compiles a single iteration of a `Group` or `Match`, ignoring its quantifier.
+    /// Capturing groups get fresh entry/exit markers on every call, so each iteration
+    /// of a repeated group overwrites the capture slots with its own span.
+    fn quantifiable_fragment(&mut self, node: &Node) -> Fragment {
         match node {
-            Node::Group(node, group_index, quantifier) => {
-                let fragment = node.accept(self);
-                match quantifier {
-                    Quantifier::None => self.add_capturing_markers(fragment, group_index),
-                    Quantifier::ZeroOrOne(lazy) => {
-                        let frag = self.add_capturing_markers(fragment, group_index);
-                        self.zero_or_one(&frag, lazy);
-                        frag
-                    }
-                    Quantifier::OneOrMore(lazy) => {
-                        let frag = self.add_capturing_markers(fragment, group_index);
-                        self.one_or_more(&frag, lazy)
-                    }
-                    Quantifier::ZeroOrMore(lazy) => {
-                        let frag = self.add_capturing_markers(fragment, group_index);
-                        self.zero_or_more(&fragment, lazy);
-                        frag
+            Node::Group(expression, group_index, _) => {
+                let fragment = expression.accept(self);
+                self.add_capturing_markers(fragment, *group_index)
+            }
+            Node::Match(item, _) => item.accept(self),
+            _ => panic!("expected Group or Match, not {:#?}", node),
+        }
+    }
+
+    /// Compiles `item` on its own, sharing the cursor layout so groups captured
+    /// inside it land in the right slots.
+    fn compile_part(&mut self, item: &Node) -> RegexNFA {
+        self.compile_within(
+            &format!("{}", item),
+            ParsedRegex {
+                root: item.clone(),
+                group_count: self.group_count,
+                script_run_count: self.script_run_count,
+                group_names: HashMap::new(),
+                group_tree: GroupTree::default(),
+                length_bounds: LengthBounds::exactly(0),
+                required_literals: RequiredLiterals::default(),
+                source_map: SourceMap::default(),
+                flags: self.flags,
+            },
+        )
+    }
+
+    /// Compiles `parsed` as an automaton of its own under the flags, options
+    /// and what is left of the memory limit of this one.
+    fn compile_within(&mut self, pattern: &str, parsed: ParsedRegex) -> RegexNFA {
+        let mut part = RegexNFA::empty(pattern);
+        part.flags = self.flags;
+        part.options = self.options.clone();
+        part.options.memory_limit = self
+            .options
+            .memory_limit
+            .map(|limit| limit.saturating_sub(self.bytes_built));
+        part.stopped = self.stopped;
+        let part = part.build(parsed);
+        self.stopped = self.stopped.or(part.stopped.map(|stop| match stop {
+            // the part's limit is what was left of this one's
+            Stop::OverBudget(..) => Stop::OverBudget(
+                self.bytes_built + part.bytes_built,
+                self.options.memory_limit.unwrap_or_default(),
+            ),
+            stop => stop,
+        }));
+        self.bytes_built += part.bytes_built;
+        part
+    }
+
+    /// Compiles `item` as the automaton `node` runs.
+    fn compile_nested(&mut self, node: &Node, item: &Node) {
+        if !self.nested.contains_key(node) {
+            let nested = self.compile_part(item);
+            self.nested.insert(node.clone(), nested);
+        }
+    }
+
+    /// Compiles what every subroutine call in `root` runs: the group it calls
+    /// without its quantifier, or `root` itself for `(?R)`. The calls inside
+    /// those are left for `Calls` to resolve while searching.
+    fn compile_subroutines(&mut self, root: &Node) {
+        fn collect<'r>(
+            node: &'r Node,
+            targets: &mut Vec<Option<usize>>,
+            groups: &mut HashMap<usize, &'r Node>,
+        ) {
+            match node {
+                Node::Subroutine(target) => targets.push(*target),
+                Node::Group(item, Some(index), _) => {
+                    groups.insert(*index, item);
+                    collect(item, targets, groups);
+                }
+                Node::Match(item, _)
+                | Node::Group(item, None, _)
+                | Node::Lookahead(item)
+                | Node::Lookbehind(item, _)
+                | Node::Atomic(item)
+                | Node::Flagged(item, _) => collect(item, targets, groups),
+                Node::Expression(items, alternative) => {
+                    for item in items {
+                        collect(item, targets, groups);
                     }
-                    Quantifier::Range(lower, upper, lazy) => {
-                        self.apply_range_quantifier(*node, lower, upper, lazy)
+                    if let Some(alternative) = alternative {
+                        collect(alternative, targets, groups);
                     }
                 }
+                _ => {}
+            }
+        }
+
+        let mut targets = Vec::new();
+        let mut groups = HashMap::new();
+        collect(root, &mut targets, &mut groups);
+        for target in targets {
+            if self.subroutines.contains_key(&target) {
+                continue;
+            }
+            let body = match target {
+                Some(index) => Node::Group(
+                    Box::new(groups[&index].clone()),
+                    Some(index),
+                    Quantifier::None,
+                ),
+                None => root.clone(),
+            };
+            let automaton = self.compile_part(&body);
+            self.subroutines.insert(target, automaton);
+        }
+    }
+
+    /// Compiles the automaton `match_suffix` runs if making repetitions in `root`
+    /// possessive changes anything.
+    fn compile_possessive(&mut self, root: &Node) {
+        if !self.flags.intersects(RegexFlags::OPTIMIZE) {
+            return;
+        }
+        let root = root.clone().normalized();
+        let possessified = root.clone().auto_possessified(self.flags);
+        if possessified != root {
+            self.possessive = Some(Box::new(self.compile_part(&possessified)));
+        }
+    }
+
+    /// Compiles the automaton `RegexNFA::reversed` gives, if the pattern `parsed`
+    /// holds can be reversed.
+    fn compile_reversed(&mut self, parsed: ParsedRegex) {
+        if self.refers_to_captures() || self.has_nested_automata() {
+            return;
+        }
+        let pattern = self.pattern.clone();
+        let reversed = self.compile_within(&pattern, parsed.reversed(self.flags));
+        self.reversed = Some(Box::new(reversed));
+    }
+
+    fn match_or_group(&mut self, node: Node) -> Fragment {
+        if let Some(atomic) = node.possessive_as_atomic() {
+            return atomic.accept(self);
+        }
+        let quantifier = match node {
+            Node::Group(_, _, ref quantifier) | Node::Match(_, ref quantifier) => {
+                quantifier.clone()
             }
-            Node::Match(node, quantifier) => match quantifier {
-                Quantifier::None => node.accept(self),
-                Quantifier::OneOrMore(lazy) => {
-                    let fragment = node.accept(self);
-                    self.one_or_more(&fragment, lazy)
-                }
-                Quantifier::ZeroOrOne(lazy) => {
-                    let fragment = node.accept(self);
-                    self.zero_or_one(&fragment, lazy);
-                    fragment
-                }
-                Quantifier::ZeroOrMore(lazy) => {
-                    let fragment = node.accept(self);
-                    self.zero_or_more(&fragment, lazy)
-                }
-                Quantifier::Range(lower, upper, lazy) => {
-                    self.apply_range_quantifier(*node, lower, upper, lazy)
-                }
-            },
             _ => panic!("expected Group or Match, not {:#?}", node),
+        };
+        match quantifier {
+            Quantifier::None => self.quantifiable_fragment(&node),
+            Quantifier::ZeroOrOne(greediness) => {
+                let fragment = self.quantifiable_fragment(&node);
+                self.zero_or_one(&fragment, greediness.is_lazy())
+            }
+            Quantifier::OneOrMore(greediness) => {
+                let fragment = self.quantifiable_fragment(&node);
+                self.one_or_more(&fragment, greediness.is_lazy())
+            }
+            Quantifier::ZeroOrMore(greediness) => {
+                let fragment = self.quantifiable_fragment(&node);
+                self.zero_or_more(&fragment, greediness.is_lazy())
+            }
+            Quantifier::Range(lower, upper, greediness) => {
+                self.apply_range_quantifier(&node, lower, upper, greediness.is_lazy())
+            }
         }
     }
 
+    /// The transitions a path at `state` with `cursor` can take next, following
+    /// zero-width ones that hold until something to consume or the accepting state.
+    /// A path that comes to a state already in `visited` at the same position
+    /// stops there, as the path that got there first is the one preferred.
+    /// Transitions on single chars are only returned if they take the char at
+    /// the path's cursor, which `take` then relies on.
     pub fn step<'c>(
         &'c self,
-        start: &Transition,
+        state: State,
         cursor: &Cursor,
         context: &Context,
-        visited: &mut HashSet<(usize, &'c Transition)>,
+        calls: Calls,
+        visited: &mut HashSet<(usize, State)>,
     ) -> Vec<(Transition, Cursor)> {
-        match self.transitions.get(&start.end) {
-            Some(initial_transitions) => {
-                let mut stack: Vec<(&Transition, Cursor)> = initial_transitions
-                    .iter()
-                    .map(|nxt| (nxt, cursor.update(&start.node)))
-                    .rev()
-                    .collect();
-                let mut transitions: Vec<(Transition, Cursor)> = Vec::new();
-                while let Some((transition, cursor)) = stack.pop() {
-                    if !visited.contains(&(cursor.position, transition)) {
-                        visited.insert((cursor.position, transition));
-
-                        if transition.node.increment() == 0 {
-                            if transition.node.accepts(&cursor, context) {
-                                transitions.push((transition.clone(), cursor))
-                            } else {
-                                match transition.node {
-                                    Node::Epsilon if transition.end == self.accept => {
-                                        transitions.push((transition.clone(), cursor))
-                                    }
-                                    _ => match self.transitions.get(&transition.end) {
-                                        Some(some_transitions) => stack.extend(
-                                            some_transitions
-                                                .iter()
-                                                .map(|nxt| (nxt, cursor.update(&transition.node)))
-                                                .rev(),
-                                        ),
-                                        _ => {}
-                                    },
-                                }
-                            }
-                        } else {
-                            transitions.push((transition.clone(), cursor))
-                        }
+        let mut stack: Vec<Outgoing<'c>> = Vec::new();
+        let mut enter = |state: State, cursor: Cursor, stack: &mut Vec<Outgoing<'c>>| {
+            if visited.insert((cursor.position, state)) {
+                stack.extend(
+                    self.transitions(state)
+                        .iter()
+                        .enumerate()
+                        .rev()
+                        .map(|(index, transition)| (state, index, transition, cursor.clone())),
+                );
+            }
+        };
+        enter(state, cursor.clone(), &mut stack);
+        let mut transitions: Vec<(Transition, Cursor)> = Vec::new();
+        while let Some((state, index, transition, cursor)) = stack.pop() {
+            if transition.node.is_char_matcher() {
+                if self.takes_char(state, index, &cursor, context) {
+                    transitions.push((transition.clone(), cursor));
+                }
+                continue;
+            }
+            if transition.node.increment() != 0 {
+                transitions.push((transition.clone(), cursor));
+                continue;
+            }
+            let holds = matches!(transition.node, Node::Epsilon | Node::GroupLink)
+                || self.accepts(&transition.node, &cursor, context, calls);
+            if !holds {
+                // a failed assertion prunes this path
+                continue;
+            }
+            if transition.end == self.accept {
+                transitions.push((transition.clone(), cursor));
+            } else {
+                enter(transition.end, cursor.update(&transition.node), &mut stack);
+            }
+        }
+        transitions
+    }
+
+    /// The cursor after taking a transition `step` returned, or `None` if it does
+    /// not match. `step` already looked up those on single chars.
+    fn take(
+        &self,
+        node: &Node,
+        cursor: &Cursor,
+        context: &Context,
+        calls: Calls,
+    ) -> Option<Cursor> {
+        match node.is_char_matcher() {
+            true => Some(cursor.update(node)),
+            false => self.advance(node, cursor, context, calls),
+        }
+    }
+
+    /// The end of the preferred match starting at `cursor`, with no subroutine
+    /// call in progress.
+    pub fn match_suffix(&self, cursor: Cursor, context: &Context) -> Option<Cursor> {
+        self.match_suffix_within(cursor, context, None)
+    }
+
+    /// Like `match_suffix`, but taking one step of `budget` for every transition
+    /// a path takes that reads chars or reaches the accepting state, and giving
+    /// up once they run out. The automata of lookarounds
+    /// and subroutine calls run without taking any.
+    pub fn match_suffix_budgeted(
+        &self,
+        cursor: Cursor,
+        context: &Context,
+        budget: &mut StepBudget,
+    ) -> Option<Cursor> {
+        self.match_suffix_within(cursor, context, Some(budget))
+    }
+
+    fn match_suffix_within(
+        &self,
+        cursor: Cursor,
+        context: &Context,
+        budget: Option<&mut StepBudget>,
+    ) -> Option<Cursor> {
+        let calls = Calls::new(self);
+        self.possessive
+            .as_deref()
+            .unwrap_or(self)
+            .run(cursor, context, calls, budget)
+    }
+
+    /// The end of the longest match starting at `cursor`, whichever path it
+    /// takes, where `match_suffix` ends with the preferred path instead. Paths
+    /// are told apart by their captures only if the pattern refers back to them.
+    pub fn longest_match_end(&self, cursor: Cursor, context: &Context) -> Option<usize> {
+        let calls = Calls::new(self);
+        let captures_matter = self.refers_to_captures();
+        let mut seen: HashSet<(Transition, usize, Vec<Option<usize>>)> = HashSet::new();
+        let mut stack = self.step(self.start, &cursor, context, calls, &mut HashSet::new());
+        let mut longest: Option<usize> = None;
+        while let Some((transition, cursor)) = stack.pop() {
+            let groups = match captures_matter {
+                true => cursor.groups.clone(),
+                false => Vec::new(),
+            };
+            if !seen.insert((transition.clone(), cursor.position, groups)) {
+                continue;
+            }
+            let Some(advanced) = self.take(&transition.node, &cursor, context, calls) else {
+                continue;
+            };
+            if transition.end == self.accept {
+                longest = longest.max(Some(advanced.position));
+                continue;
+            }
+            stack.extend(self.step(
+                transition.end,
+                &advanced,
+                context,
+                calls,
+                &mut HashSet::new(),
+            ));
+        }
+        longest
+    }
+
+    /// The paths of a run starting at `cursor`, before any of them takes a
+    /// transition.
+    fn paths(&self, cursor: &Cursor, context: &Context, calls: Calls) -> Paths {
+        let queue = self
+            .step(self.start, cursor, context, calls, &mut HashSet::new())
+            .into_iter()
+            .map(|(transition, cursor)| (transition, cursor, Vec::new()))
+            .collect();
+        Paths {
+            queue,
+            frontier: VecDeque::new(),
+            visited: HashSet::new(),
+            match_result: None,
+        }
+    }
+
+    /// Follows `paths` until the run ends, returning the end of the preferred
+    /// match, or until `budget` runs out, leaving in `paths` what is still to
+    /// follow and returning `Poll::Pending`.
+    fn follow(
+        &self,
+        paths: &mut Paths,
+        context: &Context,
+        calls: Calls,
+        mut budget: Option<&mut StepBudget>,
+    ) -> Poll<Option<Cursor>> {
+        loop {
+            while !paths.queue.is_empty() {
+                if let Some(budget) = budget.as_deref_mut() {
+                    if !budget.take() {
+                        return Poll::Pending;
                     }
                 }
-                transitions
+                let Some((transition, cursor, mut trail)) = paths.queue.pop_front() else {
+                    break;
+                };
+                let Some(advanced) = self.take(&transition.node, &cursor, context, calls) else {
+                    continue;
+                };
+                if let Some(budget) = budget.as_deref_mut() {
+                    budget.reach(&advanced);
+                }
+                if self.accept == transition.end {
+                    // the paths after this one can no longer produce a preferred match
+                    paths.match_result = Some(advanced);
+                    paths.queue.clear();
+                    break;
+                }
+                if advanced.position != cursor.position {
+                    trail.clear();
+                } else if trail.contains(&transition) {
+                    // another time round a loop that consumed nothing the first time
+                    continue;
+                } else {
+                    trail.push(transition.clone());
+                }
+                paths.frontier.extend(
+                    self.step(
+                        transition.end,
+                        &advanced,
+                        context,
+                        calls,
+                        &mut paths.visited,
+                    )
+                    .into_iter()
+                    .map(|(transition, cursor)| (transition, cursor, trail.clone())),
+                );
             }
-            None => Vec::new(),
+
+            if paths.frontier.is_empty() {
+                return Poll::Ready(paths.match_result.take());
+            }
+            std::mem::swap(&mut paths.frontier, &mut paths.queue);
+            paths.visited.clear();
+        }
+    }
+
+    fn run(
+        &self,
+        cursor: Cursor,
+        context: &Context,
+        calls: Calls,
+        budget: Option<&mut StepBudget>,
+    ) -> Option<Cursor> {
+        let mut paths = self.paths(&cursor, context, calls);
+        match self.follow(&mut paths, context, calls, budget) {
+            Poll::Ready(found) => found,
+            Poll::Pending => None,
         }
     }
 
+    /// The paths of `match_suffix` from `cursor`, for `resume` to follow a
+    /// budget of steps at a time.
+    pub(crate) fn start_run(&self, cursor: &Cursor, context: &Context) -> Paths {
+        let calls = Calls::new(self);
+        self.possessive
+            .as_deref()
+            .unwrap_or(self)
+            .paths(cursor, context, calls)
+    }
+
+    /// Follows the paths of a run from `start_run` for as long as `budget` lasts,
+    /// as `match_suffix_budgeted` does; `Poll::Pending` means the steps ran out
+    /// and calling again with more carries on from there.
+    pub(crate) fn resume(
+        &self,
+        paths: &mut Paths,
+        context: &Context,
+        budget: &mut StepBudget,
+    ) -> Poll<Option<Cursor>> {
+        let calls = Calls::new(self);
+        self.possessive
+            .as_deref()
+            .unwrap_or(self)
+            .follow(paths, context, calls, Some(budget))
+    }
+
     pub fn render(&self) -> Result<(), io::Error> {
         let mut out = String::new();
         let mut seen: HashSet<State> = HashSet::new();
@@ -429,8 +1844,32 @@ impl<'a> Visitor for RegexNFA {
     type Result = (State, State);
 
     fn visit_expression(&mut self, expression: Node) -> Self::Result {
+        if self.stopped.is_some() {
+            // the automaton is thrown away, so any fragment will do
+            return self.fragment();
+        }
         if let Node::Expression(items, alternate_expression) = expression {
-            let fragments: Vec<Self::Result> = items.iter().map(|node| node.accept(self)).collect();
+            let mut fragments: Vec<Self::Result> = Vec::with_capacity(items.len());
+            let mut after_literal = false;
+            for node in &items {
+                let literal = match &**node {
+                    Node::Match(item, Quantifier::None) => match **item {
+                        Node::Character(_) => Some(item),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                match (literal, fragments.last_mut()) {
+                    // a run of literal chars is one chain, with no epsilons between
+                    (Some(item), Some(last)) if after_literal => {
+                        let end = self.gen_state();
+                        self.add_transition(last.1, end, (**item).clone());
+                        last.1 = end;
+                    }
+                    _ => fragments.push(node.accept(self)),
+                }
+                after_literal = literal.is_some();
+            }
             for (a, b) in fragments.iter().tuple_windows() {
                 epsilon!(self; a.1 => b.0);
             }
@@ -447,10 +1886,16 @@ impl<'a> Visitor for RegexNFA {
     }
 
     fn visit_character(&mut self, char: Node) -> Self::Result {
+        if let Node::Atomic(item) = &char {
+            self.compile_nested(&char, item);
+        }
         self.symbol_transition(char)
     }
 
     fn visit_anchor(&mut self, anchor: Node) -> Self::Result {
+        if let Node::Lookahead(item) | Node::Lookbehind(item, _) = &anchor {
+            self.compile_nested(&anchor, item);
+        }
         self.symbol_transition(anchor)
     }
 
@@ -474,6 +1919,10 @@ impl<'a> Visitor for RegexNFA {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{
+        matching::Matcher,
+        parser::{run_parse, Greediness},
+    };
 
     #[test]
     fn test_visitor_creation() {
@@ -481,4 +1930,291 @@ mod tests {
         let regex = RegexNFA::new(&pattern).unwrap();
         println!("{:#?}", regex);
     }
+
+    #[test]
+    fn test_starting_chars() {
+        let regex = RegexNFA::new(r"\b(ab|c)d").unwrap();
+        assert_eq!(
+            regex.starting_chars(),
+            Some(&[Node::Character('a'), Node::Character('c')][..])
+        );
+        assert_eq!(RegexNFA::new("a*b?").unwrap().starting_chars(), None);
+    }
+
+    #[test]
+    fn test_class_transitions() {
+        let regex = RegexNFA::new("[a-c]+x").unwrap();
+        let classes = regex.equivalence_classes();
+        assert_eq!(classes.len(), 5);
+        assert_eq!(classes.class_of('a'), classes.class_of('c'));
+
+        let starting = regex.starting_classes().unwrap();
+        assert!(starting[classes.class_of('b')]);
+        assert!(!starting[classes.class_of('x')]);
+
+        let by_class = regex.class_transitions(regex.start);
+        let reachable: usize = by_class.iter().map(|targets| targets.len()).sum();
+        assert!(reachable <= 1);
+
+        // a search looks the classes up in the table, so they must agree with
+        // asking the transitions, case folding and all
+        let regex = RegexNFA::new_with_options(
+            "[a-c]+x|(?-i:k)é",
+            CompileOptions::new().with_flags(RegexFlags::IGNORECASE),
+        )
+        .unwrap();
+        for (state, transitions) in &regex.transitions {
+            for c in ['a', 'B', 'x', 'X', 'k', 'K', 'é', '_'] {
+                let mut expected: Vec<State> = transitions
+                    .iter()
+                    .filter(|t| t.node.is_char_matcher() && t.node.accepts_char(c, regex.flags))
+                    .map(|t| t.end)
+                    .collect();
+                let mut found = regex.class_transitions(*state)[regex.classes.class_of(c)].clone();
+                expected.sort();
+                found.sort();
+                assert_eq!(found, expected, "{:?}", c);
+            }
+        }
+        let spans: Vec<(usize, usize)> = regex.find_iter("ABX kék Ké").map(|m| m.span()).collect();
+        assert_eq!(spans, [(0, 3), (4, 6)]);
+    }
+
+    #[test]
+    fn test_step() {
+        let regex = RegexNFA::new("a(b|[0-9])*(?i:c)").unwrap();
+        let mut states = regex.initial_states().unwrap();
+        assert!(!regex.is_accepting(&states));
+        for (c, accepting) in [('a', false), ('7', false), ('b', false), ('C', true)] {
+            states = regex.step_char(&states, c);
+            assert_eq!(regex.is_accepting(&states), accepting, "{}", c);
+        }
+        assert!(regex.step_char(&states, 'c').is_empty());
+        assert!(regex
+            .step_char(&regex.initial_states().unwrap(), 'b')
+            .is_empty());
+
+        for pattern in [r"^a", r"a\b", r"(a)\1", "a(?=b)", "(a)(?1)", "a++"] {
+            assert!(
+                matches!(
+                    RegexNFA::new(pattern).unwrap().initial_states(),
+                    Err(ReError::Unsupported(_))
+                ),
+                "{}",
+                pattern
+            );
+        }
+    }
+
+    #[test]
+    fn test_new_literal() {
+        let literal = "a.b*(c)[^d]\\e|{2}?+ #$\n";
+        let regex = RegexNFA::new_literal(literal).unwrap();
+        assert_eq!(regex.group_count(), 0);
+        assert!(regex.prefilter().is_some());
+        let text = format!("ab {} a.b*(c)", literal);
+        assert_eq!(regex.find(&text).map(|m| m.span()), Some((3, 26)));
+        assert!(RegexNFA::new_literal("a.b").unwrap().find("axb").is_none());
+        assert!(RegexNFA::new_literal("").unwrap().is_match("x"));
+    }
+
+    #[test]
+    fn test_from_ast() {
+        let mut flags = RegexFlags::OPTIMIZE;
+        let pattern = r"(?i)([ab]+)_\d{2,3}\1";
+        let parsed = run_parse(pattern, &mut flags).unwrap();
+        let built = RegexNFA::from_ast(&parsed.root, flags).unwrap();
+        let parsed = RegexNFA::new(pattern).unwrap();
+        let text = "ab_12ab Ba_345bA a_1a bb_99BB";
+        let spans = |nfa: &RegexNFA| -> Vec<(usize, usize)> {
+            nfa.find_iter(text).map(|m| m.span()).collect()
+        };
+        assert_eq!(spans(&built), spans(&parsed));
+        assert_eq!(built.group_count(), 1);
+        assert_eq!(built.required_literals().literals(), ["_"]);
+
+        // built straight from nodes, and run backwards through its mirror image
+        let character =
+            |c: char| Box::new(Node::Match(Box::new(Node::Character(c)), Quantifier::None));
+        let root = Node::Expression(
+            vec![
+                Box::new(Node::Group(
+                    Box::new(Node::Expression(vec![character('x')], None)),
+                    Some(0),
+                    Quantifier::OneOrMore(Greediness::Greedy),
+                )),
+                character('y'),
+            ],
+            None,
+        );
+        let nfa = RegexNFA::from_ast(&root, RegexFlags::OPTIMIZE).unwrap();
+        let backwards: Vec<(usize, usize)> = nfa.rfind_iter("xy xxy y").map(|m| m.span()).collect();
+        assert_eq!(backwards, [(3, 6), (0, 2)]);
+
+        let invalid = |root: Node| match RegexNFA::from_ast(&root, RegexFlags::OPTIMIZE) {
+            Err(ReError::InvalidAst(reason)) => reason,
+            other => panic!("expected the tree to be rejected, got {:?}", other),
+        };
+        assert_eq!(
+            invalid(Node::Expression(
+                vec![Box::new(Node::Backreference(0))],
+                None
+            )),
+            "a reference to group index 0, past the last group"
+        );
+        assert_eq!(
+            invalid(Node::Expression(
+                vec![Box::new(Node::CharacterRange('a', 'z'))],
+                None
+            )),
+            "CharacterRange('a', 'z') outside a character group"
+        );
+        assert_eq!(
+            invalid(Node::Expression(Vec::new(), None)),
+            "an expression without items"
+        );
+    }
+
+    #[test]
+    fn test_normalized_before_compiling() {
+        let mut flags = RegexFlags::OPTIMIZE;
+        let root = run_parse("ab(?:cd)e{1,}(?:f)?", &mut flags).unwrap().root;
+        let optimized = RegexNFA::from_ast(&root, RegexFlags::OPTIMIZE).unwrap();
+        let plain = RegexNFA::from_ast(&root, RegexFlags::empty()).unwrap();
+        assert!(optimized.states.len() < plain.states.len());
+        let spans = |nfa: &RegexNFA| -> Vec<(usize, usize)> {
+            nfa.find_iter("abcdeef abcde abcdf")
+                .map(|m| m.span())
+                .collect()
+        };
+        assert_eq!(spans(&optimized), [(0, 7), (8, 13)]);
+        assert_eq!(spans(&optimized), spans(&plain));
+    }
+
+    #[test]
+    fn test_possessive_twin() {
+        let nfa = RegexNFA::new(r"(\d+)\s+x").unwrap();
+        let possessive = nfa.possessive.as_deref().unwrap();
+        assert!(possessive.has_nested_automata());
+        // the automaton itself can still be stepped and reversed
+        assert!(!nfa.has_nested_automata());
+        assert!(nfa.initial_states().is_ok());
+        let found: Vec<(usize, usize)> = nfa
+            .find_iter("12 x 3  y 45\t x")
+            .map(|m| m.span())
+            .collect();
+        assert_eq!(found, [(0, 4), (10, 15)]);
+        assert!(RegexNFA::new(r"\d+\w").unwrap().possessive.is_none());
+    }
+
+    #[test]
+    fn test_states_and_transitions() {
+        let nfa = RegexNFA::new("ab|c(?=d)").unwrap();
+        let states: Vec<State> = nfa.states().collect();
+        assert!(states.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(states.contains(&nfa.start) && states.contains(&nfa.accept));
+        assert!(nfa.transitions(nfa.accept).is_empty());
+
+        let mut consumed = Vec::new();
+        let mut lookaheads = 0;
+        for state in nfa.states() {
+            for transition in nfa.transitions(state) {
+                assert!(states.contains(&transition.end));
+                if transition.node.increment() != 0 {
+                    consumed.push(transition.node.to_string());
+                }
+                if let Node::Lookahead(_) = transition.node {
+                    assert!(nfa.nested(&transition.node).is_some());
+                    lookaheads += 1;
+                }
+            }
+        }
+        consumed.sort();
+        assert_eq!(consumed, ["a", "b", "c"]);
+        assert_eq!(lookaheads, 1);
+    }
+
+    #[test]
+    fn test_progress_handler() {
+        use std::sync::Mutex;
+
+        let pattern = (0..300).map(|word| format!("word{}", word)).join("|");
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let seen = reports.clone();
+        let handler = ProgressHandler::new(move |progress| {
+            seen.lock().unwrap().push(progress.states);
+            true
+        });
+        let options = CompileOptions::new().with_progress_handler(handler);
+        let nfa = RegexNFA::new_with_options(&pattern, options).unwrap();
+        assert!(nfa.is_match("word299"));
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports[..3], [0, 1024, 2048]);
+        // the reversed automaton reports the states it builds as well
+        let reversed = nfa.reversed().unwrap();
+        assert_eq!(
+            reports.len(),
+            1 + (nfa.state_counter / PROGRESS_INTERVAL)
+                + (reversed.state_counter / PROGRESS_INTERVAL)
+        );
+
+        // stopped at the second report, nothing is reported after it
+        let calls = Arc::new(Mutex::new(0));
+        let counted = calls.clone();
+        let handler = ProgressHandler::new(move |_| {
+            let mut calls = counted.lock().unwrap();
+            *calls += 1;
+            *calls < 2
+        });
+        assert!(matches!(
+            RegexNFA::new_with_options(
+                &pattern,
+                CompileOptions::new().with_progress_handler(handler)
+            ),
+            Err(ReError::CompilationCancelled(1024))
+        ));
+        assert_eq!(*calls.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_literal_prefix() {
+        let prefix = |pattern: &str| RegexNFA::new(pattern).unwrap().compute_literal_prefix();
+        assert_eq!(prefix("abc+d"), vec!['a', 'b', 'c']);
+        assert_eq!(prefix("(ab)(c|cd)"), vec!['a', 'b', 'c']);
+        assert_eq!(prefix("\\bab|ac"), vec!['a']);
+        assert_eq!(prefix("(?i)aBc"), vec!['a', 'b', 'c']);
+        // the prefilter would miss the other cases of `é` and `k`
+        assert_eq!(prefix("(?i)aéb"), vec!['a']);
+        assert_eq!(prefix("(?i)ak"), vec!['a']);
+        assert_eq!(prefix("a?b"), Vec::<char>::new());
+        assert_eq!(prefix("[ab]c"), Vec::<char>::new());
+    }
+
+    #[test]
+    fn test_line_anchored() {
+        let anchored = |pattern: &str| RegexNFA::new(pattern).unwrap().line_anchored;
+        assert!(anchored("(?m)^ab|^c"));
+        assert!(anchored("(?m)(^a)+"));
+        assert!(!anchored("(?m)a|^b"));
+        assert!(!anchored("(?m)(^)?a"));
+        assert!(!anchored("^a"));
+
+        let regex = RegexNFA::new("(?m)^([a-z]*)").unwrap();
+        let found: Vec<(usize, usize)> =
+            regex.find_iter("ab cd\nef\n\n").map(|m| m.span()).collect();
+        assert_eq!(found, vec![(0, 2), (6, 8), (9, 9), (10, 10)]);
+    }
+
+    #[test]
+    fn test_intersection_witness() {
+        let witness = |a: &str, b: &str| {
+            RegexNFA::new(a)
+                .unwrap()
+                .intersection_witness(&RegexNFA::new(b).unwrap())
+        };
+        assert_eq!(witness("[a-z]+", "if"), Some(String::from("if")));
+        assert_eq!(witness("[0-9]+", "[a-z]+"), None);
+        assert_eq!(witness("a*", "b*"), None);
+        assert_eq!(witness("(ab)+", "a(ba)*b"), Some(String::from("ab")));
+    }
 }
@@ -0,0 +1,178 @@
+//! Translates shell globs such as `src/**/*.rs` into patterns, for matching
+//! file paths with the same engine as everything else.
+//!
+//! `*` matches any run of chars within one path component and `?` any one char
+//! but `/`. `**` as a whole component matches any number of components, so
+//! `a/**/b` matches `a/b` and `a/x/y/b`, and a trailing `a/**` everything under
+//! `a`. `[...]` matches one char of the set and `[!...]` or `[^...]` one char
+//! outside it, in either case never `/`. A set may name POSIX classes, as in
+//! `[[:alpha:]_]`; an unknown name fails to compile. `\` makes the next char
+//! stand for itself. A `[` with no `]` to close it is taken literally. Leading
+//! dots are not special.
+
+use crate::{
+    fsm::ReError,
+    parser::{escape_class_char, escape_literal},
+    Regex,
+};
+
+/// The class of `glob[start..]`, which follows a `[`, and the index just past its
+/// `]`, or `None` if nothing closes it.
+fn class(glob: &[char], start: usize) -> Option<(String, usize)> {
+    let mut index = start;
+    let negated = matches!(glob.get(index), Some('!' | '^'));
+    if negated {
+        index += 1;
+    }
+    let mut members = String::new();
+    let first = index;
+    loop {
+        let c = *glob.get(index)?;
+        match glob.get(index + 1..index + 3) {
+            _ if c == ']' && index > first => break,
+            Some(&[':', _]) if c == '[' => {
+                // a POSIX class, such as `[:alpha:]`, is written as it is
+                let name: String = glob[index + 2..]
+                    .iter()
+                    .take_while(|c| c.is_ascii_lowercase())
+                    .collect();
+                let end = index + 2 + name.len();
+                if glob.get(end..end + 2) != Some(&[':', ']']) {
+                    members.push_str(&escape_class_char(c));
+                    index += 1;
+                    continue;
+                }
+                members.push_str(&format!("[:{}:]", name));
+                index = end + 2;
+            }
+            Some(&['-', end]) if end != ']' => {
+                // a range running backwards has no chars in it
+                if c <= end {
+                    members.push_str(&escape_class_char(c));
+                    members.push('-');
+                    members.push_str(&escape_class_char(end));
+                }
+                index += 3;
+            }
+            _ => {
+                members.push_str(&escape_class_char(c));
+                index += 1;
+            }
+        }
+    }
+    let class = match (negated, members.is_empty()) {
+        (false, true) => String::from(r"[^\s\S]"),
+        (false, false) => format!("[{}--/]", members),
+        (true, _) => format!("[^/{}]", members),
+    };
+    Some((class, index + 1))
+}
+
+/// `glob` written as a pattern matching exactly the paths it matches.
+pub fn translate(glob: &str) -> String {
+    let glob: Vec<char> = glob.chars().collect();
+    let mut pattern = String::from(r"\A");
+    let mut index = 0;
+    while index < glob.len() {
+        match glob[index] {
+            '*' => {
+                let stars = glob[index..].iter().take_while(|&&c| c == '*').count();
+                let whole_component = (index == 0 || glob[index - 1] == '/')
+                    && matches!(glob.get(index + stars), None | Some('/'));
+                index += stars;
+                if stars == 1 || !whole_component {
+                    pattern.push_str("[^/]*");
+                } else if index == glob.len() {
+                    pattern.push_str("(?s:.*)");
+                } else {
+                    pattern.push_str("(?:[^/]*/)*");
+                    index += 1;
+                }
+            }
+            '?' => {
+                pattern.push_str("[^/]");
+                index += 1;
+            }
+            '[' => match class(&glob, index + 1) {
+                Some((class, end)) => {
+                    pattern.push_str(&class);
+                    index = end;
+                }
+                None => {
+                    pattern.push_str(&escape_literal('['));
+                    index += 1;
+                }
+            },
+            '\\' if index + 1 < glob.len() => {
+                pattern.push_str(&escape_literal(glob[index + 1]));
+                index += 2;
+            }
+            c => {
+                pattern.push_str(&escape_literal(c));
+                index += 1;
+            }
+        }
+    }
+    pattern.push_str(r"\z");
+    pattern
+}
+
+/// The regex matching exactly the paths `glob` matches; see `translate`.
+pub fn compile(glob: &str) -> Result<Regex, ReError> {
+    Regex::new(&translate(glob))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::ParserError;
+
+    #[test]
+    fn test_translate() {
+        assert_eq!(translate("*.rs"), r"\A[^/]*\.rs\z");
+        assert_eq!(translate("a/**/b?"), r"\Aa/(?:[^/]*/)*b[^/]\z");
+        assert_eq!(translate("[!a-c&]"), r"\A[^/a-c\x26]\z");
+        assert_eq!(translate("[a-c&]"), r"\A[a-c\x26--/]\z");
+        assert_eq!(translate(r"[\*"), r"\A\[\*\z");
+
+        let matches = |glob: &str, path: &str| compile(glob).unwrap().is_match(path);
+        assert!(matches("*.rs", "lib.rs"));
+        assert!(!matches("*.rs", "src/lib.rs"));
+        assert!(!matches("*.rs", "lib.rsx"));
+        for path in ["src/b", "src/x/y/b", "b"] {
+            assert!(matches("**/b", path), "{}", path);
+        }
+        assert!(matches("src/**/b", "src/b"));
+        assert!(!matches("src/**/b", "srcb"));
+        assert!(matches("src/**", "src/a/b.rs"));
+        assert!(!matches("src/**", "src"));
+        // `**` within a component is just `*`
+        assert!(!matches("a**b", "a/b"));
+        assert!(matches("a**b", "axxb"));
+
+        assert!(matches("[]a]?", "]x"));
+        assert!(matches("[!a]", "b"));
+        assert!(!matches("[!a]", "/"));
+        assert!(!matches("[z-a]", "a"));
+        assert!(matches(r"\*.[ch]", "*.h"));
+        assert!(matches("[ab", "[ab"));
+        assert!(matches("a[-]b", "a-b"));
+        // no class matches `/`, even one naming it
+        assert!(!matches("a[/]b", "a/b"));
+        assert!(!matches("a[+-0]b", "a/b"));
+        assert!(matches("a[+-0]b", "a.b"));
+        assert!(!matches("a[[:punct:]]b", "a/b"));
+
+        assert_eq!(translate("[[:alpha:]_]"), r"\A[[:alpha:]_--/]\z");
+        assert!(matches("[[:alpha:]]x", "Qx"));
+        assert!(!matches("[[:alpha:]]x", "1x"));
+        assert!(matches("[![:digit:]]", "a"));
+        assert!(!matches("[![:digit:]]", "5"));
+        // a `[:` not closed by `:]` is two members
+        assert!(matches("[[:a]", ":"));
+        assert!(matches!(
+            compile("[[:alfa:]]"),
+            Err(ReError::ParsingFailed(ParserError::UnknownPosixClass(..)))
+        ));
+    }
+}
@@ -0,0 +1,354 @@
+//! Keeps the matches of a pattern up to date while the haystack is edited, as
+//! live highlighting in an editor needs on every keystroke, by searching again
+//! only where an edit can change the outcome.
+//!
+//! What a search finds on its way from one match to the next depends only on
+//! the chars it reads meanwhile, so `TrackedMatches` keeps the offsets read for
+//! every match. After an edit, the matches that read nothing at or past it stand
+//! as they were. The search restarts after the last of them and stops as soon
+//! as it is back in step with the old one: at an offset past the edit where the
+//! old search also tried a match, and from where it never read anything the edit
+//! touched, counting the start of the text as read by a search from 0 and
+//! touched by an edit at 0. The old matches from there on are only shifted.
+
+use std::ops::Range;
+
+use crate::matching::{CharTransform, Context, Matcher};
+
+/// A change to a haystack: the `removed` chars from `start` on replaced by
+/// `inserted` new ones. All offsets count chars.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edit {
+    pub start: usize,
+    pub removed: usize,
+    pub inserted: usize,
+}
+
+impl Edit {
+    /// Replacing the chars in `range` with `replacement`.
+    pub fn new(range: Range<usize>, replacement: &str) -> Edit {
+        Edit {
+            start: range.start,
+            removed: range.len(),
+            inserted: replacement.chars().count(),
+        }
+    }
+
+    /// Where the replaced chars ended in the old haystack.
+    fn old_end(&self) -> usize {
+        self.start + self.removed
+    }
+
+    /// Where the inserted chars end in the new haystack.
+    fn new_end(&self) -> usize {
+        self.start + self.inserted
+    }
+
+    /// Whether a search that read nothing below `floor` read nothing this edit
+    /// touched. A read from 0 takes in the start of the text, which an edit at
+    /// 0 moves even when it removes nothing.
+    fn spares(&self, floor: usize) -> bool {
+        floor >= self.old_end() && (floor > 0 || self.start > 0)
+    }
+}
+
+/// The spans of successive non-overlapping matches, as `Regex::find_iter`
+/// finds them, together with the parts of the haystack each one depends on.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrackedMatches {
+    spans: Vec<(usize, usize)>,
+    /// For each match, the offsets the search read or looked at on its way
+    /// from the previous match.
+    reads: Vec<Range<usize>>,
+    /// The offsets read on the way from the last match to the end.
+    tail: Range<usize>,
+}
+
+impl TrackedMatches {
+    pub fn spans(&self) -> &[(usize, usize)] {
+        &self.spans
+    }
+
+    pub fn len(&self) -> usize {
+        self.spans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.spans.is_empty()
+    }
+}
+
+/// Where a search moves on to after the match spanning `span`.
+fn next_start((start, end): (usize, usize)) -> usize {
+    if end == start {
+        start + 1
+    } else {
+        end
+    }
+}
+
+fn union(left: Range<usize>, right: Range<usize>) -> Range<usize> {
+    left.start.min(right.start)..left.end.max(right.end)
+}
+
+fn context(engine: &dyn Matcher, transform: Option<CharTransform>, text: &str) -> Context {
+    let mut context = Context::new_with_flags(text.chars().collect(), engine.get_flags());
+    context.transform = transform;
+    context
+}
+
+/// Searches `context` from `position` on as `Matcher::find_iter` does, adding
+/// every match to `tracked`. Stops at the first offset `in_step` accepts and
+/// returns it, leaving in `tracked.tail` only what was read since the last match.
+fn search(
+    engine: &dyn Matcher,
+    context: &Context,
+    mut position: usize,
+    tracked: &mut TrackedMatches,
+    mut in_step: impl FnMut(usize) -> bool,
+) -> Option<usize> {
+    let starting_classes = engine.starting_classes();
+    let mut reads: Option<Range<usize>> = None;
+    while position <= context.end {
+        if in_step(position) {
+            tracked.tail = reads.unwrap_or(position..position);
+            return Some(position);
+        }
+        context.reads.take();
+        let found = match &starting_classes {
+            Some((classes, starting))
+                if position == context.end
+                    || !starting[classes.class_of(context.char_at(position))] =>
+            {
+                None
+            }
+            _ => engine.match_suffix(engine.start_cursor(position), context),
+        };
+        // anchors tried at an offset look at the char before it and, to tell a
+        // final newline from the end, at the two after it; they are only tried
+        // where the match began or just past a char it read. At 0 there is no
+        // char before, and reading from 0 stands for reading the start of the text
+        let attempt = match context.reads.take() {
+            Some((lowest, highest)) => {
+                lowest.min(position.saturating_sub(1))..(highest + 3).max(position + 2)
+            }
+            None => position.saturating_sub(1)..position + 2,
+        };
+        let read = match reads.take() {
+            Some(reads) => union(reads, attempt),
+            None => attempt,
+        };
+        match found {
+            Some(cursor) => {
+                let span = (position, cursor.position);
+                tracked.spans.push(span);
+                tracked.reads.push(read);
+                position = next_start(span);
+            }
+            None => {
+                reads = Some(read);
+                position += 1;
+            }
+        }
+    }
+    tracked.tail = reads.unwrap_or(position..position);
+    None
+}
+
+/// Every match of `engine` in `text`, read through `transform` if there is one.
+pub(crate) fn track(
+    engine: &dyn Matcher,
+    transform: Option<CharTransform>,
+    text: &str,
+) -> TrackedMatches {
+    let mut tracked = TrackedMatches::default();
+    search(
+        engine,
+        &context(engine, transform, text),
+        0,
+        &mut tracked,
+        |_| false,
+    );
+    tracked
+}
+
+/// The matches of `engine` in `text`, the haystack `previous` was found in after
+/// `edit`, searching again only as far as the edit can make a difference.
+pub(crate) fn rematch(
+    engine: &dyn Matcher,
+    transform: Option<CharTransform>,
+    previous: &TrackedMatches,
+    edit: Edit,
+    text: &str,
+) -> TrackedMatches {
+    let context = context(engine, transform, text);
+    let kept = previous
+        .reads
+        .iter()
+        .take_while(|reads| reads.end <= edit.start)
+        .count();
+    let mut tracked = TrackedMatches {
+        spans: previous.spans[..kept].to_vec(),
+        reads: previous.reads[..kept].to_vec(),
+        tail: 0..0,
+    };
+    let restart = kept
+        .checked_sub(1)
+        .map_or(0, |last| next_start(previous.spans[last]));
+
+    // `floors[k]`: the lowest offset the old search read on its way to match `k`
+    // and everywhere after, with `k` one past the last match standing for the end
+    let mut floors = vec![previous.tail.start; previous.len() + 1];
+    for k in (0..previous.len()).rev() {
+        floors[k] = floors[k + 1].min(previous.reads[k].start);
+    }
+    let mut resumed = None;
+    let stopped = search(engine, &context, restart, &mut tracked, |position| {
+        if position < edit.new_end() {
+            return false;
+        }
+        let old = position - edit.new_end() + edit.old_end();
+        let next = previous.spans.partition_point(|&(start, _)| start < old);
+        let boundary = next
+            .checked_sub(1)
+            .map_or(0, |last| next_start(previous.spans[last]));
+        let in_step = boundary <= old && edit.spares(floors[next]);
+        if in_step {
+            resumed = Some(next);
+        }
+        in_step
+    });
+    let (Some(_), Some(next)) = (stopped, resumed) else {
+        return tracked;
+    };
+
+    let shift = |offset: usize| offset - edit.old_end() + edit.new_end();
+    let shift_range = |range: &Range<usize>| shift(range.start)..shift(range.end);
+    let pending = std::mem::take(&mut tracked.tail);
+    let first = tracked.reads.len();
+    tracked.spans.extend(
+        previous.spans[next..]
+            .iter()
+            .map(|&(start, end)| (shift(start), shift(end))),
+    );
+    tracked
+        .reads
+        .extend(previous.reads[next..].iter().map(shift_range));
+    tracked.tail = shift_range(&previous.tail);
+    // what the new search read since its last match leads up to the next one
+    match tracked.reads.get_mut(first) {
+        Some(reads) => *reads = union(pending, reads.clone()),
+        None => tracked.tail = union(pending, tracked.tail.clone()),
+    }
+    tracked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Regex;
+
+    /// `text` with `edit` applied, inserting copies of `c`.
+    fn apply(text: &str, edit: Edit, c: char) -> String {
+        let chars: Vec<char> = text.chars().collect();
+        chars[..edit.start]
+            .iter()
+            .copied()
+            .chain(std::iter::repeat_n(c, edit.inserted))
+            .chain(chars[edit.old_end()..].iter().copied())
+            .collect()
+    }
+
+    #[test]
+    fn test_rematch_agrees_with_a_full_search() {
+        let inserted = ['a', ' ', 'b', '\n', 'x'];
+        for pattern in [
+            r"\w+",
+            "a|ab",
+            "(?<=x)b+",
+            r"\bab\b",
+            "(?m)^a.*$",
+            "a(?=b)",
+            "x*",
+            r"(a)\1",
+            "$",
+        ] {
+            let regex = Regex::new(pattern).unwrap();
+            let mut text = String::from("ab xab\naab b xx a\nbab aa x b");
+            let mut tracked = regex.find_tracked(&text);
+            // a fixed pseudo-random walk of edits all over the haystack
+            let mut seed = 7usize;
+            for step in 0..60 {
+                seed = (seed * 1103515245 + 12345) % (1 << 31);
+                let length = text.chars().count();
+                let start = seed % (length + 1);
+                let removed = (seed / 7 % 3).min(length - start);
+                let edit = Edit {
+                    start,
+                    removed,
+                    inserted: seed / 11 % 3,
+                };
+                text = apply(&text, edit, inserted[step % inserted.len()]);
+                tracked = regex.rematch(&tracked, edit, &text);
+                let expected: Vec<(usize, usize)> =
+                    regex.find_iter(&text).map(|m| m.span()).collect();
+                assert_eq!(tracked.spans(), expected, "{} in {:?}", pattern, text);
+            }
+        }
+    }
+
+    #[test]
+    fn test_rematch_at_the_start_agrees_with_a_full_search() {
+        let regex = Regex::new("^a").unwrap();
+        let rematched = regex.rematch(&regex.find_tracked("a"), Edit::new(0..0, "\n"), "\na");
+        assert!(rematched.is_empty());
+        let regex = Regex::new(r"\bab\b").unwrap();
+        let rematched = regex.rematch(&regex.find_tracked("ab"), Edit::new(0..0, "b"), "bab");
+        assert!(rematched.is_empty());
+
+        let inserted = ['a', 'b', ' ', '\n'];
+        for pattern in [r"^a", r"\bab\b", r"\Ab+", "(?m)^b", "(?<!a)b", r"\Ba", "^$"] {
+            let regex = Regex::new(pattern).unwrap();
+            let mut text = String::from("ab\nba b");
+            let mut tracked = regex.find_tracked(&text);
+            // edits near the start, where the search reads the start of the text
+            let mut seed = 11usize;
+            for step in 0..80 {
+                seed = (seed * 1103515245 + 12345) % (1 << 31);
+                let length = text.chars().count();
+                let start = (seed % 3).min(length);
+                let removed = (seed / 7 % 3).min(length - start);
+                let edit = Edit {
+                    start,
+                    removed,
+                    inserted: seed / 11 % 3,
+                };
+                text = apply(&text, edit, inserted[step % inserted.len()]);
+                tracked = regex.rematch(&tracked, edit, &text);
+                assert_eq!(
+                    tracked.spans(),
+                    regex.find_tracked(&text).spans(),
+                    "{} in {:?}",
+                    pattern,
+                    text
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_rematch_keeps_matches_before_the_edit() {
+        let regex = Regex::new(r"\d+").unwrap();
+        let text = "1 22 333 4444";
+        let previous = regex.find_tracked(text);
+        let edit = Edit::new(12..13, "55");
+        let rematched = regex.rematch(&previous, edit, "1 22 333 44455");
+        assert_eq!(rematched.spans(), [(0, 1), (2, 4), (5, 8), (9, 14)]);
+        assert_eq!(rematched.reads[..3], previous.reads[..3]);
+
+        // a match after the edit only moves
+        let edit = Edit::new(0..1, "");
+        let rematched = regex.rematch(&previous, edit, " 22 333 4444");
+        assert_eq!(rematched.spans(), [(1, 3), (4, 7), (8, 12)]);
+    }
+}
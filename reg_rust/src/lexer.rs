@@ -0,0 +1,237 @@
+use std::{error::Error, fmt::Display};
+
+use crate::{
+    fsm::{ReError, RegexNFA},
+    matching::{char_slice, Context, Matcher},
+};
+
+/// How the lexer picks a rule when several match at the same position.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TieBreak {
+    /// The longest match wins; rules listed earlier win among equally long matches.
+    LongestThenEarliest,
+    /// The earliest listed rule that matches wins, however short its match.
+    FirstRule,
+}
+
+/// Two rules whose languages overlap, with a string both of them match.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ambiguity {
+    pub first: usize,
+    pub second: usize,
+    pub witness: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token<'t> {
+    /// Index of the rule that produced the token.
+    pub rule: usize,
+    pub start: usize,
+    pub end: usize,
+    pub text: &'t str,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum LexError {
+    /// No rule matches a non-empty token at this char offset.
+    NoMatch(usize),
+}
+
+impl Display for LexError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoMatch(position) => write!(f, "no rule matches a token at char {}", position),
+        }
+    }
+}
+
+impl Error for LexError {}
+
+/// Splits a haystack into tokens, each matched by one of several rules anchored
+/// at the end of the previous token.
+#[derive(Debug)]
+pub struct Lexer {
+    rules: Vec<RegexNFA>,
+    tie_break: TieBreak,
+    ambiguities: Vec<Ambiguity>,
+}
+
+impl Lexer {
+    /// Compiles `patterns` into rules, numbered in the order given, and intersects
+    /// every pair of them to find the inputs on which `tie_break` has to decide.
+    pub fn new(patterns: &[&str], tie_break: TieBreak) -> Result<Lexer, ReError> {
+        let rules = patterns
+            .iter()
+            .map(|pattern| RegexNFA::new(pattern))
+            .collect::<Result<Vec<RegexNFA>, ReError>>()?;
+        let mut ambiguities: Vec<Ambiguity> = Vec::new();
+        for (first, left) in rules.iter().enumerate() {
+            for (second, right) in rules.iter().enumerate().skip(first + 1) {
+                if let Some(witness) = left.intersection_witness(right) {
+                    ambiguities.push(Ambiguity {
+                        first,
+                        second,
+                        witness,
+                    });
+                }
+            }
+        }
+        Ok(Lexer {
+            rules,
+            tie_break,
+            ambiguities,
+        })
+    }
+
+    /// Pairs of rules that can both match the same token.
+    pub fn ambiguities(&self) -> &[Ambiguity] {
+        &self.ambiguities
+    }
+
+    pub fn tokens<'r, 't>(&'r self, text: &'t str) -> Tokens<'r, 't> {
+        Tokens {
+            lexer: self,
+            text,
+            context: Context::new(text.chars().collect()),
+            position: 0,
+            failed: false,
+        }
+    }
+
+    /// The rule and end of the token starting at `position`, if any. Under
+    /// `LongestThenEarliest` each rule is taken at its longest match, not the
+    /// one its pattern prefers, so `a|ab` reads all of `ab`.
+    fn token_at(&self, position: usize, context: &Context) -> Option<(usize, usize)> {
+        let mut best: Option<(usize, usize)> = None;
+        for (rule, nfa) in self.rules.iter().enumerate() {
+            let cursor = nfa.start_cursor(position);
+            let end = match self.tie_break {
+                TieBreak::FirstRule => nfa.match_suffix(cursor, context).map(|end| end.position),
+                TieBreak::LongestThenEarliest => nfa.longest_match_end(cursor, context),
+            };
+            let end = match end {
+                Some(end) if end > position => end,
+                _ => continue,
+            };
+            match self.tie_break {
+                TieBreak::FirstRule => return Some((rule, end)),
+                TieBreak::LongestThenEarliest => {
+                    if best.is_none_or(|(_, best_end)| end > best_end) {
+                        best = Some((rule, end));
+                    }
+                }
+            }
+        }
+        best
+    }
+}
+
+#[derive(Debug)]
+pub struct Tokens<'r, 't> {
+    lexer: &'r Lexer,
+    text: &'t str,
+    context: Context,
+    position: usize,
+    failed: bool,
+}
+
+impl<'r, 't> Iterator for Tokens<'r, 't> {
+    type Item = Result<Token<'t>, LexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.position >= self.context.end {
+            return None;
+        }
+        match self.lexer.token_at(self.position, &self.context) {
+            Some((rule, end)) => {
+                let token = Token {
+                    rule,
+                    start: self.position,
+                    end,
+                    text: char_slice(self.text, self.position, end),
+                };
+                self.position = end;
+                Some(Ok(token))
+            }
+            None => {
+                self.failed = true;
+                Some(Err(LexError::NoMatch(self.position)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lex(lexer: &Lexer, text: &str) -> Vec<(usize, String)> {
+        lexer
+            .tokens(text)
+            .map(|token| token.map(|token| (token.rule, token.text.to_string())))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_tie_breaking() {
+        let patterns = ["if", "[a-z]+", " +", "[0-9]+"];
+        let longest = Lexer::new(&patterns, TieBreak::LongestThenEarliest).unwrap();
+        assert_eq!(
+            lex(&longest, "if iffy"),
+            vec![
+                (0, String::from("if")),
+                (2, String::from(" ")),
+                (1, String::from("iffy")),
+            ]
+        );
+
+        let first = Lexer::new(&patterns, TieBreak::FirstRule).unwrap();
+        assert_eq!(
+            lex(&first, "iffy12"),
+            vec![
+                (0, String::from("if")),
+                (1, String::from("fy")),
+                (3, String::from("12")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_longest_match_of_each_rule() {
+        let lexer = Lexer::new(&["a|ab", "a"], TieBreak::LongestThenEarliest).unwrap();
+        assert_eq!(lex(&lexer, "ab"), vec![(0, String::from("ab"))]);
+        // a rule preferring to match nothing still reads what it can
+        let lexer = Lexer::new(&["b", "a*?", r"(a)\1?"], TieBreak::LongestThenEarliest).unwrap();
+        assert_eq!(
+            lex(&lexer, "aaab"),
+            vec![(1, String::from("aaa")), (0, String::from("b"))]
+        );
+        assert_eq!(lex(&lexer, "a"), vec![(1, String::from("a"))]);
+    }
+
+    #[test]
+    fn test_lex_error() {
+        let lexer = Lexer::new(&["[a-z]+"], TieBreak::FirstRule).unwrap();
+        let tokens: Vec<_> = lexer.tokens("ab1cd").collect();
+        assert_eq!(tokens.len(), 2);
+        assert_eq!(tokens[1], Err(LexError::NoMatch(2)));
+        assert_eq!(
+            LexError::NoMatch(2).to_string(),
+            "no rule matches a token at char 2"
+        );
+    }
+
+    #[test]
+    fn test_ambiguities() {
+        let lexer = Lexer::new(&["if", "[a-z]+", "[0-9]+"], TieBreak::FirstRule).unwrap();
+        assert_eq!(
+            lexer.ambiguities(),
+            &[Ambiguity {
+                first: 0,
+                second: 1,
+                witness: String::from("if"),
+            }]
+        );
+    }
+}
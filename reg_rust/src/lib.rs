@@ -1,6 +1,34 @@
+pub mod diff;
+pub mod ffi;
 pub mod fsm;
+pub mod glob;
+pub mod incremental;
+pub mod lexer;
+pub mod lint;
 pub mod matching;
 pub mod nom_parser;
 pub mod parser;
+pub mod regex;
+pub mod segment;
+pub mod simplify;
+#[cfg(feature = "cli")]
+pub mod spec;
+pub mod unicode;
 pub mod utils;
+pub mod verify;
 pub mod vm;
+
+pub use self::regex::{
+    AdjacentEmptyMatches, BytesCaptures, BytesGroup, Captures, CaseStyle, GroupSource,
+    MemoryBudget, Regex, RegexError, RegexSet, RegexSetError, ReplacerSet, ReplacerSetError,
+    SetMatch, SetMatches, UnsetGroups,
+};
+pub use incremental::{Edit, TrackedMatches};
+pub use matching::{
+    BudgetedMatch, Callout, CalloutHandler, Highlight, Match, Matcher, Participation,
+    RecordSeparator, Semantics, StepBudget, YieldingMatches,
+};
+pub use parser::{
+    escape, parse, GroupTree, LengthBounds, SourceMap, DEFAULT_MAX_REPETITION, DEFAULT_NEST_LIMIT,
+};
+pub use utils::{CandidatePositions, LineStarts, LiteralPrefilter, Prefilter};
@@ -0,0 +1,303 @@
+//! Reports capture groups that do no useful work, for cleaning up large sets
+//! of patterns: groups that can never take part in a match, and groups that
+//! capture the same text whenever they do. It also passes on the proven
+//! rewrites `simplify::simplifications` finds for the whole pattern.
+//!
+//! The analysis reads the parsed pattern and stays silent when in doubt. A group
+//! is dead only if something on every path through it can never match: an empty
+//! class, a `{0}`, or an anchor that cannot hold where it stands, such as `^`
+//! after a char without MULTILINE, or `\z` before one.
+
+use std::fmt::Display;
+
+use crate::{
+    fsm::ReError,
+    parser::{run_parse, Node, ParsedRegex},
+    simplify::{simplifications, Rule},
+    utils::RegexFlags,
+};
+
+/// A capture group that can be dropped or simplified, or a simpler way to write
+/// the whole pattern.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Lint {
+    /// The group with this index, counting from 1, as written in the pattern,
+    /// can never participate in a match.
+    DeadGroup(usize, String),
+    /// The group with this index as written, and the one text it captures
+    /// whenever it participates.
+    ConstantGroup(usize, String, String),
+    /// The pattern rewritten by this rule, which matches exactly the same.
+    Simplification(Rule, String),
+}
+
+impl Display for Lint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::DeadGroup(index, group) => write!(
+                f,
+                "group {} `{}` can never participate in a match",
+                index, group
+            ),
+            Self::ConstantGroup(index, group, text) => {
+                write!(f, "group {} `{}` always captures {:?}", index, group, text)
+            }
+            Self::Simplification(rule, pattern) => write!(f, "{}: `{}`", rule, pattern),
+        }
+    }
+}
+
+/// Whether a char is sure to be consumed before and after a node by the rest
+/// of the match around it.
+#[derive(Debug, Clone, Copy, Default)]
+struct Surroundings {
+    before: bool,
+    after: bool,
+}
+
+/// Whether `node` can match anything at all with `surroundings` around it.
+fn can_match(node: &Node, surroundings: Surroundings, flags: RegexFlags) -> bool {
+    match node {
+        Node::StartOfStringOnly => !surroundings.before,
+        Node::StartOfString => !surroundings.before || flags.intersects(RegexFlags::MULTILINE),
+        Node::EndOfStringOnlyNotNewline => !surroundings.after,
+        Node::Match(item, quantifier) | Node::Group(item, _, quantifier) => {
+            quantifier.repetitions().0 == 0 || can_match(item, surroundings, flags)
+        }
+        Node::Expression(items, alternative) => {
+            can_match_in_turn(items, surroundings, flags)
+                || alternative
+                    .as_ref()
+                    .is_some_and(|alternative| can_match(alternative, surroundings, flags))
+        }
+        Node::Lookahead(item) => can_match(item, surroundings.ahead(), flags),
+        Node::Lookbehind(item, false) => can_match(item, surroundings.behind(), flags),
+        Node::Atomic(item) => can_match(item, surroundings, flags),
+        Node::Flagged(item, flags) => can_match(item, surroundings, *flags),
+        _ => node
+            .class_ranges(flags)
+            .is_none_or(|mut ranges| ranges.next().is_some()),
+    }
+}
+
+impl Surroundings {
+    /// What surrounds a node that `self` surrounds from outside the sequence
+    /// it is in, and `within` from inside it.
+    fn within(self, outside: Surroundings) -> Surroundings {
+        Surroundings {
+            before: self.before || outside.before,
+            after: self.after || outside.after,
+        }
+    }
+
+    /// What surrounds the pattern of a lookahead: it starts where the lookahead
+    /// stands, but what follows it may overlap the rest of the match.
+    fn ahead(self) -> Surroundings {
+        Surroundings {
+            before: self.before,
+            after: false,
+        }
+    }
+
+    /// What surrounds the pattern of a lookbehind, which ends where it stands.
+    fn behind(self) -> Surroundings {
+        Surroundings {
+            before: false,
+            after: self.after,
+        }
+    }
+}
+
+/// The items of one alternative, each with whether the items before and after
+/// it in the alternative are sure to consume a char.
+fn sequence(items: &[Box<Node>]) -> impl Iterator<Item = (&Node, Surroundings)> {
+    let consumes: Vec<bool> = items
+        .iter()
+        .map(|item| item.length_bounds().min > 0)
+        .collect();
+    items.iter().enumerate().map(move |(index, item)| {
+        let around = Surroundings {
+            before: consumes[..index].contains(&true),
+            after: consumes[index + 1..].contains(&true),
+        };
+        (&**item, around)
+    })
+}
+
+/// Whether `items` can match one after the other with `surroundings` around them.
+fn can_match_in_turn(items: &[Box<Node>], surroundings: Surroundings, flags: RegexFlags) -> bool {
+    sequence(items).all(|(item, around)| can_match(item, around.within(surroundings), flags))
+}
+
+/// The one text `node` matches, if it always matches the same text.
+fn literal(node: &Node, flags: RegexFlags) -> Option<String> {
+    match node {
+        Node::Match(item, quantifier) | Node::Group(item, _, quantifier) => {
+            match quantifier.repetitions() {
+                (lower, Some(upper)) if lower == upper => {
+                    Some(literal(item, flags)?.repeat(usize::try_from(lower).ok()?))
+                }
+                _ => None,
+            }
+        }
+        Node::Expression(items, alternative) => {
+            let text = items
+                .iter()
+                .map(|item| literal(item, flags))
+                .collect::<Option<String>>()?;
+            match alternative {
+                Some(alternative) if literal(alternative, flags)? != text => None,
+                _ => Some(text),
+            }
+        }
+        Node::Flagged(item, flags) => literal(item, *flags),
+        _ if node.length_bounds().max == Some(0) => Some(String::new()),
+        _ => match node.single_char(flags)? {
+            (c, false) => Some(c.to_string()),
+            _ => None,
+        },
+    }
+}
+
+struct Linter<'p> {
+    pattern: &'p str,
+    parsed: ParsedRegex,
+    lints: Vec<Lint>,
+}
+
+impl Linter<'_> {
+    /// Group `index` as written in the pattern.
+    fn group(&self, index: usize) -> String {
+        self.parsed
+            .source_map
+            .group(index)
+            .map_or_else(String::new, |span| self.pattern[span].to_string())
+    }
+
+    /// Reports the groups in `node`, which can never participate in a match
+    /// if `dead` is set.
+    fn visit(&mut self, node: &Node, surroundings: Surroundings, flags: RegexFlags, dead: bool) {
+        match node {
+            Node::Group(item, index, quantifier) => {
+                let dead = dead
+                    || quantifier.repetitions().1 == Some(0)
+                    || !can_match(item, surroundings, flags);
+                if let Some(index) = index {
+                    let group = self.group(index + 1);
+                    if dead {
+                        self.lints.push(Lint::DeadGroup(index + 1, group));
+                    } else if let Some(text) = literal(item, flags) {
+                        self.lints.push(Lint::ConstantGroup(index + 1, group, text));
+                    }
+                }
+                self.visit(item, surroundings, flags, dead);
+            }
+            Node::Expression(items, alternative) => {
+                let impossible = !can_match_in_turn(items, surroundings, flags);
+                for (item, around) in sequence(items) {
+                    self.visit(item, around.within(surroundings), flags, dead || impossible);
+                }
+                if let Some(alternative) = alternative {
+                    self.visit(alternative, surroundings, flags, dead);
+                }
+            }
+            Node::Lookahead(item) => self.visit(item, surroundings.ahead(), flags, dead),
+            Node::Lookbehind(item, _) => self.visit(item, surroundings.behind(), flags, dead),
+            Node::Atomic(item) => self.visit(item, surroundings, flags, dead),
+            Node::Flagged(item, flags) => self.visit(item, surroundings, *flags, dead),
+            _ => {}
+        }
+    }
+}
+
+/// The groups of `pattern` that can never participate in a match or always
+/// capture the same text, in the order they open, followed by its simplifications.
+pub fn lint(pattern: &str) -> Result<Vec<Lint>, ReError> {
+    let mut flags = RegexFlags::OPTIMIZE;
+    let parsed = run_parse(pattern, &mut flags).map_err(ReError::ParsingFailed)?;
+    let root = parsed.root.clone();
+    let mut linter = Linter {
+        pattern,
+        parsed,
+        lints: Vec::new(),
+    };
+    linter.visit(&root, Surroundings::default(), flags, false);
+    linter.lints.extend(
+        simplifications(pattern)?.into_iter().map(|simplification| {
+            Lint::Simplification(simplification.rule, simplification.pattern)
+        }),
+    );
+    Ok(linter.lints)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dead(index: usize, group: &str) -> Lint {
+        Lint::DeadGroup(index, String::from(group))
+    }
+
+    fn constant(index: usize, group: &str, text: &str) -> Lint {
+        Lint::ConstantGroup(index, String::from(group), String::from(text))
+    }
+
+    #[test]
+    fn test_lint() {
+        assert_eq!(
+            lint(r"(a)b(c|d)(?:x(y)){0}").unwrap(),
+            [
+                constant(1, "(a)", "a"),
+                dead(3, "(y)"),
+                Lint::Simplification(Rule::MergeIntoClass, String::from("(a)b([cd])(?:x(y)){0}"))
+            ]
+        );
+        assert_eq!(
+            lint(r"a(^b)|(c)").unwrap(),
+            [dead(1, "(^b)"), constant(2, "(c)", "c")]
+        );
+        assert_eq!(lint(r"(?m)a(^b)").unwrap(), [constant(1, "(^b)", "b")]);
+        // every group in an alternative that cannot match is dead
+        assert_eq!(
+            lint(r"(x+)(?P<y>\d)[^\s\S]|(z*)").unwrap(),
+            [dead(1, "(x+)"), dead(2, r"(?P<y>\d)")]
+        );
+        assert_eq!(
+            lint(r"((\w)\z)\w").unwrap(),
+            [dead(1, r"((\w)\z)"), dead(2, r"(\w)")]
+        );
+        assert_eq!(
+            lint(r"(ab|ab)(a+)(a{3})(?i)(a)(1)").unwrap(),
+            [
+                constant(1, "(ab|ab)", "ab"),
+                constant(3, "(a{3})", "aaa"),
+                constant(5, "(1)", "1")
+            ]
+        );
+        assert_eq!(
+            dead(1, "(y)").to_string(),
+            "group 1 `(y)` can never participate in a match"
+        );
+        assert_eq!(
+            constant(1, "(^b)", "b").to_string(),
+            r#"group 1 `(^b)` always captures "b""#
+        );
+        assert!(lint("(").is_err());
+    }
+
+    #[test]
+    fn test_lint_simplifications() {
+        assert_eq!(
+            lint("(?:ab)c|d|(x)").unwrap(),
+            [
+                constant(1, "(x)", "x"),
+                Lint::Simplification(Rule::RedundantGroup, String::from("abc|d|(x)"))
+            ]
+        );
+        assert_eq!(
+            Lint::Simplification(Rule::UnreachableAlternative, String::from("ab|c")).to_string(),
+            "alternative repeats an earlier one: `ab|c`"
+        );
+        assert!(lint("a+b").unwrap().is_empty());
+    }
+}
@@ -1,15 +1,24 @@
 use std::{
-    collections::{HashSet, VecDeque},
+    cell::{Cell, OnceCell},
+    collections::HashMap,
     fmt::Debug,
-    iter::FusedIterator,
+    future::Future,
+    hash::{Hash, Hasher},
+    iter::{self, FusedIterator},
+    mem::size_of_val,
+    ops::Range,
+    pin::Pin,
+    sync::Arc,
+    task::{self, Poll},
 };
 
+use itertools::Itertools;
+
 use crate::{
-    fsm::RegexNFA,
-    fsm::Transition,
-    parser::Node,
-    utils::RegexFlags,
-    vm::{Instruction, PikeVM, Thread, VMSearchSpaceNode},
+    fsm::{Paths, RegexNFA},
+    parser::{GroupTree, LengthBounds, Node, SourceMap},
+    utils::{EquivalenceClasses, Prefilter, RegexFlags, RequiredLiterals, NO_REQUIRED_LITERALS},
+    vm::{Instruction, PikeVM, Threads},
 };
 
 #[derive(Debug, Clone)]
@@ -26,8 +35,23 @@ impl Cursor {
         }
     }
 
+    /// The span last captured by the group with this index, counting from 0.
+    pub fn backreference(&self, index: usize) -> Option<(usize, usize)> {
+        match (self.groups.get(index * 2)?, self.groups.get(index * 2 + 1)?) {
+            (Some(start), Some(end)) if start <= end => Some((*start, *end)),
+            _ => None,
+        }
+    }
+
     pub fn update(&self, node: &Node) -> Cursor {
         match node {
+            Node::Backreference(index) => Cursor {
+                position: self.position
+                    + self
+                        .backreference(*index)
+                        .map_or(0, |(start, end)| end - start),
+                groups: self.groups.clone(),
+            },
             Node::GroupEntry(index) => {
                 let mut copy = self.groups.clone();
                 copy[index * 2] = Some(self.position);
@@ -44,28 +68,54 @@ impl Cursor {
                     groups: copy,
                 }
             }
+            Node::ScriptRunEntry(index) => {
+                let mut copy = self.groups.clone();
+                let slot = copy.len() - 1 - index;
+                copy[slot] = Some(self.position);
+                Cursor {
+                    position: self.position,
+                    groups: copy,
+                }
+            }
             _ => Cursor {
                 position: self.position + node.increment(),
                 groups: self.groups.clone(),
             },
         }
     }
+}
 
-    fn to_string(&self, text: &str) -> Vec<Option<String>> {
-        (0..self.groups.len() / 2)
-            .map(|group_index| {
-                let (some_frm, some_to) = (
-                    self.groups[group_index * 2],
-                    self.groups[group_index * 2 + 1],
-                );
-                if let Some(frm) = some_frm {
-                    if let Some(to) = some_to {
-                        return Some(text.chars().skip(frm).take(to - frm).collect());
-                    }
-                }
-                return None;
-            })
-            .collect()
+/// A per-character mapping applied to the haystack as it is read, such as one of
+/// the normalizations in `utils`.
+pub type CharTransform = fn(char) -> char;
+
+/// What the engine reports when a search reaches a `(?Cn)` callout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Callout {
+    /// The `n` of `(?Cn)`, 0 for a bare `(?C)`.
+    pub number: u8,
+    /// The char offset the path being explored has reached.
+    pub position: usize,
+}
+
+/// A caller's function run at every callout a search reaches; returning `false`
+/// abandons the path through it as if an assertion had failed.
+#[derive(Clone)]
+pub struct CalloutHandler(Arc<dyn Fn(Callout) -> bool + Send + Sync>);
+
+impl CalloutHandler {
+    pub fn new(handler: impl Fn(Callout) -> bool + Send + Sync + 'static) -> CalloutHandler {
+        CalloutHandler(Arc::new(handler))
+    }
+
+    pub fn call(&self, callout: Callout) -> bool {
+        (self.0)(callout)
+    }
+}
+
+impl Debug for CalloutHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("CalloutHandler(..)")
     }
 }
 
@@ -73,18 +123,80 @@ impl Cursor {
 pub struct Context {
     pub text: Vec<char>,
     pub flags: RegexFlags,
+    /// Characters at or after `end` are never consumed by a match, but assertions
+    /// such as `$` and `\b` still look at them.
+    pub end: usize,
+    /// Applied to every character a match consumes or `\b` inspects, at the moment
+    /// it is read. Line anchors and script runs see the original characters.
+    pub transform: Option<CharTransform>,
+    /// The offsets read through `char_at` since it was last taken.
+    pub reads: ReadExtent,
+}
+
+/// The lowest and highest offsets a search has read, kept so that callers can
+/// tell which part of the haystack a result depends on. It is bookkeeping, so it
+/// takes no part in hashing a `Context`.
+#[derive(Debug, Clone, Default)]
+pub struct ReadExtent(Cell<Option<(usize, usize)>>);
+
+impl ReadExtent {
+    fn record(&self, position: usize) {
+        let (lowest, highest) = self.0.get().unwrap_or((position, position));
+        self.0
+            .set(Some((lowest.min(position), highest.max(position))));
+    }
+
+    /// The lowest and highest offsets read so far, forgetting them.
+    pub fn take(&self) -> Option<(usize, usize)> {
+        self.0.take()
+    }
+}
+
+impl Hash for ReadExtent {
+    fn hash<H: Hasher>(&self, _: &mut H) {}
 }
 
 impl<'a> Context {
     pub fn new(text: Vec<char>) -> Context {
+        let end = text.len();
         return Context {
             text,
             flags: RegexFlags::NO_FLAG,
+            end,
+            transform: None,
+            reads: ReadExtent::default(),
         };
     }
 
     pub fn new_with_flags(text: Vec<char>, flags: RegexFlags) -> Context {
-        return Context { text, flags };
+        let end = text.len();
+        Context {
+            text,
+            flags,
+            end,
+            transform: None,
+            reads: ReadExtent::default(),
+        }
+    }
+
+    pub fn new_with_bounds(text: Vec<char>, flags: RegexFlags, end: usize) -> Context {
+        let end = end.min(text.len());
+        Context {
+            text,
+            flags,
+            end,
+            transform: None,
+            reads: ReadExtent::default(),
+        }
+    }
+
+    /// The character at `position`, as seen through the transform.
+    pub fn char_at(&self, position: usize) -> char {
+        self.reads.record(position);
+        match self.transform {
+            Some(transform) => transform(self.text[position]),
+            None => self.text[position],
+        }
     }
 }
 
@@ -93,21 +205,92 @@ pub struct Match<'s> {
     start: usize,
     end: usize,
     text: &'s str,
-    captured_groups: Vec<Option<String>>,
+    /// The capture slots of the final cursor: group `i` spans `slots[2 * i]..slots[2 * i + 1]`.
+    slots: Vec<Option<usize>>,
+    /// Names of the pattern's named groups, mapped to their group index.
+    names: GroupNames,
+    /// The byte range of each group in `text`, group 0 being the whole match,
+    /// worked out in one pass the first time a group is read.
+    byte_spans: OnceCell<Vec<Option<(usize, usize)>>>,
 }
 
+/// A stretch of a match to paint as belonging to one group. The spans of a
+/// match's highlights tile it without overlapping, so a UI can style each one
+/// independently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Highlight {
+    pub start: usize,
+    pub end: usize,
+    /// The group index, stable across matches: 0 for text only the whole match
+    /// covers, otherwise the innermost group covering the span.
+    pub group: usize,
+}
+
+/// Which groups of a match participated, one bit per group with group 0 (the
+/// whole match) in the lowest bit of the first word.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Participation {
+    words: Vec<u64>,
+}
+
+impl Participation {
+    pub fn contains(&self, index: usize) -> bool {
+        self.words
+            .get(index / 64)
+            .is_some_and(|word| word & (1 << (index % 64)) != 0)
+    }
+
+    /// How many groups participated, the whole match included.
+    pub fn count(&self) -> usize {
+        self.words
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    /// The raw bitmask. For patterns with at most 63 groups it is a single word.
+    pub fn as_words(&self) -> &[u64] {
+        &self.words
+    }
+}
+
+/// How much of the haystack to show around a match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContextSize {
+    /// At most this many bytes on either side, shrunk to the nearest char boundary.
+    Bytes(usize),
+    Chars(usize),
+    /// The rest of the lines the match starts and ends on, plus this many whole
+    /// lines on either side, like `grep -C`. Line breaks at the edges are left out.
+    Lines(usize),
+}
+
+/// A match with the text around it, all borrowed from the haystack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MatchContext<'t> {
+    pub before: &'t str,
+    pub matched: &'t str,
+    pub after: &'t str,
+}
+
+/// Maps the name of every named group of a pattern to its group index.
+pub type GroupNames = Arc<HashMap<String, usize>>;
+
 impl<'s> Match<'s> {
     pub fn new(
         start: usize,
         end: usize,
         text: &'s str,
-        captured_groups: Vec<Option<String>>,
+        slots: Vec<Option<usize>>,
+        names: GroupNames,
     ) -> Self {
         Match {
             start,
             end,
             text,
-            captured_groups,
+            slots,
+            names,
+            byte_spans: OnceCell::new(),
         }
     }
 
@@ -115,265 +298,2489 @@ impl<'s> Match<'s> {
         (self.start, self.end)
     }
 
-    pub fn groups(&self) -> Vec<Option<String>> {
-        self.captured_groups.clone()
+    /// This match, found in a slice of `text` starting at char `offset`, as a
+    /// match in `text` itself.
+    fn offset_into(self, text: &'s str, offset: usize) -> Match<'s> {
+        Match {
+            start: self.start + offset,
+            end: self.end + offset,
+            text,
+            slots: self
+                .slots
+                .into_iter()
+                .map(|slot| slot.map(|position| position + offset))
+                .collect(),
+            names: self.names,
+            byte_spans: OnceCell::new(),
+        }
     }
 
-    fn group(&self, index: usize) -> Option<String> {
-        if index > self.captured_groups.len() {
-            panic!("group index out of bounds");
-        }
-        if index == 0 {
-            Some(self.text[self.start..self.end].to_string())
-        } else {
-            self.captured_groups[index].clone()
-        }
+    /// The number of capture groups, not counting the whole match.
+    pub fn group_count(&self) -> usize {
+        self.slots.len() / 2
     }
 
-    pub fn as_str(&self) -> String {
-        if self.start >= self.text.len() {
-            "".to_string()
+    /// The span of group `index`, where group 0 is the whole match; `None` if
+    /// there is no such group or it did not participate in the match. Slots that
+    /// end before they start count as not participating, as for backreferences.
+    pub fn group_span(&self, index: usize) -> Option<(usize, usize)> {
+        if index > self.group_count() {
+            None
+        } else if index == 0 {
+            Some(self.span())
         } else {
-            self.text
-                .chars()
-                .skip(self.start)
-                .take(self.end - self.start)
-                .collect::<String>()
+            match (self.slots[index * 2 - 2], self.slots[index * 2 - 1]) {
+                (Some(start), Some(end)) if start <= end => Some((start, end)),
+                _ => None,
+            }
         }
     }
-}
 
-pub trait Matcher<'s>
-where
-    Self: Debug,
-{
-    fn group_count(&self) -> usize;
-    fn get_flags(&self) -> RegexFlags;
-    fn match_suffix(&self, cursor: Cursor, context: Context) -> Option<Cursor>;
-    fn is_match(&'s self, text: &'s str) -> bool {
-        match self.find(text) {
-            Some(_) => true,
-            _ => false,
+    /// Which groups participated, read off the slots without slicing the haystack.
+    pub fn participated(&self) -> Participation {
+        let mut words = vec![0u64; (self.group_count() + 1).div_ceil(64)];
+        words[0] = 1;
+        for (index, slots) in self.slots.chunks(2).enumerate() {
+            if slots[0].is_some() && slots[1].is_some() {
+                words[(index + 1) / 64] |= 1 << ((index + 1) % 64);
+            }
         }
+        Participation { words }
     }
-    fn find(&'s self, text: &'s str) -> Option<String> {
-        self.find_iter(text).next().map(|m| m.group(0)).unwrap()
-    }
-    fn find_iter(&'s self, text: &'s str) -> Box<dyn Iterator<Item = Match> + 's>;
-}
 
-#[derive(Debug)]
-struct Matches<'s> {
-    text: &'s str,
-    pattern: Box<dyn Matcher<'s>>,
-    start: usize,
-    context: Context,
-    increment: usize,
-}
+    /// The text of group `index` sliced out of the haystack, where group 0 is the
+    /// whole match; `None` if there is no such group or it did not participate.
+    /// Nothing is copied, and the haystack is only scanned for the first group read.
+    pub fn group(&self, index: usize) -> Option<&'s str> {
+        let (start, end) = (*self.byte_spans().get(index)?)?;
+        Some(&self.text[start..end])
+    }
 
-impl<'s> Iterator for Matches<'s> {
-    type Item = Match<'s>;
+    /// Whether the match and every group in it start and end on char boundaries
+    /// of the haystack, so that slicing it at their byte offsets cannot panic.
+    /// Spans count chars and are only turned into byte offsets by walking the
+    /// haystack's chars, which always lands on a boundary, so this holds for any
+    /// match an engine reports; callers slicing by hand can check it.
+    pub fn is_char_boundary_aligned(&self) -> bool {
+        self.byte_spans().iter().flatten().all(|&(start, end)| {
+            start <= end && self.text.is_char_boundary(start) && self.text.is_char_boundary(end)
+        })
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        while self.start <= self.text.len() {
-            let cursor = Cursor::new(self.start, self.pattern.group_count());
-            let match_result = self.pattern.match_suffix(cursor, self.context.clone());
+    /// The byte range of each group, or `None` if it did not participate. An
+    /// offset past the last char, which no engine reports, is clamped to the end
+    /// of the haystack, so every range is one `text` can be sliced at.
+    pub(crate) fn byte_spans(&self) -> &[Option<(usize, usize)>] {
+        self.byte_spans.get_or_init(|| {
+            let spans: Vec<Option<(usize, usize)>> = (0..=self.group_count())
+                .map(|index| self.group_span(index))
+                .collect();
+            let mut positions: Vec<usize> = spans
+                .iter()
+                .flatten()
+                .flat_map(|&(start, end)| [start, end])
+                .collect();
+            positions.sort_unstable();
+            positions.dedup();
+            let mut offsets = self
+                .text
+                .char_indices()
+                .map(|(offset, _)| offset)
+                .chain(iter::once(self.text.len()))
+                .enumerate();
+            let bytes: Vec<usize> = positions
+                .iter()
+                .map(|&position| {
+                    offsets
+                        .find(|&(index, _)| index == position)
+                        .map_or(self.text.len(), |(_, offset)| offset)
+                })
+                .collect();
+            let byte = |position: usize| {
+                let index = positions.binary_search(&position);
+                bytes[index.expect("every position was looked up")]
+            };
+            let byte_spans: Vec<Option<(usize, usize)>> = spans
+                .into_iter()
+                .map(|span| span.map(|(start, end)| (byte(start), byte(end))))
+                .collect();
+            debug_assert!(byte_spans.iter().flatten().all(|&(start, end)| {
+                self.text.is_char_boundary(start) && self.text.is_char_boundary(end)
+            }));
+            byte_spans
+        })
+    }
 
-            if let Some(cursor) = match_result {
-                self.increment = if cursor.position == self.start {
-                    1
-                } else {
-                    cursor.position - self.start
-                };
-                let match_result = Some(Match::new(
-                    self.start,
-                    cursor.position,
-                    &self.text,
-                    cursor.to_string(self.text),
-                ));
-                self.start += self.increment;
-                return match_result;
-            }
-            self.start += 1;
-        }
-        return None;
+    pub fn groups(&self) -> Vec<Option<String>> {
+        (1..=self.group_count())
+            .map(|index| self.group(index).map(String::from))
+            .collect()
     }
-}
 
-impl<'s> FusedIterator for Matches<'s> {}
+    /// The text of the group called `name`; `None` if there is no such group or
+    /// it did not participate in the match.
+    pub fn name(&self, name: &str) -> Option<&'s str> {
+        self.names.get(name).and_then(|index| self.group(*index))
+    }
 
-impl<'s> Matcher<'s> for RegexNFA {
-    fn group_count(&self) -> usize {
-        return self.group_count();
+    /// Names of the pattern's named groups, mapped to their group index.
+    pub fn group_names(&self) -> &GroupNames {
+        &self.names
     }
 
-    fn get_flags(&self) -> RegexFlags {
-        self.get_flags()
+    /// The group index of the group called `name`, counting from 1.
+    pub fn group_index(&self, name: &str) -> Option<usize> {
+        self.names.get(name).copied()
     }
 
-    fn match_suffix(&self, cursor: Cursor, context: Context) -> Option<Cursor> {
-        let mut visited: HashSet<(usize, &Transition)> = HashSet::new();
-        let mut queue = VecDeque::from(self.step(
-            &Transition::new(Node::Epsilon, self.start),
-            &cursor,
-            &context,
-            &mut visited,
-        ));
+    /// Every named group mapped to its text, or `None` if it did not participate.
+    pub fn groupdict(&self) -> HashMap<&str, Option<&'s str>> {
+        self.names
+            .iter()
+            .map(|(name, index)| (name.as_str(), self.group(*index)))
+            .collect()
+    }
 
-        let mut match_result: Option<Cursor> = None;
-        loop {
-            let mut frontier: VecDeque<(Transition, Cursor)> = VecDeque::new();
-            visited = HashSet::new();
+    pub fn as_str(&self) -> &'s str {
+        char_slice(self.text, self.start, self.end)
+    }
 
-            while let Some((transition, cursor)) = queue.pop_front() {
-                if transition.node.accepts(&cursor, &context) {
-                    if self.accept == transition.end {
-                        match_result = Some(cursor.update(&transition.node));
-                        break;
-                    }
-                    frontier.extend(self.step(&transition, &cursor, &context, &mut visited));
-                } else if let Node::Epsilon = transition.node {
-                    if self.accept == transition.end {
-                        match_result = Some(cursor.update(&transition.node));
-                        break;
-                    }
-                    frontier.extend(self.step(&transition, &cursor, &context, &mut visited));
+    /// The match together with `size` worth of the haystack on either side.
+    pub fn context(&self, size: ContextSize) -> MatchContext<'s> {
+        let text = self.text;
+        let matched = self.as_str();
+        let start = matched.as_ptr() as usize - text.as_ptr() as usize;
+        let end = start + matched.len();
+        let (before, after) = match size {
+            ContextSize::Bytes(n) => {
+                let mut before = start.saturating_sub(n);
+                while !text.is_char_boundary(before) {
+                    before += 1;
+                }
+                let mut after = end.saturating_add(n).min(text.len());
+                while !text.is_char_boundary(after) {
+                    after -= 1;
                 }
+                (before, after)
             }
+            ContextSize::Chars(n) => {
+                let before = text[..start]
+                    .char_indices()
+                    .rev()
+                    .take(n)
+                    .last()
+                    .map_or(start, |(offset, _)| offset);
+                let after = text[end..]
+                    .char_indices()
+                    .nth(n)
+                    .map_or(text.len(), |(offset, _)| end + offset);
+                (before, after)
+            }
+            ContextSize::Lines(n) => {
+                let before = text[..start]
+                    .match_indices('\n')
+                    .rev()
+                    .nth(n)
+                    .map_or(0, |(offset, _)| offset + 1);
+                let after = text[end..]
+                    .match_indices('\n')
+                    .nth(n)
+                    .map_or(text.len(), |(offset, _)| end + offset);
+                (before, after)
+            }
+        };
+        MatchContext {
+            before: &text[before..start],
+            matched,
+            after: &text[end..after],
+        }
+    }
+
+    /// The match split into highlights, in order. Where groups nest, the inner
+    /// group wins; since a group opens after every group enclosing it, that is the
+    /// highest-numbered group covering the span. Neighbouring stretches of the
+    /// same group are merged and empty groups are left out.
+    pub fn highlights(&self) -> Vec<Highlight> {
+        let spans: Vec<(usize, usize)> = (0..=self.group_count())
+            .map(|index| self.group_span(index).unwrap_or((0, 0)))
+            .collect();
+        let mut boundaries: Vec<usize> = spans
+            .iter()
+            .flat_map(|(start, end)| [*start, *end])
+            .filter(|position| (self.start..=self.end).contains(position))
+            .collect();
+        boundaries.sort_unstable();
+        boundaries.dedup();
 
-            if frontier.is_empty() {
-                break;
+        let mut highlights: Vec<Highlight> = Vec::new();
+        for (start, end) in boundaries.into_iter().tuple_windows() {
+            let group = spans
+                .iter()
+                .rposition(|(group_start, group_end)| *group_start <= start && end <= *group_end)
+                .unwrap_or(0);
+            match highlights.last_mut() {
+                Some(last) if last.group == group && last.end == start => last.end = end,
+                _ => highlights.push(Highlight { start, end, group }),
             }
-            std::mem::swap(&mut frontier, &mut queue);
         }
-        match_result
+        highlights
     }
+}
 
-    fn find_iter(&'s self, text: &'s str) -> Box<dyn Iterator<Item = Match> + '_> {
-        Box::new(Matches {
-            text,
-            pattern: Box::new(self.clone()),
-            start: 0,
-            increment: 1,
-            context: Context::new_with_flags(text.chars().collect(), self.get_flags()),
+/// Slices `text` between two char offsets, clamping both to the end of `text`.
+pub(crate) fn char_slice(text: &str, start: usize, end: usize) -> &str {
+    let mut offsets = text
+        .char_indices()
+        .map(|(offset, _)| offset)
+        .chain(iter::once(text.len()));
+    let start_offset = offsets.nth(start).unwrap_or(text.len());
+    let end_offset = if end > start {
+        offsets.nth(end - start - 1).unwrap_or(text.len())
+    } else {
+        start_offset
+    };
+    &text[start_offset..end_offset]
+}
+
+/// The rules deciding which match an engine reports when several are possible.
+/// Every engine follows the same rules, so switching backends never changes a
+/// result.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Semantics {
+    /// The match a backtracking engine would find first:
+    ///
+    /// - the match starting leftmost wins, whatever its length;
+    /// - among matches starting there, alternatives are preferred left to right,
+    ///   greedy quantifiers prefer more repetitions, lazy ones fewer, and
+    ///   possessive ones never give a repetition back, even if the rest fails;
+    /// - a longer match does not beat a preferred shorter one, so `a|ab` matches
+    ///   `a` in `ab`;
+    /// - iteration resumes where a match ended, or one char later after an empty
+    ///   match, so matches never overlap and come out in haystack order. An empty
+    ///   match right where the previous match ended is still reported, so `a*`
+    ///   finds `aa` and then the empty string in `aab`.
+    ///
+    /// These are Python's rules. The `regex` crate agrees except that it skips
+    /// an empty match adjacent to the previous one.
+    LeftmostFirst,
+}
+
+/// A compiled pattern that can be run over haystacks.
+///
+/// The trait is object safe, so engines can be used interchangeably behind a
+/// `&dyn Matcher`. Iterators borrow the matcher for `'r` and yield matches
+/// borrowing the haystack for `'t`.
+pub trait Matcher: Debug {
+    fn group_count(&self) -> usize;
+    /// Cursor slots the engine needs beyond the capture groups, such as the starts
+    /// of script runs. They come after the group slots and never reach a `Match`.
+    fn hidden_slot_count(&self) -> usize {
+        0
+    }
+    fn get_flags(&self) -> RegexFlags;
+    /// A cursor at `position` with room for every group and hidden slot.
+    fn start_cursor(&self, position: usize) -> Cursor {
+        let mut cursor = Cursor::new(position, self.group_count());
+        cursor
+            .groups
+            .resize(cursor.groups.len() + self.hidden_slot_count(), None);
+        cursor
+    }
+    /// Runs the pattern anchored at `cursor.position`, returning the cursor at the
+    /// end of the preferred match.
+    fn match_suffix(&self, cursor: Cursor, context: &Context) -> Option<Cursor>;
+    /// Like `match_suffix`, but taking steps out of `budget` as it goes and
+    /// giving up once they run out, keeping in it how far the run got. Engines
+    /// that cannot stop midway take one step for the whole run.
+    fn match_suffix_budgeted(
+        &self,
+        cursor: Cursor,
+        context: &Context,
+        budget: &mut StepBudget,
+    ) -> Option<Cursor> {
+        if !budget.take() {
+            return None;
+        }
+        budget.reach(&cursor);
+        let found = self.match_suffix(cursor, context)?;
+        budget.reach(&found);
+        Some(found)
+    }
+    /// A run of the pattern anchored at `cursor.position` that `Run::resume`
+    /// carries on a budget of steps at a time. Engines that cannot stop midway
+    /// take one step for the whole run.
+    fn start_run<'r>(&'r self, cursor: Cursor, _context: &Context) -> Box<dyn Run + 'r> {
+        Box::new(WholeRun {
+            engine: self,
+            cursor: Some(cursor),
         })
     }
+    /// Runs `handler` at every `(?Cn)` callout a search reaches, letting it veto
+    /// the path. Engines without callouts have nothing to run it at.
+    fn set_callout_handler(&mut self, _handler: &CalloutHandler) {}
+    /// Sets how many subroutine calls can be in progress at once. Engines
+    /// without subroutine calls have nothing to limit.
+    fn set_recursion_limit(&mut self, _limit: usize) {}
+    /// Equivalence classes of the pattern together with the classes a match can
+    /// start with; `None` means a match may start anywhere.
+    fn starting_classes(&self) -> Option<(EquivalenceClasses, Vec<bool>)> {
+        None
+    }
+    /// Where to look for match starts; `None` means every position is tried.
+    fn prefilter(&self) -> Option<Arc<dyn Prefilter>> {
+        None
+    }
+    fn group_names(&self) -> GroupNames {
+        GroupNames::default()
+    }
+    /// How the groups nest; by default every group is taken to be outermost.
+    fn group_tree(&self) -> GroupTree {
+        GroupTree::new(&Node::EmptyString, self.group_count())
+    }
+    /// Where the parts of the pattern are in it; by default nothing is known.
+    fn source_map(&self) -> SourceMap {
+        SourceMap::default()
+    }
+    /// How many chars a match can span; by default nothing is known.
+    fn length_bounds(&self) -> LengthBounds {
+        LengthBounds { min: 0, max: None }
+    }
+    /// Bytes held by the compiled pattern. Engines report a count derived from
+    /// their structure, so it is deterministic across runs.
+    fn memory_usage(&self) -> usize {
+        size_of_val(self)
+    }
+    /// Which match is reported when several are possible. No engine departs from
+    /// `Semantics::LeftmostFirst`.
+    fn semantics(&self) -> Semantics {
+        Semantics::LeftmostFirst
+    }
+    /// Literals every match contains, in order; by default none are known.
+    fn required_literals(&self) -> &RequiredLiterals {
+        &NO_REQUIRED_LITERALS
+    }
+    /// Whether the pattern matches anywhere in `text`. A haystack missing one of
+    /// the required literals is rejected without running the engine.
+    fn is_match(&self, text: &str) -> bool {
+        self.required_literals().found_in(text) && self.find_iter(text).next().is_some()
+    }
+    /// The leftmost match in `text`.
+    fn find<'t>(&self, text: &'t str) -> Option<Match<'t>> {
+        self.find_iter(text).next()
+    }
+    fn find_iter<'r, 't>(&'r self, text: &'t str) -> Box<dyn Iterator<Item = Match<'t>> + 'r>
+    where
+        't: 'r,
+    {
+        self.find_iter_within(text, 0..text.chars().count())
+    }
+    /// Like `find_iter`, but only reports matches lying entirely inside `range`
+    /// (in char offsets). Anchors and word boundaries still see the whole `text`,
+    /// so `^` does not match at `range.start` unless it would in the full haystack.
+    fn find_iter_within<'r, 't>(
+        &'r self,
+        text: &'t str,
+        range: Range<usize>,
+    ) -> Box<dyn Iterator<Item = Match<'t>> + 'r>
+    where
+        't: 'r,
+    {
+        Box::new(Matches::new(text, self, range))
+    }
+    /// Like `find_iter`, but matches against `text` as mapped by `transform`, one
+    /// character at a time as the search reads it. Spans and group texts still
+    /// refer to the untransformed `text`.
+    fn find_iter_transformed<'r, 't>(
+        &'r self,
+        text: &'t str,
+        transform: CharTransform,
+    ) -> Box<dyn Iterator<Item = Match<'t>> + 'r>
+    where
+        't: 'r,
+    {
+        self.find_iter_within_transformed(text, 0..text.chars().count(), transform)
+    }
+    /// `find_iter_within` and `find_iter_transformed` together.
+    fn find_iter_within_transformed<'r, 't>(
+        &'r self,
+        text: &'t str,
+        range: Range<usize>,
+        transform: CharTransform,
+    ) -> Box<dyn Iterator<Item = Match<'t>> + 'r>
+    where
+        't: 'r,
+    {
+        Box::new(Matches::new(text, self, range).with_transform(Some(transform)))
+    }
+    /// Every char offset at which some match starts, matches overlapping or not,
+    /// in increasing order. Zero-width matches count, so `\b` yields each word
+    /// boundary, which makes this a way to find split points.
+    fn match_positions<'r>(&'r self, text: &str) -> Box<dyn Iterator<Item = usize> + 'r> {
+        match_positions(self, None, text)
+    }
+    /// Like `find_iter`, but each call to the returned iterator takes at most
+    /// `steps` steps, as described in `YieldingMatches`.
+    fn find_iter_yielding<'r, 't>(&'r self, text: &'t str, steps: usize) -> YieldingMatches<'r, 't>
+    where
+        't: 'r,
+    {
+        find_iter_yielding(self, None, text, steps)
+    }
+    /// The engine for the mirror image of the pattern, if this engine can build one.
+    fn reversed(&self) -> Option<&dyn Matcher> {
+        None
+    }
+    /// Non-overlapping matches from the last one backwards. They are found by
+    /// running `reversed` over the haystack from its end, so each is the match
+    /// ending furthest right, extended as far left as the pattern prefers; for
+    /// most patterns these are the matches of `find_iter` in reverse. Engines
+    /// without a reversed form fall back to collecting `find_iter`.
+    fn rfind_iter<'r, 't>(&'r self, text: &'t str) -> Box<dyn Iterator<Item = Match<'t>> + 'r>
+    where
+        't: 'r,
+    {
+        rfind_iter(self, None, text)
+    }
+    /// Applies the pattern to each record of `text` on its own, as if it were
+    /// the whole haystack: `^`, `$`, `\A` and `\z` hold at the record's edges and
+    /// no match spans two records. Yields the index of each match's record with
+    /// the match, whose offsets are into `text`.
+    fn find_iter_records<'r, 't>(
+        &'r self,
+        text: &'t str,
+        separator: &RecordSeparator,
+    ) -> Box<dyn Iterator<Item = (usize, Match<'t>)> + 'r>
+    where
+        't: 'r,
+    {
+        find_iter_records(self, None, text, separator)
+    }
+    /// Like `find_iter`, but also reports where each match starts and ends as
+    /// editor-style line and column numbers.
+    fn find_iter_with_positions<'r, 't>(
+        &'r self,
+        text: &'t str,
+    ) -> Box<dyn Iterator<Item = (Match<'t>, LineColumn, LineColumn)> + 'r>
+    where
+        't: 'r,
+    {
+        Box::new(LineColumns::new(text, self.find_iter(text)))
+    }
 }
 
-impl<'s> Matcher<'s> for PikeVM {
-    fn group_count(&self) -> usize {
-        self.group_count
+/// `Matcher::match_positions`, reading `text` through `transform` if there is one.
+pub(crate) fn match_positions<'r, M: Matcher + ?Sized>(
+    engine: &'r M,
+    transform: Option<CharTransform>,
+    text: &str,
+) -> Box<dyn Iterator<Item = usize> + 'r> {
+    let mut context = Context::new_with_flags(text.chars().collect(), engine.get_flags());
+    context.transform = transform;
+    Box::new((0..=context.end).filter(move |position| {
+        engine
+            .match_suffix(engine.start_cursor(*position), &context)
+            .is_some()
+    }))
+}
+
+/// `Matcher::find_iter_yielding`, reading `text` through `transform` if there is one.
+pub(crate) fn find_iter_yielding<'r, 't, M: Matcher + ?Sized>(
+    engine: &'r M,
+    transform: Option<CharTransform>,
+    text: &'t str,
+    steps: usize,
+) -> YieldingMatches<'r, 't>
+where
+    't: 'r,
+{
+    YieldingMatches {
+        search: Box::new(
+            Matches::new(text, engine, 0..text.chars().count()).with_transform(transform),
+        ),
+        steps: steps.max(1),
     }
+}
 
-    fn get_flags(&self) -> RegexFlags {
-        self.flags
+/// `Matcher::rfind_iter`, reading `text` through `transform` if there is one.
+pub(crate) fn rfind_iter<'r, 't, M: Matcher + ?Sized>(
+    engine: &'r M,
+    transform: Option<CharTransform>,
+    text: &'t str,
+) -> Box<dyn Iterator<Item = Match<'t>> + 'r>
+where
+    't: 'r,
+{
+    match engine.reversed() {
+        Some(reversed) => Box::new(ReverseMatches::new(
+            text,
+            reversed,
+            engine.group_count(),
+            transform,
+        )),
+        None => {
+            let found: Vec<Match<'t>> = Matches::new(text, engine, 0..text.chars().count())
+                .with_transform(transform)
+                .collect();
+            Box::new(found.into_iter().rev())
+        }
     }
+}
 
-    fn match_suffix(&self, cursor: Cursor, context: Context) -> Option<Cursor> {
-        let mut queue: VecDeque<Thread> = VecDeque::new();
-        let mut visited: HashSet<VMSearchSpaceNode> = HashSet::new();
+/// `Matcher::find_iter_records`, reading `text` through `transform` if there is one.
+pub(crate) fn find_iter_records<'r, 't, M: Matcher + ?Sized>(
+    engine: &'r M,
+    transform: Option<CharTransform>,
+    text: &'t str,
+    separator: &RecordSeparator,
+) -> Box<dyn Iterator<Item = (usize, Match<'t>)> + 'r>
+where
+    't: 'r,
+{
+    Box::new(
+        separator
+            .split(text)
+            .into_iter()
+            .enumerate()
+            .flat_map(move |(index, range)| {
+                let record = char_slice(text, range.start, range.end);
+                Matches::new(record, engine, 0..range.len())
+                    .with_transform(transform)
+                    .map(move |found| (index, found.offset_into(text, range.start)))
+            }),
+    )
+}
 
-        self.queue_thread(&mut queue, (self.root.clone(), cursor), &mut visited);
-        println!("{:#?}", queue);
-        let mut match_result: Option<Cursor> = None;
+/// Matches found by running a reversed engine forward over the reversed haystack,
+/// mapped back to offsets in the original haystack.
+#[derive(Debug)]
+struct ReverseMatches<'r, 't> {
+    text: &'t str,
+    reversed: &'r dyn Matcher,
+    group_count: usize,
+    /// The haystack reversed, so position `p` here is offset `len - p` in `text`.
+    context: Context,
+    start: usize,
+}
 
-        loop {
-            let mut frontier: VecDeque<Thread> = VecDeque::new();
-            let mut next_visited: HashSet<VMSearchSpaceNode> = HashSet::new();
+impl<'r, 't> ReverseMatches<'r, 't> {
+    fn new(
+        text: &'t str,
+        reversed: &'r dyn Matcher,
+        group_count: usize,
+        transform: Option<CharTransform>,
+    ) -> Self {
+        let mut context =
+            Context::new_with_flags(text.chars().rev().collect(), reversed.get_flags());
+        context.transform = transform;
+        ReverseMatches {
+            text,
+            context,
+            reversed,
+            group_count,
+            start: 0,
+        }
+    }
+}
 
-            while !queue.is_empty() {
-                let (instruction, cursor) = queue.pop_back().unwrap();
-                match instruction.clone() {
-                    Instruction::Consume(matcher) => {
-                        let next_instruction = self.next.get(&(instruction.clone())).unwrap();
-                        if matcher.accepts(&cursor, &context) {
-                            let next_cursor = cursor.update(&matcher);
-                            if next_cursor.position == cursor.position {
-                                self.queue_thread(
-                                    &mut queue,
-                                    (next_instruction.clone(), next_cursor),
-                                    &mut visited,
-                                )
-                            } else {
-                                self.queue_thread(
-                                    &mut frontier,
-                                    (next_instruction.clone(), next_cursor),
-                                    &mut next_visited,
-                                )
-                            }
-                        }
-                    }
-                    Instruction::End => {
-                        match_result = Some(cursor);
-                        break;
+impl<'t> Iterator for ReverseMatches<'_, 't> {
+    type Item = Match<'t>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let len = self.context.end;
+        while self.start <= len {
+            let cursor = self.reversed.start_cursor(self.start);
+            if let Some(cursor) = self.reversed.match_suffix(cursor, &self.context) {
+                // a reversed group enters where the original one exits
+                let slots = cursor.groups[..self.group_count * 2]
+                    .chunks(2)
+                    .flat_map(|slots| match (slots[0], slots[1]) {
+                        (Some(entry), Some(exit)) => [Some(len - exit), Some(len - entry)],
+                        _ => [None, None],
+                    })
+                    .collect();
+                let found = Match::new(
+                    len - cursor.position,
+                    len - self.start,
+                    self.text,
+                    slots,
+                    self.reversed.group_names(),
+                );
+                self.start = cursor.position.max(self.start + 1);
+                return Some(found);
+            }
+            self.start += 1;
+        }
+        None
+    }
+}
+
+impl FusedIterator for ReverseMatches<'_, '_> {}
+
+/// How to cut a haystack into records that a pattern is applied to separately.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordSeparator {
+    /// Every line is a record; the `\n` belongs to neither neighbour.
+    Line,
+    /// Runs of lines separated by one or more blank lines, such as mail headers
+    /// or stack traces. Lines holding only whitespace count as blank.
+    Paragraph,
+    /// Records are separated by this exact string.
+    Custom(String),
+}
+
+impl RecordSeparator {
+    /// The char ranges of the records of `text`, in order.
+    pub fn split(&self, text: &str) -> Vec<Range<usize>> {
+        match self {
+            RecordSeparator::Line => RecordSeparator::Custom(String::from("\n")).split(text),
+            RecordSeparator::Custom(separator) => {
+                let chars: Vec<char> = text.chars().collect();
+                let separator: Vec<char> = separator.chars().collect();
+                let mut records: Vec<Range<usize>> = Vec::new();
+                let (mut start, mut position) = (0, 0);
+                while !separator.is_empty() && position + separator.len() <= chars.len() {
+                    if chars[position..].starts_with(&separator) {
+                        records.push(start..position);
+                        position += separator.len();
+                        start = position;
+                    } else {
+                        position += 1;
                     }
-                    _ => panic!("expected instructions of type: End, Consume"),
                 }
+                records.push(start..chars.len());
+                records
             }
-            if frontier.is_empty() {
-                break;
+            RecordSeparator::Paragraph => {
+                let mut records: Vec<Range<usize>> = Vec::new();
+                let mut current: Option<Range<usize>> = None;
+                let mut start = 0;
+                for line in text.split('\n') {
+                    let end = start + line.chars().count();
+                    if line.trim().is_empty() {
+                        records.extend(current.take());
+                    } else {
+                        current = Some(current.map_or(start, |record| record.start)..end);
+                    }
+                    start = end + 1;
+                }
+                records.extend(current);
+                records
             }
-
-            std::mem::swap(&mut frontier, &mut queue);
-            std::mem::swap(&mut next_visited, &mut visited);
         }
-        match_result
     }
+}
 
-    fn find_iter(&'s self, text: &'s str) -> Box<dyn Iterator<Item = Match> + '_> {
-        Box::new(Matches {
-            text,
-            pattern: Box::new(self.clone()),
-            start: 0,
-            increment: 1,
-            context: Context::new_with_flags(text.chars().collect(), self.get_flags()),
-        })
-    }
+/// A 1-based line and column, counted in chars. Lines end after every `\n`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineColumn {
+    pub line: usize,
+    pub column: usize,
 }
 
-#[allow(unused_imports)]
-#[cfg(test)]
-mod tests {
-    use crate::{fsm::RegexNFA, matching::Matcher, vm::PikeVM};
-    use regex;
+/// Adds line and column numbers to a stream of matches in haystack order.
+/// Positions are tracked incrementally, so the haystack is walked only once
+/// however many matches there are.
+#[derive(Debug)]
+pub struct LineColumns<'t, I> {
+    matches: I,
+    chars: std::str::Chars<'t>,
+    offset: usize,
+    position: LineColumn,
+}
 
-    #[test]
-    fn test_simple_kleene_star() {
-        let pattern = "[a-z]*";
-        let regex = RegexNFA::new(&pattern).unwrap();
-        let items = &[
-            Some("abc".to_string()),
-            Some("".to_string()),
-            Some("".to_string()),
-        ];
-        for (index, s) in regex.find_iter("abcE").map(|m| m.group(0)).enumerate() {
-            assert_eq!(s, items[index]);
+impl<'t, I: Iterator<Item = Match<'t>>> LineColumns<'t, I> {
+    pub fn new(text: &'t str, matches: I) -> Self {
+        LineColumns {
+            matches,
+            chars: text.chars(),
+            offset: 0,
+            position: LineColumn { line: 1, column: 1 },
         }
     }
 
-    #[test]
-    fn test_simple_kleene_star_pike_vm() {
-        let pattern = "[a-z]*";
-        let regex = PikeVM::new(&pattern).unwrap();
-        // regex.render();
-        let items = &[
-            Some("abc".to_string()),
-            Some("".to_string()),
-            Some("".to_string()),
-        ];
-        for (index, s) in regex.find_iter("abcE").map(|m| m.group(0)).enumerate() {
-            assert_eq!(s, items[index]);
+    /// Moves to the char offset `offset`, which must not lie behind the last one.
+    fn advance_to(&mut self, offset: usize) -> LineColumn {
+        while self.offset < offset {
+            match self.chars.next() {
+                Some('\n') => {
+                    self.position.line += 1;
+                    self.position.column = 1;
+                }
+                Some(_) => self.position.column += 1,
+                None => break,
+            }
+            self.offset += 1;
         }
+        self.position
     }
+}
 
-    #[test]
-    fn test_sim() {
-        let pattern = r"[abcd]+(x)+";
-        let regex = RegexNFA::new(pattern).unwrap();
+impl<'t, I: Iterator<Item = Match<'t>>> Iterator for LineColumns<'t, I> {
+    type Item = (Match<'t>, LineColumn, LineColumn);
 
-        for item in regex.find_iter("xxxabcdxxx") {
-            println!("{:#?}", item.groups());
+    fn next(&mut self) -> Option<Self::Item> {
+        let found = self.matches.next()?;
+        let (start, end) = found.span();
+        let start = self.advance_to(start);
+        let end = self.advance_to(end);
+        Some((found, start, end))
+    }
+}
+
+/// The steps left to a search limited by `Regex::find_budgeted`, with the
+/// furthest a run of the pattern has got. A step follows one transition of the
+/// automaton.
+#[derive(Debug, Clone)]
+pub struct StepBudget {
+    steps: usize,
+    exhausted: bool,
+    reached: Option<Cursor>,
+}
+
+impl StepBudget {
+    pub fn new(steps: usize) -> StepBudget {
+        StepBudget {
+            steps,
+            exhausted: false,
+            reached: None,
+        }
+    }
+
+    /// Takes a step, or marks the budget exhausted if none are left.
+    pub fn take(&mut self) -> bool {
+        match self.steps.checked_sub(1) {
+            Some(steps) => self.steps = steps,
+            None => self.exhausted = true,
+        }
+        !self.exhausted
+    }
+
+    /// Keeps `cursor` if it got further than every cursor kept since `restart`.
+    pub fn reach(&mut self, cursor: &Cursor) {
+        if self
+            .reached
+            .as_ref()
+            .is_none_or(|reached| cursor.position > reached.position)
+        {
+            self.reached = Some(cursor.clone());
+        }
+    }
+
+    /// Forgets how far the last run got, before a new one.
+    pub fn restart(&mut self) {
+        self.reached = None;
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.exhausted
+    }
+}
+
+/// A run of a pattern from one start position, stopped whenever its steps run
+/// out and carried on by calling again; see `Matcher::start_run`.
+pub trait Run: Debug {
+    /// Carries on the run for as long as `budget` lasts, returning the end of
+    /// the preferred match once the run is over, or `Poll::Pending` if the steps
+    /// ran out first.
+    fn resume(&mut self, context: &Context, budget: &mut StepBudget) -> Poll<Option<Cursor>>;
+}
+
+/// A run of an engine that cannot stop midway, which takes its one step at once.
+#[derive(Debug)]
+struct WholeRun<'r, M: ?Sized> {
+    engine: &'r M,
+    cursor: Option<Cursor>,
+}
+
+impl<M: Matcher + ?Sized> Run for WholeRun<'_, M> {
+    fn resume(&mut self, context: &Context, budget: &mut StepBudget) -> Poll<Option<Cursor>> {
+        if !budget.take() {
+            return Poll::Pending;
+        }
+        let Some(cursor) = self.cursor.take() else {
+            return Poll::Ready(None);
+        };
+        Poll::Ready(self.engine.match_suffix(cursor, context))
+    }
+}
+
+/// A run of the automaton, whose paths are kept between steps.
+#[derive(Debug)]
+struct AutomatonRun<'r> {
+    automaton: &'r RegexNFA,
+    paths: Paths,
+}
+
+impl Run for AutomatonRun<'_> {
+    fn resume(&mut self, context: &Context, budget: &mut StepBudget) -> Poll<Option<Cursor>> {
+        self.automaton.resume(&mut self.paths, context, budget)
+    }
+}
+
+/// What a search limited to a number of steps found; see `Regex::find_budgeted`.
+#[derive(Debug)]
+pub struct BudgetedMatch<'t> {
+    /// The leftmost match or, if the steps ran out first, the longest partial
+    /// match any run had got to. Groups a partial match was still inside of end
+    /// where it stopped.
+    pub found: Option<Match<'t>>,
+    /// Whether the steps ran out, which makes `found` a partial match.
+    pub exhausted: bool,
+}
+
+/// The leftmost match of `engine` in `text`, read through `transform` if there
+/// is one, taking at most `steps` steps of the engine; see `BudgetedMatch`.
+pub(crate) fn find_budgeted<'t>(
+    engine: &dyn Matcher,
+    transform: Option<CharTransform>,
+    text: &'t str,
+    steps: usize,
+) -> BudgetedMatch<'t> {
+    let mut context = Context::new_with_flags(text.chars().collect(), engine.get_flags());
+    context.transform = transform;
+    let starting_classes = engine.starting_classes();
+    let mut budget = StepBudget::new(steps);
+    // the start and furthest cursor of the longest run so far
+    let mut longest: Option<(usize, Cursor)> = None;
+    for start in 0..=context.end {
+        if let Some((classes, starting)) = &starting_classes {
+            if start == context.end || !starting[classes.class_of(context.char_at(start))] {
+                continue;
+            }
+        }
+        budget.restart();
+        let found = engine.match_suffix_budgeted(engine.start_cursor(start), &context, &mut budget);
+        if let Some(cursor) = found {
+            return BudgetedMatch {
+                found: Some(budgeted_match(engine, text, start, cursor)),
+                exhausted: false,
+            };
+        }
+        if let Some(reached) = budget.reached.take() {
+            if longest.as_ref().is_none_or(|(begun, furthest)| {
+                reached.position - start > furthest.position - begun
+            }) {
+                longest = Some((start, reached));
+            }
+        }
+        if budget.is_exhausted() {
+            let found = longest.map(|(start, mut reached)| {
+                // groups the run was still inside of end where it stopped
+                for slots in reached.groups.chunks_mut(2) {
+                    if let [Some(entry), exit] = slots {
+                        if exit.is_none_or(|exit| exit < *entry) {
+                            *exit = Some(reached.position);
+                        }
+                    }
+                }
+                budgeted_match(engine, text, start, reached)
+            });
+            return BudgetedMatch {
+                found,
+                exhausted: true,
+            };
+        }
+    }
+    BudgetedMatch {
+        found: None,
+        exhausted: false,
+    }
+}
+
+fn budgeted_match<'t>(
+    engine: &dyn Matcher,
+    text: &'t str,
+    start: usize,
+    mut cursor: Cursor,
+) -> Match<'t> {
+    cursor.groups.truncate(engine.group_count() * 2);
+    Match::new(
+        start,
+        cursor.position,
+        text,
+        cursor.groups,
+        engine.group_names(),
+    )
+}
+
+/// A search that can be suspended after a bounded amount of work and resumed.
+trait Search<'t>: Debug {
+    /// Looks for the next match, taking steps out of `budget` if there is one:
+    /// one for every start position tried and one for every step of the engine
+    /// there. Once they run out it gives up with `Poll::Pending`, and calling
+    /// again picks up where it stopped, in the middle of a run if need be.
+    fn search(&mut self, budget: Option<&mut StepBudget>) -> Poll<Option<Match<'t>>>;
+}
+
+/// Matches found a bounded number of steps at a time, for running long searches
+/// from an async task without holding its worker thread for the whole haystack.
+/// A step is a start position tried or a step of the engine as
+/// `Regex::find_budgeted` counts them, so a single slow run of the pattern is
+/// spread over as many calls as it takes.
+///
+/// As an iterator it yields `Poll::Pending` whenever a call used up its steps
+/// without finding a match, and `Poll::Ready` for each match. `next_match`
+/// wraps the same search in a future.
+#[derive(Debug)]
+pub struct YieldingMatches<'r, 't> {
+    search: Box<dyn Search<'t> + 'r>,
+    steps: usize,
+}
+
+impl<'r, 't> YieldingMatches<'r, 't> {
+    /// A future resolving to the next match, or `None` once the haystack is
+    /// exhausted. Every poll performs at most `steps` steps and, if that was not
+    /// enough, wakes its task again before returning `Poll::Pending`, so other
+    /// tasks get to run in between.
+    pub fn next_match(&mut self) -> NextMatch<'_, 'r, 't> {
+        NextMatch { matches: self }
+    }
+}
+
+impl<'r, 't> Iterator for YieldingMatches<'r, 't> {
+    type Item = Poll<Match<'t>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.search.search(Some(&mut StepBudget::new(self.steps))) {
+            Poll::Ready(found) => found.map(Poll::Ready),
+            Poll::Pending => Some(Poll::Pending),
+        }
+    }
+}
+
+impl<'r, 't> FusedIterator for YieldingMatches<'r, 't> {}
+
+#[derive(Debug)]
+pub struct NextMatch<'a, 'r, 't> {
+    matches: &'a mut YieldingMatches<'r, 't>,
+}
+
+impl<'a, 'r, 't> Future for NextMatch<'a, 'r, 't> {
+    type Output = Option<Match<'t>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Self::Output> {
+        let matches = &mut self.get_mut().matches;
+        let polled = matches
+            .search
+            .search(Some(&mut StepBudget::new(matches.steps)));
+        if polled.is_pending() {
+            cx.waker().wake_by_ref();
+        }
+        polled
+    }
+}
+
+#[derive(Debug)]
+struct Matches<'r, 't, M: ?Sized> {
+    text: &'t str,
+    pattern: &'r M,
+    start: usize,
+    context: Context,
+    increment: usize,
+    starting_classes: Option<(EquivalenceClasses, Vec<bool>)>,
+    prefilter: Option<Arc<dyn Prefilter>>,
+    names: GroupNames,
+    /// The run from `start` a budget stopped midway, if any.
+    run: Option<Box<dyn Run + 'r>>,
+}
+
+impl<'r, 't, M: Matcher + ?Sized> Matches<'r, 't, M> {
+    fn new(text: &'t str, pattern: &'r M, range: Range<usize>) -> Self {
+        Matches {
+            text,
+            starting_classes: pattern.starting_classes(),
+            prefilter: pattern.prefilter(),
+            names: pattern.group_names(),
+            pattern,
+            start: range.start,
+            increment: 1,
+            run: None,
+            context: Context::new_with_bounds(
+                text.chars().collect(),
+                pattern.get_flags(),
+                range.end,
+            ),
+        }
+    }
+
+    /// Reads the haystack through `transform` if there is one. The prefilter is
+    /// then dropped since it scans the raw characters.
+    fn with_transform(mut self, transform: Option<CharTransform>) -> Self {
+        if transform.is_some() {
+            self.context.transform = transform;
+            self.prefilter = None;
+        }
+        self
+    }
+
+    /// Whether a match could begin at `self.start`, judging by the first character alone.
+    fn can_start_here(&self) -> bool {
+        match &self.starting_classes {
+            Some((classes, starting)) => {
+                self.start < self.context.end
+                    && starting[classes.class_of(self.context.char_at(self.start))]
+            }
+            None => true,
+        }
+    }
+}
+
+impl<'r, 't, M: Matcher + ?Sized> Matches<'r, 't, M> {
+    /// Runs the pattern from `self.start`, carrying on the run `budget` stopped
+    /// last time if there is one.
+    fn run(&mut self, budget: Option<&mut StepBudget>) -> Poll<Option<Cursor>> {
+        let Some(budget) = budget else {
+            let cursor = self.pattern.start_cursor(self.start);
+            return Poll::Ready(self.pattern.match_suffix(cursor, &self.context));
+        };
+        let mut run = match self.run.take() {
+            Some(run) => run,
+            None => {
+                let cursor = self.pattern.start_cursor(self.start);
+                self.pattern.start_run(cursor, &self.context)
+            }
+        };
+        let polled = run.resume(&self.context, budget);
+        if polled.is_pending() {
+            self.run = Some(run);
+        }
+        polled
+    }
+}
+
+impl<'r, 't, M: Matcher + ?Sized> Search<'t> for Matches<'r, 't, M> {
+    fn search(&mut self, mut budget: Option<&mut StepBudget>) -> Poll<Option<Match<'t>>> {
+        while self.start <= self.context.end {
+            if self.run.is_none() {
+                if let Some(budget) = budget.as_deref_mut() {
+                    if !budget.take() {
+                        return Poll::Pending;
+                    }
+                }
+                if let Some(prefilter) = &self.prefilter {
+                    match prefilter.find(&self.context.text, self.start, self.context.end) {
+                        Some(start) => self.start = start,
+                        None => return Poll::Ready(None),
+                    }
+                }
+                if !self.can_start_here() {
+                    self.start += 1;
+                    continue;
+                }
+            }
+            let Poll::Ready(match_result) = self.run(budget.as_deref_mut()) else {
+                return Poll::Pending;
+            };
+
+            if let Some(mut cursor) = match_result {
+                cursor.groups.truncate(self.pattern.group_count() * 2);
+                self.increment = if cursor.position == self.start {
+                    1
+                } else {
+                    cursor.position - self.start
+                };
+                let match_result = Some(Match::new(
+                    self.start,
+                    cursor.position,
+                    self.text,
+                    cursor.groups,
+                    self.names.clone(),
+                ));
+                self.start += self.increment;
+                return Poll::Ready(match_result);
+            }
+            self.start += 1;
+        }
+        Poll::Ready(None)
+    }
+}
+
+impl<'r, 't, M: Matcher + ?Sized> Iterator for Matches<'r, 't, M> {
+    type Item = Match<'t>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.search(None) {
+            Poll::Ready(found) => found,
+            Poll::Pending => None,
+        }
+    }
+}
+
+impl<'r, 't, M: Matcher + ?Sized> FusedIterator for Matches<'r, 't, M> {}
+
+/// Finds the leftmost match at or after whichever offset the caller asks for,
+/// reading the haystack into chars only once, for callers that move through a
+/// haystack by rules of their own.
+pub(crate) struct Finder<'r, 't>(Matches<'r, 't, dyn Matcher + 'r>);
+
+impl<'r, 't> Finder<'r, 't> {
+    pub(crate) fn new(
+        pattern: &'r dyn Matcher,
+        text: &'t str,
+        transform: Option<CharTransform>,
+    ) -> Finder<'r, 't> {
+        Finder(Matches::new(text, pattern, 0..text.chars().count()).with_transform(transform))
+    }
+
+    pub(crate) fn find_at(&mut self, position: usize) -> Option<Match<'t>> {
+        self.0.start = position;
+        self.0.run = None;
+        self.0.next()
+    }
+}
+
+impl Matcher for RegexNFA {
+    fn group_count(&self) -> usize {
+        return self.group_count();
+    }
+
+    fn hidden_slot_count(&self) -> usize {
+        self.script_run_count()
+    }
+
+    fn memory_usage(&self) -> usize {
+        RegexNFA::memory_usage(self)
+    }
+
+    fn group_names(&self) -> GroupNames {
+        RegexNFA::group_names(self).clone()
+    }
+
+    fn group_tree(&self) -> GroupTree {
+        RegexNFA::group_tree(self).clone()
+    }
+
+    fn source_map(&self) -> SourceMap {
+        RegexNFA::source_map(self).clone()
+    }
+
+    fn length_bounds(&self) -> LengthBounds {
+        RegexNFA::length_bounds(self)
+    }
+
+    fn required_literals(&self) -> &RequiredLiterals {
+        RegexNFA::required_literals(self)
+    }
+
+    fn reversed(&self) -> Option<&dyn Matcher> {
+        RegexNFA::reversed(self).map(|reversed| reversed as &dyn Matcher)
+    }
+
+    fn get_flags(&self) -> RegexFlags {
+        self.get_flags()
+    }
+
+    fn starting_classes(&self) -> Option<(EquivalenceClasses, Vec<bool>)> {
+        self.starting_classes()
+            .map(|starting| (self.equivalence_classes().clone(), starting.to_vec()))
+    }
+
+    fn prefilter(&self) -> Option<Arc<dyn Prefilter>> {
+        self.search_prefilter()
+    }
+
+    fn match_suffix(&self, cursor: Cursor, context: &Context) -> Option<Cursor> {
+        RegexNFA::match_suffix(self, cursor, context)
+    }
+
+    fn match_suffix_budgeted(
+        &self,
+        cursor: Cursor,
+        context: &Context,
+        budget: &mut StepBudget,
+    ) -> Option<Cursor> {
+        RegexNFA::match_suffix_budgeted(self, cursor, context, budget)
+    }
+
+    fn start_run<'r>(&'r self, cursor: Cursor, context: &Context) -> Box<dyn Run + 'r> {
+        Box::new(AutomatonRun {
+            automaton: self,
+            paths: RegexNFA::start_run(self, &cursor, context),
+        })
+    }
+
+    fn set_callout_handler(&mut self, handler: &CalloutHandler) {
+        RegexNFA::set_callout_handler(self, handler)
+    }
+
+    fn set_recursion_limit(&mut self, limit: usize) {
+        RegexNFA::set_recursion_limit(self, limit)
+    }
+}
+
+impl Matcher for PikeVM {
+    fn group_count(&self) -> usize {
+        self.group_count
+    }
+
+    fn hidden_slot_count(&self) -> usize {
+        self.script_run_count
+    }
+
+    fn memory_usage(&self) -> usize {
+        PikeVM::memory_usage(self)
+    }
+
+    fn group_names(&self) -> GroupNames {
+        self.group_names.clone()
+    }
+
+    fn group_tree(&self) -> GroupTree {
+        self.group_tree.clone()
+    }
+
+    fn source_map(&self) -> SourceMap {
+        self.source_map.clone()
+    }
+
+    fn length_bounds(&self) -> LengthBounds {
+        self.length_bounds
+    }
+
+    fn required_literals(&self) -> &RequiredLiterals {
+        &self.required_literals
+    }
+
+    fn get_flags(&self) -> RegexFlags {
+        self.flags
+    }
+
+    fn match_suffix(&self, cursor: Cursor, context: &Context) -> Option<Cursor> {
+        let mut current = Threads::new(self.program.len());
+        let mut next = Threads::new(self.program.len());
+
+        self.add_thread(&mut current, (self.root, cursor), context);
+        let mut match_result: Option<Cursor> = None;
+
+        while !current.threads.is_empty() {
+            for (pc, cursor) in current.threads.drain(..) {
+                match &self.program[pc] {
+                    Instruction::Consume(matcher) => {
+                        if matcher.accepts(&cursor, context) {
+                            self.add_thread(
+                                &mut next,
+                                (self.next[pc], cursor.update(matcher)),
+                                context,
+                            )
+                        }
+                    }
+                    Instruction::End => {
+                        // lower priority threads can no longer produce a preferred match
+                        match_result = Some(cursor);
+                        break;
+                    }
+                    _ => panic!("expected instructions of type: End, Consume"),
+                }
+            }
+            std::mem::swap(&mut current, &mut next);
+            next.clear();
+        }
+        match_result
+    }
+}
+
+/// An engine searched with a prefilter given by the caller in place of its own.
+#[derive(Debug)]
+pub(crate) struct Prefiltered {
+    engine: Box<dyn Matcher>,
+    prefilter: Arc<dyn Prefilter>,
+}
+
+impl Prefiltered {
+    pub(crate) fn new(engine: Box<dyn Matcher>, prefilter: Arc<dyn Prefilter>) -> Prefiltered {
+        Prefiltered { engine, prefilter }
+    }
+}
+
+impl Matcher for Prefiltered {
+    fn group_count(&self) -> usize {
+        self.engine.group_count()
+    }
+
+    fn hidden_slot_count(&self) -> usize {
+        self.engine.hidden_slot_count()
+    }
+
+    fn get_flags(&self) -> RegexFlags {
+        self.engine.get_flags()
+    }
+
+    fn match_suffix(&self, cursor: Cursor, context: &Context) -> Option<Cursor> {
+        self.engine.match_suffix(cursor, context)
+    }
+
+    fn match_suffix_budgeted(
+        &self,
+        cursor: Cursor,
+        context: &Context,
+        budget: &mut StepBudget,
+    ) -> Option<Cursor> {
+        self.engine.match_suffix_budgeted(cursor, context, budget)
+    }
+
+    fn start_run<'r>(&'r self, cursor: Cursor, context: &Context) -> Box<dyn Run + 'r> {
+        self.engine.start_run(cursor, context)
+    }
+
+    fn set_callout_handler(&mut self, handler: &CalloutHandler) {
+        self.engine.set_callout_handler(handler)
+    }
+
+    fn set_recursion_limit(&mut self, limit: usize) {
+        self.engine.set_recursion_limit(limit)
+    }
+
+    fn starting_classes(&self) -> Option<(EquivalenceClasses, Vec<bool>)> {
+        self.engine.starting_classes()
+    }
+
+    fn prefilter(&self) -> Option<Arc<dyn Prefilter>> {
+        Some(self.prefilter.clone())
+    }
+
+    fn group_names(&self) -> GroupNames {
+        self.engine.group_names()
+    }
+
+    fn group_tree(&self) -> GroupTree {
+        self.engine.group_tree()
+    }
+
+    fn source_map(&self) -> SourceMap {
+        self.engine.source_map()
+    }
+
+    fn length_bounds(&self) -> LengthBounds {
+        self.engine.length_bounds()
+    }
+
+    fn required_literals(&self) -> &RequiredLiterals {
+        self.engine.required_literals()
+    }
+
+    fn memory_usage(&self) -> usize {
+        size_of_val(self) + self.engine.memory_usage() + self.prefilter.memory_usage()
+    }
+}
+
+#[allow(unused_imports)]
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        future::Future,
+        pin::Pin,
+        task::{self, Poll},
+    };
+
+    use crate::{
+        fsm::{ReError, RegexNFA},
+        matching::{
+            char_slice, ContextSize, GroupNames, Match, Matcher, RecordSeparator, Semantics,
+        },
+        parser::ParserError,
+        utils::RegexFlags,
+        vm::PikeVM,
+    };
+    use regex;
+
+    #[test]
+    fn test_simple_kleene_star() {
+        let pattern = "[a-z]*";
+        let regex = RegexNFA::new(&pattern).unwrap();
+        let items = &[Some("abc"), Some(""), Some("")];
+        for (index, s) in regex.find_iter("abcE").map(|m| m.group(0)).enumerate() {
+            assert_eq!(s, items[index]);
+        }
+    }
+
+    #[test]
+    fn test_simple_kleene_star_pike_vm() {
+        let pattern = "[a-z]*";
+        let regex = PikeVM::new(&pattern).unwrap();
+        // regex.render();
+        let items = &[Some("abc"), Some(""), Some("")];
+        for (index, s) in regex.find_iter("abcE").map(|m| m.group(0)).enumerate() {
+            assert_eq!(s, items[index]);
+        }
+    }
+
+    #[test]
+    fn test_pike_vm_captures() {
+        let items = [
+            ("((a)|b)+", "ab"),
+            ("(a|ab)(c|bcd)(d*)", "abcd"),
+            ("([abc])*bcd", "abcd"),
+            ("(a+?)(a*)", "aaa"),
+            ("x(y){1,3}?y", "xyyy"),
+        ];
+        for (pattern, text) in items {
+            let expected: Option<Vec<Option<String>>> = regex::Regex::new(pattern)
+                .unwrap()
+                .captures(text)
+                .map(|caps| {
+                    caps.iter()
+                        .skip(1)
+                        .map(|m| m.map(|m| m.as_str().to_string()))
+                        .collect()
+                });
+            let regex = PikeVM::new(pattern).unwrap();
+            let actual = regex.find_iter(text).next().map(|m| m.groups());
+            assert_eq!(expected, actual, "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn test_sim() {
+        let pattern = r"[abcd]+(x)+";
+        let regex = RegexNFA::new(pattern).unwrap();
+
+        for item in regex.find_iter("xxxabcdxxx") {
+            println!("{:#?}", item.groups());
+        }
+    }
+
+    #[test]
+    fn test_find_iter_within_keeps_context() {
+        let regex = RegexNFA::new(r"\bab").unwrap();
+        let spans: Vec<(usize, usize)> = regex
+            .find_iter_within("xab ab abx", 1..9)
+            .map(|m| m.span())
+            .collect();
+        assert_eq!(spans, vec![(4, 6), (7, 9)]);
+
+        let regex = RegexNFA::new(r"a$").unwrap();
+        assert_eq!(regex.find_iter_within("aaa", 0..2).count(), 0);
+        assert_eq!(regex.find_iter_within("aaa", 1..3).count(), 1);
+    }
+
+    #[test]
+    fn test_find() {
+        let regex = RegexNFA::new("b+").unwrap();
+        let found = regex.find("aébbc").unwrap();
+        assert_eq!(found.span(), (2, 4));
+        assert_eq!(found.as_str(), "bb");
+        assert_eq!(found.group(0), Some("bb"));
+        assert!(regex.find("ac").is_none());
+    }
+
+    #[test]
+    fn test_context() {
+        let text = "one\ntwo é x\nthree\nfour";
+        let found = RegexNFA::new("x").unwrap().find(text).unwrap();
+        let context = |size| {
+            let context = found.context(size);
+            (context.before, context.matched, context.after)
+        };
+        assert_eq!(context(ContextSize::Chars(3)), (" é ", "x", "\nth"));
+        assert_eq!(context(ContextSize::Chars(0)), ("", "x", ""));
+        // `é` takes two bytes, so two bytes before the match only reach the space
+        assert_eq!(context(ContextSize::Bytes(2)), (" ", "x", "\nt"));
+        assert_eq!(context(ContextSize::Bytes(3)), ("é ", "x", "\nth"));
+        assert_eq!(context(ContextSize::Lines(0)), ("two é ", "x", ""));
+        assert_eq!(
+            context(ContextSize::Lines(1)),
+            ("one\ntwo é ", "x", "\nthree")
+        );
+        assert_eq!(
+            context(ContextSize::Lines(5)),
+            ("one\ntwo é ", "x", "\nthree\nfour")
+        );
+    }
+
+    #[test]
+    fn test_record_separators() {
+        let text = "a\n\n  \nb\nc\n\n";
+        assert_eq!(
+            RecordSeparator::Line.split(text),
+            vec![0..1, 2..2, 3..5, 6..7, 8..9, 10..10, 11..11]
+        );
+        assert_eq!(RecordSeparator::Paragraph.split(text), vec![0..1, 6..9]);
+        assert_eq!(
+            RecordSeparator::Custom(String::from("--")).split("x--y--"),
+            vec![0..1, 3..4, 6..6]
+        );
+        assert_eq!(RecordSeparator::Custom(String::new()).split("xy").len(), 1);
+    }
+
+    #[test]
+    fn test_find_iter_records() {
+        let text = "Error: x\n  at a\n\nok\n\nError: y\n  at b(c)";
+        let regex = RegexNFA::new("(?s)^Error: ([a-z]).*").unwrap();
+        let found: Vec<_> = regex
+            .find_iter_records(text, &RecordSeparator::Paragraph)
+            .map(|(record, found)| (record, found.as_str(), found.group(1), found.span()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (0, "Error: x\n  at a", Some("x"), (0, 15)),
+                (2, "Error: y\n  at b(c)", Some("y"), (21, 39)),
+            ]
+        );
+
+        let regex = RegexNFA::new("^[a-z]+$").unwrap();
+        let lines: Vec<&str> = regex
+            .find_iter_records("ab\n12\ncd", &RecordSeparator::Line)
+            .map(|(_, found)| found.as_str())
+            .collect();
+        assert_eq!(lines, vec!["ab", "cd"]);
+    }
+
+    #[test]
+    fn test_participated() {
+        let regex = RegexNFA::new("(a)|(b)(c)?").unwrap();
+        let masks: Vec<(Vec<u64>, usize)> = regex
+            .find_iter("a bc b")
+            .map(|m| {
+                let participated = m.participated();
+                (participated.as_words().to_vec(), participated.count())
+            })
+            .collect();
+        assert_eq!(
+            masks,
+            vec![(vec![0b11], 2), (vec![0b1101], 3), (vec![0b101], 2)]
+        );
+
+        let pattern = "(a)".repeat(70);
+        let text = "a".repeat(70);
+        let found = RegexNFA::new(&pattern).unwrap().find(&text).unwrap();
+        let participated = found.participated();
+        assert!(participated.contains(70));
+        assert!(!participated.contains(71));
+        assert_eq!(participated.count(), 71);
+    }
+
+    #[test]
+    fn test_highlights() {
+        let regex = RegexNFA::new("a(b(c)d)?(x*)e").unwrap();
+        let highlights: Vec<Vec<(usize, usize, usize)>> = regex
+            .find_iter("abcde ae")
+            .map(|m| {
+                m.highlights()
+                    .into_iter()
+                    .map(|highlight| (highlight.start, highlight.end, highlight.group))
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            highlights,
+            vec![
+                vec![(0, 1, 0), (1, 2, 1), (2, 3, 2), (3, 4, 1), (4, 5, 0)],
+                vec![(6, 8, 0)],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_find_iter_yielding() {
+        // a class rather than a literal, so there is no prefilter to skip ahead with
+        let regex = RegexNFA::new("[bc]+").unwrap();
+        let polls: Vec<Poll<(usize, usize)>> = regex
+            .find_iter_yielding("aaaabbab", 2)
+            .map(|polled| polled.map(|m| m.span()))
+            .collect();
+        assert_eq!(
+            polls,
+            vec![
+                Poll::Pending,
+                Poll::Pending,
+                Poll::Pending,
+                Poll::Pending,
+                Poll::Ready((4, 6)),
+                Poll::Pending,
+                Poll::Ready((7, 8))
+            ]
+        );
+
+        let mut matches = regex.find_iter_yielding("aaaabbab", 1);
+        let mut cx = task::Context::from_waker(task::Waker::noop());
+        let mut spans = Vec::new();
+        let mut pending = 0;
+        loop {
+            match Pin::new(&mut matches.next_match()).poll(&mut cx) {
+                Poll::Ready(Some(found)) => spans.push(found.span()),
+                Poll::Ready(None) => break,
+                Poll::Pending => pending += 1,
+            }
+        }
+        assert_eq!(spans, vec![(4, 6), (7, 8)]);
+        assert_eq!(pending, 11);
+
+        // one run of the pattern that takes many steps is spread over many calls,
+        // and carries on where it stopped rather than starting over
+        let regex = RegexNFA::new("(?:a|aa)*c").unwrap();
+        let text = format!("{}c", "a".repeat(30));
+        let mut polls = 0;
+        let mut found = Vec::new();
+        for polled in regex.find_iter_yielding(&text, 3) {
+            polls += 1;
+            if let Poll::Ready(m) = polled {
+                found.push(m.span());
+            }
+        }
+        assert_eq!(found, [(0, 31)]);
+        assert!(polls > 20, "{} polls", polls);
+    }
+
+    #[test]
+    fn test_engines_agree() {
+        /// A random pattern over `a` and `b` at most `depth` groups deep.
+        fn pattern(next: &mut impl FnMut(usize) -> usize, depth: usize) -> String {
+            let atoms = ["a", "b", "[ab]", ".", "a|b", "ab|a"];
+            let quantifiers = ["", "", "?", "*", "+", "??", "*?", "+?", "{0,2}", "{1,2}"];
+            (0..1 + next(3))
+                .map(|_| {
+                    let item = match next(3) {
+                        0 if depth > 0 => format!("({})", pattern(next, depth - 1)),
+                        1 if depth > 0 => format!("(?:{})", pattern(next, depth - 1)),
+                        _ => atoms[next(atoms.len())].to_string(),
+                    };
+                    let item = match item.contains('|') && !item.starts_with('(') {
+                        true => format!("(?:{})", item),
+                        false => item,
+                    };
+                    item + quantifiers[next(quantifiers.len())]
+                })
+                .collect()
+        }
+
+        // a repetition at the front of an optional group loops back to where the
+        // group starts, and must not leave the group from there
+        let nfa = RegexNFA::new("(?:[ab]+b)?[ab]").unwrap();
+        assert_eq!(nfa.find("ba").map(|m| m.span()), Some((0, 1)));
+        let nfa = RegexNFA::new(r"\w(?:[ab]+b)?([ab][ab])").unwrap();
+        assert_eq!(nfa.find("cbaa").map(|m| m.span()), Some((0, 3)));
+
+        type Found = ((usize, usize), Vec<Option<String>>);
+        let mut seed: u64 = 7;
+        let mut next = |bound: usize| {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) as usize % bound
+        };
+        for _ in 0..1000 {
+            let pattern = pattern(&mut next, 2);
+            let nfa = RegexNFA::new(&pattern).unwrap();
+            let vm = PikeVM::new(&pattern).unwrap();
+            for _ in 0..4 {
+                let text: String = (0..next(6)).map(|_| ['a', 'b'][next(2)]).collect();
+                let found = |engine: &dyn Matcher| -> Vec<Found> {
+                    engine
+                        .find_iter(&text)
+                        .map(|m| (m.span(), m.groups()))
+                        .collect()
+                };
+                assert_eq!(found(&nfa), found(&vm), "{} on {:?}", pattern, text);
+            }
+        }
+    }
+
+    #[test]
+    fn test_rfind_iter() {
+        let cases = [
+            ("[a-z]+", "ab 12 cde f"),
+            ("(a)(b)?", "ab a xab"),
+            ("\\bfoo\\b", "foo foobar foo"),
+            ("^ab|cd$", "abxabcd"),
+            ("(?script_run:\\p{L}+)", "pаypal"),
+        ];
+        type Found = ((usize, usize), Vec<Option<String>>);
+        for (pattern, text) in cases {
+            let regex = RegexNFA::new(pattern).unwrap();
+            let mut expected: Vec<Found> = regex
+                .find_iter(text)
+                .map(|m| (m.span(), m.groups()))
+                .collect();
+            expected.reverse();
+            let actual: Vec<Found> = regex
+                .rfind_iter(text)
+                .map(|m| (m.span(), m.groups()))
+                .collect();
+            assert_eq!(expected, actual, "{}", pattern);
+            // the reversed automaton is built once, with the pattern
+            assert!(std::ptr::eq(
+                regex.reversed().unwrap(),
+                regex.reversed().unwrap()
+            ));
+        }
+        let last: Vec<&str> = RegexNFA::new("b+")
+            .unwrap()
+            .rfind_iter("abbcbd")
+            .take(1)
+            .map(|m| m.as_str())
+            .collect();
+        assert_eq!(last, vec!["b"]);
+
+        // empty matches are skipped from the right, so they land differently
+        let spans: Vec<(usize, usize)> = RegexNFA::new("x*")
+            .unwrap()
+            .rfind_iter("axxb")
+            .map(|m| m.span())
+            .collect();
+        assert_eq!(spans, vec![(4, 4), (1, 3), (1, 1), (0, 0)]);
+    }
+
+    #[test]
+    fn test_find_iter_with_positions() {
+        let regex = RegexNFA::new("b+").unwrap();
+        let positions: Vec<((usize, usize), (usize, usize))> = regex
+            .find_iter_with_positions("ab\nxbb\n\nb")
+            .map(|(_, start, end)| ((start.line, start.column), (end.line, end.column)))
+            .collect();
+        assert_eq!(
+            positions,
+            vec![((1, 2), (1, 3)), ((2, 2), (2, 4)), ((4, 1), (4, 2))]
+        );
+    }
+
+    #[test]
+    fn test_groupdict() {
+        let regex = RegexNFA::new("(a)(b)?").unwrap();
+        let found = regex.find("ac").unwrap();
+        assert!(found.groupdict().is_empty());
+
+        let names = GroupNames::new(HashMap::from([
+            (String::from("first"), 1),
+            (String::from("second"), 2),
+        ]));
+        let found = Match::new(0, 1, "ac", vec![Some(0), Some(1), None, None], names);
+        assert_eq!(
+            found.groupdict(),
+            HashMap::from([("first", Some("a")), ("second", None)])
+        );
+    }
+
+    #[test]
+    fn test_group_slices() {
+        // groups read in any order, past multi-byte chars
+        let regex = RegexNFA::new(r"(é)?(ü)(x)?(ñ+)").unwrap();
+        let found: Vec<Match> = regex.find_iter("aéüññ üxñ üñ").collect();
+        assert_eq!(found.len(), 3);
+        assert_eq!(found[0].group(4), Some("ññ"));
+        assert_eq!(found[0].group(3), None);
+        assert_eq!(found[0].group(1), Some("é"));
+        assert_eq!(found[1].group(0), Some("üxñ"));
+        assert_eq!(found[1].group(3), Some("x"));
+        assert_eq!(found[1].group(5), None);
+        assert_eq!(found[2].group(2), Some("ü"));
+        assert_eq!(found[1].as_str(), "üxñ");
+    }
+
+    #[test]
+    fn test_char_boundary_alignment() {
+        let text = "héllo wörld 日本語 🎉x ñ\u{301}a";
+        for pattern in [
+            r"(\w)(\w*)",
+            r"(.)\s",
+            r"[^\x00-\x7f]+",
+            r"(?:(é)|(ö)|(日))+",
+            r"\b",
+        ] {
+            let nfa = RegexNFA::new(pattern).unwrap();
+            let length = text.chars().count();
+            let matches = nfa
+                .find_iter(text)
+                .chain(nfa.rfind_iter(text))
+                .chain(nfa.find_iter_within(text, 3..length - 2))
+                .chain(nfa.find_iter_transformed(text, |c| c.to_ascii_uppercase()));
+            for m in matches {
+                assert!(
+                    m.is_char_boundary_aligned(),
+                    "{} at {:?}",
+                    pattern,
+                    m.span()
+                );
+                for index in 0..=m.group_count() {
+                    let expected = m
+                        .group_span(index)
+                        .map(|(start, end)| char_slice(text, start, end));
+                    assert_eq!(m.group(index), expected);
+                }
+            }
+        }
+
+        // slots no engine would report still slice safely
+        let names = GroupNames::default();
+        let inverted = Match::new(1, 4, text, vec![Some(3), Some(2)], names.clone());
+        assert_eq!(inverted.group(1), None);
+        assert!(inverted.is_char_boundary_aligned());
+        let past_the_end = Match::new(19, 40, text, vec![None, None], names);
+        assert_eq!(past_the_end.as_str(), "ñ\u{301}a");
+        assert!(past_the_end.is_char_boundary_aligned());
+    }
+
+    #[test]
+    fn test_named_backreferences() {
+        let regex = RegexNFA::new(r#"(?<q>["']).*?\k<q>"#).unwrap();
+        let found: Vec<&str> = regex
+            .find_iter(r#"say "it's" and 'no'"#)
+            .map(|m| m.as_str())
+            .collect();
+        assert_eq!(found, vec![r#""it's""#, "'no'"]);
+
+        let doubled = RegexNFA::new("(?<w>[a-z]+) \\k<w>").unwrap();
+        assert_eq!(
+            doubled.find("is this this one").map(|m| m.span()),
+            Some((3, 12))
+        );
+        let ignore_case = RegexNFA::new("(?i)(?<w>[a-z]+) \\k<w>").unwrap();
+        assert_eq!(
+            ignore_case.find("bye BYE").map(|m| m.as_str()),
+            Some("bye BYE")
+        );
+        // a group that did not participate cannot be matched again
+        let unset = RegexNFA::new("(?<a>x)?y\\k<a>").unwrap();
+        assert_eq!(unset.find("y").map(|m| m.as_str()), None);
+        assert_eq!(unset.find("yxyx").map(|m| m.as_str()), Some("xyx"));
+        assert_eq!(
+            regex
+                .rfind_iter("'a' 'b'")
+                .map(|m| m.span())
+                .collect::<Vec<_>>(),
+            vec![(4, 7), (0, 3)]
+        );
+        assert!(PikeVM::new("(?<a>x)\\k<a>").is_err());
+    }
+
+    #[test]
+    fn test_match_positions() {
+        let positions = |pattern: &str, text: &str| {
+            RegexNFA::new(pattern)
+                .unwrap()
+                .match_positions(text)
+                .collect::<Vec<usize>>()
+        };
+        assert_eq!(positions("\\b", "ab cd"), vec![0, 2, 3, 5]);
+        assert_eq!(positions("aa", "aaaa"), vec![0, 1, 2]);
+        assert_eq!(positions("(?=[A-Z])", "camelCaseWord"), vec![5, 9]);
+        assert_eq!(positions("x*", "ab"), vec![0, 1, 2]);
+        assert!(positions("z", "ab").is_empty());
+    }
+
+    #[test]
+    fn test_lookahead() {
+        let regex = RegexNFA::new("[a-z]+(?=[0-9])").unwrap();
+        let found: Vec<(usize, usize)> = regex.find_iter("ab1 cd ef2").map(|m| m.span()).collect();
+        assert_eq!(found, vec![(0, 2), (7, 9)]);
+
+        // every position followed by a multiple of three digits, where a thousands
+        // separator would go
+        let regex = RegexNFA::new("(?=(?:[0-9][0-9][0-9])+$)").unwrap();
+        let found: Vec<usize> = regex.find_iter("1234567").map(|m| m.span().0).collect();
+        assert_eq!(found, vec![1, 4]);
+
+        let regex = RegexNFA::new("(?=.*[0-9])(?=.*[a-z]).+").unwrap();
+        assert!(regex.is_match("abc1"));
+        assert!(!regex.is_match("abcd"));
+        assert!(!regex.is_match("1234"));
+        assert!(PikeVM::new("a(?=b)").is_err());
+    }
+
+    #[test]
+    fn test_lookbehind() {
+        let regex = RegexNFA::new("(?<=[0-9]{2}:)[a-z]+").unwrap();
+        let found: Vec<(usize, usize)> = regex
+            .find_iter("ab 12:cd 3:ef 45:gh")
+            .map(|m| m.span())
+            .collect();
+        assert_eq!(found, vec![(6, 8), (17, 19)]);
+        let regex = RegexNFA::new(r"(?<=\b:)x").unwrap();
+        assert_eq!(
+            regex.match_positions("a:x ::x").collect::<Vec<_>>(),
+            vec![2]
+        );
+        assert!(RegexNFA::new("(?<=a|bc)d").is_err());
+        assert!(RegexNFA::new("(?<=a+)d").is_err());
+        assert!(PikeVM::new("(?<=a)b").is_err());
+    }
+
+    #[test]
+    fn test_possessive_quantifiers() {
+        let found = |pattern: &str, text: &str| -> Vec<(usize, usize)> {
+            RegexNFA::new(pattern)
+                .unwrap()
+                .find_iter(text)
+                .map(|m| m.span())
+                .collect()
+        };
+        assert_eq!(found("a*+b", "aaab"), vec![(0, 4)]);
+        assert_eq!(found("a++a", "aaa"), vec![]);
+        assert_eq!(found("x?+x", "x xx"), vec![(2, 4)]);
+        assert_eq!(found("a{1,3}+a", "aaaa aaa"), vec![(0, 4)]);
+        assert_eq!(found("(?:ab)*+ab", "ababab"), vec![]);
+        // groups captured inside the repetition are kept
+        let regex = RegexNFA::new("([a-z])*+:").unwrap();
+        let m = regex.find("ab:").unwrap();
+        assert_eq!(m.group(1), Some("b"));
+        assert!(Matcher::reversed(&regex).is_none());
+        assert!(PikeVM::new("a*+b").is_err());
+    }
+
+    #[test]
+    fn test_required_literals_reject() {
+        let pattern = "user:([a-z]+) id:[0-9]+";
+        let engines: Vec<Box<dyn Matcher>> = vec![
+            Box::new(RegexNFA::new(pattern).unwrap()),
+            Box::new(PikeVM::new(pattern).unwrap()),
+        ];
+        for engine in &engines {
+            assert_eq!(engine.required_literals().literals(), ["user:", " id:"]);
+            assert!(engine.is_match("log user:ann id:7"));
+            assert!(!engine.is_match("log id:7 user:ann"));
+            assert!(!engine.is_match("user:ann id:x"));
+        }
+    }
+
+    #[test]
+    fn test_negative_lookbehind() {
+        let regex = RegexNFA::new("(?<![0-9]:)[a-z]+").unwrap();
+        let found: Vec<&str> = regex
+            .find_iter("cd 3:ef x:gh")
+            .map(|m| m.as_str())
+            .collect();
+        assert_eq!(found, vec!["cd", "f", "x", "gh"]);
+        // nothing comes before the start, so a negative lookbehind holds there
+        assert!(RegexNFA::new("^(?<!a)b").unwrap().is_match("b"));
+        assert!(RegexNFA::new("(?<!a|bc)d").is_err());
+        assert!(PikeVM::new("(?<!a)b").is_err());
+    }
+
+    #[test]
+    fn test_numeric_backreferences() {
+        let regex = RegexNFA::new(r"([a-z])([a-z])\2\1").unwrap();
+        let found: Vec<&str> = regex
+            .find_iter("abba xyzzy noon")
+            .map(|m| m.as_str())
+            .collect();
+        assert_eq!(found, vec!["abba", "yzzy", "noon"]);
+        assert_eq!(
+            RegexNFA::new(r"(a+)b\1+")
+                .unwrap()
+                .find("aabaaaa")
+                .map(|m| m.span()),
+            Some((0, 7))
+        );
+    }
+
+    #[test]
+    fn test_semantics_agree_across_engines() {
+        // expected spans of each match and its groups, as Python reports them
+        type Spans = Vec<Vec<(usize, usize)>>;
+        let cases: Vec<(&str, &str, Spans)> = vec![
+            ("a|ab", "ab", vec![vec![(0, 1)]]),
+            ("ab|a", "ab", vec![vec![(0, 2)]]),
+            ("(a|ab)(c|bcd)", "abcd", vec![vec![(0, 4), (0, 1), (1, 4)]]),
+            (
+                "(a*)(a*)",
+                "aaa",
+                vec![vec![(0, 3), (0, 3), (3, 3)], vec![(3, 3), (3, 3), (3, 3)]],
+            ),
+            (
+                "(a*?)(a*)",
+                "aaa",
+                vec![vec![(0, 3), (0, 0), (0, 3)], vec![(3, 3), (3, 3), (3, 3)]],
+            ),
+            ("(a+?)(a*?)b", "aab", vec![vec![(0, 3), (0, 1), (1, 2)]]),
+            (
+                "a*",
+                "baaxa",
+                vec![
+                    vec![(0, 0)],
+                    vec![(1, 3)],
+                    vec![(3, 3)],
+                    vec![(4, 5)],
+                    vec![(5, 5)],
+                ],
+            ),
+            ("(a|b)*:(b|c)", "abb:bc", vec![vec![(0, 5), (2, 3), (4, 5)]]),
+            ("x{1,3}x{1,3}", "xxxxx", vec![vec![(0, 5)]]),
+        ];
+        for (pattern, text, expected) in cases {
+            let engines: Vec<Box<dyn Matcher>> = vec![
+                Box::new(RegexNFA::new(pattern).unwrap()),
+                Box::new(PikeVM::new(pattern).unwrap()),
+            ];
+            for engine in &engines {
+                assert_eq!(engine.semantics(), Semantics::LeftmostFirst);
+                let actual: Spans = engine
+                    .find_iter(text)
+                    .map(|m| {
+                        (0..=engine.group_count())
+                            .map(|index| m.group_span(index).unwrap())
+                            .collect()
+                    })
+                    .collect();
+                assert_eq!(actual, expected, "{:?} on {:?}", pattern, text);
+            }
+        }
+    }
+
+    #[test]
+    fn test_named_groups() {
+        let pattern = "(?P<key>[a-z]+):?(?<value>[0-9]+)?";
+        let engines: Vec<Box<dyn Matcher>> = vec![
+            Box::new(RegexNFA::new(pattern).unwrap()),
+            Box::new(PikeVM::new(pattern).unwrap()),
+        ];
+        for engine in &engines {
+            let found: Vec<Match> = engine.find_iter("ab:12 cd").collect();
+            assert_eq!(found[0].name("key"), Some("ab"));
+            assert_eq!(found[0].name("value"), Some("12"));
+            assert_eq!(found[0].group_index("value"), Some(2));
+            assert_eq!(found[1].name("value"), None);
+            assert_eq!(found[1].name("missing"), None);
+            assert_eq!(
+                found[1].groupdict(),
+                HashMap::from([("key", Some("cd")), ("value", None)])
+            );
+        }
+    }
+
+    #[test]
+    fn test_matchers_are_interchangeable() {
+        let engines: Vec<Box<dyn Matcher>> = vec![
+            Box::new(RegexNFA::new("a(b|c)").unwrap()),
+            Box::new(PikeVM::new("a(b|c)").unwrap()),
+        ];
+        for engine in &engines {
+            let spans: Vec<(usize, usize)> = {
+                let text = String::from("abxac");
+                engine.find_iter(&text).map(|m| m.span()).collect()
+            };
+            assert_eq!(spans, vec![(0, 2), (3, 5)]);
+            assert!(engine.is_match("ab"));
+        }
+    }
+
+    #[test]
+    fn test_unicode_properties() {
+        test_case_no_groups(r"\p{L}+", "héllo wörld 42");
+        test_case_no_groups(r"\p{Letter}\p{Nd}", "a1 é2 _3");
+        test_case_no_groups(r"\P{lowercase_letter}+", "abcXYZdef12");
+        test_case_no_groups(r"[\p{Lu}\p{Nd}]+", "abCD12ef");
+        test_case_no_groups(r"\pL\PL", "aé1 b_ 2c");
+        let greek = RegexNFA::new(r"\p{InGreek}+").unwrap();
+        let found: Vec<&str> = greek.find_iter("alpha α-ω ἀ").map(|m| m.as_str()).collect();
+        assert_eq!(found, vec!["α", "ω"]);
+        test_case_no_groups(r"[\pN\P{L}]+", "ab 12 cd3");
+    }
+
+    #[test]
+    fn test_posix_classes() {
+        test_case_no_groups("[[:alpha:]]+", "abc12DEF_g");
+        test_case_no_groups("[[:digit:][:upper:]:]+", "ab12:CDe");
+        test_case_no_groups("[[:^digit:]]+", "ab12 cd");
+        test_case_no_groups("[[:punct:]]+", "a!/@[`{~b");
+        test_case_no_groups("[^[:space:]]+", "ab\tc d\ne");
+        test_case_no_groups("[[:xdigit:]]+", "0x1fAg");
+        assert!(RegexNFA::new("[[:alfa:]]").is_err());
+    }
+
+    #[test]
+    fn test_hex_escapes() {
+        test_case_no_groups(r"\x41\x62+", "xAbbAb x41");
+        test_case_no_groups(r"[\x30\x{39}:]+", "a09:1b");
+        test_case_no_groups(r"\x{1F600}|\x{e9}", "café 😀");
+        test_case_no_groups(r"\x{0}\x7e", "\0~");
+        for (pattern, span) in [(r"a\x4", 1..4), (r"\x{}", 0..4), (r"\x{1234567}", 0..11)] {
+            match RegexNFA::new(pattern) {
+                Err(ReError::ParsingFailed(error)) => assert_eq!(error.span(), span),
+                _ => unreachable!("{} should not compile", pattern),
+            }
+        }
+        for pattern in [r"\x{D800}", r"[\x{110000}]", r"\uDFFF"] {
+            assert!(matches!(
+                RegexNFA::new(pattern),
+                Err(ReError::ParsingFailed(ParserError::InvalidCodePoint(..)))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_unicode_escapes() {
+        test_case_no_groups(r"caf\u00e9|\u{1F600}+", "café 😀😀 cafe");
+        test_case_no_groups(r"[\u0391-\u03A9\u{3b1}]+", "ΑΒΓ αβ");
+        test_case_no_groups(r"\u0041\u{62}", "Ab AB");
+        test_case_no_groups(r"[a-\x7E\.-:]+", "AZaz~.9: B");
+        assert!(RegexNFA::new(r"[\u00FF-a]").is_err());
+        for (pattern, span) in [(r"\u41", 0..4), (r"x\u{}", 1..5), (r"[\u00G0]", 1..5)] {
+            match RegexNFA::new(pattern) {
+                Err(ReError::ParsingFailed(error)) => assert_eq!(error.span(), span),
+                _ => unreachable!("{} should not compile", pattern),
+            }
+        }
+    }
+
+    #[test]
+    fn test_horizontal_and_vertical_whitespace() {
+        let text = "a\tb\u{A0}c\u{2003}d\r\ne\u{2028}f\x0Bg \u{85}";
+        let spans = |pattern: &str| -> Vec<&str> {
+            let nfa = RegexNFA::new(pattern).unwrap();
+            let vm = PikeVM::new(pattern).unwrap();
+            let found: Vec<&str> = nfa.find_iter(text).map(|m| m.as_str()).collect();
+            assert_eq!(
+                found,
+                vm.find_iter(text).map(|m| m.as_str()).collect::<Vec<_>>()
+            );
+            found
+        };
+        assert_eq!(spans(r"\h+"), ["\t", "\u{A0}", "\u{2003}", " "]);
+        assert_eq!(spans(r"\v+"), ["\r\n", "\u{2028}", "\x0B", "\u{85}"]);
+        assert_eq!(
+            spans(r"[\H\V]+"),
+            ["a\tb\u{A0}c\u{2003}d\r\ne\u{2028}f\x0Bg \u{85}"]
+        );
+        assert_eq!(spans(r"\H\h\H"), ["a\tb", "c\u{2003}d", "g \u{85}"]);
+        assert_eq!(spans(r"[^\h\v\d]+"), ["a", "b", "c", "d", "e", "f", "g"]);
+    }
+
+    #[test]
+    fn test_any_line_break() {
+        let spans = |pattern: &str, text: &str| -> Vec<(usize, usize)> {
+            let nfa = RegexNFA::new(pattern).unwrap();
+            let vm = PikeVM::new(pattern).unwrap();
+            let found: Vec<(usize, usize)> = nfa.find_iter(text).map(|m| m.span()).collect();
+            assert_eq!(
+                found,
+                vm.find_iter(text).map(|m| m.span()).collect::<Vec<_>>()
+            );
+            found
+        };
+        assert_eq!(
+            spans(r"a\Rb", "a\r\nb a\nb a\rb a\u{2028}b a\x0Cb a\n\rb"),
+            [(0, 4), (5, 8), (9, 12), (13, 16), (17, 20)]
+        );
+        // a `\r\n` is taken whole, even when the rest of the pattern then fails
+        assert_eq!(spans(r"\R\n", "\r\n"), []);
+        assert_eq!(spans(r"\R\n", "\r\n\n"), [(0, 3)]);
+        assert_eq!(spans(r"\R{2}", "\r\n\r\n\n"), [(0, 4)]);
+        assert_eq!(spans(r"x\R+y|\R?z", "x\r\n\n\ry z"), [(0, 6), (7, 8)]);
+        let backwards: Vec<(usize, usize)> = RegexNFA::new(r"\R")
+            .unwrap()
+            .rfind_iter("a\r\n\n\rb")
+            .map(|m| m.span())
+            .collect();
+        assert_eq!(backwards, [(4, 5), (3, 4), (1, 3)]);
+    }
+
+    #[test]
+    fn test_conditional_groups() {
+        fn spans<'t>(pattern: &str, text: &'t str) -> Vec<&'t str> {
+            let nfa = RegexNFA::new(pattern).unwrap();
+            let vm = PikeVM::new(pattern).unwrap();
+            let found: Vec<&str> = nfa.find_iter(text).map(|m| m.as_str()).collect();
+            assert_eq!(
+                found,
+                vm.find_iter(text).map(|m| m.as_str()).collect::<Vec<_>>()
+            );
+            found
+        }
+        assert_eq!(
+            spans(r"(\<)?\w+(?(1)\>|:)", "<ab> cd: <ef: gh>"),
+            ["<ab>", "cd:", "ef:"]
+        );
+        assert_eq!(spans(r"(?<q>')?x(?(<q>)')", "'x' x 'x"), ["'x'", "x", "x"]);
+        assert_eq!(
+            spans(r"(?<a>a)?(?('a')b)c", "abc ac c bc"),
+            ["abc", "c", "c", "c"]
+        );
+        assert_eq!(spans(r"(a)?(?(1)|b)c", "ac bc c"), ["ac", "bc"]);
+        assert_eq!(
+            spans(r"(?:(a)|b)(?(1)x|y){2}", "axx byy axy"),
+            ["axx", "byy"]
+        );
+        // a condition before its group sees it unset, unless the condition is
+        // repeated after the group captured
+        assert_eq!(spans(r"(?(1)ab|cd)(x)", "abx cdx"), ["cdx"]);
+        assert_eq!(spans(r"(?(<n>)y|z)(?<n>x)", "zx yx"), ["zx"]);
+        assert_eq!(
+            spans(r"(?:(?(1)b|a)(x))+", "axbx axax"),
+            ["axbx", "ax", "ax"]
+        );
+
+        let error = |pattern: &str| match RegexNFA::new(pattern) {
+            Err(ReError::ParsingFailed(error)) => error,
+            other => panic!("expected a parse error, got {:?}", other),
+        };
+        assert_eq!(
+            error("(?(2)a)(b)"),
+            ParserError::UnknownGroupReference(Box::new(String::from("2")), 0..5)
+        );
+        assert_eq!(
+            error("(a)(?(1)b|c|d)"),
+            ParserError::TooManyConditionalBranches(3..12)
+        );
+    }
+
+    #[test]
+    fn test_subroutine_calls() {
+        fn found<'t>(nfa: &RegexNFA, text: &'t str) -> Vec<&'t str> {
+            nfa.find_iter(text).map(|m| m.as_str()).collect()
+        }
+        let balanced = RegexNFA::new(r"\((?:[^()]|(?R))*\)").unwrap();
+        assert_eq!(
+            found(&balanced, "x(a(b)c) (() (d)"),
+            ["(a(b)c)", "()", "(d)"]
+        );
+        let balanced = RegexNFA::new(r"(\((?:[^()]|(?1))*\))").unwrap();
+        assert_eq!(
+            found(&balanced, "x(a(b)c) (() (d)"),
+            ["(a(b)c)", "()", "(d)"]
+        );
+        let named = RegexNFA::new(r"(?<list>\[(?:\w|(?&list))*\]);").unwrap();
+        assert_eq!(found(&named, "[a[b]]; [[]; [a;"), ["[a[b]];", "[];"]);
+        let nested = RegexNFA::new(r"^(a|b(?1)c)$").unwrap();
+        assert!(nested.is_match("bbacc"));
+        assert!(!nested.is_match("bbac"));
+
+        // groups captured inside a call are reset once it returns
+        let call = RegexNFA::new(r"(\w)(?1)\1").unwrap();
+        let captured: Vec<(&str, Option<&str>)> = call
+            .find_iter("abb aba")
+            .map(|m| (m.as_str(), m.group(1)))
+            .collect();
+        assert_eq!(captured, [("aba", Some("a"))]);
+
+        // a call made again from where it started fails, and the limit stops deep
+        // nesting
+        let left = RegexNFA::new(r"a|(?R)b").unwrap();
+        assert_eq!(found(&left, "abb c"), ["a"]);
+        let empty = RegexNFA::new(r"(a?)(?1)*x").unwrap();
+        assert_eq!(found(&empty, "yy aax"), ["aax"]);
+        let text = format!("{}{}", "(".repeat(30), ")".repeat(30));
+        let shallow = RegexNFA::new(r"\((?R)*\)")
+            .unwrap()
+            .with_recursion_limit(10);
+        assert_eq!(shallow.find(&text).map(|m| m.span()), Some((19, 41)));
+        assert_eq!(balanced.find(&text).map(|m| m.span()), Some((0, 60)));
+
+        // a call may come before the group it calls, and groups may call each
+        // other
+        let forward = RegexNFA::new(r"(?1)(a)").unwrap();
+        assert_eq!(found(&forward, "aa a"), ["aa"]);
+        let forward = RegexNFA::new(r"(?2)(a)(b)").unwrap();
+        assert_eq!(found(&forward, "bab ab"), ["bab"]);
+        let mutual = RegexNFA::new(r"^(?&x)$(?:(?<x>a(?&y)?)(?<y>b(?&x)?)){0}").unwrap();
+        assert!(mutual.is_match("ababa"));
+        assert!(!mutual.is_match("abba"));
+        let mutual = RegexNFA::new(r"^(?1)$(?:(a(?2)?)(b(?1)?)){0}").unwrap();
+        assert!(mutual.is_match("aba"));
+        assert!(!mutual.is_match("aab"));
+
+        assert!(matches!(
+            PikeVM::new(r"(a(?1)?b)"),
+            Err(ReError::Unsupported(_))
+        ));
+        let error = |pattern: &str| match RegexNFA::new(pattern) {
+            Err(ReError::ParsingFailed(error)) => error,
+            other => panic!("expected a parse error, got {:?}", other),
+        };
+        assert_eq!(
+            error("(?2)(a)"),
+            ParserError::UnknownGroupReference(Box::new(String::from("2")), 0..4)
+        );
+        assert_eq!(
+            error("(?&x)(?<y>a)"),
+            ParserError::UnknownGroupReference(Box::new(String::from("&x")), 0..5)
+        );
+    }
+
+    #[test]
+    fn test_empty_loops_terminate() {
+        use std::{sync::mpsc, thread, time::Duration};
+
+        let cases = [
+            ("(a?)+c", "aab", vec![]),
+            ("(a?)+c", "aac", vec![(0, 3)]),
+            ("(a*)*c", "aaac", vec![(0, 4)]),
+            ("(?:a|(b?))*c", "aac", vec![(0, 3)]),
+            ("(?R)*", "ab", vec![(0, 0), (1, 1), (2, 2)]),
+            ("(?:(?R)|a)*b", "ab", vec![(0, 2)]),
+            ("(b?)\\1*x", "ax", vec![(1, 2)]),
+        ];
+        for (pattern, text, expected) in cases {
+            let (sender, receiver) = mpsc::channel();
+            thread::spawn(move || {
+                let found: Vec<(usize, usize)> = RegexNFA::new(pattern)
+                    .unwrap()
+                    .find_iter(text)
+                    .map(|m| m.span())
+                    .collect();
+                sender.send(found).unwrap();
+            });
+            let found = receiver
+                .recv_timeout(Duration::from_secs(10))
+                .unwrap_or_else(|_| panic!("{} did not finish on {:?}", pattern, text));
+            assert_eq!(found, expected, "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn test_control_escapes() {
+        test_case_no_groups(r"a\tb|\r\n", "a\tb atb\r\n rn");
+        test_case_no_groups(r"[\t\f\v]+x", "\x0C\x0B\tx fvx");
+        let bell = RegexNFA::new(r"\0\a?").unwrap();
+        let found: Vec<&str> = bell.find_iter("\0\x07 0a \0").map(|m| m.as_str()).collect();
+        assert_eq!(found, vec!["\0\x07", "\0"]);
+        let escape = RegexNFA::new(r"\e\[[0-9]+m").unwrap();
+        assert_eq!(escape.find("\x1B[31mred").map(|m| m.span()), Some((0, 5)));
+        // `\a` is the bell, while `\A` anchors at the start
+        let anchored = RegexNFA::new(r"\Aa|[\a\e]").unwrap();
+        let found: Vec<(usize, usize)> =
+            anchored.find_iter("aa\x07\x1B").map(|m| m.span()).collect();
+        assert_eq!(found, vec![(0, 1), (2, 3), (3, 4)]);
+    }
+
+    #[test]
+    fn test_comments() {
+        let regex = RegexNFA::new(r"(?#year)([0-9]+):(?#month, as in (04)[0-9]+").unwrap();
+        assert_eq!(regex.group_count(), 1);
+        let found: Vec<&str> = regex.find_iter("on 2024:05!").map(|m| m.as_str()).collect();
+        assert_eq!(found, vec!["2024:05"]);
+        assert_eq!(
+            RegexNFA::new("(?#only a comment)")
+                .unwrap()
+                .find("ab")
+                .map(|m| m.span()),
+            Some((0, 0))
+        );
+        assert!(RegexNFA::new("a(?#unterminated").is_err());
+    }
+
+    #[test]
+    fn test_scoped_flags() {
+        test_case_no_groups("(?i:abc)def", "ABCdef abcDEF aBcdef");
+        test_case_no_groups("(?i:a[b-d]+)e", "ABDCe ABDCE abe");
+        test_case_no_groups(r"a(?s:.)b.c", "a\nb:c a\nb\nc");
+        test_case_no_groups(r"(?i:x(?s:.)y)z", "X\nYz X\nYZ");
+        test_case_no_groups(r"x\n(?m:^y$)\n", "x\ny\n x\nyy\n");
+        test_case_no_groups("(?i:ab)+c", "abABc ABc abC");
+        for engine in [
+            Box::new(PikeVM::new("(?s:a.)+b").unwrap()) as Box<dyn Matcher>,
+            Box::new(RegexNFA::new("(?s:a.)+b").unwrap()),
+        ] {
+            let found: Vec<&str> = engine.find_iter("a\na:b ab").map(|m| m.as_str()).collect();
+            assert_eq!(found, vec!["a\na:b"]);
+        }
+    }
+
+    #[test]
+    fn test_inline_flag_switches() {
+        test_case_no_groups("a(?i)bc", "aBC ABC abc");
+        test_case_no_groups("x(?i)y|z", "xY Z z XY");
+        test_case_no_groups("(?i)a(?-i)b", "AB Ab aB ab");
+        test_case_no_groups("(?i)a(?-i:b)c", "AbC ABC abc");
+        test_case_no_groups("(a(?i)b)c", "aBc aBC");
+        test_case_no_groups("x(?i)(a(?-i)b)", "XAb xAb xAB xab");
+        test_case_no_groups("(?s)a.(?-s:.)(?i-s).", "a\nbc a\n\nc a\nbC");
+        test_case_no_groups("a(?m)$\n^b", "a\nb");
+        for pattern in ["(?-)a", "(?i-)a", "a(?i-:b)", "a(?i-s-m)b"] {
+            assert!(
+                matches!(
+                    RegexNFA::new(pattern),
+                    Err(ReError::ParsingFailed(ParserError::UnrecognizedModifier(
+                        ..
+                    )))
+                ),
+                "{}",
+                pattern
+            );
+        }
+    }
+
+    #[test]
+    fn test_class_set_operations() {
+        let text = "the quick brown fox: 42 over 0xBEEF";
+        test_case_no_groups("[a-z&&[^aeiou]]+", text);
+        test_case_no_groups("[0-9a-z--[a-c]]+", text);
+        test_case_no_groups("[a-f~~d-k]+", text);
+        test_case_no_groups("[^a-z&&[^aeiou]]+", text);
+        test_case_no_groups("[a-z&&[^aeiou]--[rw]]+", text);
+        test_case_no_groups("(?i)[a-z--[e-f]]+", text);
+        // with nothing on one side, the operator is taken literally
+        let nfa = RegexNFA::new("[a&&]+").unwrap();
+        assert_eq!(nfa.find("b&a&").map(|m| m.as_str()), Some("&a&"));
+        assert!(RegexNFA::new("[a--[]]").is_err());
+    }
+
+    #[test]
+    fn test_nested_character_classes() {
+        let text = "the quick brown fox: 42 over 0xBEEF\tend";
+        test_case_no_groups("[a-c[0-9][\\s]]+", text);
+        test_case_no_groups("[x[^a-z]]+", text);
+        test_case_no_groups("[[a-f]&&[^aeiou]]+", text);
+        test_case_no_groups("[a-z&&[aeiou]xyz]+", text);
+        test_case_no_groups("[^[a-z][A-Z]]+", text);
+        test_case_no_groups("[[[:digit:]]o]+", text);
+        assert!(RegexNFA::new("[a[b]").is_err());
+        assert!(RegexNFA::new("[a[]]").is_err());
+    }
+
+    #[test]
+    fn test_free_spacing() {
+        let pattern = "(?x)
+            ^ [a-z] +     # a word
+            \\  \\d {2,3} # a space and a number
+            | \\# \\  x     # or a literal hash, space and x
+        ";
+        test_case_no_groups(pattern, "ab 12 # x ab 1234");
+        test_case_no_groups("a b(?x: c d )e f", "a bcde f a bc de f");
+        test_case_no_groups("(?x) a (?-x) b (?x) c", "a bc ab c");
+        let mut flags = RegexFlags::FREESPACING;
+        let vm = PikeVM::new_with_flags("a b # a comment", &mut flags).unwrap();
+        assert_eq!(vm.find("a b ab").map(|m| m.span()), Some((4, 6)));
+        // unlike in the regex crate, whitespace in a class still counts
+        let nfa = RegexNFA::new("(?x) a [ ] b").unwrap();
+        assert_eq!(nfa.find("ab a b").map(|m| m.span()), Some((3, 6)));
+    }
+
+    #[test]
+    fn test_script_runs() {
+        // the `а` of the second word is Cyrillic
+        let text = "paypal pаypal παράδειγμα";
+        for pattern in [r"(?script_run:\p{L}+)", r"(?script_run:(\p{L})+)"] {
+            let engines: Vec<Box<dyn Matcher>> = vec![
+                Box::new(RegexNFA::new(pattern).unwrap()),
+                Box::new(PikeVM::new(pattern).unwrap()),
+            ];
+            for engine in &engines {
+                let words: Vec<&str> = engine.find_iter(text).map(|m| m.as_str()).collect();
+                assert_eq!(words, vec!["paypal", "p", "а", "ypal", "παράδειγμα"]);
+                assert_eq!(
+                    engine.find(text).unwrap().group_count(),
+                    engine.group_count()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_case_insensitive_prefilter() {
+        test_case_no_groups("(?i)ab+c", "xABBCyabcAbC aB");
+        test_case_no_groups("(?i)hello, world", "Hello, World! HELLO, WORLD hello");
+        test_case_no_groups("(?i)a1 b", "A1 B a1 b a1 ");
+    }
+
+    #[test]
+    fn test_case_insensitive_unicode() {
+        let text = "Héllo HÉLLO héLLo ΣΟΦΙΑ σοφια ſtraße STRASSE \u{212A}elvin ıi İ";
+        for pattern in [
+            "(?i)héllo",
+            "(?i)[à-ÿ]+",
+            "(?i)σοφια",
+            "(?i)[a-z]+",
+            r"(?i)\p{Lu}+",
+            "(?i)st",
+            "(?i)kelvin",
+            "(?i)i",
+        ] {
+            test_case_no_groups(pattern, text);
         }
+        let repeated = RegexNFA::new(r"(?i)(σ)\1").unwrap();
+        let found: Vec<&str> = repeated.find_iter("σΣ ςσ σs").map(|m| m.as_str()).collect();
+        assert_eq!(found, ["σΣ", "ςσ"]);
     }
 
     #[cfg(test)]
@@ -385,7 +2792,7 @@ mod tests {
             .collect();
         let reg = RegexNFA::new(pattern).unwrap();
         // reg.render();
-        let actual: Vec<String> = reg.find_iter(text).map(|m| m.as_str()).collect();
+        let actual: Vec<&str> = reg.find_iter(text).map(|m| m.as_str()).collect();
         assert_eq!(expected, actual)
     }
 
@@ -406,11 +2813,51 @@ mod tests {
             // ("(a+|b){0,}", "ab"),
             ("(a+|b){1,}", "ab"),
             // ("(a+|b){0,1}", "ab"),
-            // ("([abc])*d", "abbbcd"),
-            // ("([abc])*bcd", "abcd"),
+            ("([abc])*d", "abbbcd"),
+            ("([abc])*bcd", "abcd"),
         ];
         for (pattern, text) in items {
             test_case_no_groups(pattern, text)
         }
     }
+
+    #[cfg(test)]
+    fn test_case_groups(pattern: &str, text: &str) {
+        let expected: Option<Vec<Option<String>>> = regex::Regex::new(pattern)
+            .unwrap()
+            .captures(text)
+            .map(|caps| {
+                caps.iter()
+                    .skip(1)
+                    .map(|m| m.map(|m| m.as_str().to_string()))
+                    .collect()
+            });
+        let reg = RegexNFA::new(pattern).unwrap();
+        let actual: Option<Vec<Option<String>>> = reg.find_iter(text).next().map(|m| m.groups());
+        assert_eq!(expected, actual, "{}", pattern)
+    }
+
+    #[test]
+    fn test_captures_inside_repetition() {
+        let items = [
+            ("((a)|b)+", "ab"),
+            ("((a)|b)+", "ba"),
+            ("((a)|b)*", "abba"),
+            ("((a)|b){2}", "ab"),
+            ("((a)|b){1,3}", "abab"),
+            ("((a)|b){0,2}?c", "abc"),
+            ("(a|b)*", "ab"),
+            ("(a+|b){0,}", "ab"),
+            ("(a+|b){0,1}", "ab"),
+            ("([abc])*d", "abbbcd"),
+            ("([abc])*bcd", "abcd"),
+            ("(x(y)?)+", "xyx"),
+            ("(a)(b)?", "a"),
+            ("x(?:(a)|(b))+", "xab"),
+            ("(a){0,1}b", "b"),
+        ];
+        for (pattern, text) in items {
+            test_case_groups(pattern, text)
+        }
+    }
 }
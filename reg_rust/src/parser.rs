@@ -1,37 +1,97 @@
-use colored::Colorize;
 use itertools::Itertools;
 
 use crate::{
     matching::{Context, Cursor},
-    utils::RegexFlags,
+    unicode::{
+        all_case_orbits, case_orbit, case_orbits_within, either_case, is_script_run, Property,
+    },
+    utils::{next_char, EquivalenceClasses, RegexFlags, RequiredLiterals},
+};
+use std::{
+    collections::HashMap, error::Error, fmt::Display, hash::Hash, mem::size_of, num::ParseIntError,
+    ops::Range,
 };
-use core::panic;
-use std::{error::Error, fmt::Display, hash::Hash, num::ParseIntError};
 
-use self::{parser::Parser, visitor::Visitor};
+use self::{
+    parser::{needs_escape, Parser, CONTROL_ESCAPES},
+    visitor::{NodeVisitor, Visitor},
+};
 
 mod parser {
     // we take a parsing state and return either a valid node or an error
 
-    use std::str::Chars;
+    use std::{collections::HashMap, ops::Range, str::Chars};
 
     use itertools::{peek_nth, PeekNth};
 
-    use super::ParserError;
+    use super::{ParserError, SourceMap, DEFAULT_MAX_REPETITION, DEFAULT_NEST_LIMIT};
+    use crate::utils::RegexFlags;
 
     static ESCAPED: &'static [char] = &[
         '$', '(', ')', '*', '+', '-', '.', '<', '=', '>', '?', '[', '\\', ']', '^', '{', '|', '}',
     ];
 
-    static CHARACTER_CLASSES: &'static [char] = &['w', 'W', 's', 'S', 'd', 'D'];
+    /// The C-style escapes for control chars, such as `\n`, with what they stand for.
+    pub(super) static CONTROL_ESCAPES: &[(char, char)] = &[
+        ('n', '\n'),
+        ('t', '\t'),
+        ('r', '\r'),
+        ('f', '\x0C'),
+        ('0', '\0'),
+        ('a', '\x07'),
+        ('e', '\x1B'),
+    ];
+
+    /// Whether `c` has to be escaped to stand for itself in a pattern.
+    pub(super) fn needs_escape(c: char) -> bool {
+        ESCAPED.contains(&c)
+    }
+
+    static CHARACTER_CLASSES: &[char] =
+        &['w', 'W', 's', 'S', 'd', 'D', 'p', 'P', 'h', 'H', 'v', 'V'];
     static ANCHORS: &'static [char] = &['A', 'z', 'Z', 'G', 'b', 'B'];
 
     #[derive(Debug)]
     pub struct Parser<'a> {
         regex: &'a str,
         group_count: usize,
+        script_run_count: usize,
+        /// Named groups mapped to their group index, counting from 1.
+        group_names: HashMap<String, usize>,
         regex_iter: PeekNth<Chars<'a>>,
-        consumed: usize,
+        /// How many bytes of the pattern have been consumed so far.
+        offset: usize,
+        /// Where each group and expression item parsed so far is in the pattern.
+        source_map: SourceMap,
+        /// The most times a repetition, or repetitions nested in one another, can
+        /// copy what they repeat.
+        max_repetition: u64,
+        /// How many groups and character groups the parser is inside.
+        depth: usize,
+        /// The most groups and character groups that can be nested in one another.
+        nest_limit: usize,
+        /// The flags in effect where the parser is, which inline flags change.
+        flags: RegexFlags,
+        /// The flags of the whole pattern, which nodes follow unless wrapped.
+        pattern_flags: RegexFlags,
+        /// References to groups not yet opened where they appear, checked once
+        /// the whole pattern is parsed.
+        forward_references: Vec<ForwardReference>,
+        /// Group names found by an earlier pass over the pattern, for references
+        /// to groups named further on.
+        later_names: Option<HashMap<String, usize>>,
+    }
+
+    /// A reference to a group opened further on in the pattern, if at all.
+    #[derive(Debug)]
+    struct ForwardReference {
+        /// The name of the group, for a reference by name.
+        name: Option<String>,
+        /// The group index, counting from 1, taken for the group meanwhile.
+        index: usize,
+        /// The reference as written, for the error if there is no such group.
+        reference: String,
+        span: Range<usize>,
     }
 
     impl<'a> PartialEq for Parser<'a> {
@@ -47,15 +107,43 @@ mod parser {
             Parser {
                 regex: input,
                 group_count: 0,
+                script_run_count: 0,
+                group_names: HashMap::new(),
                 regex_iter: peek_nth(input.chars()),
-                consumed: 0,
+                offset: 0,
+                source_map: SourceMap::default(),
+                max_repetition: DEFAULT_MAX_REPETITION,
+                depth: 0,
+                nest_limit: DEFAULT_NEST_LIMIT,
+                flags: RegexFlags::NO_FLAG,
+                pattern_flags: RegexFlags::NO_FLAG,
+                forward_references: Vec::new(),
+                later_names: None,
             }
         }
 
+        pub fn flags(&self) -> RegexFlags {
+            self.flags
+        }
+
+        pub fn set_flags(&mut self, flags: RegexFlags) {
+            self.flags = flags;
+        }
+
+        pub fn pattern_flags(&self) -> RegexFlags {
+            self.pattern_flags
+        }
+
+        /// Sets the flags of the whole pattern, which are also those in effect.
+        pub fn set_pattern_flags(&mut self, flags: RegexFlags) {
+            self.pattern_flags = flags;
+            self.flags = flags;
+        }
+
         pub fn peek(&mut self) -> Result<char, ParserError> {
             match self.regex_iter.peek() {
                 Some(c) => Ok(*c),
-                None => Err(ParserError::UnexpectedEOF),
+                None => Err(ParserError::UnexpectedEOF(self.next_span())),
             }
         }
 
@@ -67,6 +155,131 @@ mod parser {
             self.group_count += 1;
         }
 
+        pub fn script_run_count(&self) -> usize {
+            self.script_run_count
+        }
+
+        /// Names the group numbered `index`, failing if the name is already taken.
+        pub fn add_group_name(
+            &mut self,
+            name: String,
+            index: usize,
+            span: Range<usize>,
+        ) -> Result<(), ParserError> {
+            if self.group_names.contains_key(&name) {
+                return Err(ParserError::DuplicateGroupName(Box::new(name), span));
+            }
+            self.group_names.insert(name, index);
+            Ok(())
+        }
+
+        /// Records that the group numbered `index`, counting from 1, spans `span`.
+        pub fn add_group_span(&mut self, index: usize, span: Range<usize>) {
+            let groups = &mut self.source_map.groups;
+            if groups.len() < index {
+                groups.resize(index, 0..0);
+            }
+            groups[index - 1] = span;
+        }
+
+        pub fn add_item_span(&mut self, span: Range<usize>) {
+            self.source_map.items.push(span);
+        }
+
+        /// The spans recorded while parsing, the pattern itself standing for group 0.
+        pub fn take_source_map(&mut self) -> SourceMap {
+            let mut source_map = std::mem::take(&mut self.source_map);
+            source_map.pattern = 0..self.regex.len();
+            source_map
+                .items
+                .sort_by_key(|span| (span.start, std::cmp::Reverse(span.end)));
+            source_map
+        }
+
+        pub fn take_group_names(&mut self) -> HashMap<String, usize> {
+            std::mem::take(&mut self.group_names)
+        }
+
+        /// The group index, counting from 1, of the group called `name`.
+        pub fn group_index(&self, name: &str) -> Option<usize> {
+            self.group_names.get(name).copied()
+        }
+
+        /// The group index, counting from 1, that `reference`, spanning `span`,
+        /// refers to by `name` if given and otherwise by number, or `None` if it
+        /// is no group index. Unlike a backreference, it may refer to a group
+        /// opened further on, which `unresolved_reference` checks once the whole
+        /// pattern is parsed.
+        pub fn forward_group_index(
+            &mut self,
+            reference: &str,
+            name: Option<&str>,
+            span: Range<usize>,
+        ) -> Option<usize> {
+            let index = match name {
+                Some(name) => match self.group_index(name) {
+                    Some(index) => return Some(index),
+                    // any index stands in until a later pass finds the group
+                    None => self
+                        .later_names
+                        .as_ref()
+                        .and_then(|names| names.get(name).copied())
+                        .unwrap_or(1),
+                },
+                None => match reference.parse::<usize>().ok().filter(|index| *index > 0)? {
+                    index if index <= self.group_count => return Some(index),
+                    index => index,
+                },
+            };
+            self.forward_references.push(ForwardReference {
+                name: name.map(String::from),
+                index,
+                reference: String::from(reference),
+                span,
+            });
+            Some(index)
+        }
+
+        /// The error for the first reference to a group further on that the
+        /// whole pattern does not have, if there is one.
+        pub fn unresolved_reference(&self) -> Option<ParserError> {
+            self.forward_references
+                .iter()
+                .find(|forward| match &forward.name {
+                    Some(name) => !self.group_names.contains_key(name),
+                    None => forward.index > self.group_count,
+                })
+                .map(|forward| {
+                    ParserError::UnknownGroupReference(
+                        Box::new(forward.reference.clone()),
+                        forward.span.clone(),
+                    )
+                })
+        }
+
+        /// Whether a reference names a group the parser could only guess the
+        /// index of, so that the pattern has to be parsed again knowing the
+        /// names, as `set_later_names` gives them.
+        pub fn guessed_names(&self) -> bool {
+            self.later_names.is_none()
+                && self
+                    .forward_references
+                    .iter()
+                    .any(|forward| forward.name.is_some())
+        }
+
+        pub fn set_later_names(&mut self, names: HashMap<String, usize>) {
+            self.later_names = Some(names);
+        }
+
+        pub fn peek_nth(&mut self, n: usize) -> Option<char> {
+            self.regex_iter.peek_nth(n).copied()
+        }
+
+        pub fn increment_script_run_count(&mut self) {
+            self.script_run_count += 1;
+        }
+
         pub fn consume(&mut self, expected: char) -> Result<char, ParserError> {
             match self.regex_iter.peek() {
                 Some(actual) => {
@@ -74,16 +287,19 @@ mod parser {
                         self.advance_by(1);
                         Ok(expected)
                     } else {
-                        Err(ParserError::UnexpectedToken(self.get_remainder(), expected))
+                        Err(ParserError::UnexpectedToken(expected, self.next_span()))
                     }
                 }
-                None => Err(ParserError::UnexpectedEOF),
+                None => Err(ParserError::UnexpectedEOF(self.next_span())),
             }
         }
 
         pub fn advance_by(&mut self, by: usize) {
-            self.regex_iter.nth(by - 1);
-            self.consumed += by;
+            for _ in 0..by {
+                if let Some(c) = self.regex_iter.next() {
+                    self.offset += c.len_utf8();
+                }
+            }
         }
 
         pub fn matches_several(&mut self, chars: &[char]) -> bool {
@@ -99,8 +315,11 @@ mod parser {
 
         pub fn consume_unseen(&mut self) -> Result<char, ParserError> {
             match self.regex_iter.next() {
-                Some(c) => Ok(c),
-                None => Err(ParserError::UnexpectedEOF),
+                Some(c) => {
+                    self.offset += c.len_utf8();
+                    Ok(c)
+                }
+                None => Err(ParserError::UnexpectedEOF(self.next_span())),
             }
         }
 
@@ -116,6 +335,33 @@ mod parser {
             self.matches('(')
         }
 
+        pub fn max_repetition(&self) -> u64 {
+            self.max_repetition
+        }
+
+        pub fn set_max_repetition(&mut self, max_repetition: u64) {
+            self.max_repetition = max_repetition;
+        }
+
+        pub fn set_nest_limit(&mut self, nest_limit: usize) {
+            self.nest_limit = nest_limit;
+        }
+
+        /// Goes into the group or character group opening at the next char,
+        /// failing with `NestTooDeep` if that is past the nest limit.
+        pub fn enter(&mut self) -> Result<(), ParserError> {
+            if self.depth == self.nest_limit {
+                return Err(ParserError::NestTooDeep(self.nest_limit, self.next_span()));
+            }
+            self.depth += 1;
+            Ok(())
+        }
+
+        /// Comes out of the group or character group last entered.
+        pub fn leave(&mut self) {
+            self.depth -= 1;
+        }
+
         pub fn within_bounds(&mut self) -> bool {
             self.regex_iter.peek().is_some()
         }
@@ -161,24 +407,39 @@ mod parser {
         }
 
         pub fn can_parse_escaped(&mut self) -> bool {
-            if let Some(c0) = self.regex_iter.peek() {
-                if *c0 == '\\' {
-                    if let Some(c1) = self.regex_iter.peek_nth(1) {
-                        if ESCAPED.contains(c1) {
-                            return true;
-                        }
-                    }
-                }
-            }
-            return false;
+            self.can_parse_escaped_at(0)
+        }
+
+        /// Whether an escape such as `\.` or `\x41` starts `n` chars ahead.
+        pub fn can_parse_escaped_at(&mut self, n: usize) -> bool {
+            self.regex_iter.peek_nth(n) == Some(&'\\')
+                && self.regex_iter.peek_nth(n + 1).is_some_and(|c1| {
+                    ESCAPED.contains(c1)
+                        || *c1 == '#'
+                        || c1.is_whitespace()
+                        || *c1 == 'x'
+                        || *c1 == 'u'
+                        || CONTROL_ESCAPES.iter().any(|(escape, _)| escape == c1)
+                })
         }
 
         pub fn can_parse_character_group(&mut self) -> bool {
             self.matches('[')
         }
 
+        /// Whether the input starts with `\k<` or with `\` and a nonzero digit.
+        pub fn can_parse_backreference(&mut self) -> bool {
+            self.regex_iter.peek() == Some(&'\\')
+                && match self.regex_iter.peek_nth(1) {
+                    Some('k') => self.regex_iter.peek_nth(2) == Some(&'<'),
+                    Some(c) => ('1'..='9').contains(c),
+                    None => false,
+                }
+        }
+
         pub fn can_parse_match(&mut self) -> bool {
-            self.can_parse_dot()
+            self.can_parse_backreference()
+                || self.can_parse_dot()
                 || self.can_parse_character_class()
                 || self.can_parse_character_group()
                 || self.can_parse_character()
@@ -198,19 +459,95 @@ mod parser {
             return false;
         }
 
-        pub fn get_remainder(&mut self) -> Box<String> {
-            Box::new(self.regex_iter.clone().collect::<String>())
+        pub fn can_parse_line_break(&mut self) -> bool {
+            self.regex_iter.peek() == Some(&'\\') && self.regex_iter.peek_nth(1) == Some(&'R')
+        }
+
+        /// How many bytes of the pattern have been consumed so far.
+        pub fn position(&self) -> usize {
+            self.offset
         }
 
-        pub fn get_consumed(&mut self) -> Box<String> {
-            Box::new(self.regex.chars().take(self.consumed).collect())
+        /// The bytes of the next char, or the empty span at the end.
+        pub fn next_span(&mut self) -> Range<usize> {
+            let width = self.regex_iter.peek().map_or(0, |c| c.len_utf8());
+            self.offset..self.offset + width
+        }
+
+        /// The bytes of the char just consumed.
+        pub fn previous_span(&self) -> Range<usize> {
+            let width = self.regex[..self.offset]
+                .chars()
+                .next_back()
+                .map_or(0, |c| c.len_utf8());
+            self.offset - width..self.offset
+        }
+
+        /// The bytes of the pattern not yet consumed.
+        pub fn rest_span(&self) -> Range<usize> {
+            self.offset..self.regex.len()
         }
 
         pub fn can_parse_sub_expression_item(&mut self) -> bool {
-            self.can_parse_group() || self.can_parse_match() || self.can_parse_anchor()
+            self.can_parse_group()
+                || self.can_parse_match()
+                || self.can_parse_anchor()
+                || self.can_parse_line_break()
+        }
+
+        /// Whether the input starts with `(?` followed by one or more of `modifiers` and `)`.
+        pub fn can_parse_inline_modifiers(&mut self, modifiers: &[char]) -> bool {
+            if !self.matches_several(&['(', '?']) {
+                return false;
+            }
+            let mut offset = 2;
+            while let Some(c) = self.regex_iter.peek_nth(offset) {
+                if !modifiers.contains(c) {
+                    return *c == ')' && offset > 2;
+                }
+                offset += 1;
+            }
+            false
+        }
+
+        /// Whether the input starts with a scoped flag group such as `(?i:` or `(?-i:`.
+        pub fn can_parse_flag_group(&mut self, modifiers: &[char]) -> bool {
+            if !self.matches_several(&['(', '?']) {
+                return false;
+            }
+            let mut offset = 2;
+            while let Some(c) = self.regex_iter.peek_nth(offset) {
+                if !modifiers.contains(c) {
+                    return *c == ':' && offset > 2;
+                }
+                offset += 1;
+            }
+            false
         }
 
+        /// Under FREESPACING, skips whitespace and `#` comments running to the end
+        /// of the line, which are there only for the reader.
+        pub fn skip_free_spacing(&mut self) {
+            if !self.flags.intersects(RegexFlags::FREESPACING) {
+                return;
+            }
+            while let Some(&c) = self.regex_iter.peek() {
+                if c == '#' {
+                    while self.regex_iter.peek().is_some_and(|c| *c != '\n') {
+                        self.advance_by(1);
+                    }
+                } else if c.is_whitespace() {
+                    self.advance_by(1);
+                } else {
+                    break;
+                }
+            }
+        }
+
+        /// Whether a quantifier comes next, which under FREESPACING may be set
+        /// apart from what it repeats, as in `[a-z] +`.
         pub fn can_parse_quantifier(&mut self) -> bool {
+            self.skip_free_spacing();
             match self.peek() {
                 Ok(c) => match c {
                     '+' | '*' | '?' | '{' => true,
@@ -222,45 +559,189 @@ mod parser {
     }
 }
 
+fn push_class_range(boundaries: &mut Vec<char>, start: char, end: char) {
+    boundaries.push(start);
+    boundaries.extend(next_char(end));
+}
+
 fn is_word_character(char_literal: &char) -> bool {
     *char_literal == '_' || char_literal.is_ascii_alphabetic()
 }
-fn is_word_boundary(text: &Vec<char>, pos: usize) -> bool {
-    if text.is_empty() {
-        panic!("implementation error, text should never be empty")
-    } else {
-        let case1 = pos == 0 && is_word_character(&text[pos]);
-        let case2 = pos == text.len() && is_word_character(&text[pos - 1]);
-        let case3 =
-            pos < text.len() && (is_word_character(&text[pos - 1]) ^ is_word_character(&text[pos]));
-        return case1 || case2 || case3;
-    }
+fn is_word_boundary(context: &Context, pos: usize) -> bool {
+    let len = context.text.len();
+    let before = pos > 0 && pos <= len && is_word_character(&context.char_at(pos - 1));
+    let after = pos < len && is_word_character(&context.char_at(pos));
+    before ^ after
 }
 
 #[derive(Debug, Hash, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum UpperBound {
     Undefined,
     Unbounded,
     Bounded(u64),
 }
 
+/// How the two sides of a set operation in a character group combine, as in
+/// `[a-z&&[^aeiou]]`.
+#[derive(Debug, Hash, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ClassOperator {
+    /// `&&`: the chars in both.
+    Intersection,
+    /// `--`: the chars in the left side but not the right.
+    Difference,
+    /// `~~`: the chars in exactly one of them.
+    SymmetricDifference,
+}
+
+impl ClassOperator {
+    const ALL: [(ClassOperator, char); 3] = [
+        (ClassOperator::Intersection, '&'),
+        (ClassOperator::Difference, '-'),
+        (ClassOperator::SymmetricDifference, '~'),
+    ];
+
+    fn combine(self, left: bool, right: bool) -> bool {
+        match self {
+            Self::Intersection => left && right,
+            Self::Difference => left && !right,
+            Self::SymmetricDifference => left ^ right,
+        }
+    }
+}
+
+impl Display for ClassOperator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Intersection => write!(f, "&&"),
+            Self::Difference => write!(f, "--"),
+            Self::SymmetricDifference => write!(f, "~~"),
+        }
+    }
+}
+
+/// How a quantifier trades repetitions against the rest of the pattern.
+#[derive(Debug, Hash, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Greediness {
+    /// As many repetitions as possible, giving some back if the rest fails.
+    Greedy,
+    /// As few repetitions as possible, marked by a trailing `?`.
+    Lazy,
+    /// As many repetitions as possible, never giving any back, marked by a
+    /// trailing `+`.
+    Possessive,
+}
+
+impl Greediness {
+    pub fn is_lazy(self) -> bool {
+        self == Greediness::Lazy
+    }
+}
+
+impl Display for Greediness {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Greedy => write!(f, ""),
+            Self::Lazy => write!(f, "?"),
+            Self::Possessive => write!(f, "+"),
+        }
+    }
+}
+
 #[derive(Debug, Hash, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Quantifier {
-    OneOrMore(bool),
-    ZeroOrMore(bool),
-    ZeroOrOne(bool),
-    Range(u64, UpperBound, bool),
+    OneOrMore(Greediness),
+    ZeroOrMore(Greediness),
+    ZeroOrOne(Greediness),
+    Range(u64, UpperBound, Greediness),
     None,
 }
 
+impl Quantifier {
+    pub fn greediness(&self) -> Option<Greediness> {
+        match self {
+            Self::OneOrMore(greediness)
+            | Self::ZeroOrMore(greediness)
+            | Self::ZeroOrOne(greediness)
+            | Self::Range(_, _, greediness) => Some(*greediness),
+            Self::None => None,
+        }
+    }
+
+    /// Whether the quantified item is matched exactly once, as with no quantifier
+    /// or `{1}`.
+    pub fn is_once(&self) -> bool {
+        matches!(
+            self,
+            Self::None
+                | Self::Range(1, UpperBound::Undefined, _)
+                | Self::Range(1, UpperBound::Bounded(1), _)
+        )
+    }
+
+    /// How many times the quantified item is matched, at least and, if there is
+    /// a limit, at most.
+    pub fn repetitions(&self) -> (u64, Option<u64>) {
+        match self {
+            Self::None => (1, Some(1)),
+            Self::ZeroOrOne(_) => (0, Some(1)),
+            Self::ZeroOrMore(_) => (0, None),
+            Self::OneOrMore(_) => (1, None),
+            Self::Range(lower, UpperBound::Undefined, _) => (*lower, Some(*lower)),
+            Self::Range(lower, UpperBound::Unbounded, _) => (*lower, None),
+            Self::Range(lower, UpperBound::Bounded(upper), _) => (*lower, Some(*upper)),
+        }
+    }
+
+    /// The plainest way to write this quantifier: `*`, `+` or `?` for the ranges
+    /// they stand for, `{n}` for `{n,n}`, and none for `{1}` unless it is
+    /// possessive, which makes what it quantifies atomic.
+    pub fn canonical(&self) -> Quantifier {
+        match self {
+            Self::Range(0, UpperBound::Unbounded, greediness) => Self::ZeroOrMore(*greediness),
+            Self::Range(1, UpperBound::Unbounded, greediness) => Self::OneOrMore(*greediness),
+            Self::Range(0, UpperBound::Bounded(1), greediness) => Self::ZeroOrOne(*greediness),
+            Self::Range(_, _, greediness)
+                if self.is_once() && *greediness != Greediness::Possessive =>
+            {
+                Self::None
+            }
+            Self::Range(lower, UpperBound::Bounded(upper), greediness) if lower == upper => {
+                Self::Range(*lower, UpperBound::Undefined, *greediness)
+            }
+            _ => self.clone(),
+        }
+    }
+
+    /// The same quantifier with `greediness` instead of its own.
+    pub fn with_greediness(&self, greediness: Greediness) -> Quantifier {
+        match self {
+            Self::OneOrMore(_) => Self::OneOrMore(greediness),
+            Self::ZeroOrMore(_) => Self::ZeroOrMore(greediness),
+            Self::ZeroOrOne(_) => Self::ZeroOrOne(greediness),
+            Self::Range(lower, upper, _) => Self::Range(*lower, upper.clone(), greediness),
+            Self::None => Self::None,
+        }
+    }
+}
+
 impl Display for Quantifier {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::OneOrMore(lazy) => write!(f, "+{}", if *lazy { "?" } else { "" }),
-            Self::ZeroOrMore(lazy) => write!(f, "*{}", if *lazy { "?" } else { "" }),
-            Self::ZeroOrOne(lazy) => write!(f, "?{}", if *lazy { "?" } else { "" }),
-            Self::Range(n, m, lazy) => {
-                write!(f, "{{{},{:?}}}{}", n, m, if *lazy { "?" } else { "" })
+            Self::OneOrMore(greediness) => write!(f, "+{}", greediness),
+            Self::ZeroOrMore(greediness) => write!(f, "*{}", greediness),
+            Self::ZeroOrOne(greediness) => write!(f, "?{}", greediness),
+            Self::Range(n, UpperBound::Undefined, greediness) => {
+                write!(f, "{{{}}}{}", n, greediness)
+            }
+            Self::Range(n, UpperBound::Unbounded, greediness) => {
+                write!(f, "{{{},}}{}", n, greediness)
+            }
+            Self::Range(n, UpperBound::Bounded(m), greediness) => {
+                write!(f, "{{{},{}}}{}", n, m, greediness)
             }
             Self::None => write!(f, ""),
         }
@@ -268,6 +749,7 @@ impl Display for Quantifier {
 }
 
 #[derive(Debug, Hash, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Node {
     Character(char),
     Match(Box<Node>, Quantifier),
@@ -276,6 +758,10 @@ pub enum Node {
     Dot,
     CharacterGroup(Vec<Box<Node>>, bool),
     CharacterRange(char, char),
+    UnicodeProperty(Property),
+    /// Two sides of a character group combined by a set operation, such as
+    /// `[\w--\d]`; it only appears as the item of a `CharacterGroup`.
+    ClassOperation(Box<Node>, ClassOperator, Box<Node>),
     // anchors
     Epsilon,
     GroupLink,
@@ -289,6 +775,42 @@ pub enum Node {
     StartOfStringOnly,
     EndOfStringOnlyNotNewline,
     EndOfStringOnlyMaybeNewLine,
+    /// Records where the `i`th script run starts, in a slot hidden after the groups.
+    ScriptRunEntry(usize),
+    /// Asserts that everything since the matching `ScriptRunEntry` is in one script.
+    ScriptRunExit(usize),
+    /// Asserts that the pattern inside matches at this position, without
+    /// consuming anything. The engine compiles the inner pattern on its own.
+    Lookahead(Box<Node>),
+    /// Asserts that the text just before this position is a match of the
+    /// pattern inside, or with the flag set that it is not. The pattern must
+    /// always match the same number of chars.
+    Lookbehind(Box<Node>, bool),
+    /// Matches the text last captured by the group with this index, counting
+    /// from 0 like `GroupEntry`. Names are resolved to indices while parsing.
+    Backreference(usize),
+    /// Consumes what the pattern inside matches by preference here and never
+    /// backtracks into it. Engines build it from possessive quantifiers.
+    Atomic(Box<Node>),
+    /// A char matcher or line anchor inside a scoped flag group such as `(?i:...)`,
+    /// with the flags in effect there, which it uses in place of the pattern's.
+    Flagged(Box<Node>, RegexFlags),
+    /// `(?Cn)`: hands the position reached to the caller's callout handler, which
+    /// can reject the path. Without a handler it always passes.
+    Callout(u8),
+    /// A line break of one char, as `\R` matches when there is no `\r\n` to take
+    /// as a unit: any of `\v` except a `\r` followed by `\n`. With the flag set
+    /// the haystack is reversed, so it is a `\n` followed by `\r` that is left out.
+    LineBreak(bool),
+    /// Asserts that the group with this index, counting from 0, has captured
+    /// something, or with the flag unset that it has not. A conditional group
+    /// `(?(1)yes|no)` starts each of its branches with one.
+    GroupCondition(usize, bool),
+    /// Matches what the group with this index, counting from 0, matches here, or
+    /// the whole pattern with no index, as `(?1)` and `(?R)` do. Like `Atomic`,
+    /// it keeps the first way the group matches, and the groups captured inside
+    /// are reset once it returns, as in PCRE.
+    Subroutine(Option<usize>),
 }
 
 pub(crate) trait Data {
@@ -297,41 +819,60 @@ pub(crate) trait Data {
 
 impl Node {
     pub fn accepts(&self, cursor: &Cursor, context: &Context) -> bool {
+        self.accepts_with_flags(cursor, context, context.flags)
+    }
+
+    fn accepts_with_flags(&self, cursor: &Cursor, context: &Context, flags: RegexFlags) -> bool {
         match self {
             Node::Character(char_literal) => {
-                if cursor.position < context.text.len() {
-                    if context.flags.intersects(RegexFlags::IGNORECASE) {
-                        char_literal.eq_ignore_ascii_case(&context.text[cursor.position])
+                if cursor.position < context.end {
+                    if flags.intersects(RegexFlags::IGNORECASE) {
+                        either_case(context.char_at(cursor.position), |c| c == *char_literal)
                     } else {
-                        char_literal.eq(&context.text[cursor.position])
+                        char_literal.eq(&context.char_at(cursor.position))
                     }
                 } else {
                     false
                 }
             }
             Node::Dot => {
-                cursor.position < context.text.len()
-                    && (context.flags.intersects(RegexFlags::DOTALL)
-                        || context.text[cursor.position] != '\n')
+                cursor.position < context.end
+                    && (flags.intersects(RegexFlags::DOTALL)
+                        || context.char_at(cursor.position) != '\n')
             }
             Node::CharacterRange(start, end) => {
-                if cursor.position < context.text.len() {
-                    if context.flags.intersects(RegexFlags::IGNORECASE) {
-                        start
-                            .to_ascii_lowercase()
-                            .le(&context.text[cursor.position])
-                            && context.text[cursor.position].to_ascii_lowercase().le(end)
+                if cursor.position < context.end {
+                    if flags.intersects(RegexFlags::IGNORECASE) {
+                        let c = context.char_at(cursor.position);
+                        either_case(c, |c| *start <= c && c <= *end)
                     } else {
-                        start.le(&context.text[cursor.position])
-                            && context.text[cursor.position].le(end)
+                        start.le(&context.char_at(cursor.position))
+                            && context.char_at(cursor.position).le(end)
                     }
                 } else {
                     false
                 }
             }
+            Node::UnicodeProperty(property) => {
+                cursor.position < context.end && {
+                    let c = context.char_at(cursor.position);
+                    if flags.intersects(RegexFlags::IGNORECASE) {
+                        either_case(c, |c| property.contains(c))
+                    } else {
+                        property.contains(c)
+                    }
+                }
+            }
+            Node::ClassOperation(left, operator, right) => operator.combine(
+                left.accepts_with_flags(cursor, context, flags),
+                right.accepts_with_flags(cursor, context, flags),
+            ),
             Node::CharacterGroup(nodes, negated) => {
-                if cursor.position < context.text.len() {
-                    negated ^ nodes.iter().any(|node| node.accepts(cursor, context))
+                if cursor.position < context.end {
+                    negated
+                        ^ nodes
+                            .iter()
+                            .any(|node| node.accepts_with_flags(cursor, context, flags))
                 } else {
                     false
                 }
@@ -339,23 +880,24 @@ impl Node {
             // anchors
             Node::EmptyString | Node::GroupEntry(_) | Node::GroupExit(_) => true,
             Node::WordBoundary => {
-                !context.text.is_empty() && is_word_boundary(&context.text, cursor.position)
+                !context.text.is_empty() && is_word_boundary(context, cursor.position)
             }
             Node::NonWordBoundary => {
-                !context.text.is_empty() && !is_word_boundary(&context.text, cursor.position)
+                !context.text.is_empty() && !is_word_boundary(context, cursor.position)
             }
             Node::StartOfString => {
                 let pos = cursor.position;
                 pos == 0
-                    || (context.flags.intersects(RegexFlags::MULTILINE)
+                    || (flags.intersects(RegexFlags::MULTILINE)
                         && pos > 0
                         && context.text[pos - 1] == '\n')
             }
             Node::EndOfString => {
                 (cursor.position >= context.text.len()
                     || (cursor.position == context.text.len() - 1
+                        && cursor.position > 0
                         && context.text[cursor.position - 1] == '\n'))
-                    || (context.flags.intersects(RegexFlags::MULTILINE)
+                    || (flags.intersects(RegexFlags::MULTILINE)
                         && (cursor.position < context.text.len()
                             && context.text[cursor.position] == '\n'))
             }
@@ -366,224 +908,1919 @@ impl Node {
                     || (cursor.position == context.text.len() - 1
                         && context.text[cursor.position] == '\n')
             }
-            Node::Epsilon | Node::GroupLink => false,
-            Node::Match(_, _) | Node::Expression(_, _) | Node::Group(_, _, _) => {
-                panic!("accept not implemented for {:?}!", self)
+            Node::ScriptRunEntry(_) => true,
+            Node::GroupCondition(index, captured) => {
+                cursor.backreference(*index).is_some() == *captured
+            }
+            Node::ScriptRunExit(index) => match cursor.groups[cursor.groups.len() - 1 - index] {
+                Some(start) => is_script_run(&context.text[start..cursor.position]),
+                None => false,
+            },
+            // only the engine can run the inner pattern; analyses that look at
+            // single nodes treat the assertion as passing, like other anchors
+            Node::Lookahead(_)
+            | Node::Lookbehind(_, _)
+            | Node::Atomic(_)
+            | Node::Callout(_)
+            | Node::Subroutine(_) => true,
+            Node::Backreference(index) => match cursor.backreference(*index) {
+                Some((start, end)) => {
+                    let len = end - start;
+                    let ignore_case = flags.intersects(RegexFlags::IGNORECASE);
+                    // checked char by char, a text too short to repeat the group
+                    // only counts as read up to where it ends
+                    (0..len).all(|offset| {
+                        cursor.position + offset < context.end && {
+                            let (expected, actual) = (
+                                context.char_at(start + offset),
+                                context.char_at(cursor.position + offset),
+                            );
+                            if ignore_case {
+                                either_case(actual, |c| c == expected)
+                            } else {
+                                expected == actual
+                            }
+                        }
+                    })
+                }
+                // a group that did not participate matches nothing, as in Python
+                None => false,
+            },
+            Node::Flagged(item, flags) => item.accepts_with_flags(cursor, context, *flags),
+            Node::LineBreak(reversed) => {
+                let (first, second) = if *reversed {
+                    ('\n', '\r')
+                } else {
+                    ('\r', '\n')
+                };
+                let position = cursor.position;
+                position < context.end && {
+                    let c = context.char_at(position);
+                    VERTICAL_WHITESPACE
+                        .iter()
+                        .any(|&(start, end)| start <= c && c <= end)
+                        && !(c == first
+                            && position + 1 < context.end
+                            && context.char_at(position + 1) == second)
+                }
             }
+            // the compilers break these up, so no transition is ever labelled
+            // with one
+            Node::Epsilon
+            | Node::GroupLink
+            | Node::Match(_, _)
+            | Node::Expression(_, _)
+            | Node::Group(_, _, _) => false,
         }
     }
 
-    pub fn increment(&self) -> usize {
-        match self {
-            Node::Character(_) | Node::Dot | Node::CharacterGroup(_, _) => 1,
-            // anchors
-            Node::EmptyString
-            | Node::GroupEntry(_)
-            | Node::GroupExit(_)
-            | Node::WordBoundary
-            | Node::NonWordBoundary
-            | Node::StartOfString
-            | Node::EndOfString
-            | Node::StartOfStringOnly
-            | Node::EndOfStringOnlyNotNewline
-            | Node::EndOfStringOnlyMaybeNewLine
-            | Node::Epsilon
-            | Node::GroupLink => 0,
-            _ => panic!("increment not implemented!"),
+    /// Whether this consuming node accepts `c` on its own, outside of any haystack.
+    pub fn accepts_char(&self, c: char, flags: RegexFlags) -> bool {
+        self.accepts(&Cursor::new(0, 0), &Context::new_with_flags(vec![c], flags))
+    }
+
+    /// The chars this node accepts on its own under `flags`, as sorted inclusive
+    /// ranges with gaps between them, after set operations, negation and case
+    /// folding. `None` unless the node matches exactly one char.
+    pub fn class_ranges(&self, flags: RegexFlags) -> Option<impl Iterator<Item = (char, char)>> {
+        if !self.is_char_matcher() {
+            return None;
+        }
+        let mut boundaries = Vec::new();
+        self.class_boundaries(flags, &mut boundaries);
+        let classes = EquivalenceClasses::new(boundaries);
+        let mut ranges: Vec<(char, char)> = Vec::new();
+        for class in 0..classes.len() {
+            if !self.accepts_char(classes.representative(class), flags) {
+                continue;
+            }
+            let (start, end) = classes.range(class);
+            match ranges.last_mut() {
+                Some((_, last)) if next_char(*last) == Some(start) => *last = end,
+                _ => ranges.push((start, end)),
+            }
         }
+        Some(ranges.into_iter())
     }
-}
 
-impl Data for Node {
-    fn accept<V: Visitor>(&self, visitor: &mut V) -> V::Result {
+    /// Whether some char is accepted both by this node and by `other`, two char
+    /// matchers, under `flags`.
+    fn overlaps(&self, other: &Node, flags: RegexFlags) -> bool {
+        let mut boundaries = Vec::new();
+        self.class_boundaries(flags, &mut boundaries);
+        other.class_boundaries(flags, &mut boundaries);
+        let classes = EquivalenceClasses::new(boundaries);
+        let overlaps = classes
+            .representatives()
+            .any(|c| self.accepts_char(c, flags) && other.accepts_char(c, flags));
+        overlaps
+    }
+
+    pub(crate) fn is_char_matcher(&self) -> bool {
         match self {
-            Self::Character(_) => visitor.visit_character(self.clone()),
-            Self::Expression(_, _) => visitor.visit_expression(self.clone()),
-            Self::Match(_, _) => visitor.visit_match(self.clone()),
-            Self::Group(_, _, _) => visitor.visit_group(self.clone()),
-            Self::Dot => visitor.visit_dot(self.clone()),
-            Self::CharacterGroup(_, _) => visitor.visit_character_group(self.clone()),
-            Self::EmptyString
-            | Self::Epsilon
-            | Self::EndOfString
-            | Self::EndOfStringOnlyMaybeNewLine
-            | Self::EndOfStringOnlyNotNewline
-            | Self::GroupEntry(_)
-            | Self::GroupExit(_)
-            | Self::GroupLink
-            | Self::StartOfStringOnly
-            | Self::StartOfString
-            | Self::WordBoundary
-            | Self::NonWordBoundary => visitor.visit_anchor(self.clone()),
-            Self::CharacterRange(_, _) => panic!("not implemented for char range!"),
+            Node::Character(_)
+            | Node::Dot
+            | Node::CharacterGroup(_, _)
+            | Node::CharacterRange(_, _)
+            | Node::UnicodeProperty(_) => true,
+            Node::Flagged(item, _) => item.is_char_matcher(),
+            _ => false,
         }
     }
-}
 
-impl Display for Node {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self.clone() {
-            Self::Character(char_literal) => write!(f, "{}", char_literal),
-            Self::Expression(items, alternative) => {
-                let joined = items.iter().map(|node| format!("{}", node)).join("");
-                if alternative.is_some() {
-                    write!(f, "{}|{}", joined, alternative.unwrap())
-                } else {
-                    write!(f, "{}", joined)
+    /// Pushes the chars at which this node's answer in `accepts_char` can change.
+    /// Ignoring case, a char is accepted as the chars of its case orbit are, so
+    /// each char of an orbit that the node's own ranges split is set apart.
+    pub fn class_boundaries(&self, flags: RegexFlags, boundaries: &mut Vec<char>) {
+        let ignore_case = flags.intersects(RegexFlags::IGNORECASE);
+        let push_orbit = |boundaries: &mut Vec<char>, orbit: &[char]| {
+            orbit
+                .iter()
+                .for_each(|&c| push_class_range(boundaries, c, c))
+        };
+        match self {
+            Node::Character(c) => {
+                push_class_range(boundaries, *c, *c);
+                match case_orbit(*c) {
+                    Some(orbit) if ignore_case => push_orbit(boundaries, orbit),
+                    _ => {}
                 }
             }
-            Self::Match(item, quantifier) => write!(f, "{}{}", item, quantifier),
-            Self::Group(item, group_index, quantifier) => match group_index {
-                Some(_) => write!(f, "(?:{}){}", item, quantifier),
-                None => write!(f, "({}){}", item, quantifier),
-            },
-            Self::Epsilon => write!(f, "{}", 'ε'),
-            Self::CharacterGroup(items, negated) => {
-                let joined = items.iter().map(|node| format!("{}", node)).join("");
-                if negated {
-                    write!(f, "[^{}]", joined)
-                } else {
-                    write!(f, "[{}]", joined)
+            Node::CharacterRange(start, end) => {
+                push_class_range(boundaries, *start, *end);
+                if ignore_case {
+                    case_orbits_within(*start, *end)
+                        .filter(|orbit| !orbit.iter().all(|c| start <= c && c <= end))
+                        .for_each(|orbit| push_orbit(boundaries, orbit));
                 }
             }
-            Self::EmptyString
-            | Self::Dot
-            | Self::EndOfString
-            | Self::EndOfStringOnlyMaybeNewLine
-            | Self::EndOfStringOnlyNotNewline
-            | Self::GroupEntry(_)
-            | Self::GroupExit(_)
-            | Self::GroupLink
-            | Self::StartOfStringOnly
-            | Self::StartOfString
-            | Self::WordBoundary
-            | Self::NonWordBoundary => write!(f, "{:?}", *self),
-            Self::CharacterRange(from, to) => write!(f, "{from}-{to}",),
+            Node::UnicodeProperty(property) => {
+                boundaries.extend(property.boundaries());
+                if ignore_case {
+                    all_case_orbits()
+                        .filter(|orbit| !orbit.iter().map(|&c| property.contains(c)).all_equal())
+                        .for_each(|orbit| push_orbit(boundaries, orbit));
+                }
+            }
+            Node::Dot => push_class_range(boundaries, '\n', '\n'),
+            Node::LineBreak(_) => VERTICAL_WHITESPACE
+                .iter()
+                .for_each(|&(start, end)| push_class_range(boundaries, start, end)),
+            Node::CharacterGroup(items, _) => items
+                .iter()
+                .for_each(|item| item.class_boundaries(flags, boundaries)),
+            Node::ClassOperation(left, _, right) => {
+                left.class_boundaries(flags, boundaries);
+                right.class_boundaries(flags, boundaries);
+            }
+            Node::Flagged(item, flags) => item.class_boundaries(*flags, boundaries),
+            _ => {}
         }
     }
-}
 
-#[derive(Debug, PartialEq)]
-pub enum ParserError {
-    UnexpectedToken(Box<String>, char),
-    UnexpectedEOF,
-    UnableToParseChar(Box<String>),
-    CantParseCharGroup(Box<String>, Box<String>),
-    UnrecognizedAnchor(Box<String>, char),
-    UnrecognizedModifier(Box<String>, char),
-    InvalidExpression(Box<String>),
-    InvalidStartToCharacterClass(Box<String>),
-    SuffixRemaining(Box<String>),
-    UnrecognizedQuantifier(char),
-    InvalidRangeQuantifier(u64, u64),
-    CantParseRangeBound(ParseIntError),
-    InvalidCharacterRange(char, char),
-}
+    /// Bytes taken by this node and everything it owns, counted from its shape
+    /// rather than from allocator capacities.
+    pub fn memory_usage(&self) -> usize {
+        let children = match self {
+            Node::Match(item, _)
+            | Node::Group(item, _, _)
+            | Node::Lookahead(item)
+            | Node::Lookbehind(item, _)
+            | Node::Atomic(item)
+            | Node::Flagged(item, _) => item.memory_usage(),
+            Node::Expression(items, alternative) => {
+                items
+                    .iter()
+                    .map(|item| size_of::<Box<Node>>() + item.memory_usage())
+                    .sum::<usize>()
+                    + alternative
+                        .as_ref()
+                        .map_or(0, |alternative| alternative.memory_usage())
+            }
+            Node::CharacterGroup(items, _) => items
+                .iter()
+                .map(|item| size_of::<Box<Node>>() + item.memory_usage())
+                .sum(),
+            Node::ClassOperation(left, _, right) => {
+                2 * size_of::<Box<Node>>() + left.memory_usage() + right.memory_usage()
+            }
+            _ => 0,
+        };
+        size_of::<Node>() + children
+    }
 
-impl Display for ParserError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match *self {
-            Self::CantParseCharGroup(ref consumed, ref left) => {
-                write!(
-                    f,
-                    "{} {}:\n | {}{}\n | {}",
-                    format!("[{:0>3}]", 2).red().bold(),
-                    "error while parsing character group",
-                    consumed,
-                    left,
-                    "^".repeat(consumed.len()).green()
-                )
+    /// The fewest and, if there is a limit, the most chars a match of this node spans.
+    /// A backreference may repeat anything its group captured, so it is unbounded.
+    pub fn length_bounds(&self) -> LengthBounds {
+        match self {
+            Node::Character(_)
+            | Node::Dot
+            | Node::CharacterGroup(_, _)
+            | Node::CharacterRange(_, _)
+            | Node::UnicodeProperty(_)
+            | Node::LineBreak(_) => LengthBounds::exactly(1),
+            Node::Match(item, quantifier) | Node::Group(item, _, quantifier) => {
+                item.length_bounds().repeat(quantifier)
+            }
+            Node::Expression(items, alternative) => {
+                let sequence = items.iter().fold(LengthBounds::exactly(0), |bounds, item| {
+                    bounds.then(&item.length_bounds())
+                });
+                match alternative {
+                    Some(alternative) => sequence.or(&alternative.length_bounds()),
+                    None => sequence,
+                }
             }
-            _ => write!(f, "{:#?}", *self),
+            Node::Backreference(_) | Node::Subroutine(_) => LengthBounds { min: 0, max: None },
+            Node::Flagged(item, _) => item.length_bounds(),
+            _ => LengthBounds::exactly(0),
         }
     }
+
+    /// Literals every match contains, in the order they appear in it, for ruling
+    /// out a haystack before running an engine. A class of one char counts as
+    /// that char, and a class of both cases of a letter, such as `[Ff]` or `f`
+    /// under IGNORECASE, as the letter in either case, which folds the literals.
+    pub fn required_literals(&self, flags: RegexFlags) -> RequiredLiterals {
+        fn visit(
+            node: &Node,
+            flags: RegexFlags,
+            literals: &mut Vec<String>,
+            current: &mut String,
+            folded: &mut bool,
+        ) {
+            let flush = |literals: &mut Vec<String>, current: &mut String| {
+                if !current.is_empty() {
+                    literals.push(std::mem::take(current));
+                }
+            };
+            match node {
+                Node::Match(item, quantifier) | Node::Group(item, _, quantifier)
+                    if quantifier.is_once() =>
+                {
+                    visit(item, flags, literals, current, folded)
+                }
+                // one copy is required, but what surrounds it may be another copy
+                Node::Match(item, Quantifier::OneOrMore(_) | Quantifier::Range(1.., _, _))
+                | Node::Group(item, _, Quantifier::OneOrMore(_) | Quantifier::Range(1.., _, _)) => {
+                    flush(literals, current);
+                    visit(item, flags, literals, current, folded);
+                    flush(literals, current);
+                }
+                Node::Expression(items, None) => items
+                    .iter()
+                    .for_each(|item| visit(item, flags, literals, current, folded)),
+                Node::Epsilon
+                | Node::GroupEntry(_)
+                | Node::GroupExit(_)
+                | Node::StartOfString
+                | Node::EndOfString
+                | Node::EmptyString
+                | Node::WordBoundary
+                | Node::NonWordBoundary
+                | Node::StartOfStringOnly
+                | Node::EndOfStringOnlyNotNewline
+                | Node::EndOfStringOnlyMaybeNewLine
+                | Node::ScriptRunEntry(_)
+                | Node::ScriptRunExit(_)
+                | Node::GroupCondition(_, _)
+                | Node::Lookahead(_)
+                | Node::Lookbehind(_, _)
+                | Node::Callout(_) => {}
+                _ => match node.single_char(flags) {
+                    Some((c, either_case)) => {
+                        current.push(c);
+                        *folded |= either_case;
+                    }
+                    None => flush(literals, current),
+                },
+            }
+        }
+        let mut literals = Vec::new();
+        let mut current = String::new();
+        let mut folded = false;
+        visit(self, flags, &mut literals, &mut current, &mut folded);
+        if !current.is_empty() {
+            literals.push(current);
+        }
+        RequiredLiterals::new(literals, folded)
+    }
+
+    /// The one char this char matcher accepts, or the lowercase letter if it
+    /// accepts exactly that letter in both cases, which the flag then says.
+    pub(crate) fn single_char(&self, flags: RegexFlags) -> Option<(char, bool)> {
+        let ranges: Vec<(char, char)> = self.class_ranges(flags)?.collect();
+        match ranges[..] {
+            [(c, end)] if c == end => Some((c, false)),
+            [(upper, upper_end), (lower, lower_end)]
+                if upper == upper_end
+                    && lower == lower_end
+                    && upper.is_ascii_uppercase()
+                    && upper.to_ascii_lowercase() == lower =>
+            {
+                Some((lower, true))
+            }
+            _ => None,
+        }
+    }
+
+    /// For a `Group` or `Match` with a possessive quantifier, the `Atomic` node
+    /// wrapping it with a greedy one, which is how the engines run it.
+    pub fn possessive_as_atomic(&self) -> Option<Node> {
+        match self {
+            Node::Group(item, group_index, quantifier)
+                if quantifier.greediness() == Some(Greediness::Possessive) =>
+            {
+                Some(Node::Atomic(Box::new(Node::Group(
+                    item.clone(),
+                    *group_index,
+                    quantifier.with_greediness(Greediness::Greedy),
+                ))))
+            }
+            Node::Match(item, quantifier)
+                if quantifier.greediness() == Some(Greediness::Possessive) =>
+            {
+                Some(Node::Atomic(Box::new(Node::Match(
+                    item.clone(),
+                    quantifier.with_greediness(Greediness::Greedy),
+                ))))
+            }
+            _ => None,
+        }
+    }
+
+    /// This node with every char matcher and line anchor in it set to use `flags`,
+    /// except those already under flags of their own from a nested group.
+    /// Backreferences keep following the pattern's flags.
+    pub fn with_flags(self, flags: RegexFlags) -> Node {
+        let scoped = |item: Box<Node>| Box::new(item.with_flags(flags));
+        match self {
+            Node::Character(_)
+            | Node::Dot
+            | Node::CharacterGroup(_, _)
+            | Node::CharacterRange(_, _)
+            | Node::StartOfString
+            | Node::EndOfString => Node::Flagged(Box::new(self), flags),
+            Node::Match(item, quantifier) => Node::Match(scoped(item), quantifier),
+            Node::Group(item, group_index, quantifier) => {
+                Node::Group(scoped(item), group_index, quantifier)
+            }
+            Node::Expression(items, alternative) => Node::Expression(
+                items.into_iter().map(scoped).collect(),
+                alternative.map(scoped),
+            ),
+            Node::Lookahead(item) => Node::Lookahead(scoped(item)),
+            Node::Lookbehind(item, negated) => Node::Lookbehind(scoped(item), negated),
+            _ => self,
+        }
+    }
+
+    /// This node rewritten to compile to a smaller automaton, matching the same
+    /// texts the same way: quantifiers are made canonical, an unquantified
+    /// non-capturing group without alternatives is spliced into the sequence
+    /// around it, and a non-capturing group or a class holding a single item is
+    /// replaced by that item. Runs of literal chars split up by groups end up
+    /// side by side, which the NFA compiles as one chain of states.
+    pub fn normalized(self) -> Node {
+        let normalized = |item: Box<Node>| Box::new(item.normalized());
+        match self {
+            Node::Expression(items, alternative) => {
+                let mut flattened = Vec::with_capacity(items.len());
+                for item in items.into_iter().map(normalized) {
+                    match *item {
+                        Node::Group(inner, None, Quantifier::None)
+                            if matches!(*inner, Node::Expression(_, None)) =>
+                        {
+                            let Node::Expression(inner, _) = *inner else {
+                                unreachable!()
+                            };
+                            flattened.extend(inner);
+                        }
+                        item => flattened.push(Box::new(item)),
+                    }
+                }
+                match (&flattened[..], alternative) {
+                    // a pattern that is a single group of alternatives
+                    ([item], None) => match &**item {
+                        Node::Group(inner, None, Quantifier::None)
+                            if matches!(**inner, Node::Expression(_, Some(_))) =>
+                        {
+                            (**inner).clone()
+                        }
+                        _ => Node::Expression(flattened, None),
+                    },
+                    (_, alternative) => Node::Expression(flattened, alternative.map(normalized)),
+                }
+            }
+            Node::Group(item, None, quantifier) if !quantifier.is_once() => {
+                let quantifier = quantifier.canonical();
+                match item.normalized() {
+                    // `(?:a)*` is `a*`
+                    Node::Expression(items, None) if items.len() == 1 => match *items[0] {
+                        Node::Match(ref inner, Quantifier::None) => {
+                            Node::Match(inner.clone(), quantifier)
+                        }
+                        Node::Group(ref inner, group_index, Quantifier::None) => {
+                            Node::Group(inner.clone(), group_index, quantifier)
+                        }
+                        _ => Node::Group(Box::new(Node::Expression(items, None)), None, quantifier),
+                    },
+                    item => Node::Group(Box::new(item), None, quantifier),
+                }
+            }
+            Node::Group(item, group_index, quantifier) => {
+                Node::Group(normalized(item), group_index, quantifier.canonical())
+            }
+            Node::Match(item, quantifier) => Node::Match(normalized(item), quantifier.canonical()),
+            Node::CharacterGroup(items, false)
+                if items.len() == 1 && matches!(*items[0], Node::Character(_)) =>
+            {
+                *items.into_iter().next().unwrap()
+            }
+            Node::Lookahead(item) => Node::Lookahead(normalized(item)),
+            Node::Lookbehind(item, negated) => Node::Lookbehind(normalized(item), negated),
+            Node::Atomic(item) => Node::Atomic(normalized(item)),
+            Node::Flagged(item, flags) => Node::Flagged(normalized(item), flags),
+            _ => self,
+        }
+    }
+
+    /// This node with every greedy repetition of a char matcher made possessive
+    /// where giving back a char could never help: the item after it must read a
+    /// char first, and none that the repetition reads. `a+b` becomes `a++b`.
+    /// The matches stay the same, but a search following the pattern has no
+    /// shorter runs of the repetition to keep trying.
+    pub fn auto_possessified(self, flags: RegexFlags) -> Node {
+        let possessified = |item: Box<Node>| Box::new(item.auto_possessified(flags));
+        match self {
+            Node::Expression(items, alternative) => {
+                let mut items: Vec<Box<Node>> = items.into_iter().map(possessified).collect();
+                for i in 1..items.len() {
+                    let (before, after) = items.split_at_mut(i);
+                    let (Node::Match(item, quantifier), Node::Match(next, _)) =
+                        (&mut *before[i - 1], &*after[0])
+                    else {
+                        continue;
+                    };
+                    if quantifier.greediness() == Some(Greediness::Greedy)
+                        && !quantifier.is_once()
+                        && after[0].length_bounds().min > 0
+                        && item.is_char_matcher()
+                        && next.is_char_matcher()
+                        && !item.overlaps(next, flags)
+                    {
+                        *quantifier = quantifier.with_greediness(Greediness::Possessive);
+                    }
+                }
+                Node::Expression(items, alternative.map(possessified))
+            }
+            Node::Group(item, group_index, quantifier) => {
+                Node::Group(possessified(item), group_index, quantifier)
+            }
+            Node::Lookahead(item) => Node::Lookahead(possessified(item)),
+            Node::Lookbehind(item, negated) => Node::Lookbehind(possessified(item), negated),
+            Node::Atomic(item) => Node::Atomic(possessified(item)),
+            _ => self,
+        }
+    }
+
+    /// A node matching the reverse of every string this one matches, for running
+    /// over a reversed haystack. Concatenations are reversed and each anchor is
+    /// replaced by the one asserting the same thing from the other side; `^` and
+    /// `$` outside of MULTILINE become `\z` and `\A`, so `$` no longer matches
+    /// before a trailing newline.
+    pub fn reversed(&self, flags: RegexFlags) -> Node {
+        let multiline = flags.intersects(RegexFlags::MULTILINE);
+        match self {
+            Node::Expression(items, alternative) => Node::Expression(
+                items
+                    .iter()
+                    .rev()
+                    .map(|item| Box::new(item.reversed(flags)))
+                    .collect(),
+                alternative
+                    .as_ref()
+                    .map(|alternative| Box::new(alternative.reversed(flags))),
+            ),
+            Node::Match(item, quantifier) => {
+                Node::Match(Box::new(item.reversed(flags)), quantifier.clone())
+            }
+            Node::Group(item, group_index, quantifier) => Node::Group(
+                Box::new(item.reversed(flags)),
+                *group_index,
+                quantifier.clone(),
+            ),
+            Node::Flagged(item, flags) => Node::Flagged(Box::new(item.reversed(*flags)), *flags),
+            Node::ScriptRunEntry(index) => Node::ScriptRunExit(*index),
+            Node::ScriptRunExit(index) => Node::ScriptRunEntry(*index),
+            Node::LineBreak(reversed) => Node::LineBreak(!reversed),
+            Node::StartOfString if multiline => Node::EndOfString,
+            Node::EndOfString if multiline => Node::StartOfString,
+            Node::StartOfString | Node::StartOfStringOnly => Node::EndOfStringOnlyNotNewline,
+            Node::EndOfString
+            | Node::EndOfStringOnlyNotNewline
+            | Node::EndOfStringOnlyMaybeNewLine => Node::StartOfStringOnly,
+            _ => self.clone(),
+        }
+    }
+
+    /// The nodes directly inside this one, in the order they appear in the pattern.
+    pub fn children(&self) -> Vec<&Node> {
+        match self {
+            Node::Expression(items, alternative) => items
+                .iter()
+                .map(|item| &**item)
+                .chain(alternative.as_deref())
+                .collect(),
+            Node::CharacterGroup(items, _) => items.iter().map(|item| &**item).collect(),
+            Node::ClassOperation(left, _, right) => vec![left, right],
+            Node::Match(item, _)
+            | Node::Group(item, _, _)
+            | Node::Lookahead(item)
+            | Node::Lookbehind(item, _)
+            | Node::Atomic(item)
+            | Node::Flagged(item, _) => vec![item],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Shows `visitor` this node and everything inside it, depth first.
+    pub fn visit<V: NodeVisitor>(&self, visitor: &mut V) {
+        if visitor.enter(self) {
+            self.children()
+                .into_iter()
+                .for_each(|child| child.visit(visitor));
+        }
+        visitor.leave(self);
+    }
+
+    /// This node and every node inside it, each before the nodes inside it.
+    pub fn walk(&self) -> impl Iterator<Item = &Node> {
+        let mut stack = vec![self];
+        std::iter::from_fn(move || {
+            let node = stack.pop()?;
+            stack.extend(node.children().into_iter().rev());
+            Some(node)
+        })
+    }
+
+    /// A copy of the tree with every node replaced by what `rewrite` makes of it,
+    /// once the nodes inside it have been replaced.
+    pub fn transform(&self, rewrite: &mut impl FnMut(Node) -> Node) -> Node {
+        let mut recurse = |item: &Node| Box::new(item.transform(rewrite));
+        let node = match self {
+            Node::Expression(items, alternative) => Node::Expression(
+                items.iter().map(|item| recurse(item)).collect(),
+                alternative.as_deref().map(&mut recurse),
+            ),
+            Node::CharacterGroup(items, negated) => {
+                Node::CharacterGroup(items.iter().map(|item| recurse(item)).collect(), *negated)
+            }
+            Node::ClassOperation(left, operator, right) => {
+                Node::ClassOperation(recurse(left), *operator, recurse(right))
+            }
+            Node::Match(item, quantifier) => Node::Match(recurse(item), quantifier.clone()),
+            Node::Group(item, index, quantifier) => {
+                Node::Group(recurse(item), *index, quantifier.clone())
+            }
+            Node::Lookahead(item) => Node::Lookahead(recurse(item)),
+            Node::Lookbehind(item, negated) => Node::Lookbehind(recurse(item), *negated),
+            Node::Atomic(item) => Node::Atomic(recurse(item)),
+            Node::Flagged(item, flags) => Node::Flagged(recurse(item), *flags),
+            node => node.clone(),
+        };
+        rewrite(node)
+    }
+
+    /// How many copies of its innermost repeated part the node holds once every
+    /// `{m,n}` in it is expanded, multiplying the counts of nested repetitions.
+    pub fn repetition_count(&self) -> u64 {
+        let copies = match self {
+            Node::Match(_, Quantifier::Range(lower, upper, _))
+            | Node::Group(_, _, Quantifier::Range(lower, upper, _)) => match upper {
+                UpperBound::Bounded(upper) => (*upper).max(*lower),
+                _ => (*lower).max(1),
+            },
+            _ => 1,
+        };
+        let inside = self
+            .children()
+            .into_iter()
+            .map(Node::repetition_count)
+            .max()
+            .unwrap_or(1);
+        copies.saturating_mul(inside)
+    }
+
+    pub fn increment(&self) -> usize {
+        match self {
+            // a backreference consumes as many chars as its group captured, which
+            // `Cursor::update` looks up; it only counts as consuming here
+            Node::Character(_)
+            | Node::Dot
+            | Node::CharacterGroup(_, _)
+            | Node::LineBreak(_)
+            | Node::Backreference(_)
+            | Node::Atomic(_)
+            | Node::Subroutine(_)
+            | Node::CharacterRange(_, _)
+            | Node::UnicodeProperty(_)
+            | Node::ClassOperation(_, _, _) => 1,
+            // anchors
+            Node::EmptyString
+            | Node::GroupEntry(_)
+            | Node::GroupExit(_)
+            | Node::WordBoundary
+            | Node::NonWordBoundary
+            | Node::StartOfString
+            | Node::EndOfString
+            | Node::StartOfStringOnly
+            | Node::EndOfStringOnlyNotNewline
+            | Node::EndOfStringOnlyMaybeNewLine
+            | Node::ScriptRunEntry(_)
+            | Node::ScriptRunExit(_)
+            | Node::GroupCondition(_, _)
+            | Node::Lookahead(_)
+            | Node::Lookbehind(_, _)
+            | Node::Callout(_)
+            | Node::Epsilon
+            | Node::GroupLink => 0,
+            Node::Flagged(item, _) => item.increment(),
+            // never a transition, but consuming if every match of it is
+            Node::Match(_, _) | Node::Expression(_, _) | Node::Group(_, _, _) => {
+                usize::from(self.length_bounds().min > 0)
+            }
+        }
+    }
+}
+
+impl Data for Node {
+    fn accept<V: Visitor>(&self, visitor: &mut V) -> V::Result {
+        match self {
+            Self::Character(_)
+            | Self::LineBreak(_)
+            | Self::Backreference(_)
+            | Self::Atomic(_)
+            | Self::Subroutine(_)
+            | Self::CharacterRange(_, _)
+            | Self::UnicodeProperty(_)
+            | Self::ClassOperation(_, _, _) => visitor.visit_character(self.clone()),
+            Self::Expression(_, _) => visitor.visit_expression(self.clone()),
+            Self::Match(_, _) => visitor.visit_match(self.clone()),
+            Self::Group(_, _, _) => visitor.visit_group(self.clone()),
+            Self::Dot => visitor.visit_dot(self.clone()),
+            Self::CharacterGroup(_, _) => visitor.visit_character_group(self.clone()),
+            Self::EmptyString
+            | Self::Epsilon
+            | Self::EndOfString
+            | Self::EndOfStringOnlyMaybeNewLine
+            | Self::EndOfStringOnlyNotNewline
+            | Self::GroupEntry(_)
+            | Self::GroupExit(_)
+            | Self::GroupLink
+            | Self::StartOfStringOnly
+            | Self::StartOfString
+            | Self::WordBoundary
+            | Self::NonWordBoundary
+            | Self::ScriptRunEntry(_)
+            | Self::ScriptRunExit(_)
+            | Self::GroupCondition(_, _)
+            | Self::Lookahead(_)
+            | Self::Lookbehind(_, _)
+            | Self::Callout(_) => visitor.visit_anchor(self.clone()),
+            Self::Flagged(item, _) if item.increment() == 0 => visitor.visit_anchor(self.clone()),
+            Self::Flagged(_, _) => visitor.visit_character(self.clone()),
+        }
+    }
+}
+
+impl Display for Node {
+    /// Writes the node as pattern syntax that parses back to the same tree,
+    /// under no flags but those it sets itself. Nodes only the engines build,
+    /// such as `GroupEntry`, have no syntax and come out in their debug form.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let writer = PatternWriter {
+            names: HashMap::new(),
+            pattern_flags: RegexFlags::NO_FLAG,
+        };
+        writer.write(self, &mut RegexFlags::empty(), f)
+    }
+}
+
+/// Writes parsed nodes back as pattern syntax. It follows the inline flags in
+/// effect as the parser does, writing a `(?flags)` switch wherever an item is
+/// under other flags than those before it.
+struct PatternWriter<'a> {
+    /// Group names by the index of their group, counting from 0.
+    names: HashMap<usize, &'a str>,
+    /// The flags items under no flags of their own follow.
+    pattern_flags: RegexFlags,
+}
+
+/// Only the flags a pattern can set inline.
+fn inline_flags(flags: RegexFlags) -> RegexFlags {
+    INLINE_FLAGS
+        .iter()
+        .map(|(_, flag)| *flag)
+        .filter(|flag| flags.contains(*flag))
+        .fold(RegexFlags::NO_FLAG, |flags, flag| flags | flag)
+}
+
+/// The letters of `(?on-off)` turning flags `from` into flags `to`.
+fn flag_letters(from: RegexFlags, to: RegexFlags) -> String {
+    let letters = |flags: RegexFlags| -> String {
+        INLINE_FLAGS
+            .iter()
+            .filter(|(_, flag)| flags.contains(*flag))
+            .map(|(letter, _)| letter)
+            .collect()
+    };
+    let (on, off) = (letters(to - from), letters(from - to));
+    if off.is_empty() {
+        on
+    } else {
+        format!("{}-{}", on, off)
+    }
+}
+
+impl PatternWriter<'_> {
+    /// The flags a char matcher or line anchor item follows, or `None` for any
+    /// other item.
+    fn item_flags(&self, item: &Node) -> Option<RegexFlags> {
+        match item {
+            Node::Match(item, _) => self.item_flags(item),
+            Node::Flagged(_, flags) => Some(inline_flags(*flags)),
+            Node::Character(_)
+            | Node::Dot
+            | Node::CharacterGroup(_, _)
+            | Node::StartOfString
+            | Node::EndOfString => Some(inline_flags(self.pattern_flags)),
+            _ => None,
+        }
+    }
+
+    /// Writes `node` under `flags`, the inline flags in effect where it starts,
+    /// leaving in them any switched on the way.
+    fn write(
+        &self,
+        node: &Node,
+        flags: &mut RegexFlags,
+        f: &mut std::fmt::Formatter<'_>,
+    ) -> std::fmt::Result {
+        match node {
+            Node::Character(c) if flags.contains(RegexFlags::FREESPACING) => {
+                write!(f, "{}", escape(&c.to_string()))
+            }
+            Node::Character(c) => write!(f, "{}", escape_literal(*c)),
+            Node::Dot => write!(f, "."),
+            Node::EmptyString => Ok(()),
+            Node::StartOfString => write!(f, "^"),
+            Node::EndOfString => write!(f, "$"),
+            Node::WordBoundary => write!(f, "\\b"),
+            Node::NonWordBoundary => write!(f, "\\B"),
+            Node::StartOfStringOnly => write!(f, "\\A"),
+            Node::EndOfStringOnlyNotNewline => write!(f, "\\z"),
+            Node::EndOfStringOnlyMaybeNewLine => write!(f, "\\Z"),
+            Node::Epsilon => write!(f, "ε"),
+            Node::CharacterGroup(_, _) => self.write_class_item(node, f),
+            Node::Match(item, quantifier) => {
+                self.write(item, flags, f)?;
+                write!(f, "{}", quantifier)
+            }
+            Node::Expression(_, _) => self.write_alternatives(node, flags, f),
+            Node::Group(item, Some(index), quantifier) => {
+                match self.names.get(index) {
+                    Some(name) => write!(f, "(?P<{}>", name)?,
+                    None => write!(f, "(")?,
+                }
+                self.write_body(item, *flags, f)?;
+                write!(f, "){}", quantifier)
+            }
+            Node::Group(item, None, quantifier) => {
+                self.write_non_capturing(item, *flags, f)?;
+                write!(f, "{}", quantifier)
+            }
+            Node::Flagged(item, scoped) if inline_flags(*scoped) == *flags => {
+                self.write(item, flags, f)
+            }
+            Node::Flagged(item, scoped) => {
+                write!(f, "(?{}:", flag_letters(*flags, inline_flags(*scoped)))?;
+                self.write(item, &mut inline_flags(*scoped), f)?;
+                write!(f, ")")
+            }
+            Node::Backreference(index) => write!(f, "\\{}", index + 1),
+            Node::Lookahead(item) => {
+                write!(f, "(?=")?;
+                self.write_body(item, *flags, f)?;
+                write!(f, ")")
+            }
+            Node::Lookbehind(item, negated) => {
+                write!(f, "(?<{}", if *negated { '!' } else { '=' })?;
+                self.write_body(item, *flags, f)?;
+                write!(f, ")")
+            }
+            Node::Atomic(item) => {
+                write!(f, "(?>")?;
+                self.write_body(item, *flags, f)?;
+                write!(f, ")")
+            }
+            Node::Callout(number) => write!(f, "(?C{})", number),
+            Node::Subroutine(None) => write!(f, "(?R)"),
+            Node::Subroutine(Some(index)) => write!(f, "(?{})", index + 1),
+            Node::CharacterRange(_, _)
+            | Node::UnicodeProperty(_)
+            | Node::ClassOperation(_, _, _) => {
+                write!(f, "[")?;
+                self.write_class_item(node, f)?;
+                write!(f, "]")
+            }
+            Node::GroupEntry(_)
+            | Node::GroupExit(_)
+            | Node::GroupLink
+            | Node::ScriptRunEntry(_)
+            | Node::ScriptRunExit(_)
+            | Node::LineBreak(_)
+            | Node::GroupCondition(_, _) => write!(f, "{:?}", node),
+        }
+    }
+
+    /// Writes the body of a group, whose flag switches hold only until its end.
+    fn write_body(
+        &self,
+        node: &Node,
+        mut flags: RegexFlags,
+        f: &mut std::fmt::Formatter<'_>,
+    ) -> std::fmt::Result {
+        self.write(node, &mut flags, f)
+    }
+
+    /// Writes a non-capturing group, recognizing the groups the parser builds
+    /// for `\R`, conditionals and script runs by their shape.
+    fn write_non_capturing(
+        &self,
+        item: &Node,
+        mut flags: RegexFlags,
+        f: &mut std::fmt::Formatter<'_>,
+    ) -> std::fmt::Result {
+        let Node::Expression(items, alternative) = item else {
+            // only `(?flags:)` can leave a group empty
+            return write!(f, "(?{}:)", flag_letters(!flags, flags));
+        };
+        let alternative = alternative.as_deref();
+        match (&items[..], alternative) {
+            ([cr, lf], Some(Node::Expression(line_break, None)))
+                if **cr == Node::Match(Box::new(Node::Character('\r')), Quantifier::None)
+                    && **lf == Node::Match(Box::new(Node::Character('\n')), Quantifier::None)
+                    && line_break[..]
+                        == [Box::new(Node::Match(
+                            Box::new(Node::LineBreak(false)),
+                            Quantifier::None,
+                        ))] =>
+            {
+                write!(f, "\\R")
+            }
+            ([entry, run, exit], None)
+                if matches!(**entry, Node::ScriptRunEntry(_))
+                    && matches!(**exit, Node::ScriptRunExit(_)) =>
+            {
+                let Node::Group(run, None, Quantifier::None) = &**run else {
+                    return write!(f, "{:?}", item);
+                };
+                write!(f, "(?script_run:")?;
+                self.write(run, &mut flags, f)?;
+                write!(f, ")")
+            }
+            ([condition, yes @ ..], Some(Node::Expression(no, None))) => {
+                let Node::GroupCondition(index, true) = **condition else {
+                    return self.write_group(item, flags, f);
+                };
+                write!(f, "(?({})", index + 1)?;
+                self.write_items(yes, &mut flags, f)?;
+                if no.len() > 1 {
+                    write!(f, "|")?;
+                    self.write_items(&no[1..], &mut flags, f)?;
+                }
+                write!(f, ")")
+            }
+            _ => self.write_group(item, flags, f),
+        }
+    }
+
+    /// The flags a group around `body` most likely set: those of its first char
+    /// matcher or line anchor before any `(?flags)`, or failing that, those of
+    /// the group it starts with.
+    fn group_flags(&self, body: &Node) -> Option<RegexFlags> {
+        let Node::Expression(items, _) = body else {
+            return None;
+        };
+        items
+            .iter()
+            .take_while(|item| ***item != Node::EmptyString)
+            .find_map(|item| self.item_flags(item))
+            .or_else(|| match &*items[0] {
+                Node::Group(body, _, _) => self.group_flags(body),
+                _ => None,
+            })
+    }
+
+    /// Writes `(?:...)`, as `(?flags:...)` if the items in it are under other
+    /// flags than those in effect.
+    fn write_group(
+        &self,
+        item: &Node,
+        mut flags: RegexFlags,
+        f: &mut std::fmt::Formatter<'_>,
+    ) -> std::fmt::Result {
+        match self.group_flags(item) {
+            Some(scoped) if scoped != flags => {
+                write!(f, "(?{}:", flag_letters(flags, scoped))?;
+                flags = scoped;
+            }
+            _ => write!(f, "(?:")?,
+        }
+        self.write(item, &mut flags, f)?;
+        write!(f, ")")
+    }
+
+    fn write_alternatives(
+        &self,
+        node: &Node,
+        flags: &mut RegexFlags,
+        f: &mut std::fmt::Formatter<'_>,
+    ) -> std::fmt::Result {
+        let mut current = Some(node);
+        while let Some(Node::Expression(items, alternative)) = current {
+            self.write_items(items, flags, f)?;
+            if alternative.is_some() {
+                write!(f, "|")?;
+            }
+            current = alternative.as_deref();
+        }
+        Ok(())
+    }
+
+    /// Writes the items of one alternative, switching flags before an item under
+    /// other flags, at the empty item a `(?flags)` left if there is one.
+    fn write_items(
+        &self,
+        items: &[Box<Node>],
+        flags: &mut RegexFlags,
+        f: &mut std::fmt::Formatter<'_>,
+    ) -> std::fmt::Result {
+        for (k, item) in items.iter().enumerate() {
+            let upcoming = match **item {
+                Node::EmptyString => items[k + 1..].iter().find_map(|item| match &**item {
+                    // a capture group cannot set flags of its own
+                    Node::Group(body, Some(_), _) => self.group_flags(body),
+                    item => self.item_flags(item),
+                }),
+                _ => self.item_flags(item),
+            };
+            if let Some(upcoming) = upcoming.filter(|upcoming| upcoming != flags) {
+                write!(f, "(?{})", flag_letters(*flags, upcoming))?;
+                *flags = upcoming;
+            }
+            let after_backreference = k > 0
+                && matches!(&*items[k - 1], Node::Match(item, Quantifier::None)
+                    if matches!(**item, Node::Backreference(_)));
+            match &**item {
+                // a digit right after `\1` would be read as part of it
+                Node::Match(digit, quantifier) if after_backreference => match **digit {
+                    Node::Character(c) if c.is_ascii_digit() => {
+                        write!(f, "\\x{:02X}{}", c as u32, quantifier)?
+                    }
+                    _ => self.write(item, flags, f)?,
+                },
+                _ => self.write(item, flags, f)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes a character group, or an item inside one.
+    fn write_class_item(&self, item: &Node, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match item {
+            Node::Character(c) => write!(f, "{}", escape_class_char(*c)),
+            Node::CharacterRange(start, end) => write!(
+                f,
+                "{}-{}",
+                escape_class_char(*start),
+                escape_class_char(*end)
+            ),
+            Node::UnicodeProperty(property) => write!(f, "{}", property),
+            Node::CharacterGroup(items, negated) => {
+                // `\p{..}` and `\P{..}` parse to a group holding just the property
+                if let [item] = &items[..] {
+                    if let Node::UnicodeProperty(property) = &**item {
+                        let escape = property.to_string();
+                        return match negated {
+                            true => write!(f, "{}", escape.replacen("\\p", "\\P", 1)),
+                            false => write!(f, "{}", escape),
+                        };
+                    }
+                }
+                write!(f, "[{}", if *negated { "^" } else { "" })?;
+                for item in items {
+                    self.write_class_item(item, f)?;
+                }
+                write!(f, "]")
+            }
+            // both sides are groups, whose items stand in the enclosing one
+            Node::ClassOperation(left, operator, right) => {
+                self.write_class_items(left, f)?;
+                write!(f, "{}", operator)?;
+                self.write_class_items(right, f)
+            }
+            _ => write!(f, "{:?}", item),
+        }
+    }
+
+    fn write_class_items(&self, group: &Node, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Node::CharacterGroup(items, false) = group {
+            for item in items {
+                self.write_class_item(item, f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `c` written to stand for itself inside a character group, where a doubled
+/// `&` or `~` would start a set operation.
+pub(crate) fn escape_class_char(c: char) -> String {
+    match c {
+        '&' | '~' => format!("\\x{:02X}", c as u32),
+        _ => escape_literal(c),
+    }
+}
+
+/// Why a pattern failed to parse. Every variant ends with the bytes of the
+/// pattern the error is about, so that an editor can point at them.
+#[derive(Debug, PartialEq)]
+pub enum ParserError {
+    /// The char expected where another one, or none, was found.
+    UnexpectedToken(char, Range<usize>),
+    /// The pattern ended early; the span is empty, at the end.
+    UnexpectedEOF(Range<usize>),
+    UnableToParseChar(Range<usize>),
+    /// A character group, from its `[` to where it could not be read on.
+    CantParseCharGroup(Range<usize>),
+    UnrecognizedAnchor(char, Range<usize>),
+    UnrecognizedModifier(char, Range<usize>),
+    InvalidExpression(Range<usize>),
+    InvalidStartToCharacterClass(Range<usize>),
+    SuffixRemaining(Range<usize>),
+    UnrecognizedQuantifier(char, Range<usize>),
+    /// `{lower,upper}` with `upper < lower`, and where it is in the pattern.
+    InvalidRangeQuantifier(u64, u64, Range<usize>),
+    CantParseRangeBound(ParseIntError, Range<usize>),
+    /// `start-end` with `end < start`, and where it is in the pattern.
+    InvalidCharacterRange(char, char, Range<usize>),
+    /// An unknown `\p{...}` name, with the known names closest to it.
+    UnknownProperty(Box<String>, Vec<String>, Range<usize>),
+    /// A group name that is empty or not an identifier.
+    InvalidGroupName(Box<String>, Range<usize>),
+    DuplicateGroupName(Box<String>, Range<usize>),
+    /// A backreference to a group that has not been defined before it, or a
+    /// subroutine call or condition on a group the pattern does not have.
+    UnknownGroupReference(Box<String>, Range<usize>),
+    /// A `[:name:]` naming none of the POSIX classes.
+    UnknownPosixClass(Box<String>, Range<usize>),
+    /// A lookbehind whose pattern can match strings of different lengths.
+    VariableWidthLookbehind(Range<usize>),
+    /// A backreference inside a lookbehind, whose width cannot be known.
+    BackreferenceInLookbehind(Range<usize>),
+    /// A `\x` or `\u` escape without the hex digits it needs.
+    MalformedEscape(Range<usize>),
+    /// An escape naming a surrogate or a value past `U+10FFFF`.
+    InvalidCodePoint(u32, Range<usize>),
+    /// A `(?C...)` whose number is missing digits or past 255.
+    InvalidCallout(Range<usize>),
+    /// A conditional group with more than a yes and a no branch.
+    TooManyConditionalBranches(Range<usize>),
+    /// A repetition, or repetitions nested in one another, copying what they
+    /// repeat this many times, past what the parser allows.
+    RepetitionTooLarge(u64, Range<usize>),
+    /// A group or character group opening inside this many others, the most
+    /// the parser allows; the span is its opening char.
+    NestTooDeep(usize, Range<usize>),
+}
+
+impl Display for ParserError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Self::UnexpectedToken(expected, ref span) => {
+                write!(f, "expected `{}` at {:?}", expected, span)
+            }
+            Self::UnexpectedEOF(ref span) => {
+                write!(f, "pattern ends too early at {:?}", span)
+            }
+            Self::UnableToParseChar(ref span) => write!(f, "expected a char at {:?}", span),
+            Self::CantParseCharGroup(ref span) => {
+                write!(f, "invalid character group at {:?}", span)
+            }
+            Self::UnrecognizedAnchor(c, ref span) => {
+                write!(f, "unknown escape `\\{}` at {:?}", c, span)
+            }
+            Self::UnrecognizedModifier(c, ref span) => {
+                write!(f, "unknown inline flag `{}` at {:?}", c, span)
+            }
+            Self::InvalidExpression(ref span) => {
+                write!(f, "expected an expression at {:?}", span)
+            }
+            Self::InvalidStartToCharacterClass(ref span) => {
+                write!(f, "invalid start of a character class at {:?}", span)
+            }
+            Self::SuffixRemaining(ref span) => {
+                write!(f, "unexpected text after the pattern at {:?}", span)
+            }
+            Self::UnrecognizedQuantifier(c, ref span) => {
+                write!(f, "unknown quantifier `{}` at {:?}", c, span)
+            }
+            Self::CantParseRangeBound(ref error, ref span) => {
+                write!(f, "invalid range bound at {:?}: {}", span, error)
+            }
+            Self::InvalidGroupName(ref name, ref span) => {
+                write!(f, "invalid group name `{}` at {:?}", name, span)
+            }
+            Self::DuplicateGroupName(ref name, ref span) => {
+                write!(f, "group name `{}` at {:?} is already taken", name, span)
+            }
+            Self::UnknownGroupReference(ref name, ref span) => {
+                write!(f, "reference at {:?} to unknown group `{}`", span, name)
+            }
+            Self::UnknownProperty(ref name, ref candidates, _) => {
+                write!(f, "unknown unicode property `{}`", name)?;
+                if !candidates.is_empty() {
+                    write!(f, ", did you mean {}?", candidates.join(" or "))?;
+                }
+                Ok(())
+            }
+            Self::InvalidRangeQuantifier(lower, upper, ref span) => write!(
+                f,
+                "invalid range quantifier {{{},{}}} at {:?}; {}",
+                lower,
+                upper,
+                span,
+                self.suggestion().unwrap()
+            ),
+            Self::InvalidCharacterRange(start, end, ref span) => write!(
+                f,
+                "invalid character range {}-{} at {:?}; {}",
+                start,
+                end,
+                span,
+                self.suggestion().unwrap()
+            ),
+            Self::UnknownPosixClass(ref name, _) => {
+                write!(f, "unknown POSIX class `[:{}:]`", name)
+            }
+            Self::VariableWidthLookbehind(ref span) => write!(
+                f,
+                "lookbehind at {:?} must match a fixed number of chars",
+                span
+            ),
+            Self::BackreferenceInLookbehind(ref span) => {
+                write!(f, "lookbehind at {:?} cannot contain a backreference", span)
+            }
+            Self::MalformedEscape(ref span) => {
+                write!(f, "malformed escape at {:?}", span)
+            }
+            Self::InvalidCallout(ref span) => write!(
+                f,
+                "callout at {:?} must be `(?C)` or `(?Cn)` with n at most 255",
+                span
+            ),
+            Self::TooManyConditionalBranches(ref span) => write!(
+                f,
+                "conditional group at {:?} can have at most two branches",
+                span
+            ),
+            Self::RepetitionTooLarge(count, ref span) => write!(
+                f,
+                "repetition at {:?} repeats its item {} times, past the repetition limit",
+                span, count
+            ),
+            Self::NestTooDeep(limit, ref span) => write!(
+                f,
+                "group at {:?} is nested in {} others, past the nesting limit",
+                span, limit
+            ),
+            Self::InvalidCodePoint(value, ref span) => write!(
+                f,
+                "escape at {:?} names {:#X}, which is not a unicode scalar value",
+                span, value
+            ),
+        }
+    }
+}
+
+impl ParserError {
+    /// The bytes of the pattern the error is about.
+    pub fn span(&self) -> Range<usize> {
+        match self {
+            Self::UnexpectedEOF(span)
+            | Self::UnableToParseChar(span)
+            | Self::CantParseCharGroup(span)
+            | Self::InvalidExpression(span)
+            | Self::InvalidStartToCharacterClass(span)
+            | Self::SuffixRemaining(span)
+            | Self::VariableWidthLookbehind(span)
+            | Self::BackreferenceInLookbehind(span)
+            | Self::MalformedEscape(span)
+            | Self::InvalidCallout(span)
+            | Self::TooManyConditionalBranches(span) => span.clone(),
+            Self::UnexpectedToken(_, span)
+            | Self::UnrecognizedAnchor(_, span)
+            | Self::UnrecognizedModifier(_, span)
+            | Self::UnrecognizedQuantifier(_, span)
+            | Self::CantParseRangeBound(_, span)
+            | Self::InvalidGroupName(_, span)
+            | Self::DuplicateGroupName(_, span)
+            | Self::UnknownGroupReference(_, span)
+            | Self::UnknownPosixClass(_, span)
+            | Self::InvalidCodePoint(_, span)
+            | Self::RepetitionTooLarge(_, span)
+            | Self::NestTooDeep(_, span) => span.clone(),
+            Self::InvalidRangeQuantifier(_, _, span)
+            | Self::InvalidCharacterRange(_, _, span)
+            | Self::UnknownProperty(_, _, span) => span.clone(),
+        }
+    }
+
+    /// The error as rustc shows one: the message, then the line of `pattern` the
+    /// error is about, with `^` under its first char and `~` under the rest.
+    /// `pattern` is the pattern that failed to parse.
+    pub fn render(&self, pattern: &str) -> String {
+        let span = self.span();
+        if pattern.get(span.clone()).is_none() {
+            return format!("error: {}", self);
+        }
+        let line_start = pattern[..span.start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = pattern[span.start..]
+            .find('\n')
+            .map_or(pattern.len(), |i| span.start + i);
+        let number = (pattern[..line_start].matches('\n').count() + 1).to_string();
+        let gutter = " ".repeat(number.len());
+        let indent = pattern[line_start..span.start].chars().count();
+        let width = pattern[span.start..span.end.min(line_end)].chars().count();
+        format!(
+            "error: {}\n{} |\n{} | {}\n{} | {}^{}",
+            self,
+            gutter,
+            number,
+            &pattern[line_start..line_end],
+            gutter,
+            " ".repeat(indent),
+            "~".repeat(width.saturating_sub(1))
+        )
+    }
+
+    /// A fix to offer the user, phrased so that a CLI can print it as is.
+    pub fn suggestion(&self) -> Option<String> {
+        match self {
+            Self::InvalidRangeQuantifier(lower, upper, _) => {
+                Some(format!("swap the bounds: use {{{},{}}}", upper, lower))
+            }
+            Self::InvalidCharacterRange(start, end, _) => {
+                Some(format!("swap the endpoints: use {}-{}", end, start))
+            }
+            Self::UnknownProperty(_, candidates, _) => candidates
+                .first()
+                .map(|candidate| format!("use \\p{{{}}}", candidate)),
+            _ => None,
+        }
+    }
+}
+
+impl Error for ParserError {}
+
+/// A parsed pattern together with what the engines need to size their cursors.
+#[derive(Debug, Clone)]
+pub struct ParsedRegex {
+    pub root: Node,
+    pub group_count: usize,
+    /// Script runs each keep their start in a hidden cursor slot.
+    pub script_run_count: usize,
+    /// Named groups mapped to their group index, counting from 1.
+    pub group_names: HashMap<String, usize>,
+    pub group_tree: GroupTree,
+    pub length_bounds: LengthBounds,
+    pub required_literals: RequiredLiterals,
+    /// Where the groups and items are in the pattern, if parsed from one.
+    pub source_map: SourceMap,
+    /// The flags of the whole pattern, including those set at its start.
+    pub flags: RegexFlags,
+}
+
+impl Display for ParsedRegex {
+    /// Writes the pattern back out, starting with its flags, so that it parses
+    /// to the same tree. Only what the parser keeps survives: the pattern comes
+    /// out without comments or free-spacing whitespace, and with escapes such as
+    /// `\d` spelled out.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut flags = inline_flags(self.flags);
+        if !flags.is_empty() {
+            write!(f, "(?{})", flag_letters(RegexFlags::NO_FLAG, flags))?;
+        }
+        let writer = PatternWriter {
+            names: self
+                .group_names
+                .iter()
+                .map(|(name, index)| (index - 1, name.as_str()))
+                .collect(),
+            pattern_flags: self.flags,
+        };
+        writer.write(&self.root, &mut flags, f)
+    }
+}
+
+/// How many chars a match can span: at least `min`, and at most `max` if the
+/// pattern caps it. Useful for sizing input fields and for knowing how much of a
+/// haystack a match ending at some offset could reach back into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LengthBounds {
+    pub min: usize,
+    pub max: Option<usize>,
+}
+
+impl LengthBounds {
+    pub fn exactly(length: usize) -> LengthBounds {
+        LengthBounds {
+            min: length,
+            max: Some(length),
+        }
+    }
+
+    /// Bounds of a match of `self` followed by a match of `other`.
+    fn then(&self, other: &LengthBounds) -> LengthBounds {
+        LengthBounds {
+            min: self.min.saturating_add(other.min),
+            max: self
+                .max
+                .zip(other.max)
+                .and_then(|(left, right)| left.checked_add(right)),
+        }
+    }
+
+    /// Bounds of a match of either `self` or `other`.
+    fn or(&self, other: &LengthBounds) -> LengthBounds {
+        LengthBounds {
+            min: self.min.min(other.min),
+            max: self.max.zip(other.max).map(|(left, right)| left.max(right)),
+        }
+    }
+
+    fn repeat(&self, quantifier: &Quantifier) -> LengthBounds {
+        let (lower, upper) = quantifier.repetitions();
+        let times = |length: usize, count: u64| {
+            usize::try_from(count)
+                .ok()
+                .and_then(|count| length.checked_mul(count))
+        };
+        LengthBounds {
+            min: times(self.min, lower).unwrap_or(usize::MAX),
+            max: match (self.max, upper) {
+                (Some(0), _) => Some(0),
+                (Some(max), Some(upper)) => times(max, upper),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// How the groups of a pattern nest. Groups are numbered from 1 as in `Match`,
+/// and group 0, the whole match, is the root every outermost group hangs off.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GroupTree {
+    /// `parents[i]` encloses group `i`; `parents[0]` is unused.
+    parents: Vec<usize>,
+}
+
+impl GroupTree {
+    /// Reads the nesting off the parsed pattern.
+    pub fn new(root: &Node, group_count: usize) -> GroupTree {
+        fn visit(node: &Node, parent: usize, parents: &mut Vec<usize>) {
+            match node {
+                Node::Group(item, Some(index), _) => {
+                    parents[index + 1] = parent;
+                    visit(item, index + 1, parents);
+                }
+                Node::Group(item, None, _)
+                | Node::Match(item, _)
+                | Node::Lookahead(item)
+                | Node::Lookbehind(item, _) => visit(item, parent, parents),
+                Node::Expression(items, alternative) => {
+                    items.iter().for_each(|item| visit(item, parent, parents));
+                    if let Some(alternative) = alternative {
+                        visit(alternative, parent, parents);
+                    }
+                }
+                _ => {}
+            }
+        }
+        let mut parents = vec![0; group_count + 1];
+        visit(root, 0, &mut parents);
+        GroupTree { parents }
+    }
+
+    /// The number of groups, not counting the whole match.
+    pub fn len(&self) -> usize {
+        self.parents.len().saturating_sub(1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The group directly enclosing group `index`; `None` for group 0.
+    pub fn parent(&self, index: usize) -> Option<usize> {
+        (index != 0).then(|| self.parents[index])
+    }
+
+    /// The groups directly inside group `index`, in order.
+    pub fn children(&self, index: usize) -> Vec<usize> {
+        (1..self.parents.len())
+            .filter(|child| self.parents[*child] == index)
+            .collect()
+    }
+
+    /// How many groups enclose group `index`, so outermost groups have depth 1.
+    pub fn depth(&self, index: usize) -> usize {
+        let mut depth = 0;
+        let mut current = index;
+        while let Some(parent) = self.parent(current) {
+            depth += 1;
+            current = parent;
+        }
+        depth
+    }
 }
 
-impl Error for ParserError {}
+/// Where the parts of a pattern are in its source, in bytes, so that an editor
+/// can show which part of the pattern matched which part of a haystack.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    pattern: Range<usize>,
+    /// `groups[i]` spans group `i + 1`, from its `(` through its quantifier.
+    groups: Vec<Range<usize>>,
+    /// Every item of every expression: a char or class with its quantifier, a
+    /// group or an anchor. Sorted by start, with enclosing items first.
+    items: Vec<Range<usize>>,
+}
+
+impl SourceMap {
+    /// The span of group `index`, counting from 1 as in `Match`; group 0 spans
+    /// the whole pattern.
+    pub fn group(&self, index: usize) -> Option<Range<usize>> {
+        match index {
+            0 => Some(self.pattern.clone()),
+            index => self.groups.get(index - 1).cloned(),
+        }
+    }
+
+    pub fn items(&self) -> &[Range<usize>] {
+        &self.items
+    }
+
+    /// The innermost item covering the byte at `offset`.
+    pub fn item_at(&self, offset: usize) -> Option<Range<usize>> {
+        self.items
+            .iter()
+            .rev()
+            .find(|span| span.contains(&offset))
+            .cloned()
+    }
+
+    /// The innermost group covering the byte at `offset`, 0 if no group does, or
+    /// `None` past the end of the pattern.
+    pub fn group_at(&self, offset: usize) -> Option<usize> {
+        if !self.pattern.contains(&offset) {
+            return None;
+        }
+        // a group opens after every group enclosing it
+        Some(
+            (1..=self.groups.len())
+                .rev()
+                .find(|index| self.groups[index - 1].contains(&offset))
+                .unwrap_or(0),
+        )
+    }
+}
+
+impl ParsedRegex {
+    /// What the engines need to compile `root`, a tree built by hand rather than
+    /// parsed, or what makes it a tree the parser could never have produced. It
+    /// has no group names, since references to groups in a tree use indices.
+    pub fn from_root(root: Node, flags: RegexFlags) -> Result<ParsedRegex, String> {
+        #[derive(Default)]
+        struct Counts {
+            groups: usize,
+            script_runs: usize,
+            /// One past the highest group index referred to.
+            references: usize,
+        }
+
+        fn check(node: &Node, open: &mut Vec<usize>, counts: &mut Counts) -> Result<(), String> {
+            match node {
+                Node::Expression(items, alternative) => {
+                    if items.is_empty() {
+                        return Err(String::from("an expression without items"));
+                    }
+                    for item in items {
+                        check(item, open, counts)?;
+                    }
+                    if let Some(alternative) = alternative {
+                        check(alternative, open, counts)?;
+                    }
+                }
+                Node::Match(item, quantifier) | Node::Group(item, None, quantifier) => {
+                    check_quantifier(quantifier)?;
+                    check(item, open, counts)?;
+                }
+                Node::Group(item, Some(index), quantifier) => {
+                    check_quantifier(quantifier)?;
+                    if open.contains(index) {
+                        return Err(format!("group {} nested inside itself", index));
+                    }
+                    counts.groups = counts.groups.max(index + 1);
+                    open.push(*index);
+                    check(item, open, counts)?;
+                    open.pop();
+                }
+                Node::CharacterGroup(items, _) => {
+                    for item in items {
+                        check_class_item(item)?;
+                    }
+                }
+                Node::Backreference(index)
+                | Node::GroupCondition(index, _)
+                | Node::Subroutine(Some(index)) => {
+                    counts.references = counts.references.max(index + 1)
+                }
+                Node::ScriptRunEntry(index) | Node::ScriptRunExit(index) => {
+                    counts.script_runs = counts.script_runs.max(index + 1)
+                }
+                Node::Lookbehind(item, _) => {
+                    let bounds = item.length_bounds();
+                    if bounds.max != Some(bounds.min) {
+                        return Err(String::from("a lookbehind of variable width"));
+                    }
+                    check(item, open, counts)?;
+                }
+                Node::Lookahead(item) | Node::Atomic(item) | Node::Flagged(item, _) => {
+                    check(item, open, counts)?
+                }
+                Node::CharacterRange(_, _)
+                | Node::UnicodeProperty(_)
+                | Node::ClassOperation(_, _, _) => {
+                    return Err(format!("{:?} outside a character group", node))
+                }
+                Node::Epsilon | Node::GroupLink | Node::GroupEntry(_) | Node::GroupExit(_) => {
+                    return Err(format!("{:?}, which only engines create", node))
+                }
+                _ => {}
+            }
+            Ok(())
+        }
+
+        fn check_class_item(item: &Node) -> Result<(), String> {
+            match item {
+                Node::Character(_) | Node::UnicodeProperty(_) => Ok(()),
+                Node::CharacterRange(start, end) if start <= end => Ok(()),
+                Node::CharacterGroup(items, _) => {
+                    items.iter().try_for_each(|item| check_class_item(item))
+                }
+                Node::ClassOperation(left, _, right) => {
+                    check_class_item(left)?;
+                    check_class_item(right)
+                }
+                _ => Err(format!("{:?} inside a character group", item)),
+            }
+        }
+
+        fn check_quantifier(quantifier: &Quantifier) -> Result<(), String> {
+            match quantifier {
+                Quantifier::Range(lower, UpperBound::Bounded(upper), _) if upper < lower => {
+                    Err(format!("the range quantifier {}", quantifier))
+                }
+                _ => Ok(()),
+            }
+        }
+
+        let mut counts = Counts::default();
+        check(&root, &mut Vec::new(), &mut counts)?;
+        if counts.references > counts.groups {
+            return Err(format!(
+                "a reference to group index {}, past the last group",
+                counts.references - 1
+            ));
+        }
+        Ok(ParsedRegex {
+            group_count: counts.groups,
+            script_run_count: counts.script_runs,
+            group_names: HashMap::new(),
+            group_tree: GroupTree::new(&root, counts.groups),
+            length_bounds: root.length_bounds(),
+            required_literals: root.required_literals(flags),
+            source_map: SourceMap::default(),
+            flags,
+            root,
+        })
+    }
+
+    /// The mirror image of the pattern, as described in `Node::reversed`.
+    pub fn reversed(self, flags: RegexFlags) -> ParsedRegex {
+        ParsedRegex {
+            root: self.root.reversed(flags),
+            ..self
+        }
+    }
+}
 
-pub fn run_parse(input: &str, flags: &mut RegexFlags) -> Result<(Node, usize), ParserError> {
-    if input.is_empty() {
-        Ok((Node::EmptyString, 0))
+/// The chars matched by a pattern that is a single class, such as `(?i)[\w--\d]`,
+/// as `Node::class_ranges` gives them; `None` if the pattern is anything else.
+pub fn class_ranges(pattern: &str) -> Result<Option<Vec<(char, char)>>, ParserError> {
+    let mut flags = RegexFlags::OPTIMIZE;
+    let parsed = run_parse(pattern, &mut flags)?;
+    let class = match &parsed.root {
+        Node::Expression(items, None) if items.len() == 1 => match &*items[0] {
+            Node::Match(item, Quantifier::None) => item.class_ranges(flags),
+            _ => None,
+        },
+        _ => None,
+    };
+    Ok(class.map(Iterator::collect))
+}
+
+/// The tree of `pattern`, read under `flags`, without compiling it. Flags the
+/// pattern sets for itself, as a leading `(?i)` does, are kept in the tree as
+/// `Node::Flagged`, so that `flags` and the tree together say how it matches.
+pub fn parse(pattern: &str, flags: RegexFlags) -> Result<Node, ParserError> {
+    let mut own_flags = flags;
+    let parsed = run_parse(pattern, &mut own_flags)?;
+    if own_flags == flags {
+        Ok(parsed.root)
     } else {
+        Ok(parsed.root.with_flags(own_flags))
+    }
+}
+
+pub fn run_parse(input: &str, flags: &mut RegexFlags) -> Result<ParsedRegex, ParserError> {
+    run_parse_with_max_repetition(input, flags, DEFAULT_MAX_REPETITION)
+}
+
+/// Parses `input` like `run_parse`, failing with `RepetitionTooLarge` where a
+/// repetition, or repetitions nested in one another, would copy what they repeat
+/// more than `max_repetition` times.
+pub fn run_parse_with_max_repetition(
+    input: &str,
+    flags: &mut RegexFlags,
+    max_repetition: u64,
+) -> Result<ParsedRegex, ParserError> {
+    run_parse_with_limits(input, flags, max_repetition, DEFAULT_NEST_LIMIT)
+}
+
+/// Parses `input` like `run_parse_with_max_repetition`, also failing with
+/// `NestTooDeep` where groups and character groups are nested more than
+/// `nest_limit` deep. The parser recurses into each of them, so the limit keeps
+/// a pattern from overflowing the stack.
+pub fn run_parse_with_limits(
+    input: &str,
+    flags: &mut RegexFlags,
+    max_repetition: u64,
+    nest_limit: usize,
+) -> Result<ParsedRegex, ParserError> {
+    let new_parser = |later_names: Option<HashMap<String, usize>>| {
         let mut parser = Parser::new(input);
-        parse_inline_modifiers(&mut parser, flags)?;
+        parser.set_max_repetition(max_repetition);
+        parser.set_nest_limit(nest_limit);
+        if let Some(names) = later_names {
+            parser.set_later_names(names);
+        }
+        parser
+    };
+    let given_flags = *flags;
+    let mut parser = new_parser(None);
+    let mut root = parse_pattern(&mut parser, input, flags)?;
+    if parser.guessed_names() {
+        // now every group name is known, a reference to one named further on
+        // gets its index
+        let names = parser.take_group_names();
+        *flags = given_flags;
+        parser = new_parser(Some(names));
+        root = parse_pattern(&mut parser, input, flags)?;
+    }
+    Ok(ParsedRegex {
+        group_count: parser.group_count(),
+        script_run_count: parser.script_run_count(),
+        group_names: parser.take_group_names(),
+        group_tree: GroupTree::new(&root, parser.group_count()),
+        length_bounds: root.length_bounds(),
+        required_literals: root.required_literals(*flags),
+        source_map: parser.take_source_map(),
+        flags: *flags,
+        root,
+    })
+}
+
+/// The tree of the whole of `input`, failing where it refers to a group further
+/// on that it does not have.
+fn parse_pattern(
+    parser: &mut Parser,
+    input: &str,
+    flags: &mut RegexFlags,
+) -> Result<Node, ParserError> {
+    let root = if input.is_empty() {
+        Node::EmptyString
+    } else {
+        parse_inline_modifiers(parser, flags)?;
+        parser.skip_free_spacing();
         if let Ok(_) = parser.consume('^') {
             let anchor = Node::StartOfString;
             if parser.within_bounds() {
-                let mut expr = parse_expression(&mut parser)?;
-                if let Node::Expression(ref mut subexpressions, _) = expr {
-                    subexpressions.insert(0, Box::new(anchor));
-                    if parser.within_bounds() {
-                        Err(ParserError::SuffixRemaining(parser.get_remainder()))
-                    } else {
-                        Ok((expr, parser.group_count()))
+                match parse_expression(parser)? {
+                    Node::Expression(mut subexpressions, alternative) => {
+                        subexpressions.insert(0, Box::new(anchor));
+                        Node::Expression(subexpressions, alternative)
                     }
-                } else {
-                    panic!("expected an expression")
+                    other => Node::Expression(vec![Box::new(anchor), Box::new(other)], None),
                 }
             } else {
-                Ok((anchor, 0))
+                anchor
             }
         } else {
-            // assert the node returned is an expression
-            let expr = parse_expression(&mut parser)?;
-            if parser.within_bounds() {
-                Err(ParserError::SuffixRemaining(parser.get_remainder()))
+            parse_expression(parser)?
+        }
+    };
+    if parser.within_bounds() {
+        return Err(ParserError::SuffixRemaining(parser.rest_span()));
+    }
+    match parser.unresolved_reference() {
+        Some(error) => Err(error),
+        None => Ok(root),
+    }
+}
+
+/// `c` written so that the parser reads it back as that literal char, both in
+/// and out of character groups.
+pub(crate) fn escape_literal(c: char) -> String {
+    match CONTROL_ESCAPES.iter().find(|(_, control)| *control == c) {
+        Some((escape, _)) => format!("\\{}", escape),
+        None if needs_escape(c) => format!("\\{}", c),
+        None => c.to_string(),
+    }
+}
+
+/// `text` written as a pattern matching exactly `text`, which reads the same
+/// with or without the FREESPACING flag: whitespace and `#` are escaped too.
+/// Every char the parser gives a meaning outside a character group is escaped,
+/// so untrusted text can be spliced into a pattern, though not into a group.
+pub fn escape(text: &str) -> String {
+    text.chars()
+        .map(|c| {
+            let control = CONTROL_ESCAPES.iter().any(|(_, control)| *control == c);
+            if c == '#' || (c.is_whitespace() && !control) {
+                format!("\\{}", c)
             } else {
-                Ok((expr, parser.group_count()))
+                escape_literal(c)
             }
+        })
+        .collect()
+}
+
+/// The most times a repetition such as `{m,n}`, or several nested in one
+/// another, can copy what they repeat unless the parser is told otherwise. The
+/// automaton holds a copy for each, so this keeps patterns from blowing up.
+pub const DEFAULT_MAX_REPETITION: u64 = 65535;
+
+/// The most groups and character groups that can be nested in one another
+/// unless the parser is told otherwise.
+pub const DEFAULT_NEST_LIMIT: usize = 250;
+
+/// The letters of inline flags such as `(?i)`, with the flag each one sets.
+pub(crate) const INLINE_FLAGS: &[(char, RegexFlags)] = &[
+    ('i', RegexFlags::IGNORECASE),
+    ('m', RegexFlags::MULTILINE),
+    ('s', RegexFlags::DOTALL),
+    ('x', RegexFlags::FREESPACING),
+];
+
+const INLINE_FLAG_LETTERS: &[char] = &['i', 'm', 's', 'x'];
+/// What may follow `(?` in an inline flag group: letters, and a `-` before those
+/// to turn off.
+const INLINE_FLAG_SYNTAX: &[char] = &['i', 'm', 's', 'x', '-'];
+
+fn inline_flag(letter: char) -> RegexFlags {
+    INLINE_FLAGS
+        .iter()
+        .find(|(c, _)| *c == letter)
+        .map_or(RegexFlags::NO_FLAG, |(_, flag)| *flag)
+}
+
+/// Reads the letters of an inline flag group up to its `)` or `:`, turning on
+/// the flags named before a `-` and turning off those named after it.
+fn parse_flag_letters(
+    parser: &mut Parser,
+    mut flags: RegexFlags,
+) -> Result<RegexFlags, ParserError> {
+    let mut negated = false;
+    let mut previous = '?';
+    while !parser.matches(')') && !parser.matches(':') {
+        let c = parser.consume_unseen()?;
+        match c {
+            '-' if !negated => negated = true,
+            c if INLINE_FLAG_LETTERS.contains(&c) && negated => flags.remove(inline_flag(c)),
+            c if INLINE_FLAG_LETTERS.contains(&c) => flags |= inline_flag(c),
+            _ => return Err(ParserError::UnrecognizedModifier(c, parser.previous_span())),
         }
+        previous = c;
     }
+    if previous == '-' {
+        // a `-` must name at least one flag to turn off
+        return Err(ParserError::UnrecognizedModifier(
+            previous,
+            parser.previous_span(),
+        ));
+    }
+    Ok(flags)
 }
 
+/// Parses the `(?flags)` groups at the start of the pattern, which set the flags
+/// of the whole pattern.
 fn parse_inline_modifiers(
     parser: &mut Parser,
     flags: &mut RegexFlags,
 ) -> Result<bool, ParserError> {
-    const ALLOWED: &[char; 4] = &['i', 'm', 's', 'x'];
-    let mut modifiers: Vec<char> = Vec::new();
-    while parser.matches_several(&['(', '?']) {
+    while parser.can_parse_inline_modifiers(INLINE_FLAG_SYNTAX) {
         parser.advance_by(2);
-        while matches!(parser.peek(), Ok(c) if ALLOWED.contains(&c)) {
-            parser.advance_by(1);
-            modifiers.push(parser.peek().unwrap());
-        }
-    }
-    match parser.consume(')') {
-        Ok(_) => {
-            modifiers.iter().for_each(|c| match c {
-                'i' => *flags = *flags | RegexFlags::IGNORECASE,
-                's' => *flags = *flags | RegexFlags::DOTALL,
-                'm' => *flags = *flags | RegexFlags::MULTILINE,
-                'x' => *flags = *flags | RegexFlags::FREESPACING,
-                _ => panic!("unreachable code"),
-            });
-            Ok(true)
-        }
-        Err(err) => {
-            if modifiers.is_empty() {
-                Ok(true)
-            } else {
-                Err(err)
-            }
-        }
+        *flags = parse_flag_letters(parser, *flags)?;
+        parser.consume(')')?;
     }
+    parser.set_pattern_flags(*flags);
+    Ok(true)
+}
+
+/// Parses `(?flags)` past the start of the pattern, as in `a(?i)b|c`. The flags
+/// it sets hold until the end of the enclosing group, which `parse_expression`
+/// takes care of, so it leaves nothing to match.
+fn parse_flag_switch(parser: &mut Parser) -> Result<Node, ParserError> {
+    parser.advance_by(2);
+    let flags = parse_flag_letters(parser, parser.flags())?;
+    parser.consume(')')?;
+    parser.set_flags(flags);
+    Ok(Node::EmptyString)
+}
+
+/// Parses `(?flags:...)`, a non-capturing group inside which `flags` are turned
+/// on or off, as in `(?i:abc)def` or `(?-i:abc)def`.
+fn parse_flag_group(parser: &mut Parser) -> Result<Node, ParserError> {
+    parser.advance_by(2);
+    let outer = parser.flags();
+    let flags = parse_flag_letters(parser, outer)?;
+    parser.consume(':')?;
+    parser.set_flags(flags);
+    let expression = if parser.matches(')') {
+        Node::EmptyString
+    } else {
+        parse_expression(parser)?
+    };
+    parser.set_flags(outer);
+    parser.consume(')')?;
+    let quantifier = if parser.can_parse_quantifier() {
+        parse_quantifier(parser)?
+    } else {
+        Quantifier::None
+    };
+    Ok(Node::Group(Box::new(expression), None, quantifier))
 }
 
+/// Parses alternatives up to the end of the enclosing group, undoing any flags
+/// a `(?flags)` among them switched, as those hold only until that end.
 fn parse_expression(parser: &mut Parser) -> Result<Node, ParserError> {
+    let outer = parser.flags();
+    let expression = parse_alternatives(parser);
+    parser.set_flags(outer);
+    expression
+}
+
+fn parse_alternatives(parser: &mut Parser) -> Result<Node, ParserError> {
     let mut items: Vec<Box<Node>> = Vec::new();
+    parser.skip_free_spacing();
     while parser.can_parse_sub_expression_item() {
         items.push(Box::new(parse_sub_expression_item(parser)?));
+        parser.skip_free_spacing();
     }
     if items.is_empty() {
-        return Err(ParserError::InvalidExpression(parser.get_remainder()));
+        return Err(ParserError::InvalidExpression(parser.next_span()));
     }
     return if parser.matches('|') {
         parser.advance_by(1);
+        parser.skip_free_spacing();
         if parser.can_parse_sub_expression_item() {
             Ok(Node::Expression(
                 items,
-                Some(Box::new(parse_expression(parser)?)),
+                Some(Box::new(parse_alternatives(parser)?)),
             ))
         } else {
             Ok(Node::EmptyString)
@@ -594,15 +2831,16 @@ fn parse_expression(parser: &mut Parser) -> Result<Node, ParserError> {
 }
 
 fn parse_character_class(parser: &mut Parser) -> Result<Node, ParserError> {
+    let start = parser.position();
     parser.consume('\\')?;
     let c = parser.consume_unseen()?;
     return match c {
         'w' | 'W' => Ok(Node::CharacterGroup(
             vec![
                 Box::new(Node::CharacterRange('0', '9')),
-                Box::new(Node::CharacterRange('A', 'z')),
+                Box::new(Node::CharacterRange('A', 'Z')),
+                Box::new(Node::Character('_')),
                 Box::new(Node::CharacterRange('a', 'z')),
-                Box::new(Node::Character('-')),
             ],
             c == 'W',
         )),
@@ -610,6 +2848,29 @@ fn parse_character_class(parser: &mut Parser) -> Result<Node, ParserError> {
             vec![Box::new(Node::CharacterRange('0', '9'))],
             c == 'D',
         )),
+        'p' | 'P' => {
+            // `\pL` is short for `\p{L}`, as in Perl
+            let mut name = String::new();
+            if parser.consume('{').is_ok() {
+                while !parser.matches('}') {
+                    name.push(parser.consume_unseen()?);
+                }
+                parser.consume('}')?;
+            } else {
+                name.push(parser.consume_unseen()?);
+            }
+            match Property::lookup(&name) {
+                Some(property) => Ok(Node::CharacterGroup(
+                    vec![Box::new(Node::UnicodeProperty(property))],
+                    c == 'P',
+                )),
+                None => Err(ParserError::UnknownProperty(
+                    Box::new(name.clone()),
+                    Property::candidates(&name),
+                    start..parser.position(),
+                )),
+            }
+        }
         's' | 'S' => Ok(Node::CharacterGroup(
             vec![' ', '\t', '\n']
                 .iter()
@@ -617,46 +2878,196 @@ fn parse_character_class(parser: &mut Parser) -> Result<Node, ParserError> {
                 .collect_vec(),
             c == 'S',
         )),
+        'h' | 'H' => Ok(class_of_ranges(HORIZONTAL_WHITESPACE, c == 'H')),
+        'v' | 'V' => Ok(class_of_ranges(VERTICAL_WHITESPACE, c == 'V')),
         char_literal => Err(ParserError::UnrecognizedAnchor(
-            parser.get_remainder(),
             char_literal,
+            parser.previous_span(),
         )),
     };
 }
 
+/// The Unicode spaces and tab matched by `\h`, as in PCRE.
+const HORIZONTAL_WHITESPACE: &[(char, char)] = &[
+    ('\t', '\t'),
+    (' ', ' '),
+    ('\u{A0}', '\u{A0}'),
+    ('\u{1680}', '\u{1680}'),
+    ('\u{180E}', '\u{180E}'),
+    ('\u{2000}', '\u{200A}'),
+    ('\u{202F}', '\u{202F}'),
+    ('\u{205F}', '\u{205F}'),
+    ('\u{3000}', '\u{3000}'),
+];
+
+/// The line breaks matched by `\v`, as in PCRE, which makes `\v` a class
+/// rather than the vertical tab alone. `\R` matches them too, and `\r\n`.
+const VERTICAL_WHITESPACE: &[(char, char)] =
+    &[('\n', '\r'), ('\u{85}', '\u{85}'), ('\u{2028}', '\u{2029}')];
+
+fn class_of_ranges(ranges: &[(char, char)], negated: bool) -> Node {
+    let members = ranges
+        .iter()
+        .map(|&(start, end)| {
+            Box::new(if start == end {
+                Node::Character(start)
+            } else {
+                Node::CharacterRange(start, end)
+            })
+        })
+        .collect();
+    Node::CharacterGroup(members, negated)
+}
+
 fn parse_character_range(parser: &mut Parser) -> Result<Node, ParserError> {
+    let position = parser.position();
     let start = parser.consume_unseen()?;
     parser.consume('-')?;
     let end = parser.consume_unseen()?;
 
     if start > end {
-        Err(ParserError::InvalidCharacterRange(start, end))
+        Err(ParserError::InvalidCharacterRange(
+            start,
+            end,
+            position..parser.position(),
+        ))
     } else {
         Ok(Node::CharacterRange(start, end))
     }
 }
 
+/// The ASCII ranges each POSIX class names, as in the C locale.
+const POSIX_CLASSES: &[(&str, &[(char, char)])] = &[
+    ("alpha", &[('A', 'Z'), ('a', 'z')]),
+    ("digit", &[('0', '9')]),
+    ("alnum", &[('0', '9'), ('A', 'Z'), ('a', 'z')]),
+    ("upper", &[('A', 'Z')]),
+    ("lower", &[('a', 'z')]),
+    ("space", &[('\t', '\r'), (' ', ' ')]),
+    ("blank", &[('\t', '\t'), (' ', ' ')]),
+    ("punct", &[('!', '/'), (':', '@'), ('[', '`'), ('{', '~')]),
+    ("xdigit", &[('0', '9'), ('A', 'F'), ('a', 'f')]),
+    ("cntrl", &[('\0', '\x1f'), ('\x7f', '\x7f')]),
+    ("print", &[(' ', '~')]),
+    ("graph", &[('!', '~')]),
+    ("word", &[('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')]),
+];
+
+/// Parses a POSIX class such as `[:alpha:]` inside a character group, or the
+/// negated `[:^alpha:]` Perl allows.
+fn parse_posix_class(parser: &mut Parser) -> Result<Node, ParserError> {
+    let start = parser.position();
+    parser.consume('[')?;
+    parser.consume(':')?;
+    let negated = parser.consume('^').is_ok();
+    let mut name = String::new();
+    while !parser.matches(':') {
+        name.push(parser.consume_unseen()?);
+    }
+    parser.consume(':')?;
+    parser.consume(']')?;
+    match POSIX_CLASSES.iter().find(|(class, _)| *class == name) {
+        Some((_, ranges)) => Ok(class_of_ranges(ranges, negated)),
+        None => Err(ParserError::UnknownPosixClass(
+            Box::new(name),
+            start..parser.position(),
+        )),
+    }
+}
+
+/// Parses one item of a character group. A bracketed class nested in a group,
+/// as in `[a-z[0-9]]`, is one item adding its members to the group.
 fn parse_character_group_item(parser: &mut Parser) -> Result<Node, ParserError> {
-    if parser.can_parse_character_class() {
+    if parser.matches_several(&['[', ':']) {
+        parse_posix_class(parser)
+    } else if parser.matches('[') {
+        parse_character_group(parser)
+    } else if parser.can_parse_character_class() {
         parse_character_class(parser)
     } else {
         if parser.can_parse_character_range() {
             parse_character_range(parser)
         } else {
-            parse_character_in_character_group(parser)
+            parse_character_or_escaped_range(parser)
+        }
+    }
+}
+
+/// Parses a char in a group, or a range with an escape at either end such as
+/// `\u0391-\u03A9` or `a-\x7E`, which `can_parse_character_range` leaves out.
+fn parse_character_or_escaped_range(parser: &mut Parser) -> Result<Node, ParserError> {
+    let position = parser.position();
+    let escaped = parser.can_parse_escaped();
+    let start = parse_character_in_character_group(parser)?;
+    let is_range = parser.matches('-')
+        && ((escaped && parser.peek_nth(1).is_some_and(|c| c != ']'))
+            || parser.can_parse_escaped_at(1));
+    match start {
+        Node::Character(start) if is_range => {
+            parser.consume('-')?;
+            match parse_character_in_character_group(parser)? {
+                Node::Character(end) if start <= end => Ok(Node::CharacterRange(start, end)),
+                Node::Character(end) => Err(ParserError::InvalidCharacterRange(
+                    start,
+                    end,
+                    position..parser.position(),
+                )),
+                _ => Err(ParserError::UnableToParseChar(position..parser.position())),
+            }
         }
+        _ => Ok(start),
     }
 }
 
+/// Parses a character group, whose items may be combined by set operations
+/// such as `[a-z&&[^aeiou]]`, `[\w--\d]` or `[\w~~\d]`. These apply from left
+/// to right to everything before them, and `^` negates the final result.
 fn parse_character_group(parser: &mut Parser) -> Result<Node, ParserError> {
+    parser.enter()?;
+    let group = parse_nested_character_group(parser);
+    parser.leave();
+    group
+}
+
+fn parse_nested_character_group(parser: &mut Parser) -> Result<Node, ParserError> {
+    let start = parser.position();
     parser.consume('[')?;
     let mut negated = false;
     if parser.matches('^') {
         negated = true;
         parser.advance_by(1);
     }
+    let mut group = parse_character_group_items(parser)?;
+    while let Some(operator) = class_operator_ahead(parser) {
+        parser.advance_by(2);
+        let right = parse_character_group_items(parser)?;
+        if right == Node::CharacterGroup(Vec::new(), false) {
+            return Err(ParserError::CantParseCharGroup(start..parser.position()));
+        }
+        group = Node::CharacterGroup(
+            vec![Box::new(Node::ClassOperation(
+                Box::new(group),
+                operator,
+                Box::new(right),
+            ))],
+            false,
+        );
+    }
+    parser.consume(']')?;
+    match group {
+        Node::CharacterGroup(items, _) if !items.is_empty() => {
+            Ok(Node::CharacterGroup(items, negated))
+        }
+        _ => Err(ParserError::CantParseCharGroup(start..parser.position())),
+    }
+}
+
+/// Parses the items of a character group up to its `]` or a set operation,
+/// into a group that is not negated.
+fn parse_character_group_items(parser: &mut Parser) -> Result<Node, ParserError> {
     let mut items: Vec<Box<Node>> = Vec::new();
-    loop {
+    // a set operation needs something on its left, so a leading `&&` is literal
+    while items.is_empty() || class_operator_ahead(parser).is_none() {
         match parse_character_group_item(parser) {
             Ok(node) => {
                 items.push(Box::new(node));
@@ -670,20 +3081,62 @@ fn parse_character_group(parser: &mut Parser) -> Result<Node, ParserError> {
             }
         }
     }
-    parser.consume(']')?;
-    return if items.is_empty() {
-        Err(ParserError::CantParseCharGroup(
-            parser.get_consumed(),
-            parser.get_remainder(),
-        ))
-    } else {
-        Ok(Node::CharacterGroup(items, negated))
-    };
+    Ok(Node::CharacterGroup(items, false))
+}
+
+/// The set operation next in a character group, if any. One right before the
+/// closing `]` is taken literally.
+fn class_operator_ahead(parser: &mut Parser) -> Option<ClassOperator> {
+    let (operator, _) = ClassOperator::ALL
+        .into_iter()
+        .find(|(_, c)| parser.peek_nth(0) == Some(*c) && parser.peek_nth(1) == Some(*c))?;
+    parser
+        .peek_nth(2)
+        .is_some_and(|c| c != ']')
+        .then_some(operator)
 }
 
 fn parse_escaped<'a>(parser: &mut Parser) -> Result<Node, ParserError> {
+    let position = parser.position();
     parser.consume('\\')?;
-    Ok(Node::Character(parser.consume_unseen()?))
+    if parser.consume('x').is_ok() {
+        return parse_hex_escape(parser, position, 2).map(Node::Character);
+    }
+    if parser.consume('u').is_ok() {
+        return parse_hex_escape(parser, position, 4).map(Node::Character);
+    }
+    let c = parser.consume_unseen()?;
+    Ok(Node::Character(
+        CONTROL_ESCAPES
+            .iter()
+            .find(|(escape, _)| *escape == c)
+            .map_or(c, |(_, control)| *control),
+    ))
+}
+
+/// Decodes what follows `\x` or `\u`: either exactly `width` hex digits, as in
+/// `\x41` or `\u00E9`, or one to six of them in braces, as in `\u{1F600}`.
+fn parse_hex_escape(
+    parser: &mut Parser,
+    position: usize,
+    width: usize,
+) -> Result<char, ParserError> {
+    let braced = parser.consume('{').is_ok();
+    let mut digits = String::new();
+    while (braced || digits.len() < width) && parser.peek().is_ok_and(|c| c.is_ascii_hexdigit()) {
+        digits.push(parser.consume_unseen()?);
+    }
+    let well_formed = if braced {
+        parser.consume('}').is_ok() && (1..=6).contains(&digits.len())
+    } else {
+        digits.len() == width
+    };
+    if !well_formed {
+        return Err(ParserError::MalformedEscape(position..parser.position()));
+    }
+    let value = u32::from_str_radix(&digits, 16).unwrap();
+    char::from_u32(value)
+        .ok_or_else(|| ParserError::InvalidCodePoint(value, position..parser.position()))
 }
 
 fn parse_character(parser: &mut Parser) -> Result<Node, ParserError> {
@@ -691,7 +3144,7 @@ fn parse_character(parser: &mut Parser) -> Result<Node, ParserError> {
         parse_escaped(parser)
     } else {
         if !parser.can_parse_character() {
-            Err(ParserError::UnableToParseChar(parser.get_remainder()))
+            Err(ParserError::UnableToParseChar(parser.next_span()))
         } else {
             Ok(Node::Character(parser.consume_unseen()?))
         }
@@ -703,15 +3156,59 @@ fn parse_character_in_character_group(parser: &mut Parser) -> Result<Node, Parse
         parse_escaped(parser)
     } else {
         if parser.matches(']') {
-            Err(ParserError::UnableToParseChar(parser.get_remainder()))
+            Err(ParserError::UnableToParseChar(parser.next_span()))
         } else {
             Ok(Node::Character(parser.consume_unseen()?))
         }
     }
 }
 
+/// Parses `\1` to `\99` or `\k<name>`, which must refer to a group opened
+/// earlier in the pattern. As in Python, a second digit always belongs to the
+/// reference, so `\10` is group 10 and never group 1 followed by `0`.
+fn parse_backreference(parser: &mut Parser) -> Result<Node, ParserError> {
+    let start = parser.position();
+    parser.consume('\\')?;
+    if parser.matches('k') {
+        return parse_named_backreference(parser, start);
+    }
+    let mut digits = String::from(parser.consume_unseen()?);
+    if let Some(digit) = parser.peek_nth(0).filter(char::is_ascii_digit) {
+        digits.push(digit);
+        parser.advance_by(1);
+    }
+    match digits.parse::<usize>() {
+        Ok(index) if index <= parser.group_count() => Ok(Node::Backreference(index - 1)),
+        _ => Err(ParserError::UnknownGroupReference(
+            Box::new(digits),
+            start..parser.position(),
+        )),
+    }
+}
+
+/// Parses the `k<name>` of a backreference whose `\\` began at byte `start`.
+fn parse_named_backreference(parser: &mut Parser, start: usize) -> Result<Node, ParserError> {
+    parser.advance_by(2);
+    let mut name = String::new();
+    loop {
+        match parser.consume_unseen()? {
+            '>' => break,
+            c => name.push(c),
+        }
+    }
+    match parser.group_index(&name) {
+        Some(index) => Ok(Node::Backreference(index - 1)),
+        None => Err(ParserError::UnknownGroupReference(
+            Box::new(name),
+            start..parser.position(),
+        )),
+    }
+}
+
 fn parse_match_item<'a>(parser: &mut Parser) -> Result<Node, ParserError> {
-    if parser.matches('.') {
+    if parser.can_parse_backreference() {
+        parse_backreference(parser)
+    } else if parser.matches('.') {
         parser.consume('.')?;
         Ok(Node::Dot)
     } else if parser.can_parse_character_class() {
@@ -728,21 +3225,27 @@ fn parse_match_item<'a>(parser: &mut Parser) -> Result<Node, ParserError> {
 fn validate_range_quantifier(
     lower: u64,
     upper: UpperBound,
-    lazy: bool,
+    greediness: Greediness,
+    span: Range<usize>,
 ) -> Result<Quantifier, ParserError> {
     match upper {
         UpperBound::Bounded(upper_digit) => {
             if upper_digit < lower {
-                Err(ParserError::InvalidRangeQuantifier(lower, upper_digit))
+                Err(ParserError::InvalidRangeQuantifier(
+                    lower,
+                    upper_digit,
+                    span,
+                ))
             } else {
-                Ok(Quantifier::Range(lower, upper, lazy))
+                Ok(Quantifier::Range(lower, upper, greediness))
             }
         }
-        _ => Ok(Quantifier::Range(lower, upper, lazy)),
+        _ => Ok(Quantifier::Range(lower, upper, greediness)),
     }
 }
 
 fn parse_int(parser: &mut Parser) -> Result<u64, ParserError> {
+    let start = parser.position();
     let mut digits: Vec<char> = Vec::new();
     loop {
         if let Ok(digit) = parser.peek() {
@@ -757,11 +3260,15 @@ fn parse_int(parser: &mut Parser) -> Result<u64, ParserError> {
     let number_stream: String = digits.iter().collect();
     match format!("{}", number_stream).parse::<u64>() {
         Ok(num) => Ok(num),
-        Err(parse_int_error) => return Err(ParserError::CantParseRangeBound(parse_int_error)),
+        Err(parse_int_error) => Err(ParserError::CantParseRangeBound(
+            parse_int_error,
+            start..parser.position(),
+        )),
     }
 }
 
 fn parse_range_quantifier(parser: &mut Parser) -> Result<Quantifier, ParserError> {
+    let position = parser.position();
     parser.consume('{')?;
     let mut lower: u64 = 0;
     if !parser.matches(',') {
@@ -776,48 +3283,347 @@ fn parse_range_quantifier(parser: &mut Parser) -> Result<Quantifier, ParserError
             }
         }
     }
-    parser.consume('}')?;
-    let mut lazy = false;
-    if parser.matches('?') {
-        parser.advance_by(1);
-        lazy = true;
+    parser.consume('}')?;
+    let span = position..parser.position();
+    let count = match upper {
+        UpperBound::Bounded(upper) => upper.max(lower),
+        _ => lower,
+    };
+    if count > parser.max_repetition() {
+        return Err(ParserError::RepetitionTooLarge(count, span));
+    }
+    let greediness = parse_greediness(parser);
+    validate_range_quantifier(lower, upper, greediness, span)
+}
+
+/// Parses the `?` of a lazy or the `+` of a possessive quantifier, if present.
+fn parse_greediness(parser: &mut Parser) -> Greediness {
+    if parser.consume('?').is_ok() {
+        Greediness::Lazy
+    } else if parser.consume('+').is_ok() {
+        Greediness::Possessive
+    } else {
+        Greediness::Greedy
+    }
+}
+
+fn parse_quantifier(parser: &mut Parser) -> Result<Quantifier, ParserError> {
+    if parser.matches('{') {
+        parse_range_quantifier(parser)
+    } else {
+        let char_literal = parser.consume_unseen()?;
+        let quantifier: fn(Greediness) -> Quantifier = match char_literal {
+            '*' => Quantifier::ZeroOrMore,
+            '+' => Quantifier::OneOrMore,
+            '?' => Quantifier::ZeroOrOne,
+            _ => {
+                return Err(ParserError::UnrecognizedQuantifier(
+                    char_literal,
+                    parser.previous_span(),
+                ))
+            }
+        };
+        Ok(quantifier(parse_greediness(parser)))
+    }
+}
+
+const SCRIPT_RUN: &[char] = &['?', 's', 'c', 'r', 'i', 'p', 't', '_', 'r', 'u', 'n', ':'];
+
+/// Parses `(?script_run:...)` into a non-capturing group whose body is bracketed
+/// by the markers checking that it is a single-script run.
+fn parse_script_run(parser: &mut Parser) -> Result<Node, ParserError> {
+    parser.consume('(')?;
+    parser.advance_by(SCRIPT_RUN.len());
+    let index = parser.script_run_count();
+    parser.increment_script_run_count();
+    let expression = parse_expression(parser)?;
+    parser.consume(')')?;
+
+    let quantifier = if parser.can_parse_quantifier() {
+        parse_quantifier(parser)?
+    } else {
+        Quantifier::None
+    };
+    let run = Node::Expression(
+        vec![
+            Box::new(Node::ScriptRunEntry(index)),
+            Box::new(Node::Group(Box::new(expression), None, Quantifier::None)),
+            Box::new(Node::ScriptRunExit(index)),
+        ],
+        None,
+    );
+    Ok(Node::Group(Box::new(run), None, quantifier))
+}
+
+/// Parses the `?P<name>` or `?<name>` after the `(` of a named group, if present,
+/// into the name and its bytes in the pattern. Names follow Python: a letter or
+/// underscore, then letters, digits or underscores.
+fn parse_group_name(parser: &mut Parser) -> Result<Option<(String, Range<usize>)>, ParserError> {
+    let prefix = if parser.matches_several(&['?', 'P', '<']) {
+        3
+    } else if parser.peek_nth(0) == Some('?')
+        && parser.peek_nth(1) == Some('<')
+        && parser.peek_nth(2).is_some_and(|c| c != '=' && c != '!')
+    {
+        2
+    } else {
+        return Ok(None);
+    };
+    parser.advance_by(prefix);
+    let start = parser.position();
+    let mut name = String::new();
+    loop {
+        match parser.consume_unseen()? {
+            '>' => break,
+            c => name.push(c),
+        }
+    }
+    let span = start..start + name.len();
+    let mut chars = name.chars();
+    let valid = chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_');
+    if valid {
+        Ok(Some((name, span)))
+    } else {
+        Err(ParserError::InvalidGroupName(Box::new(name), span))
+    }
+}
+
+fn parse_lookahead(parser: &mut Parser) -> Result<Node, ParserError> {
+    parser.consume('(')?;
+    parser.advance_by(2);
+    let expression = if parser.matches(')') {
+        Node::EmptyString
+    } else {
+        parse_expression(parser)?
+    };
+    parser.consume(')')?;
+    Ok(Node::Lookahead(Box::new(expression)))
+}
+
+/// Parses `(?(1)yes|no)`, `(?(<name>)yes|no)` or `(?('name')yes|no)`, which
+/// matches `yes` if the group has captured something by then and `no`, or the
+/// empty string if there is no `|`, otherwise. Unlike a backreference, the
+/// condition may come before the group, which then may not have captured yet.
+fn parse_conditional(parser: &mut Parser) -> Result<Node, ParserError> {
+    let start = parser.position();
+    parser.advance_by(3);
+    let mut reference = String::new();
+    loop {
+        match parser.consume_unseen()? {
+            ')' => break,
+            c => reference.push(c),
+        }
+    }
+    let name = reference
+        .strip_prefix('<')
+        .and_then(|name| name.strip_suffix('>'))
+        .or_else(|| {
+            reference
+                .strip_prefix('\'')
+                .and_then(|name| name.strip_suffix('\''))
+        });
+    let span = start..parser.position();
+    let Some(index) = parser.forward_group_index(&reference, name, span) else {
+        return Err(ParserError::UnknownGroupReference(
+            Box::new(reference),
+            start..parser.position(),
+        ));
+    };
+
+    let outer = parser.flags();
+    let mut branches =
+        [true, false].map(|captured| vec![Box::new(Node::GroupCondition(index - 1, captured))]);
+    for (number, items) in branches.iter_mut().enumerate() {
+        if number > 0 {
+            if !parser.matches('|') {
+                break;
+            }
+            parser.advance_by(1);
+        }
+        parser.skip_free_spacing();
+        while parser.can_parse_sub_expression_item() {
+            items.push(Box::new(parse_sub_expression_item(parser)?));
+            parser.skip_free_spacing();
+        }
+    }
+    parser.set_flags(outer);
+    if parser.matches('|') {
+        // the span runs up to the `|` that starts a third branch
+        return Err(ParserError::TooManyConditionalBranches(
+            start..parser.position() + 1,
+        ));
     }
+    parser.consume(')')?;
 
-    return validate_range_quantifier(lower, upper, lazy);
+    let quantifier = if parser.can_parse_quantifier() {
+        parse_quantifier(parser)?
+    } else {
+        Quantifier::None
+    };
+    let [yes, no] = branches;
+    let branches = Node::Expression(yes, Some(Box::new(Node::Expression(no, None))));
+    Ok(Node::Group(Box::new(branches), None, quantifier))
 }
 
-fn parse_quantifier(parser: &mut Parser) -> Result<Quantifier, ParserError> {
-    if parser.matches('{') {
-        parse_range_quantifier(parser)
+/// Parses a call to a group, `(?1)` by number or `(?&name)` by name, or to the
+/// whole pattern, `(?R)` or `(?0)`. Unlike a backreference, a call may come
+/// before the group it calls, as well as inside it.
+fn parse_subroutine_call(parser: &mut Parser) -> Result<Node, ParserError> {
+    let start = parser.position();
+    parser.advance_by(2);
+    let mut reference = String::new();
+    loop {
+        match parser.consume_unseen()? {
+            ')' => break,
+            c => reference.push(c),
+        }
+    }
+    let span = start..parser.position();
+    let target = match reference.strip_prefix('&') {
+        Some(name) => parser
+            .forward_group_index(&reference, Some(name), span)
+            .map(Some),
+        None if reference == "R" || reference == "0" => Some(None),
+        None => parser.forward_group_index(&reference, None, span).map(Some),
+    };
+    let Some(target) = target else {
+        return Err(ParserError::UnknownGroupReference(
+            Box::new(reference),
+            start..parser.position(),
+        ));
+    };
+
+    let quantifier = if parser.can_parse_quantifier() {
+        parse_quantifier(parser)?
     } else {
-        let char_literal = parser.consume_unseen()?;
-        match char_literal {
-            '*' | '+' | '?' => {
-                let lazy = if let Ok(_) = parser.consume('?') {
-                    true
-                } else {
-                    false
-                };
-                match char_literal {
-                    '*' => Ok(Quantifier::ZeroOrMore(lazy)),
-                    '+' => Ok(Quantifier::OneOrMore(lazy)),
-                    '?' => Ok(Quantifier::ZeroOrOne(lazy)),
-                    _ => panic!("unrecognized quantifier {:?}", char_literal),
-                }
+        Quantifier::None
+    };
+    Ok(Node::Match(
+        Box::new(Node::Subroutine(target.map(|index| index - 1))),
+        quantifier,
+    ))
+}
+
+/// Skips `(?#...)`, which runs to the first `)` as in Python, leaving a node
+/// that matches the empty string in its place.
+fn parse_comment(parser: &mut Parser) -> Result<Node, ParserError> {
+    parser.advance_by(3);
+    while parser.consume_unseen()? != ')' {}
+    Ok(Node::EmptyString)
+}
+
+/// Parses `(?C)` or `(?Cn)` with `n` from 0 to 255, as in PCRE. A bare `(?C)`
+/// is callout 0.
+fn parse_callout(parser: &mut Parser) -> Result<Node, ParserError> {
+    let start = parser.position();
+    parser.consume('(')?;
+    parser.consume('?')?;
+    parser.consume('C')?;
+    let mut digits = String::new();
+    while parser.peek().is_ok_and(|c| c.is_ascii_digit()) {
+        digits.push(parser.consume_unseen()?);
+    }
+    let number = if digits.is_empty() {
+        Some(0)
+    } else {
+        digits.parse::<u8>().ok()
+    };
+    match (number, parser.consume(')')) {
+        (Some(number), Ok(_)) => Ok(Node::Callout(number)),
+        _ => Err(ParserError::InvalidCallout(start..parser.position())),
+    }
+}
+
+/// Parses `(?<=...)` or `(?<!...)`. Checking a lookbehind starts that many chars
+/// back, so its pattern has to have a fixed width, as in Python.
+fn parse_lookbehind(parser: &mut Parser) -> Result<Node, ParserError> {
+    fn has_backreference(node: &Node) -> bool {
+        match node {
+            Node::Backreference(_) => true,
+            Node::Match(item, _)
+            | Node::Group(item, _, _)
+            | Node::Lookahead(item)
+            | Node::Lookbehind(item, _) => has_backreference(item),
+            Node::Expression(items, alternative) => {
+                items.iter().any(|item| has_backreference(item))
+                    || alternative.as_deref().is_some_and(has_backreference)
             }
-            _ => Err(ParserError::UnrecognizedQuantifier(char_literal)),
+            _ => false,
         }
     }
+
+    let start = parser.position();
+    parser.consume('(')?;
+    parser.consume('?')?;
+    parser.consume('<')?;
+    let negated = parser.consume('!').is_ok();
+    if !negated {
+        parser.consume('=')?;
+    }
+    let expression = if parser.matches(')') {
+        Node::EmptyString
+    } else {
+        parse_expression(parser)?
+    };
+    parser.consume(')')?;
+    let span = start..parser.position();
+    if has_backreference(&expression) {
+        return Err(ParserError::BackreferenceInLookbehind(span));
+    }
+    let bounds = expression.length_bounds();
+    if bounds.max != Some(bounds.min) {
+        return Err(ParserError::VariableWidthLookbehind(span));
+    }
+    Ok(Node::Lookbehind(Box::new(expression), negated))
 }
 
 fn parse_group<'a>(parser: &mut Parser) -> Result<Node, ParserError> {
+    if parser.matches_several(&[&['('], SCRIPT_RUN].concat()) {
+        return parse_script_run(parser);
+    }
+    if parser.peek_nth(1) == Some('?') && parser.peek_nth(2) == Some('=') {
+        return parse_lookahead(parser);
+    }
+    if parser.matches_several(&['(', '?', '<', '='])
+        || parser.matches_several(&['(', '?', '<', '!'])
+    {
+        return parse_lookbehind(parser);
+    }
+    if parser.matches_several(&['(', '?', 'C']) {
+        return parse_callout(parser);
+    }
+    if parser.matches_several(&['(', '?', '(']) {
+        return parse_conditional(parser);
+    }
+    if parser.peek_nth(1) == Some('?')
+        && parser
+            .peek_nth(2)
+            .is_some_and(|c| c == 'R' || c == '&' || c.is_ascii_digit())
+    {
+        return parse_subroutine_call(parser);
+    }
+    if parser.matches_several(&['(', '?', '#']) {
+        return parse_comment(parser);
+    }
+    if parser.can_parse_flag_group(INLINE_FLAG_SYNTAX) {
+        return parse_flag_group(parser);
+    }
+    if parser.can_parse_inline_modifiers(INLINE_FLAG_SYNTAX) {
+        return parse_flag_switch(parser);
+    }
+    let start = parser.position();
     parser.consume('(')?;
 
     let group_index = if parser.matches_several(&['?', ':']) {
         parser.advance_by(2);
         None
     } else {
+        let name = parse_group_name(parser)?;
         parser.increment_group_count();
+        if let Some((name, span)) = name {
+            parser.add_group_name(name, parser.group_count(), span)?;
+        }
         Some(parser.group_count() - 1)
     };
     let expression = if parser.matches('?') {
@@ -832,6 +3638,9 @@ fn parse_group<'a>(parser: &mut Parser) -> Result<Node, ParserError> {
     } else {
         Quantifier::None
     };
+    if let Some(index) = group_index {
+        parser.add_group_span(index + 1, start..parser.position());
+    }
     Ok(Node::Group(Box::new(expression), group_index, quantifier))
 }
 
@@ -840,14 +3649,14 @@ fn parse_anchor<'a>(parser: &mut Parser) -> Result<Node, ParserError> {
         Ok(_) => {
             let char_literal = parser.consume_unseen()?;
             return match char_literal {
-                'a' => Ok(Node::StartOfStringOnly),
+                'A' => Ok(Node::StartOfStringOnly),
                 'b' => Ok(Node::WordBoundary),
                 'B' => Ok(Node::NonWordBoundary),
                 'z' => Ok(Node::EndOfStringOnlyNotNewline),
                 'Z' => Ok(Node::EndOfStringOnlyMaybeNewLine),
                 _ => Err(ParserError::UnrecognizedAnchor(
-                    parser.get_remainder(),
                     char_literal,
+                    parser.previous_span(),
                 )),
             };
         }
@@ -875,19 +3684,92 @@ fn parse_match(parser: &mut Parser) -> Result<Node, ParserError> {
     return Ok(Node::Match(Box::new(match_item), quantifier));
 }
 
-fn parse_sub_expression_item<'a>(parser: &mut Parser) -> Result<Node, ParserError> {
+/// Parses the next item and records where it is in the pattern, failing if the
+/// repetitions in it together copy something more often than the parser allows.
+fn parse_sub_expression_item(parser: &mut Parser) -> Result<Node, ParserError> {
+    let start = parser.position();
+    let item = parse_item(parser)?;
+    let span = start..parser.position();
+    // the items inside were checked as they were parsed, so only a repetition of
+    // this item can take the count past the limit
+    if let Node::Match(_, Quantifier::Range(..)) | Node::Group(_, _, Quantifier::Range(..)) = item {
+        let count = item.repetition_count();
+        if count > parser.max_repetition() {
+            return Err(ParserError::RepetitionTooLarge(count, span));
+        }
+    }
+    parser.add_item_span(span);
+    Ok(item)
+}
+
+/// Parses the next item, setting it to follow the flags in effect if inline flags
+/// made them differ from the pattern's. A group's items have already been set
+/// as they were parsed.
+fn parse_item(parser: &mut Parser) -> Result<Node, ParserError> {
     if parser.can_parse_group() {
-        parse_group(parser)
-    } else if parser.can_parse_anchor() {
-        parse_anchor(parser)
-    } else {
-        parse_match(parser)
+        parser.enter()?;
+        let group = parse_group(parser);
+        parser.leave();
+        return group;
+    }
+    if parser.can_parse_line_break() {
+        return parse_line_break(parser);
     }
+    let item = if parser.can_parse_anchor() {
+        parse_anchor(parser)?
+    } else {
+        parse_match(parser)?
+    };
+    Ok(if parser.flags() != parser.pattern_flags() {
+        item.with_flags(parser.flags())
+    } else {
+        item
+    })
+}
+
+/// Parses `\R` as the group `(?:\r\n|...)` with a one-char line break after
+/// the `|`. Only one side can match at any offset, so `\R` never matches the
+/// `\r` of a `\r\n` alone, even where the rest of the pattern would need it to.
+fn parse_line_break(parser: &mut Parser) -> Result<Node, ParserError> {
+    parser.consume('\\')?;
+    parser.consume('R')?;
+    let quantifier = if parser.can_parse_quantifier() {
+        parse_quantifier(parser)?
+    } else {
+        Quantifier::None
+    };
+    let matching = |node: Node| Box::new(Node::Match(Box::new(node), Quantifier::None));
+    Ok(Node::Group(
+        Box::new(Node::Expression(
+            vec![
+                matching(Node::Character('\r')),
+                matching(Node::Character('\n')),
+            ],
+            Some(Box::new(Node::Expression(
+                vec![matching(Node::LineBreak(false))],
+                None,
+            ))),
+        )),
+        None,
+        quantifier,
+    ))
 }
 
 pub mod visitor {
     use super::Node;
 
+    /// Follows `Node::visit` through a parsed pattern, for tools that analyze
+    /// patterns without matching on every kind of node to find the ones inside.
+    pub trait NodeVisitor {
+        /// Called on each node before the nodes inside it, which are skipped if
+        /// this returns `false`.
+        fn enter(&mut self, _node: &Node) -> bool {
+            true
+        }
+        /// Called on each node after the nodes inside it.
+        fn leave(&mut self, _node: &Node) {}
+    }
+
     pub trait Visitor {
         type Result;
         fn visit_expression(&mut self, expression: Node) -> Self::Result;
@@ -904,6 +3786,40 @@ pub mod visitor {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_class_ranges() {
+        let ranges = |pattern: &str| class_ranges(pattern).unwrap();
+        // `ſ` and the Kelvin sign fold to `s` and `k`
+        assert_eq!(
+            ranges("(?i)[\\w--\\d]"),
+            Some(vec![
+                ('A', 'Z'),
+                ('_', '_'),
+                ('a', 'z'),
+                ('ſ', 'ſ'),
+                ('\u{212A}', '\u{212A}')
+            ])
+        );
+        assert_eq!(ranges("[a-cb-f[x]]"), Some(vec![('a', 'f'), ('x', 'x')]));
+        assert_eq!(ranges("[^\\x00-\\x7f]"), Some(vec![('\u{80}', char::MAX)]));
+        assert_eq!(
+            ranges("."),
+            Some(vec![('\0', '\u{9}'), ('\u{B}', char::MAX)])
+        );
+        assert_eq!(
+            ranges("(?i)k"),
+            Some(vec![('K', 'K'), ('k', 'k'), ('\u{212A}', '\u{212A}')])
+        );
+        assert_eq!(ranges("(?i)é"), Some(vec![('É', 'É'), ('é', 'é')]));
+        assert_eq!(ranges("(?i)[^é]"), ranges("[^Éé]"));
+        let upper = ranges("(?i)\\p{Lu}").unwrap();
+        assert!(upper.iter().any(|&(start, end)| start <= 'a' && 'a' <= end));
+        assert!(upper.iter().any(|&(start, end)| start <= 'ω' && 'ω' <= end));
+        assert_eq!(ranges("ab"), None);
+        assert_eq!(ranges("[a-z]+"), None);
+        assert!(class_ranges("[a").is_err());
+    }
+
     #[test]
     fn parser_new() {
         let mut p = Parser::new("abc");
@@ -911,7 +3827,7 @@ mod tests {
         assert_eq!(p.consume_unseen(), Ok('a'));
         assert_eq!(p.consume_unseen(), Ok('b'));
         assert_eq!(p.consume_unseen(), Ok('c'));
-        assert_eq!(p.consume_unseen(), Err(ParserError::UnexpectedEOF));
+        assert_eq!(p.consume_unseen(), Err(ParserError::UnexpectedEOF(3..3)));
     }
 
     #[test]
@@ -997,4 +3913,504 @@ mod tests {
         let parsing_result = run_parse(&pattern, &mut flags);
         assert!(parsing_result.is_ok());
     }
+
+    #[test]
+    fn parse_unicode_property() {
+        let mut flags = RegexFlags::OPTIMIZE;
+        assert!(run_parse(r"\p{Letter}\P{ lowercase-letter }[\p{Nd}_]", &mut flags).is_ok());
+
+        let error = run_parse(r"\p{Lettr}", &mut flags).unwrap_err();
+        assert_eq!(
+            error,
+            ParserError::UnknownProperty(
+                Box::new(String::from("Lettr")),
+                vec![String::from("Letter")],
+                0..9
+            )
+        );
+        assert_eq!(
+            format!("{}", error),
+            "unknown unicode property `Lettr`, did you mean Letter?"
+        );
+        assert_eq!(error.suggestion(), Some(String::from(r"use \p{Letter}")));
+    }
+
+    #[test]
+    fn parse_named_groups() {
+        let mut flags = RegexFlags::OPTIMIZE;
+        let parsed = run_parse("(?P<year>[0-9]+)/?(x)(?<month_2>[0-9]+)", &mut flags).unwrap();
+        assert_eq!(parsed.group_count, 3);
+        assert_eq!(
+            parsed.group_names,
+            HashMap::from([(String::from("year"), 1), (String::from("month_2"), 3)])
+        );
+
+        assert_eq!(
+            run_parse("(?P<a>x)(?P<a>y)", &mut flags).unwrap_err(),
+            ParserError::DuplicateGroupName(Box::new(String::from("a")), 12..13)
+        );
+        for name in ["", "1st", "a b"] {
+            assert_eq!(
+                run_parse(&format!("(?P<{}>x)", name), &mut flags).unwrap_err(),
+                ParserError::InvalidGroupName(Box::new(String::from(name)), 4..4 + name.len())
+            );
+        }
+    }
+
+    #[test]
+    fn parse_named_backreferences() {
+        let mut flags = RegexFlags::OPTIMIZE;
+        let parsed = run_parse(r#"(?<q>["'])x\k<q>+"#, &mut flags).unwrap();
+        assert!(format!("{:?}", parsed.root).contains("Backreference(0)"));
+        assert_eq!(
+            run_parse(r"\k<q>(?<q>a)", &mut flags).unwrap_err(),
+            ParserError::UnknownGroupReference(Box::new(String::from("q")), 0..5)
+        );
+    }
+
+    #[test]
+    fn parse_numeric_backreferences() {
+        let mut flags = RegexFlags::OPTIMIZE;
+        let parsed = run_parse(r"(a)(b)\2\1", &mut flags).unwrap();
+        assert_eq!(
+            format!("{:?}", parsed.root)
+                .matches("Backreference")
+                .count(),
+            2
+        );
+        let ten_groups = "(a)".repeat(10);
+        assert!(run_parse(&format!(r"{}\10", ten_groups), &mut flags).is_ok());
+        for pattern in [r"\1(a)", r"(a)\2", r"(a)\10"] {
+            assert!(
+                matches!(
+                    run_parse(pattern, &mut flags),
+                    Err(ParserError::UnknownGroupReference(..))
+                ),
+                "{}",
+                pattern
+            );
+        }
+    }
+
+    #[test]
+    fn lookbehind_must_have_fixed_width() {
+        let mut flags = RegexFlags::OPTIMIZE;
+        let parsed = run_parse("(?<=ab|cd)e", &mut flags).unwrap();
+        assert_eq!(format!("{}", parsed.root), "(?<=ab|cd)e");
+        let error = run_parse("x(?<=a*)", &mut flags).unwrap_err();
+        assert_eq!(error.span(), 1..8);
+        let parsed = run_parse("(?<![0-9]:)x", &mut flags).unwrap();
+        assert_eq!(format!("{}", parsed.root), "(?<![0-9]:)x");
+        assert!(matches!(
+            run_parse("(a)(?<!\\1)", &mut flags),
+            Err(ParserError::BackreferenceInLookbehind(_))
+        ));
+    }
+
+    #[test]
+    fn possessive_quantifiers() {
+        use Greediness::*;
+        let mut flags = RegexFlags::OPTIMIZE;
+        let parsed = run_parse("a*+b++c?+d{2,3}+e+?", &mut flags).unwrap();
+        let greediness: Vec<Option<Greediness>> = match parsed.root {
+            Node::Expression(items, None) => items
+                .iter()
+                .map(|item| match item.as_ref() {
+                    Node::Match(_, quantifier) => quantifier.greediness(),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        assert_eq!(
+            greediness,
+            vec![
+                Some(Possessive),
+                Some(Possessive),
+                Some(Possessive),
+                Some(Possessive),
+                Some(Lazy)
+            ]
+        );
+        assert_eq!(format!("{}", Quantifier::ZeroOrMore(Possessive)), "*+");
+    }
+
+    #[test]
+    fn required_literals() {
+        let literals = |pattern: &str| {
+            let mut flags = RegexFlags::OPTIMIZE;
+            let required = run_parse(pattern, &mut flags).unwrap().required_literals;
+            (required.literals().to_vec(), required.is_folded())
+        };
+        let exact = |literals: &[&str]| (literals.iter().map(|l| l.to_string()).collect(), false);
+        let folded = |literals: &[&str]| (literals.iter().map(|l| l.to_string()).collect(), true);
+        assert_eq!(literals("ab[0-9]cd"), exact(&["ab", "cd"]));
+        assert_eq!(literals("^(ab)c\\bd?e"), exact(&["abc", "e"]));
+        assert_eq!(literals("x(ab)+y"), exact(&["x", "ab", "y"]));
+        assert_eq!(literals("a*b{2}c"), exact(&["b", "c"]));
+        assert_eq!(literals("ab|cd"), exact(&[]));
+        assert_eq!(literals("(?i)abc"), folded(&["abc"]));
+        // classes of one char, `{1}` and groups of one branch are seen through
+        assert_eq!(literals("[x](?:ab){1}[c-c]\\x64"), exact(&["xabcd"]));
+        assert_eq!(literals("[Ff]oo(?i:BAR)[.]"), folded(&["foobar."]));
+        assert_eq!(literals("[Fg]oo"), exact(&["oo"]));
+    }
+
+    #[test]
+    fn length_bounds() {
+        let bounds = |pattern: &str| {
+            let mut flags = RegexFlags::OPTIMIZE;
+            run_parse(pattern, &mut flags).unwrap().length_bounds
+        };
+        let between = |min, max| LengthBounds { min, max };
+        assert_eq!(bounds("abc"), LengthBounds::exactly(3));
+        assert_eq!(bounds("[0-9]{3}:[0-9]{2,4}"), between(6, Some(8)));
+        assert_eq!(bounds("a?|bcd"), between(0, Some(3)));
+        assert_eq!(bounds("^(ab)?c+$"), between(1, None));
+        assert_eq!(bounds("(a?b){2,}"), between(2, None));
+        assert_eq!(bounds("(?:\\b)*x"), LengthBounds::exactly(1));
+        assert_eq!(bounds("(a)\\1"), between(1, None));
+    }
+
+    #[test]
+    fn group_tree() {
+        let mut flags = RegexFlags::OPTIMIZE;
+        let parsed = run_parse("(a(b)(?:(c)|(d(e))))(f)", &mut flags).unwrap();
+        let tree = parsed.group_tree;
+        assert_eq!(tree.len(), 6);
+        assert_eq!(tree.children(0), vec![1, 6]);
+        assert_eq!(tree.children(1), vec![2, 3, 4]);
+        assert_eq!(tree.children(4), vec![5]);
+        assert_eq!(tree.parent(5), Some(4));
+        assert_eq!(tree.parent(0), None);
+        assert_eq!(tree.depth(5), 3);
+        assert_eq!(tree.depth(6), 1);
+    }
+
+    #[test]
+    fn walk_and_visit_nodes() {
+        let mut flags = RegexFlags::OPTIMIZE;
+        let root = run_parse(r"a(b[cd]|(?=e))+(f)", &mut flags).unwrap().root;
+        let literals: String = root
+            .walk()
+            .filter_map(|node| match node {
+                Node::Character(c) => Some(*c),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(literals, "abcdef");
+
+        /// Counts the capturing groups outside lookarounds.
+        struct Groups(usize);
+        impl NodeVisitor for Groups {
+            fn enter(&mut self, node: &Node) -> bool {
+                if let Node::Group(_, Some(_), _) = node {
+                    self.0 += 1;
+                }
+                !matches!(node, Node::Lookahead(_))
+            }
+        }
+        let mut groups = Groups(0);
+        run_parse("(a)(?=(b))(?:(c))", &mut flags)
+            .unwrap()
+            .root
+            .visit(&mut groups);
+        assert_eq!(groups.0, 2);
+
+        let upper = root.transform(&mut |node| match node {
+            Node::Character(c) => Node::Character(c.to_ascii_uppercase()),
+            node => node,
+        });
+        assert_eq!(upper.to_string(), root.to_string().to_ascii_uppercase());
+    }
+
+    #[test]
+    fn source_map() {
+        let mut flags = RegexFlags::OPTIMIZE;
+        let parsed = run_parse(r"é(a+)(?:b|(c)){2}\d", &mut flags).unwrap();
+        let source_map = parsed.source_map;
+        assert_eq!(source_map.group(0), Some(0..20));
+        assert_eq!(source_map.group(1), Some(2..6));
+        assert_eq!(source_map.group(2), Some(11..14));
+        assert_eq!(source_map.group(3), None);
+        assert_eq!(
+            source_map.items(),
+            [0..2, 2..6, 3..5, 6..18, 9..10, 11..14, 12..13, 18..20]
+        );
+        assert_eq!(source_map.item_at(12), Some(12..13));
+        assert_eq!(source_map.item_at(7), Some(6..18));
+        assert_eq!(source_map.group_at(13), Some(2));
+        assert_eq!(source_map.group_at(9), Some(0));
+        assert_eq!(source_map.group_at(20), None);
+    }
+
+    #[test]
+    fn parser_error_spans_count_bytes() {
+        let mut flags = RegexFlags::OPTIMIZE;
+        for (pattern, span) in [
+            ("日{3,1}", 3..8),
+            ("ü)", 2..3),
+            ("é(", 3..3),
+            ("αβ(?<ö>x)(?<ö>y)", 15..17),
+            (r"(?i)é\k<名>", 6..13),
+            ("[é-a]", 1..5),
+            (r"ab\p{Grëek}", 2..12),
+        ] {
+            let error = run_parse(pattern, &mut flags).unwrap_err();
+            assert_eq!(error.span(), span, "{}", pattern);
+            assert!(pattern.get(span).is_some(), "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn display_round_trips() {
+        let written = |pattern: &str| {
+            let mut flags = RegexFlags::OPTIMIZE;
+            run_parse(pattern, &mut flags).unwrap().to_string()
+        };
+        for pattern in [
+            r"[\w--\d]\P{L}[^\p{L}][a&&[b]]",
+            r"(?i)a(?-i:b)c(?m)$",
+            r"(?P<year>\d+)\-\R+(?(1)x|y)(?script_run:ab)*\k<year>\x30",
+            r"(e)(?<!b)(?<=cd)x(?=)a{2}b{2,}c{2,3}?d*+(?1)(?R)(?C3)",
+            r"(?x)a\ b\#[& ~]",
+            "\\b\\B^$\\A\\z\\Z|\u{0}\\n.\\.\\a",
+        ] {
+            let mut flags = RegexFlags::OPTIMIZE;
+            let parsed = run_parse(pattern, &mut flags).unwrap();
+            let pattern = parsed.to_string();
+            let mut flags = RegexFlags::OPTIMIZE;
+            let reparsed = run_parse(&pattern, &mut flags).unwrap();
+            assert_eq!(reparsed.root, parsed.root, "{}", pattern);
+            assert_eq!(reparsed.flags, parsed.flags, "{}", pattern);
+            assert_eq!(reparsed.group_names, parsed.group_names, "{}", pattern);
+        }
+        assert_eq!(written(r"(?x) \d+ # digits"), r"(?x)[0-9]+");
+        assert_eq!(written(r"(?i:(a)b)|[a-]"), r"(?i:(a)b)|[a\-]");
+        assert_eq!(written(r"(a)\1\u0030"), r"(a)\1\x30");
+        assert_eq!(written(r"[&&]"), r"[\x26\x26]");
+        assert_eq!(
+            Node::Match(
+                Box::new(Node::Character('+')),
+                Quantifier::Range(1, UpperBound::Bounded(3), Greediness::Lazy)
+            )
+            .to_string(),
+            r"\+{1,3}?"
+        );
+    }
+
+    #[test]
+    fn normalized() {
+        let root = |pattern: &str| {
+            let mut flags = RegexFlags::OPTIMIZE;
+            run_parse(pattern, &mut flags).unwrap().root
+        };
+        for (pattern, expected) in [
+            ("a(?:bc)d", "abcd"),
+            ("(?:a){1}b{0,}c{1,1}?d{0,1}e{1,}f{2,2}", "ab*cd?e+f{2}"),
+            ("(?:x)*[y](?:(z))+", "x*y(z)+"),
+            ("(?:a|b)", "a|b"),
+            ("(?=(?:ab)c)x", "(?=abc)x"),
+        ] {
+            assert_eq!(root(pattern).normalized(), root(expected), "{}", pattern);
+        }
+        // a possessive `{1}` still makes what it quantifies atomic
+        for pattern in ["(?:ab){1}+", "(?:a|b)c", "[^a]", "(?:a|b)|c"] {
+            assert_eq!(root(pattern).normalized(), root(pattern), "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn auto_possessified() {
+        let root = |pattern: &str| {
+            let mut flags = RegexFlags::OPTIMIZE;
+            run_parse(pattern, &mut flags).unwrap().root
+        };
+        for (pattern, expected) in [
+            (r"\d+\s", r"\d++\s"),
+            ("a*b|(x?[^x]{2})", "a*+b|(x?+[^x]{2})"),
+            ("(?=[a-c]{1,3}d)", "(?=[a-c]{1,3}+d)"),
+            (r".*\n", r".*+\n"),
+        ] {
+            let possessified = root(pattern).auto_possessified(RegexFlags::OPTIMIZE);
+            assert_eq!(possessified, root(expected), "{}", pattern);
+        }
+        // what follows could read the same char, or nothing, or is more than one char
+        for pattern in [r"\w+\d", "a+?b", "a*b?", "a+(b)"] {
+            let possessified = root(pattern).auto_possessified(RegexFlags::OPTIMIZE);
+            assert_eq!(possessified, root(pattern), "{}", pattern);
+        }
+        let possessified = root("a+A").auto_possessified(RegexFlags::IGNORECASE);
+        assert_eq!(possessified, root("a+A"));
+        let possessified = root("a+A").auto_possessified(RegexFlags::empty());
+        assert_eq!(possessified, root("a++A"));
+    }
+
+    #[test]
+    fn parse_keeps_the_patterns_own_flags() {
+        let a = || Box::new(Node::Character('a'));
+        assert_eq!(
+            parse("(?i)a.", RegexFlags::empty()),
+            Ok(Node::Expression(
+                vec![
+                    Box::new(Node::Match(
+                        Box::new(Node::Flagged(a(), RegexFlags::IGNORECASE)),
+                        Quantifier::None
+                    )),
+                    Box::new(Node::Match(
+                        Box::new(Node::Flagged(Box::new(Node::Dot), RegexFlags::IGNORECASE)),
+                        Quantifier::None
+                    )),
+                ],
+                None
+            ))
+        );
+        // flags the caller already passes need no marking
+        assert_eq!(
+            parse("(?i)a", RegexFlags::IGNORECASE),
+            Ok(Node::Expression(
+                vec![Box::new(Node::Match(a(), Quantifier::None))],
+                None
+            ))
+        );
+        assert_eq!(
+            parse("a(", RegexFlags::empty()),
+            Err(ParserError::InvalidExpression(2..2))
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trips() {
+        for pattern in [
+            r"[\w--\d]\P{L}[^\p{InGreek}]",
+            r"(?i)a(?-i:b)c(?m)$",
+            r"(a)+?\1(?<=b)(?=c)x{2,}y*+",
+        ] {
+            let mut flags = RegexFlags::OPTIMIZE;
+            let root = run_parse(pattern, &mut flags).unwrap().root;
+            let json = serde_json::to_string(&root).unwrap();
+            let read: Node = serde_json::from_str(&json).unwrap();
+            assert_eq!(read, root, "{}", pattern);
+        }
+        assert_eq!(
+            serde_json::to_string(&Quantifier::Range(
+                2,
+                UpperBound::Unbounded,
+                Greediness::Greedy
+            ))
+            .unwrap(),
+            r#"{"Range":[2,"Unbounded","Greedy"]}"#
+        );
+        assert!(serde_json::from_str::<Node>(r#"{"UnicodeProperty":"Nope"}"#).is_err());
+        assert!(serde_json::from_str::<Node>(r#"{"Flagged":["Dot",1024]}"#).is_err());
+    }
+
+    #[test]
+    fn repetition_limits() {
+        let mut flags = RegexFlags::OPTIMIZE;
+        let error = |pattern: &str, max_repetition: u64| {
+            let mut flags = RegexFlags::OPTIMIZE;
+            run_parse_with_max_repetition(pattern, &mut flags, max_repetition).unwrap_err()
+        };
+        assert!(run_parse("a{65535}", &mut flags).is_ok());
+        assert_eq!(
+            error("a{0,18446744073709551615}", DEFAULT_MAX_REPETITION),
+            ParserError::RepetitionTooLarge(u64::MAX, 1..25)
+        );
+        assert!(matches!(
+            error("a{99999999999999999999}", u64::MAX),
+            ParserError::CantParseRangeBound(..)
+        ));
+        // nested repetitions multiply
+        assert_eq!(
+            error("x(a{300}){300}", DEFAULT_MAX_REPETITION),
+            ParserError::RepetitionTooLarge(90000, 1..14)
+        );
+        assert_eq!(
+            error("((?:a{4}b)+){3}", 10),
+            ParserError::RepetitionTooLarge(12, 0..15)
+        );
+        assert!(run_parse_with_max_repetition("(a{300}){300}", &mut flags, 90000).is_ok());
+        assert_eq!(
+            error("a{11}", 10),
+            ParserError::RepetitionTooLarge(11, 1..5)
+        );
+    }
+
+    #[test]
+    fn nest_limits() {
+        let nested = |depth: usize, inner: &str| {
+            format!("{}{}{}", "(?:".repeat(depth), inner, ")".repeat(depth))
+        };
+        let error = |pattern: &str, nest_limit: usize| {
+            let mut flags = RegexFlags::OPTIMIZE;
+            run_parse_with_limits(pattern, &mut flags, DEFAULT_MAX_REPETITION, nest_limit).err()
+        };
+        assert_eq!(
+            error(&nested(DEFAULT_NEST_LIMIT, "a"), DEFAULT_NEST_LIMIT),
+            None
+        );
+        assert_eq!(
+            error(&nested(DEFAULT_NEST_LIMIT + 1, "a"), DEFAULT_NEST_LIMIT),
+            Some(ParserError::NestTooDeep(250, 750..751))
+        );
+        // character groups count too, and so does every kind of group
+        assert_eq!(
+            error(&nested(2, "[[a]]"), 3),
+            Some(ParserError::NestTooDeep(3, 7..8))
+        );
+        assert_eq!(
+            error("(?=(a))(?<=(?i:(b)))", 2),
+            Some(ParserError::NestTooDeep(2, 15..16))
+        );
+        assert_eq!(error("(a)(b)[c](?=(d))", 2), None);
+        // far past any limit, the parser fails before it runs out of stack
+        let mut flags = RegexFlags::OPTIMIZE;
+        assert!(matches!(
+            run_parse(&nested(100_000, "a"), &mut flags),
+            Err(ParserError::NestTooDeep(..))
+        ));
+    }
+
+    #[test]
+    fn render_errors() {
+        let mut flags = RegexFlags::OPTIMIZE;
+        let render = |pattern: &str, flags: &mut RegexFlags| {
+            run_parse(pattern, flags).unwrap_err().render(pattern)
+        };
+        assert_eq!(
+            render("ab{5,2}c", &mut flags),
+            "error: invalid range quantifier {5,2} at 2..7; swap the bounds: use {2,5}\n  |\n1 | ab{5,2}c\n  |   ^~~~~"
+        );
+        // columns count chars, and only the line with the error is shown
+        assert_eq!(
+            render("(?x)\né (?P<é>a)\n(?P<é>b)", &mut flags),
+            "error: group name `é` at 22..24 is already taken\n  |\n3 | (?P<é>b)\n  |     ^"
+        );
+        assert_eq!(
+            render("日本(", &mut flags),
+            "error: expected an expression at 7..7\n  |\n1 | 日本(\n  |    ^"
+        );
+    }
+
+    #[test]
+    fn explain_range_errors() {
+        let mut flags = RegexFlags::OPTIMIZE;
+        let error = run_parse("ab{5,2}c", &mut flags).unwrap_err();
+        assert_eq!(error, ParserError::InvalidRangeQuantifier(5, 2, 2..7));
+        assert_eq!(error.span(), 2..7);
+        assert_eq!(
+            format!("{}", error),
+            "invalid range quantifier {5,2} at 2..7; swap the bounds: use {2,5}"
+        );
+
+        let error = run_parse("x[0-9z-a]", &mut flags).unwrap_err();
+        assert_eq!(error, ParserError::InvalidCharacterRange('z', 'a', 5..8));
+        assert_eq!(
+            error.suggestion(),
+            Some(String::from("swap the endpoints: use a-z"))
+        );
+        assert_eq!(run_parse("a{2}", &mut flags).map(|_| ()), Ok(()));
+    }
 }
@@ -0,0 +1,2085 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    error::Error,
+    fmt::Display,
+    mem::size_of,
+    ops::{Index, Range},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use crate::{
+    fsm::{CompileOptions, ReError, RegexNFA},
+    incremental::{self, Edit, TrackedMatches},
+    matching::{
+        self, BudgetedMatch, CalloutHandler, CharTransform, Finder, Highlight, LineColumn,
+        LineColumns, Match, Matcher, Participation, Prefiltered, RecordSeparator, Semantics,
+        YieldingMatches,
+    },
+    parser::{GroupTree, LengthBounds, ParserError, SourceMap},
+    utils::{Prefilter, RegexFlags},
+};
+
+/// A compiled regular expression.
+///
+/// `Regex` hides the choice of engine: it parses and compiles the pattern once
+/// and runs every search through the same backend.
+///
+/// Nothing reachable from a `Regex` panics on any pattern or haystack: whatever
+/// can fail returns a `Result`, and whatever may be missing an `Option`. The one
+/// exception is indexing `Captures` with `[]`, which like indexing a slice
+/// panics when there is no such group; `Captures::get` is the checked form.
+#[derive(Debug)]
+pub struct Regex {
+    pattern: String,
+    engine: Box<dyn Matcher>,
+    transform: Option<CharTransform>,
+    unset_groups: UnsetGroups,
+    adjacent_empty_matches: AdjacentEmptyMatches,
+    /// The budget `Regex::new_within` charged for the pattern, by its id, and
+    /// how much it charged.
+    charged: Option<(usize, usize)>,
+}
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Regex, ReError> {
+        Regex::new_with_options(pattern, CompileOptions::default())
+    }
+
+    /// Sets what replacement templates do with groups that did not participate
+    /// in a match. The default, `UnsetGroups::Empty`, follows Python.
+    pub fn with_unset_groups(mut self, unset_groups: UnsetGroups) -> Regex {
+        self.unset_groups = unset_groups;
+        self
+    }
+
+    /// Sets whether `replace_all` replaces an empty match right where the previous
+    /// match ended. The default, `AdjacentEmptyMatches::Replace`, follows Python.
+    pub fn with_adjacent_empty_matches(mut self, adjacent: AdjacentEmptyMatches) -> Regex {
+        self.adjacent_empty_matches = adjacent;
+        self
+    }
+
+    /// Compiles `pattern` as `options` say; see `CompileOptions`.
+    pub fn new_with_options(pattern: &str, options: CompileOptions) -> Result<Regex, ReError> {
+        Ok(Regex {
+            pattern: String::from(pattern),
+            engine: Box::new(RegexNFA::new_with_options(pattern, options)?),
+            transform: None,
+            unset_groups: UnsetGroups::Empty,
+            adjacent_empty_matches: AdjacentEmptyMatches::Replace,
+            charged: None,
+        })
+    }
+
+    /// Compiles `pattern` and charges its memory to `budget`, failing with
+    /// `ReError::MemoryBudgetExceeded` if it does not fit. Compilation is held to
+    /// what remains of the budget, so it stops as soon as it goes over. The
+    /// charge stays on the budget until handed back with `MemoryBudget::release`.
+    pub fn new_within(pattern: &str, budget: &mut MemoryBudget) -> Result<Regex, ReError> {
+        let options = CompileOptions::new().with_memory_limit(budget.remaining());
+        let mut regex = Regex::new_with_options(pattern, options)?;
+        let bytes = regex.memory_usage();
+        budget.charge(bytes)?;
+        regex.charged = Some((budget.id, bytes));
+        Ok(regex)
+    }
+
+    /// Bytes held by the compiled pattern, as counted by the engine.
+    pub fn memory_usage(&self) -> usize {
+        size_of::<Regex>() + self.pattern.len() + self.engine.memory_usage()
+    }
+
+    /// Makes searches jump between the candidate starts `prefilter` reports instead
+    /// of using the engine's own prefilter. Searches reading the haystack through a
+    /// transform try every position, since the prefilter sees the raw text.
+    pub fn with_prefilter(mut self, prefilter: Arc<dyn Prefilter>) -> Regex {
+        self.engine = Box::new(Prefiltered::new(self.engine, prefilter));
+        self
+    }
+
+    /// Runs `handler` at every `(?Cn)` callout a search reaches, letting it veto
+    /// the path; see `RegexNFA::with_callout_handler`.
+    pub fn with_callout_handler(mut self, handler: CalloutHandler) -> Regex {
+        self.engine.set_callout_handler(&handler);
+        self
+    }
+
+    /// Sets how many subroutine calls such as `(?R)` can be in progress at once;
+    /// see `RegexNFA::with_recursion_limit`.
+    pub fn with_recursion_limit(mut self, limit: usize) -> Regex {
+        self.engine.set_recursion_limit(limit);
+        self
+    }
+
+    /// Makes every search read the haystack through `transform`, so for example
+    /// `utils::full_width_to_half_width` lets `[0-9]+` match `１２３` without
+    /// copying the haystack into a normalized string first.
+    pub fn with_transform(mut self, transform: CharTransform) -> Regex {
+        self.transform = Some(transform);
+        self
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    /// The number of capture groups, not counting the whole match.
+    pub fn group_count(&self) -> usize {
+        self.engine.group_count()
+    }
+
+    /// The rules deciding which match is reported when several are possible.
+    /// They hold whichever engine runs the pattern.
+    pub fn semantics(&self) -> Semantics {
+        self.engine.semantics()
+    }
+
+    /// The fewest and, if bounded, the most chars any match can span.
+    pub fn length_bounds(&self) -> LengthBounds {
+        self.engine.length_bounds()
+    }
+
+    /// How the groups nest, for rendering captures as a tree.
+    pub fn group_tree(&self) -> GroupTree {
+        self.engine.group_tree()
+    }
+
+    /// Where the groups and items of the pattern are in it, in bytes.
+    pub fn source_map(&self) -> SourceMap {
+        self.engine.source_map()
+    }
+
+    /// For every group of `captures`, group 0 first, where it is in the pattern
+    /// and what it matched in the haystack.
+    pub fn group_sources(&self, captures: &Captures) -> Vec<GroupSource> {
+        let source_map = self.source_map();
+        (0..captures.len())
+            .filter_map(|index| {
+                Some(GroupSource {
+                    pattern: source_map.group(index)?,
+                    matched: captures.whole.group_span(index),
+                })
+            })
+            .collect()
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        match self.transform {
+            Some(_) => self.find(text).is_some(),
+            None => self.engine.is_match(text),
+        }
+    }
+
+    /// The leftmost match in `text`.
+    pub fn find<'t>(&self, text: &'t str) -> Option<Match<'t>> {
+        self.find_iter(text).next()
+    }
+
+    /// The leftmost match in `text`, found in at most `steps` steps of the
+    /// engine, each taking one transition that reads chars or reaches the end of
+    /// the automaton; zero-width ones come free with them. Where the steps
+    /// run out the search is not an error: it returns the longest partial match
+    /// it had got to, flagged as such, for tools that would rather show something
+    /// than nothing. See `BudgetedMatch`.
+    pub fn find_budgeted<'t>(&self, text: &'t str, steps: usize) -> BudgetedMatch<'t> {
+        matching::find_budgeted(&*self.engine, self.transform, text, steps)
+    }
+
+    /// Successive non-overlapping matches in `text`.
+    pub fn find_iter<'r, 't>(&'r self, text: &'t str) -> Box<dyn Iterator<Item = Match<'t>> + 'r>
+    where
+        't: 'r,
+    {
+        match self.transform {
+            Some(transform) => self.engine.find_iter_transformed(text, transform),
+            None => self.engine.find_iter(text),
+        }
+    }
+
+    /// Like `find_iter`, but only the matches lying entirely inside `range`, in
+    /// chars; see `Matcher::find_iter_within`. Offsets stay those of `text`, and
+    /// a range reaching past its end is cut short there.
+    pub fn find_iter_within<'r, 't>(
+        &'r self,
+        text: &'t str,
+        range: Range<usize>,
+    ) -> Box<dyn Iterator<Item = Match<'t>> + 'r>
+    where
+        't: 'r,
+    {
+        let range = within(text, range);
+        match self.transform {
+            Some(transform) => self
+                .engine
+                .find_iter_within_transformed(text, range, transform),
+            None => self.engine.find_iter_within(text, range),
+        }
+    }
+
+    /// The spans `find_iter` finds in `text`, kept with what `rematch` needs to
+    /// update them after an edit.
+    pub fn find_tracked(&self, text: &str) -> TrackedMatches {
+        incremental::track(&*self.engine, self.transform, text)
+    }
+
+    /// The matches in `text`, which is the haystack `previous` was found in with
+    /// `edit` applied. Only the stretch around the edit is searched again; the
+    /// matches before it are kept and those after it shifted.
+    pub fn rematch(&self, previous: &TrackedMatches, edit: Edit, text: &str) -> TrackedMatches {
+        incremental::rematch(&*self.engine, self.transform, previous, edit, text)
+    }
+
+    /// Like `find_iter`, but taking at most `steps` steps per call so a long
+    /// search can be interleaved with other work; see `YieldingMatches`.
+    pub fn find_iter_yielding<'r, 't>(
+        &'r self,
+        text: &'t str,
+        steps: usize,
+    ) -> YieldingMatches<'r, 't>
+    where
+        't: 'r,
+    {
+        matching::find_iter_yielding(&*self.engine, self.transform, text, steps)
+    }
+
+    /// Every offset at which a match starts, overlapping and zero-width matches
+    /// included; see `Matcher::match_positions`.
+    pub fn match_positions<'r>(&'r self, text: &str) -> Box<dyn Iterator<Item = usize> + 'r> {
+        matching::match_positions(&*self.engine, self.transform, text)
+    }
+
+    /// Matches within each record of `text`, with the index of their record; see
+    /// `Matcher::find_iter_records`.
+    pub fn find_iter_records<'r, 't>(
+        &'r self,
+        text: &'t str,
+        separator: &RecordSeparator,
+    ) -> Box<dyn Iterator<Item = (usize, Match<'t>)> + 'r>
+    where
+        't: 'r,
+    {
+        matching::find_iter_records(&*self.engine, self.transform, text, separator)
+    }
+
+    /// Successive non-overlapping matches in `text`, from the last one backwards.
+    pub fn rfind_iter<'r, 't>(&'r self, text: &'t str) -> Box<dyn Iterator<Item = Match<'t>> + 'r>
+    where
+        't: 'r,
+    {
+        matching::rfind_iter(&*self.engine, self.transform, text)
+    }
+
+    /// Like `find_iter`, with the line and column at which each match starts and ends.
+    pub fn find_iter_with_positions<'r, 't>(
+        &'r self,
+        text: &'t str,
+    ) -> Box<dyn Iterator<Item = (Match<'t>, LineColumn, LineColumn)> + 'r>
+    where
+        't: 'r,
+    {
+        Box::new(LineColumns::new(text, self.find_iter(text)))
+    }
+
+    /// The highlights of every match in `text`, in order; see `Match::highlights`.
+    pub fn highlights(&self, text: &str) -> Vec<Highlight> {
+        self.find_iter(text)
+            .flat_map(|found| found.highlights())
+            .collect()
+    }
+
+    /// `text` as HTML, every match wrapped in `<mark data-group="0">` and each of
+    /// its highlights in a capture group in a nested `<mark data-group="n">`,
+    /// `n` being the innermost group. Everything else is escaped as it is.
+    pub fn highlight_html(&self, text: &str) -> String {
+        let chars: Vec<char> = text.chars().collect();
+        let mut html = String::new();
+        let mut position = 0;
+        for found in self.find_iter(text) {
+            let (start, end) = found.span();
+            push_escaped_html(&mut html, &chars[position..start]);
+            html.push_str(r#"<mark data-group="0">"#);
+            for highlight in found.highlights() {
+                let stretch = &chars[highlight.start..highlight.end];
+                if highlight.group == 0 {
+                    push_escaped_html(&mut html, stretch);
+                } else {
+                    html.push_str(&format!(r#"<mark data-group="{}">"#, highlight.group));
+                    push_escaped_html(&mut html, stretch);
+                    html.push_str("</mark>");
+                }
+            }
+            html.push_str("</mark>");
+            position = end;
+        }
+        push_escaped_html(&mut html, &chars[position..]);
+        html
+    }
+
+    /// The capture groups of the leftmost match in `text`.
+    pub fn captures<'t>(&self, text: &'t str) -> Option<Captures<'t>> {
+        self.captures_iter(text).next()
+    }
+
+    pub fn captures_iter<'r, 't>(
+        &'r self,
+        text: &'t str,
+    ) -> Box<dyn Iterator<Item = Captures<'t>> + 'r>
+    where
+        't: 'r,
+    {
+        Box::new(self.find_iter(text).map(|whole| Captures {
+            whole,
+            unset_groups: self.unset_groups,
+        }))
+    }
+
+    /// The capture groups of the leftmost match in `haystack`, which need not be
+    /// UTF-8; see `captures_bytes_iter`.
+    pub fn captures_bytes<'t>(&self, haystack: &'t [u8]) -> Option<BytesCaptures<'t>> {
+        let (text, offsets) = decode_lossy(haystack);
+        self.find(&text)
+            .map(|found| BytesCaptures::new(haystack, &found, &offsets))
+    }
+
+    /// The capture groups of every match in `haystack`, which need not be UTF-8.
+    /// Each invalid byte is read as one U+FFFD, so `.` matches it and a literal
+    /// never does, but the groups slice `haystack` itself, corrupt bytes and all,
+    /// so a log scanner neither crashes nor loses data on them. The matches are
+    /// all found up front, since the decoded text does not outlive the call.
+    pub fn captures_bytes_iter<'r, 't>(
+        &'r self,
+        haystack: &'t [u8],
+    ) -> Box<dyn Iterator<Item = BytesCaptures<'t>> + 'r>
+    where
+        't: 'r,
+    {
+        let (text, offsets) = decode_lossy(haystack);
+        let all: Vec<_> = self
+            .find_iter(&text)
+            .map(|found| BytesCaptures::new(haystack, &found, &offsets))
+            .collect();
+        Box::new(all.into_iter())
+    }
+
+    /// The groups of the first match in each of `lines`, laid out by column: entry
+    /// `i` of the result holds group `i + 1` for every line, in order, with `None`
+    /// where the line does not match or the group did not participate. The texts
+    /// borrow from `lines` and each column is allocated once, which suits loading
+    /// the columns straight into a dataframe.
+    pub fn extract_columns<'t>(&self, lines: &[&'t str]) -> Vec<Vec<Option<&'t str>>> {
+        let mut columns: Vec<Vec<Option<&'t str>>> = (0..self.group_count())
+            .map(|_| Vec::with_capacity(lines.len()))
+            .collect();
+        for line in lines {
+            let found = self.find(line);
+            for (index, column) in columns.iter_mut().enumerate() {
+                column.push(found.as_ref().and_then(|found| found.group(index + 1)));
+            }
+        }
+        columns
+    }
+
+    /// Replaces every match in `text` with `template`, expanded against the
+    /// match's captures as described in `Template`.
+    pub fn replace_all(&self, text: &str, template: &str) -> Result<String, TemplateError> {
+        self.replace_all_with(text, 0..usize::MAX, template, |_, replacement| replacement)
+    }
+
+    /// Like `replace_all`, but only the matches `find_iter_within` finds in
+    /// `range`, so an editor can replace in a selection of its buffer. The whole
+    /// of `text` is returned with the chars outside `range` left as they are.
+    pub fn replace_range(
+        &self,
+        text: &str,
+        range: Range<usize>,
+        template: &str,
+    ) -> Result<String, TemplateError> {
+        self.replace_all_with(text, range, template, |_, replacement| replacement)
+    }
+
+    /// The char ranges of `text` inside `range` that lie between the matches
+    /// found there, in order, as `str::split` cuts at a separator. An empty match
+    /// cuts too, and a match at either end of `range` leaves an empty range there.
+    pub fn split_within(&self, text: &str, range: Range<usize>) -> Vec<Range<usize>> {
+        let range = within(text, range);
+        let mut pieces = Vec::new();
+        let mut start = range.start;
+        for found in self.find_iter_within(text, range.clone()) {
+            let (found_start, found_end) = found.span();
+            pieces.push(start..found_start);
+            start = found_end;
+        }
+        pieces.push(start..range.end);
+        pieces
+    }
+
+    /// Like `replace_all`, but each replacement takes on the case of the text it
+    /// replaces, as editors do: with `cat` as the template, `DOG` becomes `CAT`,
+    /// `Dog` becomes `Cat` and `dog` becomes `cat`. See `CaseStyle::of`.
+    pub fn replace_all_preserving_case(
+        &self,
+        text: &str,
+        template: &str,
+    ) -> Result<String, TemplateError> {
+        self.replace_all_with(text, 0..usize::MAX, template, |matched, replacement| {
+            CaseStyle::of(matched).apply(&replacement)
+        })
+    }
+
+    fn replace_all_with(
+        &self,
+        text: &str,
+        range: Range<usize>,
+        template: &str,
+        adjust: impl Fn(&str, String) -> String,
+    ) -> Result<String, TemplateError> {
+        let template = Template::new(template, self.group_count(), &self.engine.group_names())?;
+        let mut replaced = String::with_capacity(text.len());
+        let mut chars = text.chars();
+        let mut last_end = 0;
+        let mut matched = false;
+        for whole in self.find_iter_within(text, range) {
+            let captures = Captures {
+                whole,
+                unset_groups: self.unset_groups,
+            };
+            let (start, end) = captures.whole.span();
+            if matched
+                && start == end
+                && start == last_end
+                && self.adjacent_empty_matches == AdjacentEmptyMatches::Skip
+            {
+                continue;
+            }
+            matched = true;
+            replaced.extend(chars.by_ref().take(start - last_end));
+            let mut replacement = String::new();
+            template.expand(&captures, &mut replacement)?;
+            replaced.push_str(&adjust(captures.whole.as_str(), replacement));
+            chars.by_ref().take(end - start).for_each(drop);
+            last_end = end;
+        }
+        replaced.extend(chars);
+        Ok(replaced)
+    }
+}
+
+/// `range` cut down to the chars of `text`, and to nothing if it runs backwards.
+fn within(text: &str, range: Range<usize>) -> Range<usize> {
+    let end = range.end.min(text.chars().count());
+    range.start.min(end)..end
+}
+
+/// Appends `chars` to `html` with the chars that mean something in HTML escaped.
+fn push_escaped_html(html: &mut String, chars: &[char]) {
+    for &c in chars {
+        match c {
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            _ => html.push(c),
+        }
+    }
+}
+
+/// Joins the parts of a pattern written across several lines into one pattern
+/// string, so each part can have a Rust comment beside it. String literals are
+/// taken as pattern syntax, while `{expr}` stands for the value of `expr`,
+/// written with `escape` so that it only ever matches itself.
+///
+/// Parts that are all literals are joined by `concat!` into a `&'static str`;
+/// with any `{expr}`, the pattern is built at run time into a `String`. As the
+/// parts are joined as written, a pattern using `(?x)` should keep its comments
+/// out of the literals, since a `#` comment runs on into the next part.
+#[macro_export]
+macro_rules! pattern {
+    (@push $pattern:ident $literal:literal) => {
+        $pattern.push_str(concat!($literal));
+    };
+    (@push $pattern:ident {$value:expr}) => {
+        $pattern.push_str(&$crate::escape(&$value.to_string()));
+    };
+    ($($literal:literal)*) => {
+        concat!($($literal),*)
+    };
+    ($($part:tt)*) => {{
+        let mut pattern = String::new();
+        $($crate::pattern!(@push pattern $part);)*
+        pattern
+    }};
+}
+
+impl Display for Regex {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.pattern)
+    }
+}
+
+/// Any error the crate fails with, in one type, so that errors from compiling a
+/// pattern and from using it compose with `?`.
+#[derive(Debug)]
+pub enum RegexError {
+    /// The pattern does not parse.
+    Parse(ParserError),
+    /// The pattern parsed but cannot be compiled or run, as when it needs more
+    /// memory than the budget allows or the engine does not support it.
+    Compile(ReError),
+    /// A replacement template is malformed or names a group that did not match.
+    Template(TemplateError),
+    /// One of the rules of a `ReplacerSet` failed.
+    ReplacerSet(ReplacerSetError),
+    /// One of the patterns of a `RegexSet` failed to compile.
+    RegexSet(RegexSetError),
+}
+
+impl Display for RegexError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Parse(error) => write!(f, "{}", error),
+            Self::Compile(error) => write!(f, "{}", error),
+            Self::Template(error) => write!(f, "{}", error),
+            Self::ReplacerSet(error) => write!(f, "{}", error),
+            Self::RegexSet(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl Error for RegexError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Parse(error) => Some(error),
+            Self::Compile(error) => Some(error),
+            Self::Template(error) => Some(error),
+            Self::ReplacerSet(error) => Some(error),
+            Self::RegexSet(error) => Some(error),
+        }
+    }
+}
+
+impl From<ParserError> for RegexError {
+    fn from(error: ParserError) -> RegexError {
+        RegexError::Parse(error)
+    }
+}
+
+impl From<ReError> for RegexError {
+    fn from(error: ReError) -> RegexError {
+        match error {
+            ReError::ParsingFailed(error) => RegexError::Parse(error),
+            error => RegexError::Compile(error),
+        }
+    }
+}
+
+impl From<TemplateError> for RegexError {
+    fn from(error: TemplateError) -> RegexError {
+        RegexError::Template(error)
+    }
+}
+
+impl From<ReplacerSetError> for RegexError {
+    fn from(error: ReplacerSetError) -> RegexError {
+        RegexError::ReplacerSet(error)
+    }
+}
+
+impl From<RegexSetError> for RegexError {
+    fn from(error: RegexSetError) -> RegexError {
+        RegexError::RegexSet(error)
+    }
+}
+
+/// Several patterns, each with a replacement template, applied to a text in a
+/// single left-to-right pass rather than one `replace_all` per pattern. Where
+/// matches of different patterns overlap, the leftmost wins, and of those
+/// starting at the same offset, the one whose pattern was given first. Text a
+/// replacement produced is never matched again, so rules can swap words.
+#[derive(Debug)]
+pub struct ReplacerSet {
+    rules: Vec<(Regex, Template)>,
+}
+
+/// Why a `ReplacerSet` could not be built, with the index of the offending rule.
+#[derive(Debug)]
+pub enum ReplacerSetError {
+    Pattern(usize, ReError),
+    Template(usize, TemplateError),
+}
+
+impl Display for ReplacerSetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Pattern(index, error) => write!(f, "pattern of rule {}: {}", index, error),
+            Self::Template(index, error) => write!(f, "template of rule {}: {}", index, error),
+        }
+    }
+}
+
+impl Error for ReplacerSetError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Pattern(_, error) => Some(error),
+            Self::Template(_, error) => Some(error),
+        }
+    }
+}
+
+impl ReplacerSet {
+    /// Compiles each `(pattern, template)` rule, templates as for
+    /// `Regex::replace_all`.
+    pub fn new<'p>(
+        rules: impl IntoIterator<Item = (&'p str, &'p str)>,
+    ) -> Result<ReplacerSet, ReplacerSetError> {
+        let rules = rules
+            .into_iter()
+            .enumerate()
+            .map(|(index, (pattern, template))| {
+                let regex =
+                    Regex::new(pattern).map_err(|error| ReplacerSetError::Pattern(index, error))?;
+                let template =
+                    Template::new(template, regex.group_count(), &regex.engine.group_names())
+                        .map_err(|error| ReplacerSetError::Template(index, error))?;
+                Ok((regex, template))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(ReplacerSet { rules })
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// `text` with every match of every rule replaced.
+    pub fn replace_all(&self, text: &str) -> Result<String, TemplateError> {
+        let mut replaced = String::with_capacity(text.len());
+        let mut chars = text.chars();
+        let mut last_end = 0;
+        let regexes = self.rules.iter().map(|(regex, _)| regex);
+        for (winner, whole) in Leftmost::new(regexes, text) {
+            let (start, end) = whole.span();
+            let (regex, template) = &self.rules[winner];
+            replaced.extend(chars.by_ref().take(start - last_end));
+            let captures = Captures {
+                whole,
+                unset_groups: regex.unset_groups,
+            };
+            template.expand(&captures, &mut replaced)?;
+            chars.by_ref().take(end - start).for_each(drop);
+            last_end = end;
+        }
+        replaced.extend(chars);
+        Ok(replaced)
+    }
+}
+
+/// The matches of several patterns in one left-to-right pass, each paired with
+/// the index of its pattern. Where matches overlap, the leftmost wins, and of
+/// those starting at the same offset, the one whose pattern came first.
+struct Leftmost<'r, 't> {
+    finders: Vec<Finder<'r, 't>>,
+    /// The leftmost match of each pattern at or after `position`, found again
+    /// whenever the winner of a round overlaps it.
+    upcoming: Vec<Option<Match<'t>>>,
+    position: usize,
+}
+
+impl<'r, 't> Leftmost<'r, 't> {
+    fn new(regexes: impl Iterator<Item = &'r Regex>, text: &'t str) -> Leftmost<'r, 't> {
+        let mut finders: Vec<Finder> = regexes
+            .map(|regex| Finder::new(&*regex.engine, text, regex.transform))
+            .collect();
+        let upcoming = finders.iter_mut().map(|finder| finder.find_at(0)).collect();
+        Leftmost {
+            finders,
+            upcoming,
+            position: 0,
+        }
+    }
+}
+
+impl<'t> Iterator for Leftmost<'_, 't> {
+    type Item = (usize, Match<'t>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for (finder, found) in self.finders.iter_mut().zip(self.upcoming.iter_mut()) {
+            if found
+                .as_ref()
+                .is_some_and(|found| found.span().0 < self.position)
+            {
+                *found = finder.find_at(self.position);
+            }
+        }
+        let (_, winner) = self
+            .upcoming
+            .iter()
+            .enumerate()
+            .filter_map(|(index, found)| Some((found.as_ref()?.span().0, index)))
+            .min()?;
+        let whole = self.upcoming[winner]
+            .take()
+            .expect("the winner has a match");
+        let (start, end) = whole.span();
+        self.position = if end == start { start + 1 } else { end };
+        self.upcoming[winner] = self.finders[winner].find_at(self.position);
+        Some((winner, whole))
+    }
+}
+
+/// Several patterns, each compiled with flags of its own and carrying a payload
+/// of the caller's, such as the rule it stands for, searched for in a single
+/// left-to-right pass. Matches are chosen as by `ReplacerSet` and come back
+/// with the payload of the pattern that found them.
+#[derive(Debug)]
+pub struct RegexSet<T> {
+    patterns: Vec<(Regex, T)>,
+}
+
+/// A pattern of a `RegexSet` that failed to compile, by its index in the set.
+#[derive(Debug)]
+pub struct RegexSetError {
+    pub index: usize,
+    pub error: ReError,
+}
+
+impl Display for RegexSetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "pattern {} of the set: {}", self.index, self.error)
+    }
+}
+
+impl Error for RegexSetError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl<T> RegexSet<T> {
+    /// Compiles each pattern with its flags set as by `CompileOptions::with_flags`,
+    /// keeping its payload alongside.
+    pub fn new<'p>(
+        patterns: impl IntoIterator<Item = (&'p str, RegexFlags, T)>,
+    ) -> Result<RegexSet<T>, RegexSetError> {
+        let patterns = patterns
+            .into_iter()
+            .enumerate()
+            .map(|(index, (pattern, flags, payload))| {
+                let options = CompileOptions::new().with_flags(flags);
+                let regex = Regex::new_with_options(pattern, options)
+                    .map_err(|error| RegexSetError { index, error })?;
+                Ok((regex, payload))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(RegexSet { patterns })
+    }
+
+    pub fn len(&self) -> usize {
+        self.patterns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// The payload given with pattern `index`.
+    pub fn payload(&self, index: usize) -> Option<&T> {
+        self.patterns.get(index).map(|(_, payload)| payload)
+    }
+
+    /// The non-overlapping matches of the set's patterns in `text`, left to right.
+    pub fn find_iter<'s, 't>(&'s self, text: &'t str) -> SetMatches<'s, 't, T> {
+        let regexes = self.patterns.iter().map(|(regex, _)| regex);
+        SetMatches {
+            set: self,
+            leftmost: Leftmost::new(regexes, text),
+        }
+    }
+}
+
+/// A match found by a `RegexSet`, with the pattern that found it.
+#[derive(Debug)]
+pub struct SetMatch<'s, 't, T> {
+    /// The index of the pattern in the set.
+    pub index: usize,
+    pub payload: &'s T,
+    pub whole: Match<'t>,
+}
+
+/// The matches of a `RegexSet`, from `RegexSet::find_iter`.
+pub struct SetMatches<'s, 't, T> {
+    set: &'s RegexSet<T>,
+    leftmost: Leftmost<'s, 't>,
+}
+
+impl<'s, 't, T> Iterator for SetMatches<'s, 't, T> {
+    type Item = SetMatch<'s, 't, T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (index, whole) = self.leftmost.next()?;
+        Some(SetMatch {
+            index,
+            payload: &self.set.patterns[index].1,
+            whole,
+        })
+    }
+}
+
+/// A memory allowance shared by a set of patterns, such as all the patterns
+/// compiled on behalf of one tenant. Compiling through `Regex::new_within`
+/// charges each pattern's `memory_usage` against it.
+#[derive(Debug, PartialEq, Eq)]
+pub struct MemoryBudget {
+    /// Tells this budget's charges apart from any other's.
+    id: usize,
+    limit: usize,
+    used: usize,
+}
+
+static NEXT_BUDGET_ID: AtomicUsize = AtomicUsize::new(0);
+
+impl MemoryBudget {
+    pub fn new(limit: usize) -> MemoryBudget {
+        let id = NEXT_BUDGET_ID.fetch_add(1, Ordering::Relaxed);
+        MemoryBudget { id, limit, used: 0 }
+    }
+
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.limit - self.used
+    }
+
+    /// Takes `bytes` out of the budget, leaving it untouched if they do not fit.
+    pub fn charge(&mut self, bytes: usize) -> Result<(), ReError> {
+        if bytes > self.remaining() {
+            return Err(ReError::MemoryBudgetExceeded(bytes, self.remaining()));
+        }
+        self.used += bytes;
+        Ok(())
+    }
+
+    /// Drops `regex` and hands back the memory `Regex::new_within` charged to
+    /// this budget for it, returning how much that was. It is the amount charged
+    /// even if `regex` has been changed since, as by `Regex::with_prefilter`.
+    /// A pattern charged to another budget, or to none, hands back nothing.
+    pub fn release(&mut self, regex: Regex) -> usize {
+        match regex.charged {
+            Some((id, bytes)) if id == self.id => {
+                self.used -= bytes;
+                bytes
+            }
+            _ => 0,
+        }
+    }
+}
+
+/// A group of a pattern paired with what it matched, from `Regex::group_sources`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupSource {
+    /// The bytes of the pattern the group spans.
+    pub pattern: Range<usize>,
+    /// The chars of the haystack the group matched, if it participated.
+    pub matched: Option<(usize, usize)>,
+}
+
+/// The capture groups of a single match. Group 0 is the whole match.
+#[derive(Debug)]
+pub struct Captures<'t> {
+    whole: Match<'t>,
+    unset_groups: UnsetGroups,
+}
+
+impl<'t> Captures<'t> {
+    /// The text of group `index`, or `None` if there is no such group or it did
+    /// not participate.
+    pub fn get(&self, index: usize) -> Option<&'t str> {
+        self.whole.group(index)
+    }
+
+    /// The text of the group called `name`, or `None` if it did not participate.
+    pub fn name(&self, name: &str) -> Option<&'t str> {
+        self.whole.name(name)
+    }
+
+    /// The number of groups, including the whole match.
+    pub fn len(&self) -> usize {
+        self.whole.group_count() + 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// Which groups participated, as a bitmask; see `Match::participated`.
+    pub fn participated(&self) -> Participation {
+        self.whole.participated()
+    }
+
+    pub fn whole(&self) -> &Match<'t> {
+        &self.whole
+    }
+
+    /// Every named group mapped to its text, as in `Match::groupdict`.
+    pub fn groupdict(&self) -> HashMap<&str, Option<&'t str>> {
+        self.whole.groupdict()
+    }
+
+    /// The whole match and the text of every group, so that parsing code can
+    /// destructure them in one line, as in `let (_, [key, value]) = c.extract()?;`.
+    /// A group that did not participate gives `""`. `None` unless the pattern has
+    /// exactly `N` groups.
+    pub fn extract<const N: usize>(&self) -> Option<(&'t str, [&'t str; N])> {
+        (self.whole.group_count() == N).then(|| {
+            (
+                self.whole.as_str(),
+                std::array::from_fn(|index| self.get(index + 1).unwrap_or("")),
+            )
+        })
+    }
+
+    /// Appends `template` expanded against these captures to `dst`, treating
+    /// groups that did not participate as the regex's `UnsetGroups` says.
+    pub fn expand(&self, template: &str, dst: &mut String) -> Result<(), TemplateError> {
+        Template::new(template, self.whole.group_count(), self.whole.group_names())?
+            .expand(self, dst)
+    }
+}
+
+impl<'t> Index<usize> for Captures<'t> {
+    type Output = str;
+
+    fn index(&self, index: usize) -> &str {
+        self.get(index)
+            .unwrap_or_else(|| panic!("no group at index {}", index))
+    }
+}
+
+/// `haystack` as text with every invalid byte replaced by U+FFFD, and the byte
+/// offset in `haystack` of every char of the text followed by that of its end.
+fn decode_lossy(haystack: &[u8]) -> (String, Vec<usize>) {
+    let mut text = String::with_capacity(haystack.len());
+    let mut offsets = Vec::with_capacity(haystack.len() + 1);
+    let mut start = 0;
+    for chunk in haystack.utf8_chunks() {
+        let valid = chunk.valid();
+        offsets.extend(valid.char_indices().map(|(offset, _)| start + offset));
+        text.push_str(valid);
+        start += valid.len();
+        for _ in chunk.invalid() {
+            offsets.push(start);
+            text.push(char::REPLACEMENT_CHARACTER);
+            start += 1;
+        }
+    }
+    offsets.push(haystack.len());
+    (text, offsets)
+}
+
+/// The capture groups of a match in a haystack of raw bytes, from
+/// `Regex::captures_bytes`. Group 0 is the whole match.
+#[derive(Debug, Clone)]
+pub struct BytesCaptures<'t> {
+    haystack: &'t [u8],
+    spans: Vec<Option<(usize, usize)>>,
+}
+
+impl<'t> BytesCaptures<'t> {
+    fn new(haystack: &'t [u8], found: &Match, offsets: &[usize]) -> BytesCaptures<'t> {
+        BytesCaptures {
+            haystack,
+            spans: (0..=found.group_count())
+                .map(|index| {
+                    found
+                        .group_span(index)
+                        .map(|(start, end)| (offsets[start], offsets[end]))
+                })
+                .collect(),
+        }
+    }
+
+    /// Group `index`, or `None` if the group did not participate.
+    pub fn get(&self, index: usize) -> Option<BytesGroup<'t>> {
+        let (start, end) = (*self.spans.get(index)?)?;
+        Some(BytesGroup {
+            haystack: self.haystack,
+            start,
+            end,
+        })
+    }
+
+    /// The number of groups, including the whole match.
+    pub fn len(&self) -> usize {
+        self.spans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+}
+
+/// The bytes a group matched, exactly as they appear in the haystack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BytesGroup<'t> {
+    haystack: &'t [u8],
+    start: usize,
+    end: usize,
+}
+
+impl<'t> BytesGroup<'t> {
+    /// The byte offset of the start of the group in the haystack.
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    /// The byte offset just past the end of the group in the haystack.
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    pub fn as_bytes(&self) -> &'t [u8] {
+        &self.haystack[self.start..self.end]
+    }
+
+    /// The group as text, borrowed if it is UTF-8 and with every invalid
+    /// sequence replaced by U+FFFD otherwise.
+    pub fn to_str_lossy(&self) -> Cow<'t, str> {
+        String::from_utf8_lossy(self.as_bytes())
+    }
+}
+
+/// What a replacement template does with a reference to a group that did not
+/// participate in the match. Tools disagree: Perl and Python 3.5+ substitute
+/// the empty string, while older Pythons raised an "unmatched group" error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnsetGroups {
+    /// Fail the expansion with `TemplateError::UnsetGroup`.
+    Error,
+    /// Insert nothing.
+    #[default]
+    Empty,
+    /// Insert the reference itself, as it was written in the template.
+    KeepLiteral,
+}
+
+/// Whether a replacement also applies to an empty match that starts right where
+/// the previous match ended, such as the one `x*` finds after the `x` in `axb`.
+/// Tools disagree: Python 3.7+ replaces it, turning `axb` into `-a--b-` with
+/// `-` as the template, while older Pythons and many other engines skip it and
+/// give `-a-b-`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AdjacentEmptyMatches {
+    /// Replace it like any other match.
+    #[default]
+    Replace,
+    /// Leave it out, as if the search had started one char further on.
+    Skip,
+}
+
+/// The case pattern of a piece of text, for carrying it over to a replacement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaseStyle {
+    /// `HELLO`: at least two cased letters, all uppercase.
+    Upper,
+    /// `Hello`, and also a lone `H`: an uppercase letter, then lowercase ones.
+    Capitalized,
+    /// `hello`: every cased letter is lowercase.
+    Lower,
+    /// Anything else, such as `hElLo` or text without cased letters.
+    Mixed,
+}
+
+impl CaseStyle {
+    /// The case pattern of `text`, judged from its cased letters only, so that
+    /// `FOO_BAR` is `Upper` and `foo-1` is `Lower`.
+    pub fn of(text: &str) -> CaseStyle {
+        let cased: Vec<char> = text
+            .chars()
+            .filter(|c| c.is_uppercase() || c.is_lowercase())
+            .collect();
+        match cased.split_first() {
+            None => CaseStyle::Mixed,
+            Some((_, [])) if cased[0].is_uppercase() => CaseStyle::Capitalized,
+            _ if cased.iter().all(|c| c.is_lowercase()) => CaseStyle::Lower,
+            _ if cased.iter().all(|c| c.is_uppercase()) => CaseStyle::Upper,
+            Some((first, rest))
+                if first.is_uppercase() && rest.iter().all(|c| c.is_lowercase()) =>
+            {
+                CaseStyle::Capitalized
+            }
+            _ => CaseStyle::Mixed,
+        }
+    }
+
+    /// `text` recased to this pattern; `Mixed` leaves it as it is.
+    pub fn apply(&self, text: &str) -> String {
+        match self {
+            CaseStyle::Upper => text.to_uppercase(),
+            CaseStyle::Lower => text.to_lowercase(),
+            CaseStyle::Capitalized => {
+                let mut chars = text.chars();
+                match chars.next() {
+                    Some(first) => first
+                        .to_uppercase()
+                        .chain(chars.as_str().to_lowercase().chars())
+                        .collect(),
+                    None => String::new(),
+                }
+            }
+            CaseStyle::Mixed => text.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TemplateError {
+    InvalidGroupReference(String),
+    BadEscape(char),
+    DanglingBackslash,
+    /// The template refers to this group but it did not participate in the match.
+    UnsetGroup(usize),
+}
+
+impl Display for TemplateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidGroupReference(reference) => write!(
+                f,
+                "the template refers to group `{}`, which the pattern does not have",
+                reference
+            ),
+            Self::BadEscape(c) => write!(f, "`\\{}` is not an escape templates support", c),
+            Self::DanglingBackslash => write!(f, "the template ends in a lone `\\`"),
+            Self::UnsetGroup(index) => write!(
+                f,
+                "the template refers to group {}, which did not participate in the match",
+                index
+            ),
+        }
+    }
+}
+
+impl Error for TemplateError {}
+
+#[derive(Debug, PartialEq, Eq)]
+enum TemplatePart {
+    Literal(String),
+    /// A group index together with the reference as written, e.g. `\g<2>`.
+    Group(usize, String),
+}
+
+/// A replacement template in Python's `re.sub` syntax: `\1` to `\99`, `\g<1>`
+/// and `\g<name>` insert a group, `\\`, `\n` and `\t` insert the escaped character.
+/// Groups that did not participate are handled according to `UnsetGroups`.
+#[derive(Debug, PartialEq, Eq)]
+struct Template {
+    parts: Vec<TemplatePart>,
+}
+
+impl Template {
+    fn new(
+        template: &str,
+        group_count: usize,
+        names: &HashMap<String, usize>,
+    ) -> Result<Template, TemplateError> {
+        let mut parts: Vec<TemplatePart> = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+        let check = |index: usize, reference: &str| {
+            if index > group_count {
+                Err(TemplateError::InvalidGroupReference(reference.to_string()))
+            } else {
+                Ok(index)
+            }
+        };
+
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                literal.push(c);
+                continue;
+            }
+            let (index, written) = match chars.next() {
+                Some('\\') => {
+                    literal.push('\\');
+                    continue;
+                }
+                Some('n') => {
+                    literal.push('\n');
+                    continue;
+                }
+                Some('t') => {
+                    literal.push('\t');
+                    continue;
+                }
+                Some(digit @ '0'..='9') => {
+                    let mut reference = String::from(digit);
+                    if let Some(next) = chars.next_if(|c| c.is_ascii_digit()) {
+                        reference.push(next);
+                    }
+                    let index = check(reference.parse().unwrap(), &reference)?;
+                    (index, format!("\\{}", reference))
+                }
+                Some('g') => {
+                    if chars.next() != Some('<') {
+                        return Err(TemplateError::BadEscape('g'));
+                    }
+                    let reference: String = chars.by_ref().take_while(|c| *c != '>').collect();
+                    let index = match (reference.parse(), names.get(&reference)) {
+                        (Ok(index), _) => check(index, &reference)?,
+                        (Err(_), Some(index)) => *index,
+                        (Err(_), None) => {
+                            return Err(TemplateError::InvalidGroupReference(reference))
+                        }
+                    };
+                    (index, format!("\\g<{}>", reference))
+                }
+                Some(other) => return Err(TemplateError::BadEscape(other)),
+                None => return Err(TemplateError::DanglingBackslash),
+            };
+            if !literal.is_empty() {
+                parts.push(TemplatePart::Literal(std::mem::take(&mut literal)));
+            }
+            parts.push(TemplatePart::Group(index, written));
+        }
+        if !literal.is_empty() {
+            parts.push(TemplatePart::Literal(literal));
+        }
+        Ok(Template { parts })
+    }
+
+    fn expand(&self, captures: &Captures, dst: &mut String) -> Result<(), TemplateError> {
+        for part in &self.parts {
+            match part {
+                TemplatePart::Literal(literal) => dst.push_str(literal),
+                TemplatePart::Group(index, written) => {
+                    match (captures.get(*index), captures.unset_groups) {
+                        (Some(text), _) => dst.push_str(text),
+                        (None, UnsetGroups::Error) => {
+                            return Err(TemplateError::UnsetGroup(*index))
+                        }
+                        (None, UnsetGroups::Empty) => {}
+                        (None, UnsetGroups::KeepLiteral) => dst.push_str(written),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::Mutex, task::Poll};
+
+    use crate::{
+        escape,
+        fsm::ProgressHandler,
+        matching::{Callout, ContextSize},
+        parser::{ParserError, DEFAULT_NEST_LIMIT},
+        utils::{ascii_fold, full_width_to_half_width, CandidatePositions, LineStarts},
+        vm::PikeVM,
+    };
+
+    #[test]
+    fn test_pattern_macro() {
+        let date: &'static str = pattern! {
+            "([0-9]{4})" // year
+            "-"
+            "([0-9]{2})" // month
+        };
+        assert_eq!(date, "([0-9]{4})-([0-9]{2})");
+
+        let domain = "co.uk";
+        let host = pattern! {
+            r"\w+\."
+            {domain}
+            "$"
+        };
+        assert_eq!(host, r"\w+\.co\.uk$");
+        let regex = Regex::new(&host).unwrap();
+        assert!(regex.is_match("bbc.co.uk"));
+        assert!(!regex.is_match("bbc.coxuk"));
+
+        // an interpolated value stays literal under free-spacing
+        let spaced = pattern! { "(?x) a" {"b c#"} " d" };
+        assert_eq!(spaced, r"(?x) ab\ c\# d");
+        let regex = Regex::new(&spaced).unwrap();
+        assert_eq!(regex.find("xab c#d").map(|m| m.span()), Some((1, 7)));
+        assert_eq!(escape("a\tb\u{A0}"), "a\\tb\\\u{A0}");
+    }
+
+    #[test]
+    fn test_escape_round_trips() {
+        // every metacharacter, control char and space, doubled so a quantifier
+        // or range left unescaped would show
+        for c in (0u32..0x100).filter_map(char::from_u32) {
+            let text = format!("{}{}", c, c);
+            for flags in ["", "(?x)"] {
+                let pattern = format!("{}{}", flags, escape(&text));
+                let regex = Regex::new(&pattern).unwrap();
+                assert_eq!(
+                    regex.find(&text).map(|m| m.span()),
+                    Some((0, 2)),
+                    "{}",
+                    pattern
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_regex_error() {
+        fn swap(pattern: &str, text: &str) -> Result<String, RegexError> {
+            let mut budget = MemoryBudget::new(1 << 20);
+            Ok(Regex::new_within(pattern, &mut budget)?.replace_all(text, r"\2\1")?)
+        }
+        assert_eq!(swap("(a)(b)", "abab").unwrap(), "baba");
+        let error = swap("(a)(b", "ab").unwrap_err();
+        assert!(matches!(
+            error,
+            RegexError::Parse(ParserError::UnexpectedEOF(_))
+        ));
+        assert_eq!(error.to_string(), "pattern ends too early at 5..5");
+        assert!(error.source().is_some());
+        assert!(matches!(
+            swap("(a)", "a").unwrap_err(),
+            RegexError::Template(TemplateError::InvalidGroupReference(_))
+        ));
+
+        let mut budget = MemoryBudget::new(0);
+        let error: RegexError = Regex::new_within("a", &mut budget).unwrap_err().into();
+        assert!(matches!(
+            error,
+            RegexError::Compile(ReError::MemoryBudgetExceeded(_, 0))
+        ));
+        assert!(error
+            .to_string()
+            .ends_with("only 0 remain in the memory budget"));
+    }
+
+    #[test]
+    fn test_group_sources() {
+        let regex = Regex::new(r"(\w+)@(x)?").unwrap();
+        let captures = regex.captures("hé ab@y").unwrap();
+        let sources: Vec<_> = regex
+            .group_sources(&captures)
+            .into_iter()
+            .map(|source| (source.pattern, source.matched))
+            .collect();
+        assert_eq!(
+            sources,
+            [(0..10, Some((3, 6))), (0..5, Some((3, 5))), (6..10, None)]
+        );
+        assert_eq!(regex.source_map().group_at(7), Some(2));
+    }
+
+    #[test]
+    fn test_regex_facade() {
+        let regex = Regex::new("(a+)(b)?").unwrap();
+        assert_eq!(regex.as_str(), "(a+)(b)?");
+        assert_eq!(regex.group_count(), 2);
+        assert_eq!(regex.group_tree().children(0), vec![1, 2]);
+        assert_eq!(regex.length_bounds(), LengthBounds { min: 1, max: None });
+        assert!(regex.is_match("xaab"));
+        assert!(!regex.is_match("xyz"));
+        assert_eq!(regex.find("xaab").map(|m| m.span()), Some((1, 4)));
+        assert_eq!(regex.find_iter("ab a aab").count(), 3);
+        assert_eq!(
+            regex
+                .rfind_iter("ab a aab")
+                .map(|m| m.span())
+                .collect::<Vec<_>>(),
+            vec![(5, 8), (3, 4), (0, 2)]
+        );
+
+        let captures = regex.captures("éaa!").unwrap();
+        assert_eq!(captures.len(), 3);
+        assert_eq!(&captures[0], "aa");
+        assert_eq!(captures.get(1), Some("aa"));
+        assert_eq!(captures.get(2), None);
+        assert_eq!(captures.get(3), None);
+    }
+
+    #[test]
+    fn test_with_transform() {
+        let regex = Regex::new("[0-9]+")
+            .unwrap()
+            .with_transform(full_width_to_half_width);
+        let found: Vec<&str> = regex.find_iter("ab１２3 ４").map(|m| m.as_str()).collect();
+        assert_eq!(found, vec!["１２3", "４"]);
+
+        let regex = Regex::new("cafe").unwrap().with_transform(ascii_fold);
+        assert_eq!(regex.find("un café").map(|m| m.span()), Some((3, 7)));
+        assert_eq!(regex.replace_all("CAFÉ café", "tea").unwrap(), "CAFÉ tea");
+    }
+
+    #[test]
+    fn test_every_entry_point_reads_through_the_transform() {
+        let regex = Regex::new("[0-9]+")
+            .unwrap()
+            .with_transform(full_width_to_half_width);
+        let text = "１２ ab\n３４";
+        let expected = [(0, 2), (6, 8)];
+        let spans = |matches: &mut dyn Iterator<Item = Match>| -> Vec<(usize, usize)> {
+            matches.map(|m| m.span()).collect()
+        };
+        assert_eq!(spans(&mut regex.find_iter(text)), expected);
+        assert_eq!(spans(&mut regex.find_iter_within(text, 0..8)), expected);
+        let mut backwards = spans(&mut regex.rfind_iter(text));
+        backwards.reverse();
+        assert_eq!(backwards, expected);
+        let yielded: Vec<(usize, usize)> = regex
+            .find_iter_yielding(text, 2)
+            .filter_map(|polled| match polled {
+                Poll::Ready(found) => Some(found.span()),
+                Poll::Pending => None,
+            })
+            .collect();
+        assert_eq!(yielded, expected);
+        let records: Vec<(usize, (usize, usize))> = regex
+            .find_iter_records(text, &RecordSeparator::Line)
+            .map(|(index, found)| (index, found.span()))
+            .collect();
+        assert_eq!(records, [(0, (0, 2)), (1, (6, 8))]);
+        assert_eq!(
+            regex.match_positions(text).collect::<Vec<_>>(),
+            [0, 1, 6, 7]
+        );
+        assert_eq!(regex.find_tracked(text).spans(), expected);
+        assert_eq!(
+            regex.find_budgeted(text, 100).found.map(|m| m.span()),
+            Some((0, 2))
+        );
+        assert!(regex.is_match("４"));
+        assert_eq!(regex.replace_all(text, "#").unwrap(), "# ab\n#");
+    }
+
+    #[test]
+    fn test_with_prefilter() {
+        let regex = Regex::new("[a-z]+")
+            .unwrap()
+            .with_prefilter(Arc::new(CandidatePositions::new(vec![10, 4])));
+        let found: Vec<&str> = regex
+            .find_iter("abc def ghi jkl")
+            .map(|m| m.as_str())
+            .collect();
+        assert_eq!(found, vec!["def", "i"]);
+
+        /// Only tries the char after each `:`.
+        #[derive(Debug)]
+        struct AfterColon;
+        impl Prefilter for AfterColon {
+            fn find(&self, text: &[char], start: usize, end: usize) -> Option<usize> {
+                (start.max(1)..end).find(|&position| text[position - 1] == ':')
+            }
+        }
+        let regex = Regex::new("[0-9]+")
+            .unwrap()
+            .with_prefilter(Arc::new(AfterColon));
+        let found: Vec<&str> = regex.find_iter("12 a:34 :5").map(|m| m.as_str()).collect();
+        assert_eq!(found, vec!["34", "5"]);
+        assert!(regex.memory_usage() > Regex::new("[0-9]+").unwrap().memory_usage());
+    }
+
+    #[test]
+    fn test_callouts() {
+        // a word may only end at an even offset
+        let regex = Regex::new(r"\w+(?C1)")
+            .unwrap()
+            .with_callout_handler(CalloutHandler::new(|callout| callout.position % 2 == 0));
+        let found: Vec<&str> = regex.find_iter("abcde fg").map(|m| m.as_str()).collect();
+        assert_eq!(found, vec!["abcd", "fg"]);
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&seen);
+        let regex = Regex::new("a(?C7)b(?=(?C)c)")
+            .unwrap()
+            .with_callout_handler(CalloutHandler::new(move |callout| {
+                log.lock().unwrap().push(callout);
+                callout.number == 7
+            }));
+        assert!(!regex.is_match("xabc"));
+        let seen = seen.lock().unwrap();
+        assert!(seen.contains(&Callout {
+            number: 7,
+            position: 2
+        }));
+        assert!(seen.contains(&Callout {
+            number: 0,
+            position: 3
+        }));
+
+        assert!(Regex::new("a(?C)b").unwrap().is_match("ab"));
+        assert!(Regex::new("(?C256)").is_err());
+
+        // the handler and limit go on the engine as compiled, options and all
+        let nested = format!("{}a(?C1){}", "(".repeat(260), ")".repeat(260));
+        let options = CompileOptions::new().with_nest_limit(300);
+        let regex = Regex::new_with_options(&nested, options)
+            .unwrap()
+            .with_callout_handler(CalloutHandler::new(|_| false));
+        assert!(!regex.is_match("a"));
+        let options = CompileOptions::new().with_flags(RegexFlags::IGNORECASE);
+        let regex = Regex::new_with_options("abc(?C1)", options)
+            .unwrap()
+            .with_recursion_limit(10)
+            .with_callout_handler(CalloutHandler::new(|_| true));
+        assert!(regex.is_match("ABC"));
+        assert!(Regex::new("(?Cx)").is_err());
+        assert!(PikeVM::new("(?C1)a").is_err());
+    }
+
+    #[test]
+    fn test_replace_preserving_case() {
+        let regex = Regex::new("(?i)colou?r").unwrap();
+        assert_eq!(
+            regex
+                .replace_all_preserving_case("Color, COLOUR, color, CoLoR", "hue")
+                .unwrap(),
+            "Hue, HUE, hue, hue"
+        );
+        assert_eq!(
+            regex.replace_all("Color, COLOUR", "hue").unwrap(),
+            "hue, hue"
+        );
+
+        assert_eq!(CaseStyle::of("FOO_BAR"), CaseStyle::Upper);
+        assert_eq!(CaseStyle::of("X"), CaseStyle::Capitalized);
+        assert_eq!(CaseStyle::of("x-1"), CaseStyle::Lower);
+        assert_eq!(CaseStyle::of("iPhone"), CaseStyle::Mixed);
+        assert_eq!(CaseStyle::of("42"), CaseStyle::Mixed);
+        assert_eq!(CaseStyle::Capitalized.apply("éCOLE"), "École");
+        assert_eq!(CaseStyle::Mixed.apply("iPad"), "iPad");
+    }
+
+    #[test]
+    fn test_extract_columns() {
+        let regex = Regex::new("([a-z]+):([0-9]+)(!)?").unwrap();
+        let columns = regex.extract_columns(&["a:1!", "nothing here", "xy:23", ""]);
+        assert_eq!(
+            columns,
+            vec![
+                vec![Some("a"), None, Some("xy"), None],
+                vec![Some("1"), None, Some("23"), None],
+                vec![Some("!"), None, None, None],
+            ]
+        );
+        assert!(Regex::new("[a-z]+")
+            .unwrap()
+            .extract_columns(&["ab"])
+            .is_empty());
+    }
+
+    #[test]
+    fn test_highlight_html() {
+        let regex = Regex::new(r"(\w+)@((\w+)\.com)").unwrap();
+        assert_eq!(
+            regex.highlight_html("<a> & b@x.com, \"c@y.com\""),
+            concat!(
+                "&lt;a&gt; &amp; ",
+                r#"<mark data-group="0"><mark data-group="1">b</mark>@"#,
+                r#"<mark data-group="3">x</mark><mark data-group="2">.com</mark></mark>, "#,
+                r#"&quot;<mark data-group="0"><mark data-group="1">c</mark>@"#,
+                r#"<mark data-group="3">y</mark><mark data-group="2">.com</mark></mark>&quot;"#,
+            )
+        );
+        let regex = Regex::new("x*").unwrap();
+        assert_eq!(
+            regex.highlight_html("axé"),
+            concat!(
+                r#"<mark data-group="0"></mark>a<mark data-group="0">x</mark>"#,
+                r#"<mark data-group="0"></mark>é<mark data-group="0"></mark>"#
+            )
+        );
+    }
+
+    #[test]
+    fn test_captures_bytes() {
+        let regex = Regex::new("user:([a-z]+) msg:(.*)").unwrap();
+        let line = b"\xff user:bob msg:caf\xc3\xa9 \xfe\xfd!";
+        let captures = regex.captures_bytes(line).unwrap();
+        assert_eq!(captures.len(), 3);
+        let user = captures.get(1).unwrap();
+        assert_eq!(
+            (user.start(), user.end(), user.as_bytes()),
+            (7, 10, &b"bob"[..])
+        );
+        let message = captures.get(2).unwrap();
+        assert_eq!(message.as_bytes(), b"caf\xc3\xa9 \xfe\xfd!");
+        assert_eq!(message.to_str_lossy(), "café \u{FFFD}\u{FFFD}!");
+        assert!(captures.get(3).is_none());
+
+        let all: Vec<_> = Regex::new("[a-z]\\d?")
+            .unwrap()
+            .captures_bytes_iter(b"a\x80b1\xe2\x82c")
+            .map(|captures| captures.get(0).unwrap().as_bytes())
+            .collect();
+        assert_eq!(all, [&b"a"[..], b"b1", b"c"]);
+        assert!(regex.captures_bytes(b"\xc3user:").is_none());
+    }
+
+    #[test]
+    fn test_extract() {
+        let regex = Regex::new("([0-9]+)/([0-9]+)(/[0-9]+)?").unwrap();
+        let captures = regex.captures("on 5/17!").unwrap();
+        let (whole, [month, day, year]) = captures.extract().unwrap();
+        assert_eq!((whole, month, day, year), ("5/17", "5", "17", ""));
+        let captures = Regex::new("ab").unwrap().captures("cab").unwrap();
+        let (whole, []) = captures.extract().unwrap();
+        assert_eq!(whole, "ab");
+    }
+
+    #[test]
+    fn test_extract_wrong_arity() {
+        let regex = Regex::new("([0-9]+)/([0-9]+)(/[0-9]+)?").unwrap();
+        assert!(regex.captures("5/17").unwrap().extract::<1>().is_none());
+    }
+
+    #[test]
+    fn test_named_references() {
+        let regex = Regex::new("(?P<key>[a-z]+):(?P<value>[0-9]+)").unwrap();
+        assert_eq!(
+            regex
+                .replace_all("a:1 bc:23", r"\g<value>:\g<key>")
+                .unwrap(),
+            "1:a 23:bc"
+        );
+        assert_eq!(regex.captures("x:9").unwrap().name("value"), Some("9"));
+        assert_eq!(
+            regex.replace_all("a:1", r"\g<other>"),
+            Err(TemplateError::InvalidGroupReference(String::from("other")))
+        );
+    }
+
+    #[test]
+    fn test_unset_groups() {
+        let text = "a ab";
+        let template = r"[\2|\g<2>]";
+        let replace = |unset_groups| {
+            Regex::new("a(b)?(b)?")
+                .unwrap()
+                .with_unset_groups(unset_groups)
+                .replace_all(text, template)
+        };
+        assert_eq!(replace(UnsetGroups::Empty).unwrap(), "[|] [|]");
+        assert_eq!(
+            replace(UnsetGroups::KeepLiteral).unwrap(),
+            r"[\2|\g<2>] [\2|\g<2>]"
+        );
+        assert_eq!(
+            replace(UnsetGroups::Error),
+            Err(TemplateError::UnsetGroup(2))
+        );
+
+        let captures = Regex::new("(a)|(b)")
+            .unwrap()
+            .with_unset_groups(UnsetGroups::Error)
+            .captures("a")
+            .unwrap();
+        let mut dst = String::new();
+        assert!(captures.expand(r"\1", &mut dst).is_ok());
+        assert_eq!(
+            captures.expand(r"\2", &mut dst),
+            Err(TemplateError::UnsetGroup(2))
+        );
+        assert_eq!(dst, "a");
+    }
+
+    #[test]
+    fn test_adjacent_empty_matches() {
+        let replace = |pattern: &str, text: &str, adjacent| {
+            Regex::new(pattern)
+                .unwrap()
+                .with_adjacent_empty_matches(adjacent)
+                .replace_all(text, "-")
+                .unwrap()
+        };
+        assert_eq!(
+            replace("x*", "axb", AdjacentEmptyMatches::Replace),
+            "-a--b-"
+        );
+        assert_eq!(replace("x*", "axb", AdjacentEmptyMatches::Skip), "-a-b-");
+        // only an empty match right after another match is skipped
+        assert_eq!(replace("x*", "xx", AdjacentEmptyMatches::Skip), "-");
+        assert_eq!(replace("", "ab", AdjacentEmptyMatches::Skip), "-a-b-");
+        assert_eq!(replace("a|b*", "abc", AdjacentEmptyMatches::Skip), "--c-");
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let small = Regex::new("a").unwrap().memory_usage();
+        let large = Regex::new("(a|b)*c[x-z]{2,5}").unwrap().memory_usage();
+        assert!(small < large);
+        assert_eq!(
+            large,
+            Regex::new("(a|b)*c[x-z]{2,5}").unwrap().memory_usage()
+        );
+
+        let mut budget = MemoryBudget::new(small * 2 + 1);
+        let first = Regex::new_within("a", &mut budget).unwrap();
+        assert_eq!(budget.used(), small);
+        assert!(Regex::new_within("a", &mut budget).is_ok());
+        match Regex::new_within("a", &mut budget) {
+            // compiling stops with the first state past the one byte left
+            Err(ReError::MemoryBudgetExceeded(needed, remaining)) => {
+                assert_eq!((needed > 1, remaining), (true, 1))
+            }
+            other => panic!("expected the budget to run out, got {:?}", other),
+        }
+        assert_eq!(budget.used(), small * 2);
+        let first = first.with_prefilter(Arc::new(LineStarts));
+        assert_eq!(budget.release(first), small);
+        assert_eq!(budget.used(), small);
+        let second = Regex::new_within("a", &mut budget).unwrap();
+        let mut other = MemoryBudget::new(small * 2);
+        assert_eq!(other.release(second), 0);
+        assert_eq!(other.used(), 0);
+        assert_eq!(budget.release(Regex::new("a").unwrap()), 0);
+        assert_eq!(budget.used(), small * 2);
+
+        // a pattern far over the budget is stopped partway through compiling
+        let reports = Arc::new(Mutex::new(0));
+        let counted = Arc::clone(&reports);
+        let options = CompileOptions::new()
+            .with_memory_limit(4096)
+            .with_progress_handler(ProgressHandler::new(move |_| {
+                *counted.lock().unwrap() += 1;
+                true
+            }));
+        assert!(matches!(
+            Regex::new_with_options("[a-z]{5000}", options),
+            Err(ReError::MemoryBudgetExceeded(_, 4096))
+        ));
+        assert_eq!(*reports.lock().unwrap(), 1);
+        // the limit is checked as each state is built, not only when progress is
+        let options = CompileOptions::new().with_memory_limit(256);
+        match Regex::new_with_options("[a-z]{100}", options) {
+            Err(ReError::MemoryBudgetExceeded(built, 256)) => assert!(built < 512),
+            other => panic!("expected the limit to stop compiling, got {:?}", other),
+        }
+        let mut budget = MemoryBudget::new(1 << 16);
+        assert!(matches!(
+            Regex::new_within("(?:[a-z]{1000}(?=b)){20}", &mut budget),
+            Err(ReError::MemoryBudgetExceeded(_, 65536))
+        ));
+        assert_eq!(budget.used(), 0);
+    }
+
+    #[test]
+    fn test_replace_all() {
+        let regex = Regex::new("(a+)(b)?").unwrap();
+        assert_eq!(
+            regex.replace_all("xaab ä a", r"<\2\g<1>>").unwrap(),
+            "x<baa> ä <a>"
+        );
+        assert_eq!(regex.replace_all("a", r"\\\t").unwrap(), "\\\t");
+        assert_eq!(
+            regex.replace_all("a", r"\3"),
+            Err(TemplateError::InvalidGroupReference(String::from("3")))
+        );
+        assert_eq!(
+            regex.replace_all("a", r"\q"),
+            Err(TemplateError::BadEscape('q'))
+        );
+        assert_eq!(
+            regex.replace_all("a", "\\"),
+            Err(TemplateError::DanglingBackslash)
+        );
+        assert_eq!(
+            TemplateError::BadEscape('q').to_string(),
+            r"`\q` is not an escape templates support"
+        );
+    }
+
+    #[test]
+    fn test_find_budgeted() {
+        let regex = Regex::new(r"(a+)(b)(c)").unwrap();
+        let text = "xaaabd aaabc";
+        let complete = regex.find_budgeted(text, 1000);
+        assert!(!complete.exhausted);
+        assert_eq!(complete.found.unwrap().span(), (7, 12));
+
+        // the run from 1 gets furthest before failing at `d`
+        let partial = regex.find_budgeted(text, 10);
+        assert!(partial.exhausted);
+        let found = partial.found.unwrap();
+        assert_eq!(found.span(), (1, 5));
+        assert_eq!(
+            [1, 2, 3].map(|index| found.group_span(index)),
+            [Some((1, 4)), Some((4, 5)), None]
+        );
+        // a group the run is still inside of ends where it stopped
+        let found = regex.find_budgeted(text, 1).found.unwrap();
+        assert_eq!((found.span(), found.group_span(1)), ((1, 2), Some((1, 2))));
+
+        let nothing = regex.find_budgeted(text, 0);
+        assert!(nothing.exhausted && nothing.found.is_none());
+        let none = regex.find_budgeted("xyz", 1000);
+        assert!(!none.exhausted && none.found.is_none());
+    }
+
+    #[test]
+    fn test_replace_range_and_split_within() {
+        let regex = Regex::new("a+").unwrap();
+        let text = "ä a aa a ä";
+        assert_eq!(
+            regex.replace_range(text, 2..7, r"<\g<0>>").unwrap(),
+            "ä <a> <aa> a ä"
+        );
+        // a match is cut short where the range ends
+        assert_eq!(
+            regex.replace_range(text, 2..5, r"<\g<0>>").unwrap(),
+            "ä <a> <a>a a ä"
+        );
+        assert_eq!(regex.split_within(text, 2..7), vec![2..2, 3..4, 6..7]);
+        assert_eq!(regex.split_within(text, 8..100), vec![8..10]);
+
+        // word boundaries still see the chars outside the range
+        let bounded = Regex::new(r"\ba+").unwrap();
+        assert_eq!(bounded.split_within(text, 5..6), vec![5..6]);
+        assert_eq!(
+            bounded.replace_range(text, 5..8, "b").unwrap(),
+            "ä a aa b ä"
+        );
+
+        let upper = Regex::new("A")
+            .unwrap()
+            .with_transform(|c| c.to_ascii_uppercase());
+        assert_eq!(upper.split_within(text, 0..4), vec![0..2, 3..4]);
+    }
+
+    #[test]
+    fn test_replacer_set() {
+        let swap = ReplacerSet::new([("cat", "dog"), ("dog", "cat")]).unwrap();
+        assert_eq!(swap.len(), 2);
+        assert_eq!(
+            swap.replace_all("cat dog catdog").unwrap(),
+            "dog cat dogcat"
+        );
+        // leftmost wins, then the rule given first
+        let rules = ReplacerSet::new([("b+", "X"), ("ab", "Y"), ("a", "Z")]).unwrap();
+        assert_eq!(rules.replace_all("abb ab a").unwrap(), "YX Y Z");
+        let dates = ReplacerSet::new([(r"(\d+)/(\d+)", r"\2.\1"), ("x*", "-")]).unwrap();
+        assert_eq!(dates.replace_all("12/3 ä").unwrap(), "3.12- -ä-");
+
+        // a single rule replaces as `Regex::replace_all` does
+        for (pattern, template) in [("(a+)(b)?", r"<\2\g<1>>"), ("x*", "-"), (r"\b", "|")] {
+            let text = "xaab ä a xx";
+            assert_eq!(
+                ReplacerSet::new([(pattern, template)])
+                    .unwrap()
+                    .replace_all(text),
+                Regex::new(pattern).unwrap().replace_all(text, template)
+            );
+        }
+
+        assert!(matches!(
+            ReplacerSet::new([("a", "b"), ("(", "c")]),
+            Err(ReplacerSetError::Pattern(1, _))
+        ));
+        assert!(matches!(
+            ReplacerSet::new([("a", r"\1")]),
+            Err(ReplacerSetError::Template(
+                0,
+                TemplateError::InvalidGroupReference(_)
+            ))
+        ));
+        assert_eq!(
+            ReplacerSet::new([("a", r"\1")]).unwrap_err().to_string(),
+            "template of rule 0: the template refers to group `1`, which the pattern does not have"
+        );
+    }
+
+    #[test]
+    fn test_compile_options() {
+        let nested = |depth: usize| format!("{}a{}", "(".repeat(depth), ")".repeat(depth));
+        let regex = Regex::new(&nested(DEFAULT_NEST_LIMIT)).unwrap();
+        assert_eq!(regex.find("xa").map(|m| m.span()), Some((1, 2)));
+        assert!(matches!(
+            Regex::new(&nested(DEFAULT_NEST_LIMIT + 1)),
+            Err(ReError::ParsingFailed(ParserError::NestTooDeep(..)))
+        ));
+        let nest_limit = |limit: usize| CompileOptions::new().with_nest_limit(limit);
+        assert!(Regex::new_with_options(&nested(DEFAULT_NEST_LIMIT + 1), nest_limit(300)).is_ok());
+        assert!(Regex::new_with_options("[[a]]", nest_limit(1)).is_err());
+
+        // options combine, and the engine keeps them
+        let options = CompileOptions::new()
+            .with_flags(RegexFlags::IGNORECASE)
+            .with_max_repetition(3);
+        let regex = Regex::new_with_options("a{3}", options.clone()).unwrap();
+        assert!(regex.is_match("xAaA"));
+        assert!(Regex::new_with_options("a{4}", options).is_err());
+    }
+
+    #[test]
+    fn test_regex_set() {
+        #[derive(Debug, PartialEq)]
+        enum Rule {
+            Keyword,
+            Number,
+            Shout,
+        }
+        let set = RegexSet::new([
+            ("let|fn", RegexFlags::IGNORECASE, Rule::Keyword),
+            (r"\d+", RegexFlags::NO_FLAG, Rule::Number),
+            ("^[a-z]+!$", RegexFlags::MULTILINE, Rule::Shout),
+        ])
+        .unwrap();
+        assert_eq!(set.len(), 3);
+        assert_eq!(set.payload(1), Some(&Rule::Number));
+        let found: Vec<(&Rule, &str)> = set
+            .find_iter("LET x = 42\nhey!\nFn")
+            .map(|found| (found.payload, found.whole.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (&Rule::Keyword, "LET"),
+                (&Rule::Number, "42"),
+                (&Rule::Shout, "hey!"),
+                (&Rule::Keyword, "Fn"),
+            ]
+        );
+        // the flags of one pattern leave the others alone
+        let set = RegexSet::new([
+            ("a", RegexFlags::IGNORECASE, 'a'),
+            ("b", RegexFlags::NO_FLAG, 'b'),
+        ])
+        .unwrap();
+        let found: Vec<(usize, (usize, usize))> = set
+            .find_iter("AB ab")
+            .map(|found| (found.index, found.whole.span()))
+            .collect();
+        assert_eq!(found, [(0, (0, 1)), (0, (3, 4)), (1, (4, 5))]);
+
+        let error = RegexSet::new([("a", RegexFlags::NO_FLAG, 1), ("(", RegexFlags::NO_FLAG, 2)])
+            .unwrap_err();
+        assert_eq!(error.index, 1);
+        assert!(matches!(RegexError::from(error), RegexError::RegexSet(_)));
+    }
+
+    #[test]
+    fn test_api_is_panic_free() {
+        // patterns glued together from fragments that tend to break parsers,
+        // run through every entry point on small haystacks
+        let fragments = [
+            "a",
+            "b",
+            "(",
+            ")",
+            "|",
+            "*",
+            "+",
+            "?",
+            "{2}",
+            "{1,}",
+            "{,3}",
+            "[",
+            "]",
+            "^",
+            "$",
+            "\\b",
+            "\\d",
+            ".",
+            "(?i)",
+            "(?m)",
+            "(?x)",
+            "(?=",
+            "(?<=",
+            "(?<!",
+            "(?P<n>",
+            "(?P=n)",
+            "\\1",
+            "\\2",
+            "-",
+            "&&",
+            "[:alpha:]",
+            "\\",
+            "é",
+            "\n",
+            "(?:",
+            "(?>",
+            "{",
+            "}",
+            "#",
+            " ",
+            "\\Z",
+            "\\p{L}",
+            "(?C1)",
+            "*?",
+            "++",
+            "\\x4",
+            "(?-i)",
+            "[^",
+            "(a?)+",
+            "(a*)*",
+            "(?R)",
+            "(?1)",
+        ];
+        let alphabet: Vec<char> = "ab\nxé1 _".chars().collect();
+        let mut seed: u64 = 42;
+        let mut next = |bound: usize| {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) as usize % bound
+        };
+        // loops around groups that can match empty used to never return
+        let fixed = ["(a?)+c", "(a*)*c", "(?R)*"];
+        for round in 0..1500 {
+            let pattern: String = match fixed.get(round) {
+                Some(pattern) => pattern.to_string(),
+                None => (0..1 + next(6))
+                    .map(|_| fragments[next(fragments.len())])
+                    .collect(),
+            };
+            let texts: Vec<String> = (0..3)
+                .map(|_| {
+                    (0..next(7))
+                        .map(|_| alphabet[next(alphabet.len())])
+                        .collect()
+                })
+                .collect();
+            let outcome = std::panic::catch_unwind(|| {
+                let Ok(regex) = Regex::new(&pattern) else {
+                    return;
+                };
+                let regex = regex
+                    .with_callout_handler(CalloutHandler::new(|callout| callout.position % 2 == 0))
+                    .with_recursion_limit(3);
+                for text in &texts {
+                    for found in regex.find_iter(text) {
+                        for index in 0..regex.group_count() + 2 {
+                            found.group(index);
+                        }
+                        found.highlights();
+                        found.context(ContextSize::Lines(1));
+                    }
+                    for captures in regex.captures_iter(text) {
+                        captures.get(regex.group_count() + 1);
+                        captures.extract::<1>();
+                    }
+                    regex.rfind_iter(text).count();
+                    regex.match_positions(text).count();
+                    regex.find_tracked(text);
+                    regex.highlight_html(text);
+                    let _ = regex.replace_all(text, "<\\1\\g<n>>");
+                    regex.captures_bytes(b"a\xffb\n");
+                }
+            });
+            assert!(outcome.is_ok(), "panicked on {:?}", pattern);
+        }
+    }
+}
@@ -0,0 +1,325 @@
+//! Splits text into graphemes, words and sentences after the rules of Unicode
+//! text segmentation (UAX #29), as far as the general categories of the chars
+//! tell them apart, and the `split_*` helpers that pick out the segments a
+//! pattern matches in.
+//!
+//! Graphemes keep marks, joiners and variation selectors with their base char,
+//! `\r\n` together, and flags as pairs of regional indicators. Words keep
+//! apostrophes and dots between letters, as in `can't` and `e.g`, and dots and
+//! commas between digits, as in `1,000.5`; each Han or Hiragana char is a word
+//! of its own. A sentence ends after `.`, `!` or `?` and any closing quotes or
+//! brackets, once followed by whitespace, unless a `.` is followed by a word
+//! in lowercase, as after `p.m.`; it always ends at a line break.
+//!
+//! Segments tile the text. Their offsets count chars, as match offsets do.
+
+use std::ops::Range;
+
+use unicode_general_category::{get_general_category, GeneralCategory};
+use unicode_script::{Script, UnicodeScript};
+
+use crate::Regex;
+
+use GeneralCategory::*;
+
+fn is_regional_indicator(c: char) -> bool {
+    ('\u{1F1E6}'..='\u{1F1FF}').contains(&c)
+}
+
+fn is_control(c: char) -> bool {
+    matches!(
+        get_general_category(c),
+        Control | LineSeparator | ParagraphSeparator
+    )
+}
+
+/// Whether `c` belongs with the char before it: a mark, a joiner, or one of
+/// the skin tone modifiers, which are symbols only by category.
+fn extends(c: char) -> bool {
+    matches!(
+        get_general_category(c),
+        NonspacingMark | EnclosingMark | SpacingMark
+    ) || c == '\u{200D}'
+        || ('\u{1F3FB}'..='\u{1F3FF}').contains(&c)
+}
+
+/// Where each grapheme of `chars` ends.
+fn grapheme_breaks(chars: &[char]) -> impl Iterator<Item = usize> + '_ {
+    let mut regional_indicators = 0;
+    (1..=chars.len()).filter(move |&end| {
+        let before = chars[end - 1];
+        regional_indicators = match is_regional_indicator(before) {
+            true => regional_indicators + 1,
+            false => 0,
+        };
+        let Some(&after) = chars.get(end) else {
+            return true;
+        };
+        match (before, after) {
+            ('\r', '\n') => false,
+            _ if is_control(before) || is_control(after) => true,
+            _ if extends(after) => false,
+            ('\u{200D}', _) => get_general_category(after) != OtherSymbol,
+            _ => !(regional_indicators % 2 == 1 && is_regional_indicator(after)),
+        }
+    })
+}
+
+/// The char ranges of the graphemes of `text`, in order.
+pub fn graphemes(text: &str) -> Vec<Range<usize>> {
+    let chars: Vec<char> = text.chars().collect();
+    tile(grapheme_breaks(&chars))
+}
+
+/// Whether `c` can be part of a word: a letter, digit, mark or connector such
+/// as `_`.
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric()
+        || matches!(
+            get_general_category(c),
+            NonspacingMark | EnclosingMark | SpacingMark | ConnectorPunctuation
+        )
+}
+
+fn is_ideograph(c: char) -> bool {
+    matches!(c.script(), Script::Han | Script::Hiragana) && c.is_alphabetic()
+}
+
+/// Whether `c` keeps a word going between `before` and `after`, as the `'` in
+/// `can't` or the `,` in `1,000` do.
+fn joins(before: char, c: char, after: char) -> bool {
+    let letters = before.is_alphabetic() && after.is_alphabetic();
+    let digits = before.is_numeric() && after.is_numeric();
+    match c {
+        '\'' | '\u{2019}' | '.' => letters || digits,
+        ',' => digits,
+        _ => false,
+    }
+}
+
+fn is_horizontal_space(c: char) -> bool {
+    c == '\t' || get_general_category(c) == SpaceSeparator
+}
+
+/// Where each word, run of spaces or other char of `chars` ends.
+fn word_breaks(chars: &[char]) -> impl Iterator<Item = usize> + '_ {
+    (1..=chars.len()).filter(move |&end| {
+        let before = chars[end - 1];
+        let Some(&after) = chars.get(end) else {
+            return true;
+        };
+        let in_word = |index: usize| {
+            is_word_char(chars[index])
+                || (0 < index
+                    && index + 1 < chars.len()
+                    && joins(chars[index - 1], chars[index], chars[index + 1]))
+        };
+        match (before, after) {
+            ('\r', '\n') => false,
+            _ if is_control(after) || is_control(before) => true,
+            _ if is_horizontal_space(before) && is_horizontal_space(after) => false,
+            _ if extends(after) => false,
+            _ if is_ideograph(before) || is_ideograph(after) => true,
+            _ => !(in_word(end - 1) && in_word(end)),
+        }
+    })
+}
+
+/// The char ranges of the words of `text` and of everything between them, in
+/// order. Runs of spaces make one range and any other char not in a word one
+/// of its own.
+pub fn words(text: &str) -> Vec<Range<usize>> {
+    let chars: Vec<char> = text.chars().collect();
+    tile(word_breaks(&chars))
+}
+
+fn is_terminator(c: char) -> bool {
+    matches!(c, '.' | '!' | '?' | '\u{3002}' | '\u{FF01}' | '\u{FF1F}')
+}
+
+fn is_line_break(c: char) -> bool {
+    matches!(c, '\n' | '\r' | '\u{85}' | '\u{2028}' | '\u{2029}')
+}
+
+fn closes(c: char) -> bool {
+    matches!(c, '"' | '\'')
+        || matches!(get_general_category(c), ClosePunctuation | FinalPunctuation)
+}
+
+/// Where the sentence whose terminators start at `chars[start]` ends, with its
+/// closing punctuation, spaces and a line break, or `None` if it goes on.
+fn sentence_end(chars: &[char], start: usize) -> Option<usize> {
+    let mut end = start;
+    while chars.get(end).is_some_and(|&c| is_terminator(c)) {
+        end += 1;
+    }
+    while chars.get(end).is_some_and(|&c| closes(c)) {
+        end += 1;
+    }
+    let Some(&next) = chars.get(end) else {
+        return Some(end);
+    };
+    if !next.is_whitespace() {
+        return None;
+    }
+    while chars.get(end).is_some_and(|&c| is_horizontal_space(c)) {
+        end += 1;
+    }
+    match chars.get(end..) {
+        Some(['\r', '\n', ..]) => Some(end + 2),
+        Some([c, ..]) if is_line_break(*c) => Some(end + 1),
+        // a `.` before a word in lowercase ends an abbreviation instead
+        Some([c, ..]) if chars[start] == '.' && c.is_lowercase() => None,
+        _ => Some(end),
+    }
+}
+
+/// Where each sentence of `chars` ends.
+fn sentence_breaks(chars: &[char]) -> Vec<usize> {
+    let mut breaks = Vec::new();
+    let mut index = 0;
+    while index < chars.len() {
+        let c = chars[index];
+        let end = if c == '\r' && chars.get(index + 1) == Some(&'\n') {
+            Some(index + 2)
+        } else if is_line_break(c) {
+            Some(index + 1)
+        } else if is_terminator(c) {
+            sentence_end(chars, index)
+        } else {
+            None
+        };
+        match end {
+            Some(end) => {
+                breaks.push(end);
+                index = end;
+            }
+            None => index += 1,
+        }
+    }
+    if breaks.last() != Some(&chars.len()) && !chars.is_empty() {
+        breaks.push(chars.len());
+    }
+    breaks
+}
+
+/// The char ranges of the sentences of `text`, in order, each with the spaces
+/// and line break after it.
+pub fn sentences(text: &str) -> Vec<Range<usize>> {
+    let chars: Vec<char> = text.chars().collect();
+    tile(sentence_breaks(&chars))
+}
+
+/// The ranges between successive `breaks`, starting from 0.
+fn tile(breaks: impl IntoIterator<Item = usize>) -> Vec<Range<usize>> {
+    let mut start = 0;
+    breaks
+        .into_iter()
+        .map(|end| {
+            let range = start..end;
+            start = end;
+            range
+        })
+        .collect()
+}
+
+/// The segments of `text` at `ranges` that `filter` matches in, or that `keep`
+/// accepts if there is no filter.
+fn pick<'t>(
+    text: &'t str,
+    ranges: Vec<Range<usize>>,
+    filter: Option<&Regex>,
+    keep: impl Fn(&str) -> bool,
+) -> Vec<&'t str> {
+    let offsets: Vec<usize> = text
+        .char_indices()
+        .map(|(offset, _)| offset)
+        .chain([text.len()])
+        .collect();
+    ranges
+        .into_iter()
+        .map(|range| &text[offsets[range.start]..offsets[range.end]])
+        .filter(|segment| match filter {
+            Some(filter) => filter.is_match(segment),
+            None => keep(segment),
+        })
+        .collect()
+}
+
+/// The graphemes of `text`, or with a `filter` only those it matches in.
+pub fn split_graphemes<'t>(text: &'t str, filter: Option<&Regex>) -> Vec<&'t str> {
+    pick(text, graphemes(text), filter, |_| true)
+}
+
+/// The words of `text`, leaving out the spaces and punctuation between them,
+/// or with a `filter` the segments of `words` it matches in, whatever they are.
+pub fn split_words<'t>(text: &'t str, filter: Option<&Regex>) -> Vec<&'t str> {
+    pick(text, words(text), filter, |segment| {
+        segment.chars().any(char::is_alphanumeric)
+    })
+}
+
+/// The sentences of `text` with the whitespace after them, leaving out blank
+/// lines, or with a `filter` only the sentences it matches in.
+pub fn split_sentences<'t>(text: &'t str, filter: Option<&Regex>) -> Vec<&'t str> {
+    pick(text, sentences(text), filter, |segment| {
+        !segment.trim().is_empty()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_graphemes() {
+        let text = "e\u{301}a\r\n\u{1F1EB}\u{1F1F7}\u{1F1E9}\u{1F1EA}\u{1F469}\u{200D}\u{1F4BB}\u{1F44D}\u{1F3FD}";
+        assert_eq!(
+            graphemes(text),
+            vec![0..2, 2..3, 3..5, 5..7, 7..9, 9..12, 12..14]
+        );
+        let vowels = Regex::new("[aeiou]").unwrap();
+        assert_eq!(
+            split_graphemes("e\u{301}xa", Some(&vowels)),
+            ["e\u{301}", "a"]
+        );
+        assert!(graphemes("").is_empty());
+    }
+
+    #[test]
+    fn test_words() {
+        let text = "Can't stop,  e.g. 3.14 and 1,000 cats!\r\n漢字";
+        assert_eq!(
+            split_words(text, None),
+            ["Can't", "stop", "e.g", "3.14", "and", "1,000", "cats", "漢", "字"]
+        );
+        assert_eq!(&words(text)[..4], [0..5, 5..6, 6..10, 10..11]);
+        assert_eq!(
+            words(text).iter().map(|range| range.len()).sum::<usize>(),
+            text.chars().count()
+        );
+        let capitalized = Regex::new("^[A-Z]").unwrap();
+        assert_eq!(split_words(text, Some(&capitalized)), ["Can't"]);
+    }
+
+    #[test]
+    fn test_sentences() {
+        let text = "Hello there.  It is 3.14 p.m. today! \"Ok?\"\nNew line\n\nEnd";
+        assert_eq!(
+            split_sentences(text, None),
+            [
+                "Hello there.  ",
+                "It is 3.14 p.m. today! ",
+                "\"Ok?\"\n",
+                "New line\n",
+                "End"
+            ]
+        );
+        assert_eq!(sentences(text).len(), 6);
+        let numbers = Regex::new(r"\d").unwrap();
+        assert_eq!(
+            split_sentences(text, Some(&numbers)),
+            ["It is 3.14 p.m. today! "]
+        );
+    }
+}
@@ -0,0 +1,332 @@
+//! Suggests simpler patterns that match the same texts as a given one, each
+//! proven equivalent by `verify::equivalent` before it is offered.
+//!
+//! Every rule keeps the groups and their numbering, and only rewrites parts of
+//! the pattern that match the same text by preference, so a suggestion can
+//! replace the pattern outright. Patterns with named groups, free-spacing or
+//! anything `render` cannot write back get no suggestions.
+
+use std::fmt::Display;
+
+use itertools::Itertools;
+
+use crate::{
+    fsm::ReError,
+    parser::{escape_literal, run_parse, Node, ParsedRegex, Quantifier, INLINE_FLAGS},
+    utils::RegexFlags,
+    verify::equivalent,
+};
+
+/// A rewrite that can make a pattern simpler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rule {
+    /// `(?:ab)c` becomes `abc` and `(?:a)+` becomes `a+`.
+    RedundantGroup,
+    /// `a|[b-d]|x` becomes `[ab-dx]`.
+    MergeIntoClass,
+    /// `ab|c|ab` becomes `ab|c`, as the last `ab` can never be the one that matches.
+    UnreachableAlternative,
+}
+
+const RULES: [Rule; 3] = [
+    Rule::RedundantGroup,
+    Rule::UnreachableAlternative,
+    Rule::MergeIntoClass,
+];
+
+impl Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::RedundantGroup => write!(f, "non-capturing group can be dropped"),
+            Self::MergeIntoClass => write!(f, "single-char alternatives can be one class"),
+            Self::UnreachableAlternative => write!(f, "alternative repeats an earlier one"),
+        }
+    }
+}
+
+/// The pattern after applying `rule` everywhere it fits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Simplification {
+    pub rule: Rule,
+    pub pattern: String,
+}
+
+/// One suggestion for each rule that simplifies `pattern`, applied on its own.
+pub fn simplifications(pattern: &str) -> Result<Vec<Simplification>, ReError> {
+    let (parsed, flags) = parse(pattern)?;
+    if render(&parsed.root, flags).is_none() || !parsed.group_names.is_empty() {
+        return Ok(Vec::new());
+    }
+    Ok(RULES
+        .iter()
+        .filter_map(|&rule| {
+            let pattern = prove(&parsed.root, rewrite(&parsed.root, rule), flags)?;
+            Some(Simplification { rule, pattern })
+        })
+        .collect())
+}
+
+/// `pattern` with every rule applied until none fits, or the pattern as it was
+/// if none does.
+pub fn simplify(pattern: &str) -> Result<String, ReError> {
+    let mut simplified = pattern.to_string();
+    while let Some(simplification) = simplifications(&simplified)?.into_iter().next() {
+        if simplification.pattern == simplified {
+            break;
+        }
+        simplified = simplification.pattern;
+    }
+    Ok(simplified)
+}
+
+fn parse(pattern: &str) -> Result<(ParsedRegex, RegexFlags), ReError> {
+    let mut flags = RegexFlags::OPTIMIZE;
+    let parsed = run_parse(pattern, &mut flags).map_err(ReError::ParsingFailed)?;
+    Ok((parsed, flags))
+}
+
+/// `rewritten` as a pattern, if it differs from `original` and provably matches
+/// the same texts.
+fn prove(original: &Node, rewritten: Node, flags: RegexFlags) -> Option<String> {
+    if rewritten == *original || equivalent(original, &rewritten, flags) != Some(true) {
+        return None;
+    }
+    render(&rewritten, flags)
+}
+
+fn rewrite(node: &Node, rule: Rule) -> Node {
+    let recurse = |item: &Node| Box::new(rewrite(item, rule));
+    let node = match node {
+        Node::Expression(items, alternative) => {
+            let mut rewritten: Vec<Box<Node>> = Vec::with_capacity(items.len());
+            for item in items.iter().map(|item| rewrite(item, rule)) {
+                match item {
+                    Node::Group(inner, None, Quantifier::None) if rule == Rule::RedundantGroup => {
+                        match *inner {
+                            Node::Expression(inner, None) => rewritten.extend(inner),
+                            inner => rewritten.push(Box::new(Node::Group(
+                                Box::new(inner),
+                                None,
+                                Quantifier::None,
+                            ))),
+                        }
+                    }
+                    item => rewritten.push(Box::new(item)),
+                }
+            }
+            Node::Expression(rewritten, alternative.as_deref().map(recurse))
+        }
+        Node::Group(item, group_index, quantifier) => {
+            let item = rewrite(item, rule);
+            match (&item, group_index) {
+                (Node::Expression(items, None), None)
+                    if rule == Rule::RedundantGroup && items.len() == 1 =>
+                {
+                    match &*items[0] {
+                        Node::Match(atom, Quantifier::None) => {
+                            Node::Match(atom.clone(), quantifier.clone())
+                        }
+                        _ => Node::Group(Box::new(item), None, quantifier.clone()),
+                    }
+                }
+                _ => Node::Group(Box::new(item), *group_index, quantifier.clone()),
+            }
+        }
+        Node::Match(item, quantifier) => Node::Match(recurse(item), quantifier.clone()),
+        _ => node.clone(),
+    };
+    match rule {
+        Rule::MergeIntoClass => merge_into_class(node),
+        Rule::UnreachableAlternative => drop_repeated_alternatives(node),
+        Rule::RedundantGroup => node,
+    }
+}
+
+/// The alternatives of an expression, in order.
+fn alternatives(node: &Node) -> Vec<&[Box<Node>]> {
+    let mut alternatives = Vec::new();
+    let mut current = Some(node);
+    while let Some(Node::Expression(items, alternative)) = current {
+        alternatives.push(items.as_slice());
+        current = alternative.as_deref();
+    }
+    alternatives
+}
+
+/// Alternatives chained back into an expression.
+fn chain(alternatives: &[&[Box<Node>]]) -> Node {
+    alternatives
+        .iter()
+        .rev()
+        .fold(None, |alternative, items| {
+            Some(Node::Expression(items.to_vec(), alternative.map(Box::new)))
+        })
+        .expect("at least one alternative")
+}
+
+fn merge_into_class(node: Node) -> Node {
+    let branches = alternatives(&node);
+    if branches.len() < 2 {
+        return node;
+    }
+    let mut members: Vec<Box<Node>> = Vec::new();
+    for branch in branches {
+        match branch {
+            [item] => match &**item {
+                Node::Match(atom, Quantifier::None) => match &**atom {
+                    Node::Character(_) | Node::CharacterRange(_, _) => members.push(atom.clone()),
+                    Node::CharacterGroup(items, false) => members.extend(items.iter().cloned()),
+                    _ => return node,
+                },
+                _ => return node,
+            },
+            _ => return node,
+        }
+    }
+    Node::Expression(
+        vec![Box::new(Node::Match(
+            Box::new(Node::CharacterGroup(
+                members.into_iter().unique().collect(),
+                false,
+            )),
+            Quantifier::None,
+        ))],
+        None,
+    )
+}
+
+fn drop_repeated_alternatives(node: Node) -> Node {
+    let branches = alternatives(&node);
+    if branches.len() < 2 {
+        return node;
+    }
+    let mut kept: Vec<&[Box<Node>]> = Vec::new();
+    for branch in branches {
+        if !kept.contains(&branch) {
+            kept.push(branch);
+        }
+    }
+    chain(&kept)
+}
+
+/// `root` written back as a pattern under `flags`, or `None` if it uses a node
+/// that cannot be written back as the parser would read it.
+pub fn render(root: &Node, flags: RegexFlags) -> Option<String> {
+    if flags.intersects(RegexFlags::FREESPACING) {
+        return None;
+    }
+    let letters: String = INLINE_FLAGS
+        .iter()
+        .filter(|(_, flag)| flags.contains(*flag))
+        .map(|(letter, _)| letter)
+        .collect();
+    let mut pattern = if letters.is_empty() {
+        String::new()
+    } else {
+        format!("(?{})", letters)
+    };
+    render_node(root, &mut pattern)?;
+    Some(pattern)
+}
+
+fn render_node(node: &Node, out: &mut String) -> Option<()> {
+    match node {
+        Node::Character(c) => out.push_str(&escape_literal(*c)),
+        Node::Dot => out.push('.'),
+        Node::EmptyString => {}
+        Node::StartOfString => out.push('^'),
+        Node::EndOfString => out.push('$'),
+        Node::WordBoundary => out.push_str("\\b"),
+        Node::NonWordBoundary => out.push_str("\\B"),
+        Node::CharacterGroup(items, negated) => {
+            out.push('[');
+            if *negated {
+                out.push('^');
+            }
+            for item in items {
+                render_class_item(item, out)?;
+            }
+            out.push(']');
+        }
+        Node::Match(item, quantifier) => {
+            render_node(item, out)?;
+            out.push_str(&quantifier.to_string());
+        }
+        Node::Group(item, group_index, quantifier) => {
+            out.push_str(if group_index.is_some() { "(" } else { "(?:" });
+            render_node(item, out)?;
+            out.push(')');
+            out.push_str(&quantifier.to_string());
+        }
+        Node::Expression(items, alternative) => {
+            for item in items {
+                render_node(item, out)?;
+            }
+            if let Some(alternative) = alternative {
+                out.push('|');
+                render_node(alternative, out)?;
+            }
+        }
+        _ => return None,
+    }
+    Some(())
+}
+
+fn render_class_item(item: &Node, out: &mut String) -> Option<()> {
+    match item {
+        Node::Character(c) => out.push_str(&escape_literal(*c)),
+        Node::CharacterRange(start, end) => {
+            out.push_str(&escape_literal(*start));
+            out.push('-');
+            out.push_str(&escape_literal(*end));
+        }
+        Node::UnicodeProperty(property) => out.push_str(&property.to_string()),
+        // a class such as `\d` inside a group stands for its members
+        Node::CharacterGroup(items, false) => {
+            for item in items {
+                render_class_item(item, out)?;
+            }
+        }
+        _ => return None,
+    }
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_simplifications() {
+        let suggested = |pattern: &str| -> Vec<(Rule, String)> {
+            simplifications(pattern)
+                .unwrap()
+                .into_iter()
+                .map(|simplification| (simplification.rule, simplification.pattern))
+                .collect()
+        };
+        assert_eq!(
+            suggested("(?:ab)c(?:x)+"),
+            vec![(Rule::RedundantGroup, String::from("abcx+"))]
+        );
+        assert_eq!(
+            suggested("(a|[b-d]|\\.)z"),
+            vec![(Rule::MergeIntoClass, String::from("([ab-d\\.])z"))]
+        );
+        assert_eq!(
+            suggested("(?i)ab|c|ab"),
+            vec![(Rule::UnreachableAlternative, String::from("(?i)ab|c"))]
+        );
+        // the groups would lose their numbers, and named groups their names
+        assert!(suggested("(a)|(b)").is_empty());
+        assert!(suggested("(?P<x>(?:a))").is_empty());
+        assert!(suggested("a(?=b)|a(?=b)").is_empty());
+    }
+
+    #[test]
+    fn test_simplify() {
+        assert_eq!(simplify("(?:(?:a|b)|c)+d{2}").unwrap(), "[abc]+d{2}");
+        assert_eq!(simplify("x{1,}?y{3,5}").unwrap(), "x{1,}?y{3,5}");
+        assert!(simplify("a{").is_err());
+    }
+}
@@ -0,0 +1,168 @@
+use std::{collections::BTreeMap, error::Error, fmt::Display};
+
+use serde::Deserialize;
+
+use crate::regex::Regex;
+
+/// A regression suite of patterns and what they should match, read from TOML:
+///
+/// ```toml
+/// [[case]]
+/// name = "iso date"
+/// pattern = "(?P<year>[0-9]+)/(?P<month>[0-9]+)"
+/// text = "due 2024/05, paid 2024/06"
+/// matches = ["2024/05", "2024/06"]
+/// groups = { year = "2024", month = "05" }
+/// ```
+///
+/// Every expectation is optional: `is_match` checks whether the pattern matches
+/// at all, `matches` lists every match in order, and `groups` gives the text of
+/// named groups in the first match, with `""` for a group that must not participate.
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+pub struct Spec {
+    #[serde(rename = "case", default)]
+    pub cases: Vec<Case>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Case {
+    pub name: Option<String>,
+    pub pattern: String,
+    pub text: String,
+    pub is_match: Option<bool>,
+    pub matches: Option<Vec<String>>,
+    pub groups: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Debug)]
+pub struct SpecError(String);
+
+impl Display for SpecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for SpecError {}
+
+impl Spec {
+    pub fn parse(source: &str) -> Result<Spec, SpecError> {
+        toml::from_str(source).map_err(|error| SpecError(error.to_string()))
+    }
+
+    /// Runs every case, returning one outcome per case in order.
+    pub fn run(&self) -> Vec<Outcome> {
+        self.cases
+            .iter()
+            .enumerate()
+            .map(|(index, case)| Outcome {
+                name: case
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| format!("case {}", index + 1)),
+                failures: case.check(),
+            })
+            .collect()
+    }
+}
+
+impl Case {
+    /// Every way in which the pattern falls short of the case.
+    fn check(&self) -> Vec<String> {
+        let regex = match Regex::new(&self.pattern) {
+            Ok(regex) => regex,
+            Err(error) => return vec![format!("`{}` does not compile: {:?}", self.pattern, error)],
+        };
+        let mut failures: Vec<String> = Vec::new();
+        if let Some(expected) = self.is_match {
+            let actual = regex.is_match(&self.text);
+            if actual != expected {
+                failures.push(format!("expected is_match {}, got {}", expected, actual));
+            }
+        }
+        if let Some(expected) = &self.matches {
+            let actual: Vec<&str> = regex.find_iter(&self.text).map(|m| m.as_str()).collect();
+            if &actual != expected {
+                failures.push(format!("expected matches {:?}, got {:?}", expected, actual));
+            }
+        }
+        if let Some(expected) = &self.groups {
+            match regex.captures(&self.text) {
+                Some(captures) => {
+                    for (name, expected) in expected {
+                        let actual = captures.name(name).unwrap_or("");
+                        if actual != expected {
+                            failures.push(format!(
+                                "expected group `{}` to be {:?}, got {:?}",
+                                name, expected, actual
+                            ));
+                        }
+                    }
+                }
+                None => failures.push(String::from("expected groups, but nothing matched")),
+            }
+        }
+        failures
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Outcome {
+    pub name: String,
+    pub failures: Vec<String>,
+}
+
+impl Outcome {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_spec() {
+        let spec = Spec::parse(
+            r#"
+            [[case]]
+            name = "dates"
+            pattern = "(?P<year>[0-9]+)/(?P<month>[0-9]+)(?P<day>/[0-9]+)?"
+            text = "due 2024/05, paid 2024/06"
+            matches = ["2024/05", "2024/06"]
+            groups = { year = "2024", month = "05", day = "" }
+
+            [[case]]
+            pattern = "[a-z]+"
+            text = "123"
+            is_match = true
+            matches = ["123"]
+            "#,
+        )
+        .unwrap();
+        let outcomes = spec.run();
+        assert!(outcomes[0].passed(), "{:?}", outcomes[0]);
+        assert_eq!(
+            outcomes[1],
+            Outcome {
+                name: String::from("case 2"),
+                failures: vec![
+                    String::from("expected is_match true, got false"),
+                    String::from(r#"expected matches ["123"], got []"#),
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_bad_spec() {
+        assert!(Spec::parse("[[case]]\npattern = \"a\"").is_err());
+        assert!(Spec::parse("[[case]]\npattern = \"a\"\ntext = \"a\"\ntypo = 1").is_err());
+        let outcomes = Spec::parse("[[case]]\npattern = \"a{2,1}\"\ntext = \"\"")
+            .unwrap()
+            .run();
+        assert!(!outcomes[0].passed());
+    }
+}
@@ -0,0 +1,535 @@
+use std::{collections::HashMap, fmt::Display, sync::OnceLock};
+
+use itertools::{Either, Itertools};
+
+use unicode_blocks::{find_unicode_block, UnicodeBlock};
+use unicode_general_category::{get_general_category, GeneralCategory};
+use unicode_script::ScriptExtension;
+
+use crate::utils::next_char;
+
+use GeneralCategory::*;
+
+/// A general category or group of categories sharing a first letter, as named
+/// by `\p{Lu}` or `\p{L}`, or a block, as named by `\p{InGreek}`.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub struct Property {
+    pub abbreviation: &'static str,
+    pub name: &'static str,
+    members: Members,
+}
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+enum Members {
+    Categories(&'static [GeneralCategory]),
+    /// The first and last char of a block.
+    Block(char, char),
+}
+
+const fn property(
+    abbreviation: &'static str,
+    name: &'static str,
+    categories: &'static [GeneralCategory],
+) -> Property {
+    Property {
+        abbreviation,
+        name,
+        members: Members::Categories(categories),
+    }
+}
+
+/// Every property `\p{...}` can name, with the long names of UAX #44.
+static PROPERTIES: &[Property] = &[
+    property(
+        "L",
+        "Letter",
+        &[
+            UppercaseLetter,
+            LowercaseLetter,
+            TitlecaseLetter,
+            ModifierLetter,
+            OtherLetter,
+        ],
+    ),
+    property(
+        "LC",
+        "Cased_Letter",
+        &[UppercaseLetter, LowercaseLetter, TitlecaseLetter],
+    ),
+    property("Lu", "Uppercase_Letter", &[UppercaseLetter]),
+    property("Ll", "Lowercase_Letter", &[LowercaseLetter]),
+    property("Lt", "Titlecase_Letter", &[TitlecaseLetter]),
+    property("Lm", "Modifier_Letter", &[ModifierLetter]),
+    property("Lo", "Other_Letter", &[OtherLetter]),
+    property("M", "Mark", &[NonspacingMark, SpacingMark, EnclosingMark]),
+    property("Mn", "Nonspacing_Mark", &[NonspacingMark]),
+    property("Mc", "Spacing_Mark", &[SpacingMark]),
+    property("Me", "Enclosing_Mark", &[EnclosingMark]),
+    property("N", "Number", &[DecimalNumber, LetterNumber, OtherNumber]),
+    property("Nd", "Decimal_Number", &[DecimalNumber]),
+    property("Nl", "Letter_Number", &[LetterNumber]),
+    property("No", "Other_Number", &[OtherNumber]),
+    property(
+        "P",
+        "Punctuation",
+        &[
+            ConnectorPunctuation,
+            DashPunctuation,
+            OpenPunctuation,
+            ClosePunctuation,
+            InitialPunctuation,
+            FinalPunctuation,
+            OtherPunctuation,
+        ],
+    ),
+    property("Pc", "Connector_Punctuation", &[ConnectorPunctuation]),
+    property("Pd", "Dash_Punctuation", &[DashPunctuation]),
+    property("Ps", "Open_Punctuation", &[OpenPunctuation]),
+    property("Pe", "Close_Punctuation", &[ClosePunctuation]),
+    property("Pi", "Initial_Punctuation", &[InitialPunctuation]),
+    property("Pf", "Final_Punctuation", &[FinalPunctuation]),
+    property("Po", "Other_Punctuation", &[OtherPunctuation]),
+    property(
+        "S",
+        "Symbol",
+        &[MathSymbol, CurrencySymbol, ModifierSymbol, OtherSymbol],
+    ),
+    property("Sm", "Math_Symbol", &[MathSymbol]),
+    property("Sc", "Currency_Symbol", &[CurrencySymbol]),
+    property("Sk", "Modifier_Symbol", &[ModifierSymbol]),
+    property("So", "Other_Symbol", &[OtherSymbol]),
+    property(
+        "Z",
+        "Separator",
+        &[SpaceSeparator, LineSeparator, ParagraphSeparator],
+    ),
+    property("Zs", "Space_Separator", &[SpaceSeparator]),
+    property("Zl", "Line_Separator", &[LineSeparator]),
+    property("Zp", "Paragraph_Separator", &[ParagraphSeparator]),
+    property(
+        "C",
+        "Other",
+        &[Control, Format, Surrogate, PrivateUse, Unassigned],
+    ),
+    property("Cc", "Control", &[Control]),
+    property("Cf", "Format", &[Format]),
+    property("Cs", "Surrogate", &[Surrogate]),
+    property("Co", "Private_Use", &[PrivateUse]),
+    property("Cn", "Unassigned", &[Unassigned]),
+];
+
+/// Other names UAX #44 gives to some of the properties.
+static ALIASES: &[(&str, &str)] = &[
+    ("Combining_Mark", "M"),
+    ("digit", "Nd"),
+    ("punct", "P"),
+    ("cntrl", "Cc"),
+];
+
+/// Older names of blocks, which Java still accepts.
+static BLOCK_ALIASES: &[(&str, &str)] = &[
+    ("Greek", "Greek and Coptic"),
+    ("Cyrillic Supplementary", "Cyrillic Supplement"),
+    (
+        "Combining Marks for Symbols",
+        "Combining Diacritical Marks for Symbols",
+    ),
+];
+
+/// Every block that holds at least one char, in code point order. Built once,
+/// on first use.
+fn blocks() -> &'static [UnicodeBlock] {
+    static BLOCKS: OnceLock<Vec<UnicodeBlock>> = OnceLock::new();
+    BLOCKS.get_or_init(|| {
+        let mut blocks: Vec<UnicodeBlock> = Vec::new();
+        let mut c = Some('\0');
+        while let Some(current) = c {
+            c = match find_unicode_block(current) {
+                Some(block) => {
+                    blocks.push(block);
+                    char::from_u32(block.end()).and_then(next_char)
+                }
+                None => next_char(current),
+            };
+        }
+        blocks
+    })
+}
+
+fn block_property(block: &UnicodeBlock) -> Property {
+    let first = char::from_u32(block.start()).expect("blocks holding chars start at one");
+    let last = char::from_u32(block.end()).expect("blocks holding chars end at one");
+    Property {
+        abbreviation: block.name(),
+        name: block.name(),
+        members: Members::Block(first, last),
+    }
+}
+
+/// The block `name` loosely refers to, after the `In` prefix.
+fn lookup_block(name: &str) -> Option<Property> {
+    let name = BLOCK_ALIASES
+        .iter()
+        .find(|(alias, _)| loose(alias) == name)
+        .map(|(_, block)| loose(block))
+        .unwrap_or_else(|| String::from(name));
+    blocks()
+        .iter()
+        .find(|block| loose(block.name()) == name)
+        .map(block_property)
+}
+
+/// The loose form of a property name: UAX #44 ignores case, whitespace,
+/// underscores and hyphens when comparing names.
+fn loose(name: &str) -> String {
+    name.chars()
+        .filter(|c| !(c.is_whitespace() || *c == '_' || *c == '-'))
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != *b);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+impl Property {
+    /// Looks `name` up loosely among abbreviations, long names and aliases, or
+    /// among blocks if it starts with `In`.
+    pub fn lookup(name: &str) -> Option<Property> {
+        let name = loose(name);
+        if let Some(block) = name.strip_prefix("in").and_then(lookup_block) {
+            return Some(block);
+        }
+        let abbreviation = ALIASES
+            .iter()
+            .find(|(alias, _)| loose(alias) == name)
+            .map(|(_, abbreviation)| loose(abbreviation))
+            .unwrap_or(name);
+        PROPERTIES.iter().copied().find(|property| {
+            loose(property.abbreviation) == abbreviation || loose(property.name) == abbreviation
+        })
+    }
+
+    /// Long names within a small edit distance of `name`, closest first. Names
+    /// starting with `In` are compared against blocks instead.
+    pub fn candidates(name: &str) -> Vec<String> {
+        let name = loose(name);
+        let threshold = (name.len() / 3).max(1);
+        let names: Vec<(String, String)> = match name.strip_prefix("in") {
+            Some(_) => blocks()
+                .iter()
+                .map(|block| {
+                    let spelled = format!("In{}", block.name().replace(' ', "_"));
+                    (loose(&spelled), spelled)
+                })
+                .collect(),
+            None => PROPERTIES
+                .iter()
+                .flat_map(|property| {
+                    [property.name, property.abbreviation]
+                        .map(|spelling| (loose(spelling), String::from(property.name)))
+                })
+                .collect(),
+        };
+        names
+            .into_iter()
+            .map(|(loose_name, spelled)| (edit_distance(&name, &loose_name), spelled))
+            .filter(|(distance, _)| *distance <= threshold)
+            .sorted_by_key(|(distance, _)| *distance)
+            .map(|(_, spelled)| spelled)
+            .unique()
+            .collect()
+    }
+
+    pub fn contains(&self, c: char) -> bool {
+        match self.members {
+            Members::Categories(categories) => categories.contains(&get_general_category(c)),
+            Members::Block(first, last) => (first..=last).contains(&c),
+        }
+    }
+
+    /// The first char of every maximal run of chars the property agrees on.
+    pub fn boundaries(&self) -> impl Iterator<Item = char> + '_ {
+        match self.members {
+            Members::Categories(categories) => Either::Left(
+                category_runs()
+                    .iter()
+                    .map(|(start, category)| (*start, categories.contains(category)))
+                    .scan(None, |previous, (start, member)| {
+                        let changed = *previous != Some(member);
+                        *previous = Some(member);
+                        Some(changed.then_some(start))
+                    })
+                    .flatten(),
+            ),
+            Members::Block(first, last) => {
+                Either::Right(['\0', first].into_iter().dedup().chain(next_char(last)))
+            }
+        }
+    }
+}
+
+impl Property {
+    /// The name `lookup` finds the property by, as written between the braces
+    /// of `\p{...}`.
+    fn spelled(&self) -> String {
+        match self.members {
+            Members::Categories(_) => String::from(self.abbreviation),
+            Members::Block(_, _) => format!("In{}", self.name.replace(' ', "_")),
+        }
+    }
+}
+
+impl Display for Property {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "\\p{{{}}}", self.spelled())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Property {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.spelled())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Property {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Property::lookup(&name)
+            .ok_or_else(|| serde::de::Error::custom(format!("unknown property `{}`", name)))
+    }
+}
+
+/// Whether `chars` all belong to one script. Common and Inherited characters
+/// such as digits, punctuation and combining marks fit in with any script.
+pub fn is_script_run(chars: &[char]) -> bool {
+    let mut scripts = ScriptExtension::default();
+    for c in chars {
+        scripts.intersect_with(ScriptExtension::from(*c));
+    }
+    !scripts.is_empty()
+}
+
+/// The whole char space split into maximal runs sharing a general category,
+/// each given by its first char. Built once, on first use.
+fn category_runs() -> &'static [(char, GeneralCategory)] {
+    static RUNS: OnceLock<Vec<(char, GeneralCategory)>> = OnceLock::new();
+    RUNS.get_or_init(|| {
+        let mut runs: Vec<(char, GeneralCategory)> = Vec::new();
+        let mut c = Some('\0');
+        while let Some(current) = c {
+            let category = get_general_category(current);
+            if runs.last().map(|(_, last)| *last) != Some(category) {
+                runs.push((current, category));
+            }
+            c = next_char(current);
+        }
+        runs
+    })
+}
+
+/// The chars with another case, each with the index of its orbit: the chars
+/// simple case folding takes to the same char, itself among them. Sorted by
+/// char. Built once, on first use.
+struct CaseOrbits {
+    members: Vec<(char, usize)>,
+    orbits: Vec<Vec<char>>,
+}
+
+/// What a char is in the one other case the standard library maps it to, or
+/// the char itself if that takes more than one char.
+fn simple_case(mut mapped: impl ExactSizeIterator<Item = char>, c: char) -> char {
+    match mapped.len() {
+        1 => mapped.next().unwrap_or(c),
+        _ => c,
+    }
+}
+
+/// Past this char nothing has another case.
+const LAST_CASED: char = '\u{1FFFF}';
+
+fn case_orbits() -> &'static CaseOrbits {
+    static ORBITS: OnceLock<CaseOrbits> = OnceLock::new();
+    ORBITS.get_or_init(|| {
+        let mut by_fold: HashMap<char, Vec<char>> = HashMap::new();
+        let mut c = Some('\0');
+        while let Some(current) = c.filter(|&c| c <= LAST_CASED) {
+            let upper = simple_case(current.to_uppercase(), current);
+            let fold = simple_case(upper.to_lowercase(), upper);
+            // dotless `ı` only folds to `i` in Turkic languages
+            if fold != current && current != 'ı' {
+                by_fold.entry(fold).or_default().push(current);
+            }
+            c = next_char(current);
+        }
+        // what a char folds to is in its orbit, though it maps to nothing itself
+        let mut orbits: Vec<Vec<char>> = by_fold
+            .into_iter()
+            .map(|(fold, mut orbit)| {
+                orbit.push(fold);
+                orbit.sort();
+                orbit
+            })
+            .collect();
+        orbits.sort();
+        let mut members: Vec<(char, usize)> = orbits
+            .iter()
+            .enumerate()
+            .flat_map(|(index, orbit)| orbit.iter().map(move |&c| (c, index)))
+            .collect();
+        members.sort();
+        CaseOrbits { members, orbits }
+    })
+}
+
+/// The chars that match `c` when case is ignored, `c` among them, in order, or
+/// `None` if `c` has no other case. Simple case folding as `char::to_uppercase`
+/// and `char::to_lowercase` give it, so `é` goes with `É` and `k` with `K` and
+/// the Kelvin sign, though `ß` not with `SS` nor, outside Turkic, `ı` with `i`.
+pub fn case_orbit(c: char) -> Option<&'static [char]> {
+    let orbits = case_orbits();
+    let index = orbits
+        .members
+        .binary_search_by_key(&c, |&(member, _)| member)
+        .ok()?;
+    Some(&orbits.orbits[orbits.members[index].1])
+}
+
+/// Whether `c`, or a char matching it when case is ignored, passes `test`.
+pub fn either_case(c: char, test: impl Fn(char) -> bool) -> bool {
+    match case_orbit(c) {
+        Some(orbit) => orbit.iter().any(|&c| test(c)),
+        None => test(c),
+    }
+}
+
+/// Every orbit `case_orbit` gives with a char in `start..=end`.
+pub fn case_orbits_within(start: char, end: char) -> impl Iterator<Item = &'static [char]> {
+    let orbits = case_orbits();
+    let first = orbits
+        .members
+        .partition_point(|&(member, _)| member < start);
+    let last = orbits.members.partition_point(|&(member, _)| member <= end);
+    orbits.members[first..last]
+        .iter()
+        .map(|&(_, index)| index)
+        .unique()
+        .map(|index| orbits.orbits[index].as_slice())
+}
+
+/// Every orbit `case_orbit` gives of more than one char.
+pub fn all_case_orbits() -> impl Iterator<Item = &'static [char]> {
+    case_orbits().orbits.iter().map(Vec::as_slice)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_loose_lookup() {
+        for name in ["L", "l", "Letter", "LETTER", "le tt-er"] {
+            assert_eq!(
+                Property::lookup(name).unwrap().abbreviation,
+                "L",
+                "{}",
+                name
+            );
+        }
+        for name in [
+            "Ll",
+            "lowercase_letter",
+            "Lowercase-Letter",
+            "LowercaseLetter",
+        ] {
+            assert_eq!(
+                Property::lookup(name).unwrap().abbreviation,
+                "Ll",
+                "{}",
+                name
+            );
+        }
+        assert_eq!(Property::lookup("digit").unwrap().abbreviation, "Nd");
+        assert_eq!(Property::lookup("Lettre"), None);
+    }
+
+    #[test]
+    fn test_candidates() {
+        assert_eq!(Property::candidates("Lettr"), vec![String::from("Letter")]);
+        assert_eq!(
+            Property::candidates("uppercase_leter"),
+            vec![
+                String::from("Uppercase_Letter"),
+                String::from("Lowercase_Letter")
+            ]
+        );
+        assert!(Property::candidates("Greek").is_empty());
+        assert_eq!(
+            Property::candidates("InGrek_and_Coptic"),
+            vec![String::from("InGreek_and_Coptic")]
+        );
+    }
+
+    #[test]
+    fn test_blocks() {
+        let greek = Property::lookup("InGreek").unwrap();
+        assert_eq!(greek, Property::lookup("in greek and coptic").unwrap());
+        assert!(greek.contains('λ'));
+        assert!(!greek.contains('a'));
+        assert_eq!(greek.to_string(), "\\p{InGreek_and_Coptic}");
+        let boundaries: Vec<char> = greek.boundaries().collect();
+        assert_eq!(boundaries, vec!['\0', '\u{370}', '\u{400}']);
+        assert!(Property::lookup("InBasicLatin").unwrap().contains('~'));
+        assert_eq!(Property::lookup("InKlingon"), None);
+    }
+
+    #[test]
+    fn test_is_script_run() {
+        let chars = |text: &str| text.chars().collect::<Vec<char>>();
+        assert!(is_script_run(&chars("")));
+        assert!(is_script_run(&chars("paypal 123!")));
+        assert!(is_script_run(&chars("παράδειγμα")));
+        // the second letter is a Cyrillic `а`
+        assert!(!is_script_run(&chars("pаypal")));
+    }
+
+    #[test]
+    fn test_contains_and_boundaries() {
+        let letter = Property::lookup("L").unwrap();
+        assert!(letter.contains('é'));
+        assert!(!letter.contains('1'));
+
+        let digits = Property::lookup("Nd").unwrap();
+        let boundaries: Vec<char> = digits.boundaries().take(3).collect();
+        assert_eq!(boundaries, vec!['\0', '0', ':']);
+    }
+
+    #[test]
+    fn test_case_orbit() {
+        assert_eq!(case_orbit('é'), Some(&['É', 'é'][..]));
+        assert_eq!(case_orbit('k'), Some(&['K', 'k', '\u{212A}'][..]));
+        assert_eq!(case_orbit('ς'), Some(&['Σ', 'ς', 'σ'][..]));
+        assert_eq!(case_orbit('ẞ'), Some(&['ß', 'ẞ'][..]));
+        assert_eq!(case_orbit('ı'), None);
+        assert_eq!(case_orbit('1'), None);
+        assert!(either_case('É', |c| c == 'é'));
+        // every char is in one orbit at most
+        let members = &case_orbits().members;
+        assert!(members.windows(2).all(|pair| pair[0].0 < pair[1].0));
+
+        let orbits: Vec<&[char]> = case_orbits_within('y', 'z').collect();
+        assert_eq!(orbits, [&['Y', 'y'][..], &['Z', 'z'][..]]);
+    }
+}
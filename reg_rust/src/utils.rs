@@ -1,4 +1,10 @@
+use std::{
+    fmt::Debug,
+    mem::{size_of, size_of_val},
+};
+
 use bitflags::bitflags;
+use memchr::memmem;
 
 bitflags! {
     pub struct RegexFlags: u32 {
@@ -12,3 +18,503 @@ bitflags! {
         const DEBUG = 1 << 7;
     }
 }
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for RegexFlags {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u32(self.bits())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for RegexFlags {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bits = u32::deserialize(deserializer)?;
+        RegexFlags::from_bits(bits)
+            .ok_or_else(|| serde::de::Error::custom(format!("unknown flag bits {:#x}", bits)))
+    }
+}
+
+/// `c` lowercased, when its lowercase form is a single character.
+pub fn simple_lowercase(c: char) -> char {
+    let mut lower = c.to_lowercase();
+    match (lower.next(), lower.next()) {
+        (Some(lower), None) => lower,
+        _ => c,
+    }
+}
+
+/// Maps the full-width forms of ASCII (U+FF01 to U+FF5E) and the ideographic
+/// space to their ASCII counterparts.
+pub fn full_width_to_half_width(c: char) -> char {
+    match c {
+        '\u{3000}' => ' ',
+        '\u{FF01}'..='\u{FF5E}' => char::from_u32(c as u32 - 0xFEE0).unwrap(),
+        _ => c,
+    }
+}
+
+/// Strips the accent from the accented Latin-1 letters, so `é` reads as `e`.
+pub fn ascii_fold(c: char) -> char {
+    match c {
+        'À'..='Å' => 'A',
+        'Ç' => 'C',
+        'È'..='Ë' => 'E',
+        'Ì'..='Ï' => 'I',
+        'Ñ' => 'N',
+        'Ò'..='Ö' | 'Ø' => 'O',
+        'Ù'..='Ü' => 'U',
+        'Ý' => 'Y',
+        'à'..='å' => 'a',
+        'ç' => 'c',
+        'è'..='ë' => 'e',
+        'ì'..='ï' => 'i',
+        'ñ' => 'n',
+        'ò'..='ö' | 'ø' => 'o',
+        'ù'..='ü' => 'u',
+        'ý' | 'ÿ' => 'y',
+        _ => c,
+    }
+}
+
+/// A set of integers drawn from `0..capacity` supporting O(1) `insert`, `contains`
+/// and `clear` with no hashing, iterating in insertion order.
+///
+/// `dense` holds the members in insertion order and `sparse[v]` holds the index of
+/// `v` within `dense`. A value is a member only if the two arrays point at each
+/// other, so stale entries left behind by `clear` are harmless and never need zeroing.
+#[derive(Debug, Clone)]
+pub struct SparseSet {
+    dense: Vec<usize>,
+    sparse: Vec<usize>,
+    len: usize,
+}
+
+impl SparseSet {
+    pub fn new(capacity: usize) -> SparseSet {
+        SparseSet {
+            dense: vec![0; capacity],
+            sparse: vec![0; capacity],
+            len: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.dense.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Inserts `value`, returning `false` if it was already present.
+    pub fn insert(&mut self, value: usize) -> bool {
+        if self.contains(value) {
+            return false;
+        }
+        self.dense[self.len] = value;
+        self.sparse[value] = self.len;
+        self.len += 1;
+        true
+    }
+
+    pub fn contains(&self, value: usize) -> bool {
+        let index = self.sparse[value];
+        index < self.len && self.dense[index] == value
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &usize> {
+        self.dense[..self.len].iter()
+    }
+}
+
+/// A partition of the whole char space into classes of characters that none of a
+/// pattern's matchers can tell apart. Class `i` is the half-open interval
+/// `starts[i]..starts[i + 1]`, so a pattern like `[a-c]+x` needs five classes:
+/// the chars before `a`, `a` to `c`, those up to `x`, `x`, and the rest.
+/// Searches look up whether a match can start in a char's class; the
+/// transitions themselves still test the chars they read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EquivalenceClasses {
+    starts: Vec<char>,
+}
+
+impl EquivalenceClasses {
+    /// Builds the partition from the chars at which some matcher's answer may change.
+    pub fn new(mut boundaries: Vec<char>) -> EquivalenceClasses {
+        boundaries.push('\0');
+        boundaries.sort_unstable();
+        boundaries.dedup();
+        EquivalenceClasses { starts: boundaries }
+    }
+
+    pub fn len(&self) -> usize {
+        self.starts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.starts.is_empty()
+    }
+
+    pub fn class_of(&self, c: char) -> usize {
+        self.starts.partition_point(|start| *start <= c) - 1
+    }
+
+    /// The smallest char of `class`; any member would do as all behave the same.
+    pub fn representative(&self, class: usize) -> char {
+        self.starts[class]
+    }
+
+    pub fn representatives(&self) -> impl Iterator<Item = char> + '_ {
+        self.starts.iter().copied()
+    }
+
+    /// The first and last char of `class`.
+    pub fn range(&self, class: usize) -> (char, char) {
+        let end = match self.starts.get(class + 1) {
+            Some('\u{E000}') => '\u{D7FF}',
+            Some(next) => char::from_u32(*next as u32 - 1).expect("boundaries skip surrogates"),
+            None => char::MAX,
+        };
+        (self.starts[class], end)
+    }
+
+    /// Bytes taken by the class boundaries.
+    pub fn memory_usage(&self) -> usize {
+        self.starts.len() * size_of::<char>()
+    }
+}
+
+/// The char following `c`, skipping the surrogate gap; `None` after `char::MAX`.
+pub fn next_char(c: char) -> Option<char> {
+    match c {
+        '\u{D7FF}' => Some('\u{E000}'),
+        _ => char::from_u32(c as u32 + 1),
+    }
+}
+
+/// Whether each of `literals` occurs in `haystack`, each one after the end of
+/// the one before.
+pub fn contains_in_order(haystack: &str, literals: &[String]) -> bool {
+    let mut rest = haystack.as_bytes();
+    literals
+        .iter()
+        .all(|literal| match memmem::find(rest, literal.as_bytes()) {
+            Some(start) => {
+                rest = &rest[start + literal.len()..];
+                true
+            }
+            None => false,
+        })
+}
+
+/// Literals every match contains, in the order they appear in it, for ruling out
+/// a haystack before running an engine. Folded literals are ASCII-lowercased and
+/// looked for in the lowercased haystack, as some of their chars may match in
+/// either case.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RequiredLiterals {
+    literals: Vec<String>,
+    folded: bool,
+}
+
+/// What engines that know no literals report.
+pub static NO_REQUIRED_LITERALS: RequiredLiterals = RequiredLiterals {
+    literals: Vec::new(),
+    folded: false,
+};
+
+impl RequiredLiterals {
+    pub fn new(literals: Vec<String>, folded: bool) -> RequiredLiterals {
+        let literals = if folded {
+            literals
+                .into_iter()
+                .map(|literal| literal.to_ascii_lowercase())
+                .collect()
+        } else {
+            literals
+        };
+        RequiredLiterals { literals, folded }
+    }
+
+    pub fn literals(&self) -> &[String] {
+        &self.literals
+    }
+
+    pub fn is_folded(&self) -> bool {
+        self.folded
+    }
+
+    /// Whether `haystack` contains every literal, each after the one before.
+    pub fn found_in(&self, haystack: &str) -> bool {
+        if self.folded && !self.literals.is_empty() {
+            contains_in_order(&haystack.to_ascii_lowercase(), &self.literals)
+        } else {
+            contains_in_order(haystack, &self.literals)
+        }
+    }
+}
+
+/// Above this many case variants a case-insensitive prefix is searched for folded instead.
+const MAX_CASE_VARIANTS: usize = 16;
+
+/// Finds where matches may start so a search can skip everything in between.
+/// Engines supply a `LiteralPrefilter` when their pattern has a literal prefix,
+/// and `Regex::with_prefilter` installs any other implementation, such as one
+/// backed by an index of the haystack kept outside the engine.
+pub trait Prefilter: Debug {
+    /// The first position in `start..end` at which a match might start, or `None`
+    /// if no match can start there. It must never skip a real match start.
+    fn find(&self, text: &[char], start: usize, end: usize) -> Option<usize>;
+
+    /// Bytes held by the prefilter.
+    fn memory_usage(&self) -> usize {
+        size_of_val(self)
+    }
+}
+
+/// A literal every match starts with, used to jump straight to candidate starts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LiteralPrefilter {
+    /// Any of these spellings of the prefix, e.g. `ab`, `aB`, `Ab` and `AB` for
+    /// a case-insensitive `ab`.
+    Variants(Vec<Vec<char>>),
+    /// The ASCII-lowercased prefix, compared against the lowercased haystack.
+    Folded(Vec<char>),
+}
+
+impl LiteralPrefilter {
+    /// Returns `None` for an empty prefix, which cannot rule out any position.
+    pub fn new(prefix: &[char], ignore_case: bool) -> Option<LiteralPrefilter> {
+        if prefix.is_empty() {
+            return None;
+        }
+        if !ignore_case {
+            return Some(LiteralPrefilter::Variants(vec![prefix.to_vec()]));
+        }
+        let letters = prefix.iter().filter(|c| c.is_ascii_alphabetic()).count();
+        if letters >= usize::BITS as usize || 1 << letters > MAX_CASE_VARIANTS {
+            return Some(LiteralPrefilter::Folded(
+                prefix.iter().map(|c| c.to_ascii_lowercase()).collect(),
+            ));
+        }
+        let mut variants: Vec<Vec<char>> = vec![Vec::new()];
+        for c in prefix {
+            if c.is_ascii_alphabetic() {
+                variants = variants
+                    .into_iter()
+                    .flat_map(|variant| {
+                        [c.to_ascii_lowercase(), c.to_ascii_uppercase()].map(|c| {
+                            let mut variant = variant.clone();
+                            variant.push(c);
+                            variant
+                        })
+                    })
+                    .collect();
+            } else {
+                variants.iter_mut().for_each(|variant| variant.push(*c));
+            }
+        }
+        Some(LiteralPrefilter::Variants(variants))
+    }
+}
+
+impl Prefilter for LiteralPrefilter {
+    /// The first position in `start..end` at which the prefix occurs and fits before `end`.
+    fn find(&self, text: &[char], start: usize, end: usize) -> Option<usize> {
+        let end = end.min(text.len());
+        match self {
+            LiteralPrefilter::Variants(variants) => (start..end).find(|&position| {
+                variants
+                    .iter()
+                    .any(|variant| text[position..end].starts_with(variant))
+            }),
+            LiteralPrefilter::Folded(prefix) => (start..end).find(|&position| {
+                position + prefix.len() <= end
+                    && text[position..position + prefix.len()]
+                        .iter()
+                        .zip(prefix)
+                        .all(|(c, p)| c.to_ascii_lowercase() == *p)
+            }),
+        }
+    }
+
+    /// Bytes taken by the spellings of the prefix.
+    fn memory_usage(&self) -> usize {
+        match self {
+            LiteralPrefilter::Variants(variants) => variants
+                .iter()
+                .map(|variant| size_of::<Vec<char>>() + variant.len() * size_of::<char>())
+                .sum(),
+            LiteralPrefilter::Folded(prefix) => prefix.len() * size_of::<char>(),
+        }
+    }
+}
+
+/// Match starts known ahead of time, for instance from a search index, given as
+/// ascending char offsets. Positions not listed are never tried.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CandidatePositions(Vec<usize>);
+
+impl CandidatePositions {
+    pub fn new(mut positions: Vec<usize>) -> CandidatePositions {
+        positions.sort_unstable();
+        positions.dedup();
+        CandidatePositions(positions)
+    }
+}
+
+impl Prefilter for CandidatePositions {
+    fn find(&self, _text: &[char], start: usize, end: usize) -> Option<usize> {
+        let index = self.0.partition_point(|&position| position < start);
+        self.0
+            .get(index)
+            .copied()
+            .filter(|&position| position < end)
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.0.len() * size_of::<usize>()
+    }
+}
+
+/// The starts of lines, for patterns that begin with `^` in MULTILINE mode and so
+/// can only match at the haystack's start or right after a `\n`. Unlike other
+/// prefilters it can report `end` itself, where an empty last line starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineStarts;
+
+impl Prefilter for LineStarts {
+    fn find(&self, text: &[char], start: usize, end: usize) -> Option<usize> {
+        let end = end.min(text.len());
+        if start > end {
+            return None;
+        }
+        if start == 0 || text[start - 1] == '\n' {
+            return Some(start);
+        }
+        text[start..end]
+            .iter()
+            .position(|c| *c == '\n')
+            .map(|offset| start + offset + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_starts() {
+        let text: Vec<char> = "ab\ncd\n".chars().collect();
+        assert_eq!(LineStarts.find(&text, 0, 6), Some(0));
+        assert_eq!(LineStarts.find(&text, 1, 6), Some(3));
+        assert_eq!(LineStarts.find(&text, 4, 6), Some(6));
+        assert_eq!(LineStarts.find(&text, 4, 5), None);
+    }
+
+    #[test]
+    fn test_sparse_set() {
+        let mut set = SparseSet::new(8);
+        assert!(set.is_empty());
+        assert!(set.insert(5));
+        assert!(set.insert(0));
+        assert!(!set.insert(5));
+        assert!(set.contains(5) && set.contains(0) && !set.contains(3));
+        assert_eq!(set.iter().copied().collect::<Vec<_>>(), vec![5, 0]);
+
+        set.clear();
+        assert!(!set.contains(5) && set.is_empty());
+        assert!(set.insert(3));
+        assert_eq!(set.len(), 1);
+    }
+
+    #[test]
+    fn test_equivalence_classes() {
+        let classes = EquivalenceClasses::new(vec!['a', 'd', 'x', 'y']);
+        assert_eq!(classes.len(), 5);
+        assert_eq!(classes.class_of('b'), classes.class_of('c'));
+        assert_ne!(classes.class_of('c'), classes.class_of('d'));
+        assert_eq!(classes.class_of('\0'), classes.class_of('0'));
+        assert_eq!(classes.class_of('z'), classes.class_of(char::MAX));
+        assert_eq!(classes.representative(classes.class_of('x')), 'x');
+    }
+
+    #[test]
+    fn test_char_transforms() {
+        assert_eq!(simple_lowercase('Ä'), 'ä');
+        assert_eq!(simple_lowercase('İ'), 'İ');
+        assert_eq!(full_width_to_half_width('Ａ'), 'A');
+        assert_eq!(full_width_to_half_width('～'), '~');
+        assert_eq!(full_width_to_half_width('\u{3000}'), ' ');
+        assert_eq!(full_width_to_half_width('あ'), 'あ');
+        assert_eq!(ascii_fold('É'), 'E');
+        assert_eq!(ascii_fold('ø'), 'o');
+        assert_eq!(ascii_fold('ß'), 'ß');
+    }
+
+    #[test]
+    fn test_prefilter() {
+        let text: Vec<char> = "xxAbcabC".chars().collect();
+        assert_eq!(LiteralPrefilter::new(&[], true), None);
+
+        let exact = LiteralPrefilter::new(&['a', 'b'], false).unwrap();
+        assert_eq!(exact.find(&text, 0, text.len()), Some(5));
+        assert_eq!(exact.find(&text, 0, 6), None);
+
+        let variants = LiteralPrefilter::new(&['a', '-', 'b'], true).unwrap();
+        assert_eq!(
+            variants,
+            LiteralPrefilter::Variants(vec![
+                vec!['a', '-', 'b'],
+                vec!['a', '-', 'B'],
+                vec!['A', '-', 'b'],
+                vec!['A', '-', 'B'],
+            ])
+        );
+        let ignore_case = LiteralPrefilter::new(&['a', 'b'], true).unwrap();
+        assert_eq!(ignore_case.find(&text, 0, text.len()), Some(2));
+        assert_eq!(ignore_case.find(&text, 3, text.len()), Some(5));
+
+        let folded = LiteralPrefilter::new(&"abcab".chars().collect::<Vec<_>>(), true).unwrap();
+        assert!(matches!(folded, LiteralPrefilter::Folded(_)));
+        assert_eq!(folded.find(&text, 0, text.len()), Some(2));
+        assert_eq!(folded.find(&text, 3, text.len()), None);
+        assert_eq!(
+            folded.find(&"xAbCaB".chars().collect::<Vec<_>>(), 0, 6),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn test_contains_in_order() {
+        let literals = vec![String::from("ab"), String::from("cd")];
+        assert!(contains_in_order("xxabyycd", &literals));
+        assert!(!contains_in_order("cdab", &literals));
+        assert!(!contains_in_order("abcx", &literals));
+        assert!(contains_in_order("", &[]));
+
+        let folded = RequiredLiterals::new(vec![String::from("Ab"), String::from("c")], true);
+        assert_eq!(folded.literals(), ["ab", "c"]);
+        assert!(folded.found_in("xaBxC"));
+        assert!(!folded.found_in("cab"));
+    }
+
+    #[test]
+    fn test_candidate_positions() {
+        let text: Vec<char> = "abcdefgh".chars().collect();
+        let candidates = CandidatePositions::new(vec![6, 2, 4, 2]);
+        assert_eq!(candidates.find(&text, 0, 8), Some(2));
+        assert_eq!(candidates.find(&text, 3, 8), Some(4));
+        assert_eq!(candidates.find(&text, 5, 6), None);
+        assert_eq!(candidates.find(&text, 7, 8), None);
+    }
+}
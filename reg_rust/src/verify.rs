@@ -0,0 +1,369 @@
+//! Checks a compiled engine against an oracle that needs no compiler at all:
+//! Brzozowski derivatives taken straight off the parsed pattern.
+//!
+//! The oracle knows which texts the pattern matches, not which match an engine
+//! prefers, so the check compares the offsets at which some match starts, as
+//! `Matcher::match_positions` reports them, and then that the pattern matches
+//! the text of every match `Matcher::find_iter` finds. The haystacks are every short string
+//! over one char of each equivalence class of the pattern, so every transition
+//! gets exercised. Patterns using anything beyond plain regular expressions,
+//! such as anchors, lookarounds, backreferences or possessive quantifiers, are
+//! not checked.
+//!
+//! With the `verify` feature, `RegexNFA::new` and `PikeVM::new` run the check
+//! on everything they compile. It is slow and meant for developing engines.
+//!
+//! The same derivatives also decide whether two patterns match the same texts,
+//! which `equivalent` does for proving rewrites of a pattern sound, and find
+//! texts telling two patterns apart, which `distinguishing_texts` does.
+
+use std::{collections::VecDeque, rc::Rc};
+
+use itertools::Itertools;
+
+use crate::{
+    fsm::ReError,
+    matching::Matcher,
+    parser::{run_parse_with_limits, Greediness, Node, Quantifier, UpperBound},
+    utils::{EquivalenceClasses, RegexFlags},
+};
+
+/// Haystacks are every string of up to this many chars over the sample alphabet.
+const MAX_TEXT_LENGTH: usize = 3;
+/// Most chars in the sample alphabet; beyond it, classes are sampled evenly.
+const MAX_ALPHABET_SIZE: usize = 8;
+/// Most pairs of derivatives `equivalent` explores before giving up.
+const MAX_DERIVATIVE_PAIRS: usize = 2000;
+
+/// A pattern as a regular expression in the textbook sense, which derivatives
+/// are defined on. Shared subterms keep repeated derivatives small.
+#[derive(Debug, PartialEq)]
+enum Language {
+    Nothing,
+    Empty,
+    Char(Node),
+    Concat(Rc<Language>, Rc<Language>),
+    Union(Rc<Language>, Rc<Language>),
+    Star(Rc<Language>),
+}
+
+use Language::*;
+
+fn concat(left: Rc<Language>, right: Rc<Language>) -> Rc<Language> {
+    match (&*left, &*right) {
+        (Nothing, _) | (_, Nothing) => Rc::new(Nothing),
+        (Empty, _) => right,
+        (_, Empty) => left,
+        _ => Rc::new(Concat(left, right)),
+    }
+}
+
+fn union(left: Rc<Language>, right: Rc<Language>) -> Rc<Language> {
+    match (&*left, &*right) {
+        (Nothing, _) => right,
+        (_, Nothing) => left,
+        _ if left == right => left,
+        _ => Rc::new(Union(left, right)),
+    }
+}
+
+/// `language` repeated `count` times in a row.
+fn power(language: &Rc<Language>, count: u64) -> Rc<Language> {
+    (0..count).fold(Rc::new(Empty), |acc, _| concat(acc, language.clone()))
+}
+
+impl Language {
+    /// The language of `node`, or `None` if it uses something that is not a
+    /// plain regular expression.
+    fn of(node: &Node) -> Option<Rc<Language>> {
+        Some(match node {
+            Node::Character(_)
+            | Node::Dot
+            | Node::CharacterGroup(_, _)
+            | Node::CharacterRange(_, _)
+            | Node::UnicodeProperty(_) => Rc::new(Char(node.clone())),
+            Node::Flagged(item, _) if item.increment() != 0 => Rc::new(Char(node.clone())),
+            Node::EmptyString
+            | Node::Epsilon
+            | Node::GroupLink
+            | Node::GroupEntry(_)
+            | Node::GroupExit(_)
+            | Node::Callout(_) => Rc::new(Empty),
+            Node::Expression(items, alternative) => {
+                let mut sequence = Rc::new(Empty);
+                for item in items {
+                    sequence = concat(sequence, Language::of(item)?);
+                }
+                match alternative {
+                    Some(alternative) => union(sequence, Language::of(alternative)?),
+                    None => sequence,
+                }
+            }
+            Node::Match(item, quantifier) | Node::Group(item, _, quantifier) => {
+                Language::repeat(Language::of(item)?, quantifier)?
+            }
+            _ => return None,
+        })
+    }
+
+    fn repeat(item: Rc<Language>, quantifier: &Quantifier) -> Option<Rc<Language>> {
+        if quantifier.greediness() == Some(Greediness::Possessive) {
+            // giving nothing back can make texts fail that the plain repetition matches
+            return None;
+        }
+        let optional = union(item.clone(), Rc::new(Empty));
+        Some(match quantifier {
+            Quantifier::None => item,
+            Quantifier::ZeroOrOne(_) => optional,
+            Quantifier::ZeroOrMore(_) => Rc::new(Star(item)),
+            Quantifier::OneOrMore(_) => concat(item.clone(), Rc::new(Star(item))),
+            Quantifier::Range(lower, upper, _) => match upper {
+                UpperBound::Undefined => power(&item, *lower),
+                UpperBound::Unbounded => concat(power(&item, *lower), Rc::new(Star(item))),
+                UpperBound::Bounded(upper) => {
+                    concat(power(&item, *lower), power(&optional, upper - lower))
+                }
+            },
+        })
+    }
+
+    fn nullable(&self) -> bool {
+        match self {
+            Nothing | Char(_) => false,
+            Empty | Star(_) => true,
+            Concat(left, right) => left.nullable() && right.nullable(),
+            Union(left, right) => left.nullable() || right.nullable(),
+        }
+    }
+
+    /// The texts `t` such that `c` followed by `t` is in `self`.
+    fn derivative(self: &Rc<Language>, c: char, flags: RegexFlags) -> Rc<Language> {
+        match &**self {
+            Nothing | Empty => Rc::new(Nothing),
+            Char(node) if node.accepts_char(c, flags) => Rc::new(Empty),
+            Char(_) => Rc::new(Nothing),
+            Concat(left, right) => {
+                let through_left = concat(left.derivative(c, flags), right.clone());
+                if left.nullable() {
+                    union(through_left, right.derivative(c, flags))
+                } else {
+                    through_left
+                }
+            }
+            Union(left, right) => union(left.derivative(c, flags), right.derivative(c, flags)),
+            Star(item) => concat(item.derivative(c, flags), self.clone()),
+        }
+    }
+
+    /// Whether `text` is in `self`.
+    fn matches(self: &Rc<Language>, text: &[char], flags: RegexFlags) -> bool {
+        text.iter()
+            .fold(self.clone(), |language, &c| language.derivative(c, flags))
+            .nullable()
+    }
+
+    /// Every offset of `text` at which some match starts.
+    fn match_starts(self: &Rc<Language>, text: &[char], flags: RegexFlags) -> Vec<usize> {
+        (0..=text.len())
+            .filter(|&start| {
+                let mut language = self.clone();
+                language.nullable()
+                    || text[start..].iter().any(|&c| {
+                        language = language.derivative(c, flags);
+                        language.nullable()
+                    })
+            })
+            .collect()
+    }
+}
+
+/// Runs `engine`, compiled from `pattern`, on short generated haystacks and
+/// fails with `ReError::VerificationFailed` on the first one where it starts
+/// matches at other offsets than the derivative oracle, or with
+/// `ReError::MatchRejected` on the first match it finds that the pattern does
+/// not match. Patterns outside what the oracle understands pass unchecked.
+pub fn verify(pattern: &str, engine: &dyn Matcher) -> Result<(), ReError> {
+    // the engine's flags include any it was compiled with beyond the pattern's own
+    let mut flags = engine.get_flags();
+    // the engine was compiled under whatever limits it was given
+    let parsed = run_parse_with_limits(pattern, &mut flags, u64::MAX, usize::MAX)
+        .map_err(ReError::ParsingFailed)?;
+    let Some(language) = Language::of(&parsed.root) else {
+        return Ok(());
+    };
+    for text in haystacks(&[(&parsed.root, flags)]) {
+        let expected = language.match_starts(&text, flags);
+        let chars = text;
+        let text: String = chars.iter().collect();
+        let actual: Vec<usize> = engine.match_positions(&text).collect();
+        if actual != expected {
+            return Err(ReError::VerificationFailed(text, expected, actual));
+        }
+        let rejected = engine
+            .find_iter(&text)
+            .map(|found| found.span())
+            .find(|&(start, end)| !language.matches(&chars[start..end], flags));
+        if let Some((start, end)) = rejected {
+            return Err(ReError::MatchRejected(text, start, end));
+        }
+    }
+    Ok(())
+}
+
+/// Whether `left` and `right` match exactly the same texts under `flags`. Pairs
+/// of their derivatives by one char of each class are explored until a pair
+/// disagrees on matching the empty text, or none is new. `None` if either uses
+/// something beyond the oracle, or the derivatives do not settle soon enough.
+pub fn equivalent(left: &Node, right: &Node, flags: RegexFlags) -> Option<bool> {
+    distinguishing_texts(left, right, flags, 1).map(|texts| texts.is_empty())
+}
+
+/// Up to `limit` texts that exactly one of `left` and `right` matches in full
+/// under `flags`, each with whether `left` is the one matching it. The pairs of
+/// derivatives are explored breadth first, so the shortest texts come first.
+/// `None` if either uses something beyond the oracle, or the derivatives do not
+/// settle before the first such text turns up.
+pub fn distinguishing_texts(
+    left: &Node,
+    right: &Node,
+    flags: RegexFlags,
+    limit: usize,
+) -> Option<Vec<(String, bool)>> {
+    let start = (Language::of(left)?, Language::of(right)?);
+    let mut boundaries = Vec::new();
+    collect_boundaries(left, flags, &mut boundaries);
+    collect_boundaries(right, flags, &mut boundaries);
+    let alphabet: Vec<char> = EquivalenceClasses::new(boundaries)
+        .representatives()
+        .collect();
+    let mut texts = Vec::new();
+    let mut seen = vec![start.clone()];
+    let mut pending = VecDeque::from([(start, String::new())]);
+    while let Some(((left, right), text)) = pending.pop_front() {
+        if left.nullable() != right.nullable() {
+            texts.push((text.clone(), left.nullable()));
+            if texts.len() == limit {
+                break;
+            }
+        }
+        for &c in &alphabet {
+            let next = (left.derivative(c, flags), right.derivative(c, flags));
+            if !seen.contains(&next) {
+                if seen.len() == MAX_DERIVATIVE_PAIRS {
+                    return (!texts.is_empty()).then_some(texts);
+                }
+                seen.push(next.clone());
+                let mut text = text.clone();
+                text.push(c);
+                pending.push_back((next, text));
+            }
+        }
+    }
+    Some(texts)
+}
+
+/// The boundaries of the char classes that the char matchers in `node` tell apart.
+fn collect_boundaries(node: &Node, flags: RegexFlags, boundaries: &mut Vec<char>) {
+    match node {
+        Node::Expression(items, alternative) => {
+            for item in items {
+                collect_boundaries(item, flags, boundaries);
+            }
+            if let Some(alternative) = alternative {
+                collect_boundaries(alternative, flags, boundaries);
+            }
+        }
+        Node::Match(item, _) | Node::Group(item, _, _) => {
+            collect_boundaries(item, flags, boundaries)
+        }
+        _ => node.class_boundaries(flags, boundaries),
+    }
+}
+
+/// Every string of up to `MAX_TEXT_LENGTH` chars over one char of each class
+/// the char matchers of the patterns, each under its flags, tell apart.
+pub(crate) fn haystacks(patterns: &[(&Node, RegexFlags)]) -> Vec<Vec<char>> {
+    let mut boundaries = Vec::new();
+    for (root, flags) in patterns {
+        collect_boundaries(root, *flags, &mut boundaries);
+    }
+    let classes = EquivalenceClasses::new(boundaries);
+    let step = classes.len().div_ceil(MAX_ALPHABET_SIZE).max(1);
+    let alphabet: Vec<char> = classes.representatives().step_by(step).collect();
+    (0..=MAX_TEXT_LENGTH)
+        .flat_map(|length| {
+            (0..length)
+                .map(|_| alphabet.iter().copied())
+                .multi_cartesian_product()
+        })
+        .chain([Vec::new()])
+        .unique()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fsm::RegexNFA, parser::run_parse, vm::PikeVM};
+
+    #[test]
+    fn test_engines_agree_with_derivatives() {
+        for pattern in [
+            "ab|a",
+            "(a|b)*c",
+            "[a-c]+?x{1,2}",
+            "(?i)Ab?",
+            "a{2}|b{0,2}c",
+            r"\d+\.\d*",
+            "(?:ab)*",
+            "(?i:ab)c",
+            "(?:[ab]+b)?[ab]",
+            "",
+        ] {
+            let nfa = RegexNFA::new(pattern).unwrap();
+            let vm = PikeVM::new(pattern).unwrap();
+            assert!(verify(pattern, &nfa).is_ok(), "{}", pattern);
+            assert!(verify(pattern, &vm).is_ok(), "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn test_catches_a_wrong_engine() {
+        // an automaton for another pattern stands in for a miscompiled one
+        let wrong = RegexNFA::new("ab*").unwrap();
+        match verify("ab+", &wrong) {
+            Err(ReError::VerificationFailed(text, expected, actual)) => {
+                assert_eq!(text, "a");
+                assert_eq!((expected, actual), (vec![], vec![0]));
+            }
+            other => unreachable!("{:?}", other),
+        }
+        // matches that start in the right places but end in the wrong ones
+        let wrong = RegexNFA::new("ba|a|b").unwrap();
+        match verify("a|b", &wrong) {
+            Err(error @ ReError::MatchRejected(..)) => assert_eq!(
+                error.to_string(),
+                r#"the engine found a match at 0..2 in "ba", which the pattern does not match"#
+            ),
+            other => unreachable!("{:?}", other),
+        }
+        // anchors are beyond the oracle, so nothing is checked
+        assert!(verify("^a", &wrong).is_ok());
+    }
+
+    #[test]
+    fn test_equivalent() {
+        let equivalent = |left: &str, right: &str| {
+            let mut flags = RegexFlags::OPTIMIZE;
+            let left = run_parse(left, &mut flags).unwrap().root;
+            let right = run_parse(right, &mut flags).unwrap().root;
+            equivalent(&left, &right, flags)
+        };
+        assert_eq!(equivalent("a|b|c", "[a-c]"), Some(true));
+        assert_eq!(equivalent("(?:ab)*a", "a(?:ba)*"), Some(true));
+        assert_eq!(equivalent("a+|b", "a*|b"), Some(false));
+        assert_eq!(equivalent("x{2,3}", "xx|xxx"), Some(true));
+        assert_eq!(equivalent("(?i)k", "[Kk]"), Some(true));
+        assert_eq!(equivalent("a$", "a"), None);
+    }
+}
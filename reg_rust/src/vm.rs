@@ -1,213 +1,241 @@
-use std::{
-    collections::{HashMap, HashSet, VecDeque},
-    hash::Hash,
-};
+use std::mem::size_of;
 
 use itertools::Itertools;
 
 use crate::{
     fsm::ReError,
-    matching::Cursor,
+    matching::{Context, Cursor, GroupNames},
     parser::{run_parse, visitor::Visitor, Quantifier, UpperBound},
-    parser::{Data, Node},
-    utils::RegexFlags,
+    parser::{Data, GroupTree, LengthBounds, Node, SourceMap},
+    utils::{RegexFlags, RequiredLiterals, SparseSet},
 };
 
+/// Index of an instruction in `PikeVM::program`.
+pub type Pc = usize;
+
 #[derive(Debug, Hash, Eq, PartialEq, Clone)]
 pub enum Instruction {
     End,
     EmptyString,
-    Jump(Box<Instruction>),
-    Fork(Box<Instruction>, Box<Instruction>),
+    Jump(Pc),
+    Fork(Pc, Pc),
     Consume(Box<Node>),
     Capture(Box<Node>),
 }
 
 #[derive(Debug, Clone)]
 pub struct PikeVM {
-    pub next: HashMap<Instruction, Instruction>,
-    pub root: Instruction,
+    pub program: Vec<Instruction>,
+    /// Successor of every instruction that falls through
+    /// (`EmptyString`, `Consume` and `Capture`).
+    pub next: Vec<Pc>,
+    pub root: Pc,
     pub group_count: usize,
+    pub script_run_count: usize,
+    pub group_names: GroupNames,
+    pub group_tree: GroupTree,
+    pub source_map: SourceMap,
+    pub length_bounds: LengthBounds,
+    pub required_literals: RequiredLiterals,
     pub flags: RegexFlags,
 }
 
-pub type Thread<'inst> = (Instruction, Cursor);
-pub type VMSearchSpaceNode<'inst> = (usize, Instruction);
+pub type Thread = (Pc, Cursor);
+
+/// The threads alive at one position of the haystack, in priority order.
+/// `visited` holds every instruction already entered at this position, so each
+/// one is expanded at most once per step.
+#[derive(Debug)]
+pub struct Threads {
+    pub visited: SparseSet,
+    pub threads: Vec<Thread>,
+}
+
+impl Threads {
+    pub fn new(capacity: usize) -> Threads {
+        Threads {
+            visited: SparseSet::new(capacity),
+            threads: Vec::new(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.visited.clear();
+        self.threads.clear();
+    }
+}
+
+type Fragment = (Pc, Pc);
 
 impl PikeVM {
-    fn add_capturing_markers(
-        &mut self,
-        codes: &(Instruction, Instruction),
-        group_index: Option<usize>,
-    ) -> (Instruction, Instruction) {
+    /// Bytes held by the compiled program, counted from its instructions.
+    pub fn memory_usage(&self) -> usize {
+        let program: usize = self
+            .program
+            .iter()
+            .map(|instruction| {
+                size_of::<Instruction>()
+                    + match instruction {
+                        Instruction::Consume(node) | Instruction::Capture(node) => {
+                            node.memory_usage()
+                        }
+                        _ => 0,
+                    }
+            })
+            .sum();
+        size_of::<PikeVM>() + program + self.next.len() * size_of::<Pc>()
+    }
+
+    fn emit(&mut self, instruction: Instruction) -> Pc {
+        self.program.push(instruction);
+        self.next.push(Pc::MAX);
+        self.program.len() - 1
+    }
+
+    fn link(&mut self, from: Pc, to: Pc) {
+        self.next[from] = to;
+    }
+
+    fn fork(&mut self, preferred: Pc, alternative: Pc, lazy: bool) -> Pc {
+        if lazy {
+            self.emit(Instruction::Fork(alternative, preferred))
+        } else {
+            self.emit(Instruction::Fork(preferred, alternative))
+        }
+    }
+
+    fn empty(&mut self) -> Fragment {
+        let empty = self.emit(Instruction::EmptyString);
+        (empty, empty)
+    }
+
+    fn add_capturing_markers(&mut self, codes: Fragment, group_index: Option<usize>) -> Fragment {
         match group_index {
             Some(index) => {
-                let capturing_anchors = (
-                    Instruction::Capture(Box::new(Node::GroupEntry(index))),
-                    Instruction::Capture(Box::new(Node::GroupExit(index))),
-                );
-                self.next
-                    .insert(capturing_anchors.0.clone(), codes.0.clone());
-                self.next
-                    .insert(codes.1.clone(), capturing_anchors.1.clone());
-                capturing_anchors
+                let entry = self.emit(Instruction::Capture(Box::new(Node::GroupEntry(index))));
+                let exit = self.emit(Instruction::Capture(Box::new(Node::GroupExit(index))));
+                self.link(entry, codes.0);
+                self.link(codes.1, exit);
+                (entry, exit)
             }
-            _ => (codes.0.clone(), codes.1.clone()),
+            _ => codes,
         }
     }
 
-    fn zero_or_one(
-        &mut self,
-        codes: &(Instruction, Instruction),
-        lazy: bool,
-    ) -> (Instruction, Instruction) {
-        let empty = Instruction::EmptyString;
-        self.next.insert(codes.1.clone(), empty.clone());
-        (
-            if lazy {
-                Instruction::Fork(Box::new(empty.clone()), Box::new(codes.0.clone()))
-            } else {
-                Instruction::Fork(Box::new(codes.0.clone()), Box::new(empty.clone()))
-            },
-            empty,
-        )
+    fn zero_or_one(&mut self, codes: Fragment, lazy: bool) -> Fragment {
+        let (empty, _) = self.empty();
+        let fork = self.fork(codes.0, empty, lazy);
+        self.link(codes.1, empty);
+        (fork, empty)
     }
 
-    fn one_or_more(
-        &mut self,
-        codes: &(Instruction, Instruction),
-        lazy: bool,
-    ) -> (Instruction, Instruction) {
-        let empty = Instruction::EmptyString;
-        let fork = if lazy {
-            Instruction::Fork(Box::new(empty.clone()), Box::new(codes.0.clone()))
-        } else {
-            Instruction::Fork(Box::new(codes.0.clone()), Box::new(empty.clone()))
-        };
-        self.next.insert(codes.1.clone(), empty.clone());
-        (fork, empty)
+    fn one_or_more(&mut self, codes: Fragment, lazy: bool) -> Fragment {
+        let (empty, _) = self.empty();
+        let fork = self.fork(codes.0, empty, lazy);
+        self.link(codes.1, fork);
+        (codes.0, empty)
     }
 
-    fn zero_or_more(
-        &mut self,
-        codes: &(Instruction, Instruction),
-        lazy: bool,
-    ) -> (Instruction, Instruction) {
-        let empty = Instruction::EmptyString;
-        let fork = if lazy {
-            Instruction::Fork(Box::new(empty.clone()), Box::new(codes.0.clone()))
-        } else {
-            Instruction::Fork(Box::new(codes.0.clone()), Box::new(empty.clone()))
-        };
-        self.next
-            .insert(codes.1.clone(), Instruction::Jump(Box::new(empty.clone())));
+    fn zero_or_more(&mut self, codes: Fragment, lazy: bool) -> Fragment {
+        let (empty, _) = self.empty();
+        let fork = self.fork(codes.0, empty, lazy);
+        self.link(codes.1, fork);
         (fork, empty)
     }
 
+    fn concatenate(&mut self, fragments: &[Fragment]) -> Fragment {
+        for (a, b) in fragments.iter().tuple_windows() {
+            self.link(a.1, b.0);
+        }
+        (fragments.first().unwrap().0, fragments.last().unwrap().1)
+    }
+
     fn apply_range_quantifier(
         &mut self,
-        node: Node,
+        node: &Node,
         lower: u64,
         upperbound: UpperBound,
         lazy: bool,
-    ) -> (Instruction, Instruction) {
+    ) -> Fragment {
         if lower == 0 {
             if let UpperBound::Unbounded = upperbound {
-                let codes = node.accept(self);
-                return self.zero_or_more(&codes, lazy);
+                let codes = self.quantifiable_fragment(node);
+                return self.zero_or_more(codes, lazy);
             }
-            if let UpperBound::Undefined = upperbound {
-                return (Instruction::EmptyString, Instruction::EmptyString);
+            if let UpperBound::Undefined | UpperBound::Bounded(0) = upperbound {
+                return self.empty();
             }
         }
-        let mut fragments: Vec<(Instruction, Instruction)> = Vec::new();
+        let mut fragments: Vec<Fragment> = Vec::new();
         match upperbound {
             UpperBound::Unbounded => {
                 for _ in 0..(lower - 1) {
-                    let codes = node.accept(self);
-                    fragments.push(codes);
+                    fragments.push(self.quantifiable_fragment(node));
                 }
-                let instructions = node.accept(self);
-                fragments.push(self.one_or_more(&instructions, lazy));
+                let codes = self.quantifiable_fragment(node);
+                fragments.push(self.one_or_more(codes, lazy));
             }
             UpperBound::Undefined => {
                 for _ in 0..lower {
-                    let frag = node.accept(self);
-                    fragments.push(frag);
+                    fragments.push(self.quantifiable_fragment(node));
                 }
             }
             UpperBound::Bounded(upper) => {
-                for _ in 0..upper {
-                    fragments.push(node.accept(self));
+                for _ in 0..lower {
+                    fragments.push(self.quantifiable_fragment(node));
                 }
-                let empty = Instruction::EmptyString;
-                for _ in lower as usize..upper as usize {
-                    let codes = node.accept(self);
-                    let jump = Instruction::Jump(Box::new(empty.clone()));
-                    let fork = if lazy {
-                        Instruction::Fork(Box::new(jump), Box::new(codes.0.clone()))
-                    } else {
-                        Instruction::Fork(Box::new(codes.0.clone()), Box::new(jump))
-                    };
+                // every optional iteration can bail out straight to the common exit
+                let (exit, _) = self.empty();
+                for _ in lower..upper {
+                    let codes = self.quantifiable_fragment(node);
+                    let fork = self.fork(codes.0, exit, lazy);
                     fragments.push((fork, codes.1));
                 }
-                fragments.push((empty.clone(), empty))
+                fragments.push((exit, exit))
             }
         }
-        for (a, b) in fragments.iter().tuple_windows() {
-            self.next.insert(a.1.clone(), b.0.clone());
-        }
-        (
-            fragments.first().unwrap().0.clone(),
-            fragments.last().unwrap().1.clone(),
-        )
+        self.concatenate(&fragments)
     }
 
-    fn match_or_group(&mut self, node: Node) -> (Instruction, Instruction) {
+    /// Compiles a single iteration of a `Group` or `Match`, ignoring its quantifier.
+    fn quantifiable_fragment(&mut self, node: &Node) -> Fragment {
         match node {
-            Node::Group(node, group_index, quantifier) => {
-                let instructions = node.accept(self);
-                match quantifier {
-                    Quantifier::None => self.add_capturing_markers(&instructions, group_index),
-                    Quantifier::ZeroOrOne(lazy) => {
-                        let instructions = self.add_capturing_markers(&instructions, group_index);
-                        self.zero_or_one(&instructions, lazy);
-                        instructions
-                    }
-                    Quantifier::OneOrMore(lazy) => {
-                        let instructions = self.add_capturing_markers(&instructions, group_index);
-                        self.one_or_more(&instructions, lazy)
-                    }
-                    Quantifier::ZeroOrMore(lazy) => {
-                        let instructions = self.add_capturing_markers(&instructions, group_index);
-                        self.zero_or_more(&instructions, lazy);
-                        instructions
-                    }
-                    Quantifier::Range(lower, upper, lazy) => {
-                        self.apply_range_quantifier(*node, lower, upper, lazy)
-                    }
-                }
+            Node::Group(expression, group_index, _) => {
+                let codes = expression.accept(self);
+                self.add_capturing_markers(codes, *group_index)
+            }
+            Node::Match(item, _) => item.accept(self),
+            _ => panic!("expected Group or Match, not {:#?}", node),
+        }
+    }
+
+    fn match_or_group(&mut self, node: Node) -> Fragment {
+        if let Some(atomic) = node.possessive_as_atomic() {
+            return atomic.accept(self);
+        }
+        let quantifier = match node {
+            Node::Group(_, _, ref quantifier) | Node::Match(_, ref quantifier) => {
+                quantifier.clone()
             }
-            Node::Match(node, quantifier) => match quantifier {
-                Quantifier::None => node.accept(self),
-                Quantifier::OneOrMore(lazy) => {
-                    let instructions = node.accept(self);
-                    self.one_or_more(&instructions, lazy)
-                }
-                Quantifier::ZeroOrOne(lazy) => {
-                    let instructions = node.accept(self);
-                    self.zero_or_one(&instructions, lazy);
-                    instructions
-                }
-                Quantifier::ZeroOrMore(lazy) => {
-                    let instructions = node.accept(self);
-                    self.zero_or_more(&instructions, lazy)
-                }
-                Quantifier::Range(lower, upper, lazy) => {
-                    self.apply_range_quantifier(*node, lower, upper, lazy)
-                }
-            },
             _ => panic!("expected Group or Match, not {:#?}", node),
+        };
+        match quantifier {
+            Quantifier::None => self.quantifiable_fragment(&node),
+            Quantifier::ZeroOrOne(greediness) => {
+                let codes = self.quantifiable_fragment(&node);
+                self.zero_or_one(codes, greediness.is_lazy())
+            }
+            Quantifier::OneOrMore(greediness) => {
+                let codes = self.quantifiable_fragment(&node);
+                self.one_or_more(codes, greediness.is_lazy())
+            }
+            Quantifier::ZeroOrMore(greediness) => {
+                let codes = self.quantifiable_fragment(&node);
+                self.zero_or_more(codes, greediness.is_lazy())
+            }
+            Quantifier::Range(lower, upper, greediness) => {
+                self.apply_range_quantifier(&node, lower, upper, greediness.is_lazy())
+            }
         }
     }
 
@@ -218,94 +246,120 @@ impl PikeVM {
     pub fn new_with_flags(input: &str, flags: &mut RegexFlags) -> Result<PikeVM, ReError> {
         let parsing_result = run_parse(input, flags);
         match parsing_result {
-            Ok((node, gc)) => {
+            Ok(parsed) => {
                 let mut vm = PikeVM {
-                    next: HashMap::new(),
-                    root: Instruction::End,
-                    group_count: gc,
+                    program: Vec::new(),
+                    next: Vec::new(),
+                    root: 0,
+                    group_count: parsed.group_count,
+                    script_run_count: parsed.script_run_count,
+                    group_names: GroupNames::new(parsed.group_names),
+                    group_tree: parsed.group_tree,
+                    source_map: parsed.source_map,
+                    length_bounds: parsed.length_bounds,
+                    required_literals: parsed.required_literals,
                     flags: *flags,
                 };
-                let res = node.accept(&mut vm);
-                vm.root = res.0;
-                vm.next.insert(res.1, Instruction::End);
+                let root = if flags.intersects(RegexFlags::OPTIMIZE) {
+                    parsed.root.normalized()
+                } else {
+                    parsed.root
+                };
+                let codes = root.accept(&mut vm);
+                let end = vm.emit(Instruction::End);
+                vm.link(codes.1, end);
+                vm.root = codes.0;
+                // threads advance in lockstep one char at a time, so none can
+                // consume a whole captured string, an atomic repetition or a
+                // subroutine call in one step, or look around
+                for instruction in &vm.program {
+                    if let Instruction::Consume(node) = instruction {
+                        match **node {
+                            Node::Backreference(_) => {
+                                return Err(ReError::Unsupported(String::from("backreferences")))
+                            }
+                            Node::Lookahead(_) => {
+                                return Err(ReError::Unsupported(String::from("lookaheads")))
+                            }
+                            Node::Lookbehind(_, _) => {
+                                return Err(ReError::Unsupported(String::from("lookbehinds")))
+                            }
+                            Node::Callout(_) => {
+                                return Err(ReError::Unsupported(String::from("callouts")))
+                            }
+                            Node::Atomic(_) => {
+                                return Err(ReError::Unsupported(String::from(
+                                    "possessive quantifiers",
+                                )))
+                            }
+                            Node::Subroutine(_) => {
+                                return Err(ReError::Unsupported(String::from("subroutine calls")))
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                #[cfg(feature = "verify")]
+                crate::verify::verify(input, &vm)?;
                 Ok(vm)
             }
             Err(err) => Err(ReError::ParsingFailed(err)),
         }
     }
 
-    fn primitive(node: Node) -> (Instruction, Instruction) {
-        (
-            Instruction::Consume(Box::new(node.clone())),
-            Instruction::Consume(Box::new(node)),
-        )
+    fn primitive(&mut self, node: Node) -> Fragment {
+        let consume = self.emit(Instruction::Consume(Box::new(node)));
+        (consume, consume)
     }
 
-    pub fn queue_thread(
-        &self,
-        queue: &mut VecDeque<Thread>,
-        thread: Thread,
-        visited: &mut HashSet<VMSearchSpaceNode>,
-    ) {
+    /// Follows every non-consuming instruction reachable from `thread`, queueing
+    /// the `Consume` and `End` instructions it reaches in priority order.
+    pub fn add_thread(&self, threads: &mut Threads, thread: Thread, context: &Context) {
         let mut stack = vec![thread];
 
-        while !stack.is_empty() {
-            let (instruction, cursor) = stack.pop().unwrap();
-            let vm_node = (cursor.position, instruction.clone());
-
-            if visited.contains(&vm_node) {
+        while let Some((pc, cursor)) = stack.pop() {
+            if !threads.visited.insert(pc) {
                 continue;
             }
-            visited.insert(vm_node);
 
-            match instruction.clone() {
-                Instruction::EmptyString => {
-                    stack.push((self.next.get(&instruction).unwrap().clone(), cursor))
-                }
+            match &self.program[pc] {
+                Instruction::EmptyString => stack.push((self.next[pc], cursor)),
                 Instruction::Jump(target) => stack.push((*target, cursor)),
                 Instruction::Fork(preferred, alternative) => {
                     stack.push((*alternative, cursor.clone()));
                     stack.push((*preferred, cursor));
                 }
-                Instruction::Capture(capturing_anchor) => stack.push((
-                    self.next.get(&instruction).unwrap().clone(),
-                    cursor.update(&*capturing_anchor),
-                )),
-                _ => queue.push_front((instruction.clone(), cursor.clone())),
+                Instruction::Capture(capturing_anchor) => {
+                    stack.push((self.next[pc], cursor.update(capturing_anchor)))
+                }
+                Instruction::Consume(anchor) if anchor.increment() == 0 => {
+                    if anchor.accepts(&cursor, context) {
+                        stack.push((self.next[pc], cursor))
+                    }
+                }
+                Instruction::Consume(_) | Instruction::End => threads.threads.push((pc, cursor)),
             }
         }
     }
 }
 
 impl Visitor for PikeVM {
-    type Result = (Instruction, Instruction);
+    type Result = Fragment;
 
     fn visit_expression(&mut self, expression: Node) -> Self::Result {
         match expression {
             Node::Expression(items, alternate) => {
-                let mut preferred_codes: Vec<(Instruction, Instruction)> = Vec::new();
-                for subexpr in items {
-                    preferred_codes.push(subexpr.accept(self))
-                }
-                for (a, b) in preferred_codes.iter().tuple_windows() {
-                    self.next.insert(a.1.clone(), b.0.clone());
-                }
-                let codes = (
-                    preferred_codes.first().unwrap().0.clone(),
-                    preferred_codes.last().unwrap().1.clone(),
-                );
+                let preferred_codes: Vec<Fragment> =
+                    items.iter().map(|subexpr| subexpr.accept(self)).collect();
+                let codes = self.concatenate(&preferred_codes);
 
                 if let Some(node) = alternate {
                     let alt_codes = node.accept(self);
-                    let empty = Instruction::EmptyString;
-                    self.next
-                        .insert(codes.1, Instruction::Jump(Box::new(empty.clone())));
-                    self.next.insert(alt_codes.1, empty.clone());
-
-                    return (
-                        Instruction::Fork(Box::new(codes.0), Box::new(alt_codes.0)),
-                        empty,
-                    );
+                    let (empty, _) = self.empty();
+                    self.link(codes.1, empty);
+                    self.link(alt_codes.1, empty);
+
+                    return (self.fork(codes.0, alt_codes.0, false), empty);
                 }
                 codes
             }
@@ -314,18 +368,22 @@ impl Visitor for PikeVM {
     }
 
     fn visit_character(&mut self, character: Node) -> Self::Result {
-        Self::primitive(character)
+        self.primitive(character)
     }
 
     fn visit_anchor(&mut self, anchor: Node) -> Self::Result {
         match anchor {
-            Node::EmptyString => (Instruction::EmptyString, Instruction::EmptyString),
-            _ => Self::primitive(anchor),
+            Node::EmptyString => self.empty(),
+            Node::ScriptRunEntry(_) => {
+                let capture = self.emit(Instruction::Capture(Box::new(anchor)));
+                (capture, capture)
+            }
+            _ => self.primitive(anchor),
         }
     }
 
     fn visit_dot(&mut self, dot: Node) -> Self::Result {
-        Self::primitive(dot)
+        self.primitive(dot)
     }
 
     fn visit_match(&mut self, match_: Node) -> Self::Result {
@@ -333,7 +391,7 @@ impl Visitor for PikeVM {
     }
 
     fn visit_character_group(&mut self, character_group: Node) -> Self::Result {
-        Self::primitive(character_group)
+        self.primitive(character_group)
     }
 
     fn visit_group(&mut self, group: Node) -> Self::Result {